@@ -0,0 +1,237 @@
+/// Benchmark an existing index (opened read-only), printing the results as JSON:
+/// - indexing throughput over a block range (fetched via JSONRPC, without persisting the rows),
+/// - status computation latency, for script hashes sampled from the block range's outputs,
+/// - DB scan throughput, per row type.
+///
+/// The block range and the sample size are set by `BENCH_START_HEIGHT` (default: 100 blocks
+/// below the indexed tip), `BENCH_BLOCKS` (default: 100) and `BENCH_SAMPLES` (default: 100).
+/// Run it using `cargo run --release --example bench -- <electrs options>`, and compare its
+/// output between releases for detecting performance regressions.
+extern crate electrs;
+#[macro_use]
+extern crate error_chain;
+
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate serde_json;
+
+use electrs::{
+    app::App,
+    cache::{BlockCache, BlockTxIDsCache, MerkleProofCache, StatusCache, TransactionCache},
+    config::Config,
+    daemon::Daemon,
+    errors::*,
+    index::{index_block, Index},
+    inspect::ROW_TYPES,
+    metrics::Metrics,
+    query::Query,
+    signal::Waiter,
+    store::{DBStore, ReadStore},
+    types::ScriptHash,
+};
+use error_chain::ChainedError;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::env;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+fn env_usize(name: &str) -> Result<Option<usize>> {
+    match env::var(name) {
+        Ok(value) => Ok(Some(
+            value.parse().chain_err(|| format!("invalid {}", name))?,
+        )),
+        Err(_) => Ok(None),
+    }
+}
+
+fn rate(count: usize, elapsed: Duration) -> f64 {
+    count as f64 / elapsed.as_secs_f64().max(1e-9)
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1e3
+}
+
+/// Indexes the blocks at `heights`, returning the results and up to `samples` script hashes.
+fn bench_index(app: &App, heights: &[usize], samples: usize) -> Result<(Value, Vec<ScriptHash>)> {
+    let (mut fetch, mut index) = (Duration::default(), Duration::default());
+    let (mut txs, mut rows) = (0, 0);
+    let mut script_hashes = HashSet::new();
+    for &height in heights {
+        let entry = app
+            .index()
+            .get_header(height)
+            .chain_err(|| format!("no header at height {}", height))?;
+        let start = Instant::now();
+        let block = app.daemon().getblock(entry.hash())?;
+        fetch += start.elapsed();
+
+        let start = Instant::now();
+        rows += index_block(&block, height).count();
+        index += start.elapsed();
+
+        txs += block.txdata.len();
+        for output in block.txdata.iter().flat_map(|tx| tx.output.iter()) {
+            if script_hashes.len() < samples {
+                script_hashes.insert(ScriptHash::from_script(&output.script_pubkey));
+            }
+        }
+    }
+    let result = json!({
+        "blocks": heights.len(),
+        "txs": txs,
+        "rows": rows,
+        "fetch_secs": fetch.as_secs_f64(),
+        "index_secs": index.as_secs_f64(),
+        "blocks_per_sec": rate(heights.len(), fetch + index),
+        "rows_per_sec": rate(rows, index),
+    });
+    Ok((result, script_hashes.into_iter().collect()))
+}
+
+/// Computes the (uncached) status of each script hash, using the confirmed history only.
+fn bench_status(query: &Query, script_hashes: &[ScriptHash]) -> Result<Value> {
+    let mut durations = vec![];
+    let mut history_len = 0;
+    for script_hash in script_hashes {
+        let start = Instant::now();
+        let status = query.status(script_hash)?;
+        durations.push(start.elapsed());
+        history_len += status.history().len();
+    }
+    durations.sort();
+    let percentile = |p: usize| {
+        durations
+            .get(durations.len().saturating_sub(1) * p / 100)
+            .map(|d| millis(*d))
+    };
+    Ok(json!({
+        "samples": durations.len(),
+        "history_entries": history_len,
+        "min_ms": percentile(0),
+        "median_ms": percentile(50),
+        "p90_ms": percentile(90),
+        "p99_ms": percentile(99),
+        "max_ms": percentile(100),
+    }))
+}
+
+/// Scans each row type via `ReadStore::scan()` (as queries do), in 256 chunks to bound memory
+/// usage.
+fn bench_scan(store: &dyn ReadStore) -> Value {
+    let mut result = serde_json::Map::new();
+    for (name, prefix) in ROW_TYPES {
+        let start = Instant::now();
+        let (mut rows, mut bytes) = (0, 0);
+        if let Some(value) = store.get(prefix) {
+            rows += 1; // e.g. the tip row, whose key is just the prefix
+            bytes += prefix.len() + value.len();
+        }
+        for byte in 0..=255u8 {
+            let mut chunk = prefix.to_vec();
+            chunk.push(byte);
+            for row in store.scan(&chunk) {
+                rows += 1;
+                bytes += row.key.len() + row.value.len();
+            }
+        }
+        let elapsed = start.elapsed();
+        result.insert(
+            name.to_string(),
+            json!({
+                "rows": rows,
+                "bytes": bytes,
+                "secs": elapsed.as_secs_f64(),
+                "rows_per_sec": rate(rows, elapsed),
+                "bytes_per_sec": rate(bytes, elapsed),
+            }),
+        );
+    }
+    Value::Object(result)
+}
+
+fn run(config: Config) -> Result<()> {
+    if !config.db_path.exists() {
+        bail!(
+            "DB {:?} must exist when running this benchmark!",
+            config.db_path
+        );
+    }
+    let signal = Waiter::start();
+    let metrics = Metrics::new(config.monitoring_addr);
+    let daemon = Daemon::new(
+        &config.daemon_dir,
+        &config.blocks_dir,
+        &config.daemon_rpc_addr,
+        config.cookie_getter(),
+        config.daemon_limits,
+        config.network_type,
+        signal.clone(),
+        Arc::new(BlockTxIDsCache::new(0, &metrics)),
+        &metrics,
+    )?;
+    let store = DBStore::open_read_only(&config.db_path)?;
+    let index = Index::load(&store, &daemon, &metrics, config.index_batch_size, None)?;
+    let tip_height = index
+        .best_header()
+        .chain_err(|| "no headers indexed")?
+        .height();
+    let app = App::new(store, index, daemon, &config)?;
+
+    let blocks = env_usize("BENCH_BLOCKS")?.unwrap_or(100);
+    let samples = env_usize("BENCH_SAMPLES")?.unwrap_or(100);
+    let start_height =
+        env_usize("BENCH_START_HEIGHT")?.unwrap_or_else(|| (tip_height + 1).saturating_sub(blocks));
+    let heights: Vec<usize> = (start_height..=tip_height).take(blocks).collect();
+    info!(
+        "benchmarking {} blocks from height {}",
+        heights.len(),
+        start_height
+    );
+    let (index_result, script_hashes) = bench_index(&app, &heights, samples)?;
+
+    let query = Query::new(
+        app.clone(),
+        &metrics,
+        TransactionCache::new(config.tx_cache_size, &metrics),
+        BlockCache::new(config.block_cache_size, &metrics),
+        MerkleProofCache::new(
+            config.merkle_cache_size,
+            config.merkle_tree_blocks,
+            &metrics,
+        ),
+        StatusCache::new(0, &metrics), // measure the computation
+        None,
+        config.mempool_filter,
+        config.txid_limit,
+        config.max_history,
+        config.max_status_size,
+        config.max_fee_rate,
+        None,
+    );
+    info!("benchmarking {} script hashes", script_hashes.len());
+    let status_result = bench_status(&query, &script_hashes)?;
+
+    info!("benchmarking DB scans");
+    let scan_result = bench_scan(app.read_store());
+
+    let result = json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "network": format!("{:?}", config.network_type),
+        "tip_height": tip_height,
+        "index": index_result,
+        "status": status_result,
+        "scan": scan_result,
+    });
+    println!("{}", serde_json::to_string_pretty(&result).unwrap());
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run(Config::from_args()) {
+        error!("{}", e.display_chain());
+        std::process::exit(1);
+    }
+}
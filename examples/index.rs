@@ -16,21 +16,28 @@ fn run() -> Result<()> {
     let signal = Waiter::start();
     let config = Config::from_args();
     let metrics = Metrics::new(config.monitoring_addr);
-    metrics.start();
+    metrics.start(config.monitoring_access.clone(), config.ready_max_lag);
     let cache = Arc::new(BlockTxIDsCache::new(0, &metrics));
 
     let daemon = Daemon::new(
         &config.daemon_dir,
         &config.blocks_dir,
-        config.daemon_rpc_addr,
+        &config.daemon_rpc_addr,
         config.cookie_getter(),
+        config.daemon_limits,
         config.network_type,
         signal.clone(),
         cache,
         &metrics,
     )?;
     let fake_store = FakeStore {};
-    let index = Index::load(&fake_store, &daemon, &metrics, config.index_batch_size)?;
+    let index = Index::load(
+        &fake_store,
+        &daemon,
+        &metrics,
+        config.index_batch_size,
+        None,
+    )?;
     index.update(&fake_store, &signal)?;
     Ok(())
 }
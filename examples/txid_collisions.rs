@@ -40,7 +40,7 @@ fn run(config: Config) {
     if !config.db_path.exists() {
         panic!("DB {:?} must exist when running this tool!", config.db_path);
     }
-    let store = DBStore::open(&config.db_path, /*low_memory=*/ false);
+    let store = DBStore::open(&config.db_path, /*low_memory=*/ false).expect("failed to open DB");
     max_collision(store, b"T");
 }
 
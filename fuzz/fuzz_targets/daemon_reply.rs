@@ -0,0 +1,18 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use electrs::daemon::{parse_jsonrpc_reply, read_response};
+use std::io::Cursor;
+
+const MAX_RESPONSE_SIZE: usize = 1 << 20;
+
+// bitcoind's HTTP responses, containing JSONRPC replies.
+fuzz_target!(|data: &[u8]| {
+    let mut reader = Cursor::new(data);
+    if let Ok(contents) = read_response(&mut reader, Some(MAX_RESPONSE_SIZE), String::new) {
+        assert!(contents.len() <= MAX_RESPONSE_SIZE);
+        if let Ok(reply) = serde_json::from_str(&contents) {
+            let _ = parse_jsonrpc_reply(reply, "getblockchaininfo", 1);
+        }
+    }
+});
@@ -0,0 +1,16 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use electrs::protocol::{parse_request, read_line, MAX_METHOD_LEN, MAX_PARAMS};
+use std::io::Cursor;
+
+// Electrum connections' input: newline-delimited JSONRPC requests.
+fuzz_target!(|data: &[u8]| {
+    let mut reader = Cursor::new(data);
+    while let Ok(Some(line)) = read_line(&mut reader) {
+        if let (_id, Ok(request)) = parse_request(&line) {
+            assert!(request.method.len() <= MAX_METHOD_LEN);
+            assert!(request.params.len() <= MAX_PARAMS);
+        }
+    }
+});
@@ -0,0 +1,19 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use electrs::index::{TxInRow, TxKey, TxOutRow};
+
+// DB rows' keys and values, which may be read from a corrupted (or foreign) DB.
+fn check<T: serde::Serialize + serde::de::DeserializeOwned>(data: &[u8]) {
+    if let Ok(row) = bincode::deserialize::<T>(data) {
+        let encoded = bincode::serialize(&row).unwrap();
+        assert!(data.starts_with(&encoded)); // fixed-size encoding
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    check::<TxInRow>(data);
+    check::<TxOutRow>(data);
+    check::<TxKey>(data);
+    check::<u32>(data); // TxRow's height
+});
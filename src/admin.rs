@@ -0,0 +1,195 @@
+//! Admin control socket, for inspecting and managing a running server.
+//!
+//! Accepts newline-separated JSON requests (e.g. `{"method": "clients"}`) over a Unix socket,
+//! and responds with a single line of `{"result": ...}` or `{"error": "..."}`.
+//! Supported methods: `clients`, `disconnect` (with `params: ["addr:port"]`), `sync`,
+//! `cache_stats`, `stats`, `reload` and `compact`.
+
+use error_chain::ChainedError;
+use serde_json::Value;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::errors::*;
+use crate::metrics::Metrics;
+use crate::query::Query;
+use crate::rpc::Clients;
+use crate::signal::Waker;
+use crate::util::spawn_thread;
+
+// metrics returned by `stats` (in addition to the caches' ones)
+const STATS_METRICS: &[&str] = &[
+    "electrs_electrum_connections",
+    "electrs_electrum_subscriptions",
+    "electrs_electrum_header_subscriptions",
+    "electrs_electrum_queued_requests",
+    "electrs_index_height",
+    "electrs_index_lag",
+    "electrs_index_tip_age",
+    "electrs_daemon_header_height",
+    "electrs_daemon_sync_progress",
+    "electrs_mempool_count",
+    "electrs_mempool_vsize",
+    "electrs_process_memory_rss",
+    "electrs_process_open_fds",
+    "electrs_process_threads",
+];
+
+#[derive(Clone)]
+struct Admin {
+    clients: Clients,
+    query: Arc<Query>,
+    metrics: Metrics,
+    waker: Waker,
+}
+
+impl Admin {
+    fn handle_command(&self, method: &str, params: &[Value]) -> Result<Value> {
+        Ok(match method {
+            "clients" => {
+                let clients: Vec<Value> = self
+                    .clients
+                    .list()
+                    .into_iter()
+                    .map(|(addr, stats)| {
+                        json!({
+                            "addr": addr.to_string(),
+                            "requests": stats.requests,
+                            "bytes_received": stats.bytes_received,
+                            "bytes_sent": stats.bytes_sent,
+                            "subscriptions": stats.subscriptions,
+                            "software": stats.software,
+                            "protocol": stats.protocol,
+                        })
+                    })
+                    .collect();
+                json!(clients)
+            }
+            "disconnect" => {
+                let addr: SocketAddr = params
+                    .get(0)
+                    .and_then(Value::as_str)
+                    .chain_err(|| "missing client address")?
+                    .parse()
+                    .chain_err(|| "invalid client address")?;
+                if !self.clients.disconnect(&addr) {
+                    bail!("client {} is not connected", addr);
+                }
+                Value::Null
+            }
+            "sync" => {
+                self.waker.wake(); // handled asynchronously by the main loop
+                Value::Null
+            }
+            "cache_stats" => self.metrics.dump(|name| name.contains("_cache")),
+            "stats" => {
+                let metrics = self
+                    .metrics
+                    .dump(|name| name.contains("_cache") || STATS_METRICS.contains(&name));
+                let db: serde_json::Map<String, Value> = self
+                    .query
+                    .db_properties()
+                    .into_iter()
+                    .map(|(name, value)| (name.to_owned(), json!(value)))
+                    .collect();
+                json!({"metrics": metrics, "db": db})
+            }
+            "reload" => {
+                self.waker.reload(); // handled asynchronously by the main loop, like SIGHUP
+                Value::Null
+            }
+            "compact" => {
+                self.waker.compact(); // handled asynchronously by the main loop, like SIGUSR2
+                Value::Null
+            }
+            _ => bail!("unknown method {}", method),
+        })
+    }
+
+    fn handle_request(&self, line: &str) -> Value {
+        let result = serde_json::from_str::<Value>(line)
+            .chain_err(|| "invalid JSON")
+            .and_then(|request| {
+                let method = request
+                    .get("method")
+                    .and_then(Value::as_str)
+                    .chain_err(|| "missing method")?;
+                let params = request
+                    .get("params")
+                    .and_then(Value::as_array)
+                    .map_or(&[][..], Vec::as_slice);
+                self.handle_command(method, params)
+            });
+        match result {
+            Ok(result) => json!({ "result": result }),
+            Err(e) => json!({ "error": e.display_chain().to_string() }),
+        }
+    }
+
+    fn handle_connection(&self, stream: UnixStream) -> Result<()> {
+        let mut writer = stream
+            .try_clone()
+            .chain_err(|| "failed to clone UnixStream")?;
+        for line in BufReader::new(stream).lines() {
+            let line = line.chain_err(|| "failed to read request")?;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            debug!("admin request: {}", line);
+            let response = self.handle_request(line);
+            writer
+                .write_all(format!("{}\n", response).as_bytes())
+                .chain_err(|| "failed to send response")?;
+        }
+        Ok(())
+    }
+}
+
+/// Serves admin requests at `path` (accessible only by the current user).
+pub fn start(
+    path: &Path,
+    clients: Clients,
+    query: Arc<Query>,
+    metrics: &Metrics,
+    waker: Waker,
+) -> Result<()> {
+    if path.exists() {
+        // stale socket, left by a previous run
+        fs::remove_file(path).chain_err(|| format!("failed to remove {:?}", path))?;
+    }
+    let listener =
+        UnixListener::bind(path).chain_err(|| format!("failed to bind admin socket {:?}", path))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+        .chain_err(|| format!("failed to set permissions of {:?}", path))?;
+    info!("admin socket listening on {:?}", path);
+    let admin = Admin {
+        clients,
+        query,
+        metrics: metrics.clone(),
+        waker,
+    };
+    spawn_thread("admin", move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("admin socket accept failed: {}", e);
+                    continue;
+                }
+            };
+            let admin = admin.clone();
+            spawn_thread("admin_conn", move || {
+                if let Err(e) = admin.handle_connection(stream) {
+                    warn!("admin connection failed: {}", e.display_chain());
+                }
+            });
+        }
+    });
+    Ok(())
+}
@@ -1,14 +1,28 @@
 use bitcoin::hash_types::BlockHash;
 use std::sync::{Arc, Mutex};
+use std::thread;
 
-use crate::{config::Config, daemon, errors::*, index, signal::Waiter, store};
+use crate::{
+    config::Config,
+    daemon,
+    errors::*,
+    index,
+    replication::Publisher,
+    signal::Waiter,
+    store::{self, WriteStore},
+    util::spawn_thread,
+};
 
 pub struct App {
     store: store::DBStore,
     index: index::Index,
     daemon: daemon::Daemon,
-    banner: String,
+    banner: Mutex<String>,
     tip: Mutex<BlockHash>,
+    daemon_ibd: Mutex<Option<f64>>, // bitcoind's progress, while in IBD
+    compaction: Mutex<()>,          // held by a running manual compaction
+    compaction_threads: Mutex<Vec<thread::JoinHandle<()>>>, // joined on shutdown
+    publisher: Mutex<Option<Publisher>>, // streams the index updates to hot-standby servers
 }
 
 impl App {
@@ -22,12 +36,16 @@ impl App {
             store,
             index,
             daemon: daemon.reconnect()?,
-            banner: config.server_banner.clone(),
+            banner: Mutex::new(config.server_banner.clone()),
             tip: Mutex::new(BlockHash::default()),
+            daemon_ibd: Mutex::new(None),
+            compaction: Mutex::new(()),
+            compaction_threads: Mutex::new(vec![]),
+            publisher: Mutex::new(None),
         }))
     }
 
-    fn write_store(&self) -> &impl store::WriteStore {
+    fn write_store(&self) -> &impl WriteStore {
         &self.store
     }
     // TODO: use index for queries.
@@ -40,21 +58,115 @@ impl App {
     pub fn daemon(&self) -> &daemon::Daemon {
         &self.daemon
     }
+    /// Persists the DB's memtables, e.g. before exiting while other threads still hold the app
+    /// (so it's not dropped).
+    pub fn flush_store(&self) {
+        self.store.flush_memtables();
+    }
+
+    pub fn db_properties(&self) -> Vec<(&'static str, String)> {
+        self.store.properties()
+    }
 
     pub fn update(&self, signal: &Waiter) -> Result<bool> {
         let mut tip = self.tip.lock().expect("failed to lock tip");
         let new_block = *tip != self.daemon().getbestblockhash()?;
         if new_block {
-            *tip = self.index().update(self.write_store(), signal)?;
+            *tip = match *self.publisher.lock().unwrap() {
+                Some(ref publisher) => {
+                    let store = publisher.store(self.write_store());
+                    self.index().update(&store, signal)?
+                }
+                None => self.index().update(self.write_store(), signal)?,
+            };
         }
         Ok(new_block)
     }
 
+    /// Publishes the following index updates to hot-standby servers.
+    pub fn set_publisher(&self, publisher: Publisher) {
+        *self.publisher.lock().unwrap() = Some(publisher);
+    }
+
+    /// Writes an index update replicated from a primary server, returning false if its blocks
+    /// don't connect to the index (e.g. after missing some of the primary's updates).
+    pub fn apply_replicated(&self, rows: &store::RowBatch) -> Result<bool> {
+        let mut tip = self.tip.lock().expect("failed to lock tip");
+        if !self.index().connects(rows)? {
+            return Ok(false);
+        }
+        self.write_store().write_batch(rows);
+        self.write_store().flush();
+        self.index().reload(&self.store)?;
+        if let Some(header) = self.index().best_header() {
+            *tip = *header.hash();
+        }
+        Ok(true)
+    }
+
+    /// Exports the index and bitcoind heights, returning bitcoind's best header height.
+    pub fn update_lag(&self) -> Result<usize> {
+        let daemon_height = self.daemon().get_header_height()?;
+        self.index().update_lag(daemon_height);
+        Ok(daemon_height)
+    }
+
+    /// Checks whether bitcoind is in initial block download (e.g. after restarting it with
+    /// `-reindex`), in which case the index shouldn't follow its partial chain.
+    pub fn update_daemon_ibd(&self) -> Result<Option<f64>> {
+        let progress = self.daemon().ibd_progress()?;
+        *self.daemon_ibd.lock().unwrap() = progress;
+        Ok(progress)
+    }
+
+    /// Fails while bitcoind is in initial block download, since the index may be incomplete.
+    pub fn check_daemon_synced(&self) -> Result<()> {
+        match *self.daemon_ibd.lock().unwrap() {
+            Some(progress) => bail!(ErrorKind::DaemonSyncing(progress)),
+            None => Ok(()),
+        }
+    }
+
+    /// Flushes and compacts the DB in a background thread (unless it's already running),
+    /// so the index keeps being updated meanwhile.
+    pub fn start_compaction(app: &Arc<App>) {
+        let thread_app = Arc::clone(app);
+        let handle = spawn_thread("compaction", move || {
+            match thread_app.compaction.try_lock() {
+                Ok(_guard) => thread_app.store.flush_and_compact(),
+                Err(_) => warn!("manual compaction is already running"),
+            }
+        });
+        app.compaction_threads.lock().unwrap().push(handle);
+    }
+
+    /// Waits for the manual compactions to finish (e.g. before exiting), since their threads
+    /// hold the app (and keep the DB open).
+    pub fn join_compactions(&self) {
+        let handles: Vec<_> = self.compaction_threads.lock().unwrap().drain(..).collect();
+        if !handles.is_empty() {
+            info!("waiting for manual compaction to finish");
+        }
+        for handle in handles {
+            if let Err(e) = handle.join() {
+                error!("compaction thread panicked: {:?}", e);
+            }
+        }
+    }
+
+    pub fn set_banner(&self, banner: String) {
+        *self.banner.lock().unwrap() = banner;
+    }
+
     pub fn get_banner(&self) -> Result<String> {
-        Ok(format!(
+        let mut banner = format!(
             "{}\n{}",
-            self.banner,
+            self.banner.lock().unwrap(),
             self.daemon.get_subversion()?
-        ))
+        );
+        if let Err(e) = self.check_daemon_synced() {
+            banner.push_str(&format!("\n{}", e));
+        }
+        Ok(banner)
     }
 }
@@ -1,88 +1,494 @@
 extern crate electrs;
 
+#[macro_use]
 extern crate error_chain;
 #[macro_use]
 extern crate log;
 
+use bitcoin::hashes::hex::ToHex;
 use error_chain::ChainedError;
+use serde_json::{json, Value};
+use std::net::TcpListener;
 use std::process;
 use std::sync::Arc;
 
 use electrs::{
+    admin,
     app::App,
     bulk,
-    cache::{BlockTxIDsCache, TransactionCache},
-    config::Config,
+    cache::{
+        BlockCache, BlockTxIDsCache, MerkleProofCache, StatusCache, TransactionCache, TweaksCache,
+    },
+    config::{Command, Config, NetworkConfig},
     daemon::Daemon,
+    daemonize,
     errors::*,
     index::Index,
+    inspect, logger,
     metrics::Metrics,
+    migration,
     query::Query,
+    replication::{self, Publisher},
+    rest,
     rpc::RPC,
-    signal::Waiter,
+    signal::{Waiter, Waker},
     store::{full_compaction, is_fully_compacted, DBStore},
+    systemd,
+    types::ScriptHash,
 };
 
-fn run_server(config: &Config) -> Result<()> {
-    let signal = Waiter::start();
-    let metrics = Metrics::new(config.monitoring_addr);
-    metrics.start();
+fn reload_config(app: &App, server: Option<&RPC>) {
+    match Config::reload() {
+        Ok(config) => {
+            info!("reloaded {:?}", config);
+            logger::set_verbosity(config.verbosity);
+            app.set_banner(config.server_banner.clone());
+            if let Some(server) = server {
+                server.reload(&config);
+            }
+        }
+        Err(e) => error!("failed to reload configuration: {}", e.display_chain()),
+    }
+}
+
+/// Opens the network's DB (read-only, if `read_only` is set), and performs the initial indexing.
+fn open_app(
+    config: &Config,
+    network: &NetworkConfig,
+    read_only: bool,
+    signal: &Waiter,
+    metrics: &Metrics,
+) -> Result<Arc<App>> {
     let blocktxids_cache = Arc::new(BlockTxIDsCache::new(config.blocktxids_cache_size, &metrics));
 
     let daemon = Daemon::new(
-        &config.daemon_dir,
-        &config.blocks_dir,
-        config.daemon_rpc_addr,
-        config.cookie_getter(),
-        config.network_type,
+        &network.daemon_dir,
+        &network.blocks_dir,
+        &network.daemon_rpc_addr,
+        Arc::clone(&network.cookie_getter),
+        config.daemon_limits,
+        network.network_type,
         signal.clone(),
         blocktxids_cache,
-        &metrics,
+        metrics,
     )?;
     // Perform initial indexing from local blk*.dat block files.
-    let store = DBStore::open(&config.db_path, /*low_memory=*/ config.jsonrpc_import);
-    let index = Index::load(&store, &daemon, &metrics, config.index_batch_size)?;
+    let store = if read_only {
+        DBStore::open_read_only(&network.db_path)?
+    } else {
+        DBStore::open(&network.db_path, /*low_memory=*/ config.low_memory)?
+    };
+    migration::migrate(&store, read_only)?;
+    let index = Index::load(
+        &store,
+        &daemon,
+        metrics,
+        config.index_batch_size,
+        config.watch.as_ref(),
+    )?;
     let store = if is_fully_compacted(&store) {
         store // initial import and full compaction are over
-    } else if config.jsonrpc_import {
-        index.update(&store, &signal)?; // slower: uses JSONRPC for fetching blocks
+    } else if read_only {
+        bail!("read-only mode requires a fully indexed DB at {:?}", network.db_path);
+    } else if config.jsonrpc_import || !bulk::can_index_blk_files(&daemon) {
+        if !config.jsonrpc_import {
+            warn!("falling back to JSONRPC import, which is much slower (see 'jsonrpc_import' option)");
+        }
+        index.update(&store, signal)?; // slower: uses JSONRPC for fetching blocks
         full_compaction(store)
     } else {
         // faster, but uses more memory
         let store =
-            bulk::index_blk_files(&daemon, config.bulk_index_threads, &metrics, &signal, store)?;
+            bulk::index_blk_files(&daemon, config.bulk_index_threads, metrics, signal, store)?;
         let store = full_compaction(store);
-        index.reload(&store); // make sure the block header index is up-to-date
+        index.reload(&store)?; // make sure the block header index is up-to-date
         store
     }
     .enable_compaction(); // enable auto compactions before starting incremental index updates.
 
-    let app = App::new(store, index, daemon, &config)?;
+    App::new(store, index, daemon, config)
+}
+
+fn create_query(config: &Config, app: Arc<App>, metrics: &Metrics) -> Arc<Query> {
     let tx_cache = TransactionCache::new(config.tx_cache_size, &metrics);
-    let query = Query::new(app.clone(), &metrics, tx_cache, config.txid_limit);
-    let relayfee = query.get_relayfee()?;
-    debug!("relayfee: {} BTC", relayfee);
+    let block_cache = BlockCache::new(config.block_cache_size, &metrics);
+    let merkle_cache = MerkleProofCache::new(
+        config.merkle_cache_size,
+        config.merkle_tree_blocks,
+        &metrics,
+    );
+    let status_cache = StatusCache::new(config.status_cache_size, &metrics);
+    let tweaks_cache = if config.silent_payments {
+        Some(TweaksCache::new(config.tweaks_cache_size, metrics))
+    } else {
+        None
+    };
+    Query::new(
+        app,
+        metrics,
+        tx_cache,
+        block_cache,
+        merkle_cache,
+        status_cache,
+        tweaks_cache,
+        config.mempool_filter,
+        config.txid_limit,
+        config.max_history,
+        config.max_status_size,
+        config.max_fee_rate,
+        config.electrum_request_timeout,
+    )
+}
 
-    let mut server = None; // Electrum RPC server
-    loop {
-        app.update(&signal)?;
-        query.update_mempool()?;
-        server
-            .get_or_insert_with(|| {
-                RPC::start(config.electrum_rpc_addr, query.clone(), &metrics, relayfee)
-            })
-            .notify(); // update subscribed clients
+fn is_interrupt(e: &Error) -> bool {
+    let mut cause: Option<&(dyn std::error::Error + 'static)> = Some(e);
+    while let Some(err) = cause {
+        if let Some(Error(ErrorKind::Interrupt(_), _)) = err.downcast_ref::<Error>() {
+            return true;
+        }
+        cause = err.source();
+    }
+    false
+}
+
+/// Syncs the index and the mempool with bitcoind, unless it's in initial block download.
+fn sync(
+    config: &Config,
+    app: &App,
+    query: &Query,
+    metrics: &Metrics,
+    signal: &Waiter,
+) -> Result<()> {
+    if !config.read_only {
+        match app.update_daemon_ibd()? {
+            Some(progress) => warn!("waiting for bitcoind IBD ({:.2}%)", progress * 100.0),
+            None => {
+                app.update(signal)?;
+                query.update_mempool()?;
+                query.notify_watchers();
+            }
+        }
+    }
+    let daemon_height = app.update_lag()?;
+    metrics.set_synced(query.get_best_header()?.height(), daemon_height);
+    Ok(())
+}
+
+/// Starts the Electrum RPC server, and the admin and REST servers (if configured).
+fn start_servers(
+    config: &Config,
+    listener: Option<TcpListener>,
+    query: &Arc<Query>,
+    metrics: &Metrics,
+    relayfee: f64,
+    waker: Waker,
+) -> RPC {
+    let server = RPC::start(
+        config,
+        listener,
+        query.clone(),
+        metrics,
+        relayfee,
+        waker.clone(),
+    );
+    if let Some(ref path) = config.admin_socket {
+        if let Err(e) = admin::start(path, server.clients(), query.clone(), metrics, waker) {
+            error!("failed to start admin socket: {}", e.display_chain());
+        }
+    }
+    if let Some(addr) = config.http_addr {
+        let threads = config.electrum_rpc_threads;
+        let network = config.network_type;
+        if let Err(e) = rest::start(addr, threads, query.clone(), network, relayfee, metrics) {
+            error!("failed to start REST server: {}", e.display_chain());
+        }
+    }
+    server
+}
+
+/// A served network, with its own DB, bitcoind, caches, metrics and Electrum server.
+struct Instance {
+    network: NetworkConfig,
+    metrics: Metrics,
+    app: Arc<App>,
+    query: Arc<Query>,
+    relayfee: f64,
+    server: Option<RPC>, // stopped before the DB is closed
+    ready: bool,
+}
+
+impl Instance {
+    fn open(config: &Config, network: NetworkConfig, signal: &Waiter) -> Result<Instance> {
+        let metrics = Metrics::new(network.monitoring_addr);
+        metrics.start(config.monitoring_access.clone(), config.ready_max_lag);
+        let app = open_app(config, &network, config.read_only, signal, &metrics)?;
+        let query = create_query(config, app.clone(), &metrics);
+        let relayfee = query.get_relayfee()?;
+        debug!("{} relayfee: {} BTC", network.network_type, relayfee);
+        Ok(Instance {
+            network,
+            metrics,
+            app,
+            query,
+            relayfee,
+            server: None,
+            ready: false,
+        })
+    }
+
+    /// Starts serving the main network (using the socket activation `listener`, if given) with
+    /// the configured addresses, and the extra ones with their default Electrum port.
+    fn start_servers(
+        &mut self,
+        config: &Config,
+        listener: Option<TcpListener>,
+        signal: &Waiter,
+    ) -> Result<()> {
+        self.server = Some(if self.network.network_type == config.network_type {
+            start_servers(
+                config,
+                listener,
+                &self.query,
+                &self.metrics,
+                self.relayfee,
+                signal.waker(),
+            )
+        } else {
+            let addr = self.network.electrum_rpc_addr;
+            let listener =
+                TcpListener::bind(addr).chain_err(|| format!("bind({}) failed", addr))?;
+            RPC::start(
+                config,
+                Some(listener),
+                self.query.clone(),
+                &self.metrics,
+                self.relayfee,
+                signal.waker(),
+            )
+        });
+        Ok(())
+    }
+}
+
+/// Keeps syncing the indexes and the mempools, and serves clients unless `listen` is false
+/// (i.e. for `electrs sync`).
+fn run_server(config: &Config, listen: bool) -> Result<()> {
+    // must fork before starting any thread
+    let mut detached = daemonize::start(
+        config.daemonize,
+        config.pid_file.as_deref(),
+        config.log_file.is_some(),
+    )?;
+    let signal = Waiter::start();
+    let watchdog = systemd::watchdog_timeout();
+    if let Some(timeout) = watchdog {
+        debug!(
+            "notifying systemd watchdog after each sync (timeout: {:?})",
+            timeout
+        );
+        if timeout < config.wait_duration * 2 {
+            warn!(
+                "WatchdogSec ({:?}) should be much longer than wait_duration_secs ({:?})",
+                timeout, config.wait_duration
+            );
+        }
+    }
+    let mut listener = systemd::take_listener()?; // socket activation
+    let mut instances = vec![];
+    for network in config.networks() {
+        instances.push(Instance::open(config, network, &signal)?);
+    }
+    if let Some(addr) = config.monitoring_push_addr {
+        instances[0]
+            .metrics
+            .start_pusher(addr, config.monitoring_push_interval);
+    }
+    if let Some(addr) = config.replicate_from {
+        // a standby is ready once it follows its primary, and starts serving after taking over
+        systemd::notify("READY=1");
+        detached.ready();
+        let secret = &config.replication_secret;
+        let timeout = config.replication_timeout;
+        replication::follow(&instances[0].app, addr, secret, timeout, &signal)?;
+    }
+    if let Some(addr) = config.replication_addr {
+        let secret = config.replication_secret.clone();
+        let publisher = Publisher::start(addr, secret, &instances[0].metrics)?;
+        instances[0].app.set_publisher(publisher);
+    }
+
+    let mut ready = false;
+    let result = 'serve: loop {
+        for instance in instances.iter_mut() {
+            let query = &instance.query;
+            match sync(config, &instance.app, query, &instance.metrics, &signal) {
+                Ok(()) => (),
+                Err(e) if is_interrupt(&e) => break 'serve Err(e),
+                Err(e) => {
+                    // e.g. bitcoind was restarted (the connection is re-established on the next sync)
+                    error!(
+                        "{} sync failed, retrying in {:?}: {}",
+                        instance.network.network_type,
+                        config.wait_duration,
+                        e.display_chain()
+                    );
+                    continue;
+                }
+            }
+            if !instance.ready {
+                if listen {
+                    if let Err(e) = instance.start_servers(config, listener.take(), &signal) {
+                        break 'serve Err(e);
+                    }
+                }
+                instance.ready = true;
+            }
+        }
+        if watchdog.is_some() {
+            systemd::notify_watchdog(); // the loop is alive, even if bitcoind isn't
+        }
+        if !ready && instances.iter().all(|instance| instance.ready) {
+            systemd::notify("READY=1"); // the indexes have caught up
+            detached.ready();
+            ready = true;
+        }
+        if signal.take_reload() {
+            for instance in &instances {
+                reload_config(&instance.app, instance.server.as_ref());
+            }
+        }
+        if signal.take_compact() {
+            if config.read_only {
+                warn!("ignoring compaction request, since the DB is read-only");
+            } else {
+                for instance in &instances {
+                    App::start_compaction(&instance.app);
+                }
+            }
+        }
+        for instance in &instances {
+            if let Some(ref server) = instance.server {
+                server.notify(); // update subscribed clients
+            }
+        }
         if let Err(err) = signal.wait(config.wait_duration) {
             info!("stopping server: {}", err);
-            break;
+            systemd::notify("STOPPING=1");
+            break Ok(());
         }
+    };
+    for instance in &mut instances {
+        instance.server.take(); // waits for the RPC server to stop (up to shutdown_timeout_secs)
+    }
+    for instance in &instances {
+        instance.app.join_compactions();
+        // in case stuck clients' threads still hold the app, so it's not dropped
+        instance.app.flush_store();
+    }
+    result
+}
+
+/// Indexes the blockchain up to bitcoind's tip, and exits (without tracking the mempool).
+fn run_index(config: &Config) -> Result<()> {
+    let signal = Waiter::start();
+    let metrics = Metrics::new(config.monitoring_addr);
+    metrics.start(config.monitoring_access.clone(), config.ready_max_lag);
+    let app = open_app(
+        config,
+        &config.main_network(),
+        config.read_only,
+        &signal,
+        &metrics,
+    )?;
+    if !config.read_only {
+        app.update(&signal)?;
+    }
+    let tip = app
+        .index()
+        .best_header()
+        .chain_err(|| "no headers indexed")?;
+    info!("indexed {} blocks, up to {}", tip.height() + 1, tip.hash());
+    Ok(())
+}
+
+/// Compacts the whole DB (which must not be used by another process).
+fn run_compact(config: &Config) -> Result<()> {
+    let store = DBStore::open(&config.db_path, /*low_memory=*/ config.low_memory)?;
+    store.compact();
+    Ok(())
+}
+
+/// Prints the status of an Electrum script hash (using a read-only DB, and bitcoind for
+/// loading transactions), as JSON.
+fn run_query(config: &Config, script_hash: &str) -> Result<()> {
+    let script_hash: ScriptHash = script_hash.parse()?;
+    let signal = Waiter::start();
+    let metrics = Metrics::new(config.monitoring_addr);
+    let app = open_app(
+        config,
+        &config.main_network(),
+        /*read_only=*/ true,
+        &signal,
+        &metrics,
+    )?;
+    let query = create_query(config, app, &metrics);
+    query.update_mempool()?;
+    let status = query.status(&script_hash)?;
+    let tip_height = query.get_best_header()?.height() as u32;
+    let balance = status.balance(tip_height);
+    let history: Vec<Value> = status
+        .history()
+        .into_iter()
+        .map(|entry| json!({"tx_hash": entry.txid.to_hex(), "height": entry.height}))
+        .collect();
+    let unspent: Vec<Value> = status
+        .utxos()
+        .into_iter()
+        .map(|utxo| {
+            json!({
+                "tx_hash": utxo.outpoint.txid.to_hex(),
+                "tx_pos": utxo.outpoint.vout,
+                "height": utxo.height,
+                "value": utxo.value.as_sat(),
+            })
+        })
+        .collect();
+    let result = json!({
+        "tip_height": tip_height,
+        "balance": {
+            "confirmed": balance.confirmed.as_sat(),
+            "unconfirmed": balance.unconfirmed.as_sat(),
+            "immature": balance.immature.as_sat(),
+        },
+        "history": history,
+        "unspent": unspent,
+    });
+    println!("{}", serde_json::to_string_pretty(&result).unwrap());
+    Ok(())
+}
+
+/// Inspects the DB (opened read-only), without connecting bitcoind.
+fn run_inspect(config: &Config, args: &[String]) -> Result<()> {
+    if !config.db_path.is_dir() {
+        bail!("no index at {:?}", config.db_path);
     }
+    let store = DBStore::open_read_only(&config.db_path)?;
+    let result = inspect::run(&store, args)?;
+    println!("{}", serde_json::to_string_pretty(&result).unwrap());
     Ok(())
 }
 
 fn main() {
     let config = Config::from_args();
-    if let Err(e) = run_server(&config) {
+    let result = match config.command {
+        Command::Serve => run_server(&config, /*listen=*/ true),
+        Command::Sync => run_server(&config, /*listen=*/ false),
+        Command::Index => run_index(&config),
+        Command::Compact => run_compact(&config),
+        Command::Query(ref script_hash) => run_query(&config, script_hash),
+        Command::Inspect(ref args) => run_inspect(&config, args),
+    };
+    if let Err(e) = result {
         error!("server failed: {}", e.display_chain());
         process::exit(1);
     }
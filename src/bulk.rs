@@ -1,6 +1,7 @@
 use bitcoin::blockdata::block::Block;
 use bitcoin::consensus::encode::{deserialize, Decodable};
 use bitcoin::hash_types::BlockHash;
+use error_chain::ChainedError;
 use std::collections::HashSet;
 use std::fs;
 use std::io::Cursor;
@@ -13,10 +14,10 @@ use std::thread;
 
 use crate::daemon::Daemon;
 use crate::errors::*;
-use crate::index::{index_block, last_indexed_block, read_indexed_blockhashes};
+use crate::index::{index_block_into, last_indexed_block, read_indexed_blockhashes};
 use crate::metrics::{CounterVec, Histogram, HistogramOpts, HistogramVec, MetricOpts, Metrics};
 use crate::signal::Waiter;
-use crate::store::{DBStore, Row, WriteStore};
+use crate::store::{DBStore, Row, RowBatch, WriteStore};
 use crate::util::{spawn_thread, HeaderList, SyncChannel};
 
 struct Parser {
@@ -27,6 +28,7 @@ struct Parser {
     duration: HistogramVec,
     block_count: CounterVec,
     bytes_read: Histogram,
+    rows: Histogram,
 }
 
 impl Parser {
@@ -55,6 +57,10 @@ impl Parser {
                 "electrs_parse_bytes_read",
                 "# of bytes read (from blk*.dat)",
             )),
+            rows: metrics.histogram(
+                HistogramOpts::new("electrs_parse_rows", "# of rows written per blk*.dat file")
+                    .buckets(prometheus::exponential_buckets(10.0, 4.0, 10).unwrap()),
+            ),
         }))
     }
 
@@ -79,12 +85,12 @@ impl Parser {
         Ok(blob)
     }
 
-    fn index_blkfile(&self, blob: Vec<u8>) -> Result<Vec<Row>> {
+    /// Indexes the blocks of a blk*.dat file into `rows` (which should be empty).
+    fn index_blkfile(&self, blob: Vec<u8>, rows: &mut RowBatch) -> Result<()> {
         let timer = self.duration.with_label_values(&["parse"]).start_timer();
         let blocks = parse_blocks(blob, self.magic)?;
         timer.observe_duration();
 
-        let mut rows = Vec::<Row>::new();
         let timer = self.duration.with_label_values(&["index"]).start_timer();
         for block in blocks {
             let blockhash = block.block_hash();
@@ -95,7 +101,7 @@ impl Parser {
                     .expect("indexed_blockhashes")
                     .insert(blockhash)
                 {
-                    rows.extend(index_block(&block, header.height()));
+                    index_block_into(&block, header.height(), rows);
                     self.block_count.with_label_values(&["indexed"]).inc();
                 } else {
                     self.block_count.with_label_values(&["duplicate"]).inc();
@@ -108,9 +114,9 @@ impl Parser {
         timer.observe_duration();
 
         let timer = self.duration.with_label_values(&["sort"]).start_timer();
-        rows.sort_unstable_by(|a, b| a.key.cmp(&b.key));
+        rows.sort();
         timer.observe_duration();
-        Ok(rows)
+        Ok(())
     }
 }
 
@@ -158,7 +164,7 @@ fn load_headers(daemon: &Daemon) -> Result<HeaderList> {
     let tip = daemon.getbestblockhash()?;
     let mut headers = HeaderList::empty();
     let new_headers = headers.order(daemon.get_new_headers(&headers, &tip)?);
-    headers.apply(new_headers, tip);
+    headers.apply(new_headers, tip)?;
     Ok(headers)
 }
 
@@ -199,14 +205,17 @@ fn start_reader(blk_files: Vec<PathBuf>, parser: Arc<Parser>) -> (BlobReceiver,
 fn start_indexer(
     blobs: BlobReceiver,
     parser: Arc<Parser>,
-    writer: SyncSender<(Vec<Row>, PathBuf)>,
+    writer: SyncSender<(RowBatch, PathBuf)>,
+    batches: crossbeam_channel::Receiver<RowBatch>,
 ) -> JoinHandle {
     spawn_thread("bulk_index", move || -> Result<()> {
         loop {
             let msg = blobs.lock().unwrap().recv();
             if let Ok((blob, path)) = msg {
-                let rows = parser
-                    .index_blkfile(blob)
+                // reuse a written batch (if available), to avoid re-allocating its buffers
+                let mut rows = batches.try_recv().unwrap_or_default();
+                parser
+                    .index_blkfile(blob, &mut rows)
                     .chain_err(|| format!("failed to index {:?}", path))?;
                 writer
                     .send((rows, path))
@@ -220,6 +229,30 @@ fn start_indexer(
     })
 }
 
+/// Bulk indexing requires local read access to bitcoind's blk*.dat files.
+pub fn can_index_blk_files(daemon: &Daemon) -> bool {
+    let blk_files = match daemon.list_blk_files() {
+        Ok(blk_files) => blk_files,
+        Err(e) => {
+            warn!("{}", e.display_chain());
+            return false;
+        }
+    };
+    match blk_files.first() {
+        Some(path) => match fs::File::open(path) {
+            Ok(_) => true,
+            Err(e) => {
+                warn!("failed to open {:?}: {}", path, e);
+                false
+            }
+        },
+        None => {
+            warn!("no blk*.dat files found");
+            false
+        }
+    }
+}
+
 pub fn index_blk_files(
     daemon: &Daemon,
     index_threads: usize,
@@ -235,13 +268,27 @@ pub fn index_blk_files(
     let parser = Parser::new(daemon, metrics, indexed_blockhashes)?;
     let (blobs, reader) = start_reader(blk_files, parser.clone());
     let rows_chan = SyncChannel::new(0);
+    let (written, batches) = crossbeam_channel::unbounded();
     let indexers: Vec<JoinHandle> = (0..index_threads)
-        .map(|_| start_indexer(blobs.clone(), parser.clone(), rows_chan.sender()))
+        .map(|_| {
+            start_indexer(
+                blobs.clone(),
+                parser.clone(),
+                rows_chan.sender(),
+                batches.clone(),
+            )
+        })
         .collect();
 
-    for (rows, path) in rows_chan.into_receiver() {
+    for (mut rows, path) in rows_chan.into_receiver() {
         trace!("indexed {:?}: {} rows", path, rows.len());
-        store.write(rows);
+        parser.rows.observe(rows.len() as f64);
+        let timer = parser.duration.with_label_values(&["write"]).start_timer();
+        store.write_batch(&rows);
+        timer.observe_duration();
+        rows.clear();
+        let _ = written.send(rows); // the indexers may have exited
+
         signal
             .poll()
             .chain_err(|| "stopping bulk indexing due to signal")?;
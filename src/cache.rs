@@ -1,13 +1,19 @@
 use crate::errors::*;
-use crate::metrics::{CounterVec, MetricOpts, Metrics};
+use crate::metrics::{Counter, CounterVec, MetricOpts, Metrics};
+use crate::status::{Status, StatusChanges, StatusHashState};
+use crate::types::ScriptHash;
+use crate::util::MerkleTree;
 
+use bitcoin::blockdata::block::Block;
 use bitcoin::blockdata::transaction::Transaction;
 use bitcoin::consensus::encode::deserialize;
-use bitcoin::hash_types::{BlockHash, Txid};
+use bitcoin::hash_types::{BlockHash, TxMerkleNode, Txid};
+use bitcoin::secp256k1::PublicKey;
 use lru::LruCache;
 use prometheus::IntGauge;
+use std::collections::{BTreeMap, HashMap};
 use std::hash::Hash;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
 struct SizedLruCache<K, V> {
     map: LruCache<K, (V, usize)>,
@@ -15,19 +21,56 @@ struct SizedLruCache<K, V> {
     bytes_capacity: usize,
     lookups: CounterVec,
     usage: IntGauge,
+    evictions: Counter,
 }
 
 impl<K: Hash + Eq, V> SizedLruCache<K, V> {
-    fn new(bytes_capacity: usize, lookups: CounterVec, usage: IntGauge) -> SizedLruCache<K, V> {
+    fn new(
+        bytes_capacity: usize,
+        lookups: CounterVec,
+        usage: IntGauge,
+        evictions: Counter,
+    ) -> SizedLruCache<K, V> {
         SizedLruCache {
             map: LruCache::unbounded(),
             bytes_usage: 0,
             bytes_capacity,
             lookups,
             usage,
+            evictions,
         }
     }
 
+    /// Register the cache metrics, named after `name` (e.g. "electrs_<name>_cache").
+    fn with_metrics(
+        bytes_capacity: usize,
+        metrics: &Metrics,
+        name: &str,
+        description: &str,
+    ) -> SizedLruCache<K, V> {
+        let lookups = metrics.counter_vec(
+            MetricOpts::new(
+                format!("electrs_{}_cache", name),
+                format!("# of cache lookups for {}", description),
+            ),
+            &["type"],
+        );
+        let usage = metrics.gauge_int(MetricOpts::new(
+            format!("electrs_{}_cache_size", name),
+            format!("Cache usage for {} (bytes)", description),
+        ));
+        let evictions = metrics.counter(MetricOpts::new(
+            format!("electrs_{}_cache_evictions", name),
+            format!("# of {} evicted from cache", description),
+        ));
+        let capacity = metrics.gauge_int(MetricOpts::new(
+            format!("electrs_{}_cache_capacity", name),
+            format!("Cache capacity for {} (bytes)", description),
+        ));
+        capacity.set(bytes_capacity as i64);
+        SizedLruCache::new(bytes_capacity, lookups, usage, evictions)
+    }
+
     fn get(&mut self, key: &K) -> Option<&V> {
         match self.map.get(key) {
             None => {
@@ -41,6 +84,13 @@ impl<K: Hash + Eq, V> SizedLruCache<K, V> {
         }
     }
 
+    fn remove(&mut self, key: &K) -> Option<V> {
+        let (value, byte_size) = self.map.pop(key)?;
+        self.bytes_usage -= byte_size;
+        self.usage.set(self.bytes_usage as i64);
+        Some(value)
+    }
+
     fn put(&mut self, key: K, value: V, byte_size: usize) {
         if byte_size > self.bytes_capacity {
             return;
@@ -52,7 +102,10 @@ impl<K: Hash + Eq, V> SizedLruCache<K, V> {
 
         while self.bytes_usage > self.bytes_capacity {
             match self.map.pop_lru() {
-                Some((_, (_, popped_size))) => self.bytes_usage -= popped_size,
+                Some((_, (_, popped_size))) => {
+                    self.bytes_usage -= popped_size;
+                    self.evictions.inc();
+                }
                 None => break,
             }
         }
@@ -62,41 +115,40 @@ impl<K: Hash + Eq, V> SizedLruCache<K, V> {
 }
 
 pub struct BlockTxIDsCache {
-    map: Mutex<SizedLruCache<BlockHash, Vec<Txid>>>,
+    // Shared with the callers, to avoid copying large blocks' txids.
+    map: Mutex<SizedLruCache<BlockHash, Arc<Vec<Txid>>>>,
 }
 
 impl BlockTxIDsCache {
     pub fn new(bytes_capacity: usize, metrics: &Metrics) -> BlockTxIDsCache {
-        let lookups = metrics.counter_vec(
-            MetricOpts::new(
-                "electrs_blocktxids_cache",
-                "# of cache lookups for list of transactions in a block",
-            ),
-            &["type"],
-        );
-        let usage = metrics.gauge_int(MetricOpts::new(
-            "electrs_blocktxids_cache_size",
-            "Cache usage for list of transactions in a block (bytes)",
-        ));
         BlockTxIDsCache {
-            map: Mutex::new(SizedLruCache::new(bytes_capacity, lookups, usage)),
+            map: Mutex::new(SizedLruCache::with_metrics(
+                bytes_capacity,
+                metrics,
+                "blocktxids",
+                "lists of transactions in a block",
+            )),
         }
     }
 
-    pub fn get_or_else<F>(&self, blockhash: &BlockHash, load_txids_func: F) -> Result<Vec<Txid>>
+    pub fn get_or_else<F>(
+        &self,
+        blockhash: &BlockHash,
+        load_txids_func: F,
+    ) -> Result<Arc<Vec<Txid>>>
     where
         F: FnOnce() -> Result<Vec<Txid>>,
     {
         if let Some(txids) = self.map.lock().unwrap().get(blockhash) {
-            return Ok(txids.clone());
+            return Ok(Arc::clone(txids));
         }
 
-        let txids = load_txids_func()?;
+        let txids = Arc::new(load_txids_func()?);
         let byte_size = 32 /* hash size */ * (1 /* key */ + txids.len() /* values */);
         self.map
             .lock()
             .unwrap()
-            .put(*blockhash, txids.clone(), byte_size);
+            .put(*blockhash, Arc::clone(&txids), byte_size);
         Ok(txids)
     }
 }
@@ -108,19 +160,13 @@ pub struct TransactionCache {
 
 impl TransactionCache {
     pub fn new(bytes_capacity: usize, metrics: &Metrics) -> TransactionCache {
-        let lookups = metrics.counter_vec(
-            MetricOpts::new(
-                "electrs_transactions_cache",
-                "# of cache lookups for transactions",
-            ),
-            &["type"],
-        );
-        let usage = metrics.gauge_int(MetricOpts::new(
-            "electrs_transactions_cache_size",
-            "Cache usage for list of transactions (bytes)",
-        ));
         TransactionCache {
-            map: Mutex::new(SizedLruCache::new(bytes_capacity, lookups, usage)),
+            map: Mutex::new(SizedLruCache::with_metrics(
+                bytes_capacity,
+                metrics,
+                "transactions",
+                "transactions",
+            )),
         }
     }
 
@@ -142,6 +188,262 @@ impl TransactionCache {
     }
 }
 
+pub struct BlockCache {
+    // Store serialized blocks (should use less RAM).
+    map: Mutex<SizedLruCache<BlockHash, Vec<u8>>>,
+}
+
+impl BlockCache {
+    pub fn new(bytes_capacity: usize, metrics: &Metrics) -> BlockCache {
+        BlockCache {
+            map: Mutex::new(SizedLruCache::with_metrics(
+                bytes_capacity,
+                metrics,
+                "blocks",
+                "blocks",
+            )),
+        }
+    }
+
+    pub fn get_or_else<F>(&self, blockhash: &BlockHash, load_block_func: F) -> Result<Block>
+    where
+        F: FnOnce() -> Result<Vec<u8>>,
+    {
+        if let Some(serialized_block) = self.map.lock().unwrap().get(blockhash) {
+            return deserialize(serialized_block).chain_err(|| "failed to parse cached block");
+        }
+        let serialized_block = load_block_func()?;
+        let block = deserialize(&serialized_block).chain_err(|| "failed to parse block")?;
+        let byte_size = 32 /* key (hash size) */ + serialized_block.len();
+        self.map
+            .lock()
+            .unwrap()
+            .put(*blockhash, serialized_block, byte_size);
+        Ok(block)
+    }
+}
+
+/// Silent payments tweaks of the blocks requested by scanning wallets.
+pub struct TweaksCache {
+    map: Mutex<SizedLruCache<BlockHash, Arc<Vec<PublicKey>>>>,
+}
+
+impl TweaksCache {
+    pub fn new(bytes_capacity: usize, metrics: &Metrics) -> TweaksCache {
+        TweaksCache {
+            map: Mutex::new(SizedLruCache::with_metrics(
+                bytes_capacity,
+                metrics,
+                "tweaks",
+                "blocks' silent payments tweaks",
+            )),
+        }
+    }
+
+    pub fn get_or_else<F>(
+        &self,
+        blockhash: &BlockHash,
+        compute_tweaks_func: F,
+    ) -> Result<Arc<Vec<PublicKey>>>
+    where
+        F: FnOnce() -> Result<Vec<PublicKey>>,
+    {
+        if let Some(tweaks) = self.map.lock().unwrap().get(blockhash) {
+            return Ok(Arc::clone(tweaks));
+        }
+        let tweaks = Arc::new(compute_tweaks_func()?);
+        let byte_size = 32 /* key (hash size) */ + tweaks.len() * std::mem::size_of::<PublicKey>();
+        self.map
+            .lock()
+            .unwrap()
+            .put(*blockhash, Arc::clone(&tweaks), byte_size);
+        Ok(tweaks)
+    }
+}
+
+/// Merkle branch and position of a transaction in a block.
+pub type MerkleProof = (Vec<TxMerkleNode>, usize);
+
+/// A block's txids and merkle tree.
+pub type BlockMerkleTree = Arc<(Arc<Vec<Txid>>, MerkleTree<TxMerkleNode>)>;
+
+pub struct MerkleProofCache {
+    map: Mutex<SizedLruCache<(Txid, BlockHash), MerkleProof>>,
+    // The most recent blocks' trees (by height), since their transactions were just confirmed
+    // and their proofs are likely to be requested by many clients.
+    trees: Mutex<BTreeMap<usize, (BlockHash, BlockMerkleTree)>>,
+    max_trees: usize,
+    tree_lookups: CounterVec,
+}
+
+impl MerkleProofCache {
+    pub fn new(bytes_capacity: usize, max_trees: usize, metrics: &Metrics) -> MerkleProofCache {
+        MerkleProofCache {
+            map: Mutex::new(SizedLruCache::with_metrics(
+                bytes_capacity,
+                metrics,
+                "merkle_proofs",
+                "merkle proofs",
+            )),
+            trees: Mutex::new(BTreeMap::new()),
+            max_trees,
+            tree_lookups: metrics.counter_vec(
+                MetricOpts::new(
+                    "electrs_merkle_trees_cache",
+                    "# of cache lookups for recent blocks' merkle trees",
+                ),
+                &["type"],
+            ),
+        }
+    }
+
+    /// Returns the merkle tree of the block at `height`, keeping the `max_trees` highest ones
+    /// (a reorged block's tree is replaced by its successor's).
+    pub fn tree_or_else<F>(
+        &self,
+        height: usize,
+        blockhash: &BlockHash,
+        build_tree_func: F,
+    ) -> Result<BlockMerkleTree>
+    where
+        F: FnOnce() -> Result<BlockMerkleTree>,
+    {
+        if self.max_trees == 0 {
+            return build_tree_func();
+        }
+        if let Some((hash, tree)) = self.trees.lock().unwrap().get(&height) {
+            if hash == blockhash {
+                self.tree_lookups.with_label_values(&["hit"]).inc();
+                return Ok(Arc::clone(tree));
+            }
+        }
+        self.tree_lookups.with_label_values(&["miss"]).inc();
+        let tree = build_tree_func()?;
+        let mut trees = self.trees.lock().unwrap();
+        trees.insert(height, (*blockhash, Arc::clone(&tree)));
+        while trees.len() > self.max_trees {
+            let lowest = *trees.keys().next().unwrap();
+            trees.remove(&lowest);
+        }
+        Ok(tree)
+    }
+
+    pub fn get_or_else<F>(
+        &self,
+        txid: &Txid,
+        blockhash: &BlockHash,
+        compute_proof_func: F,
+    ) -> Result<MerkleProof>
+    where
+        F: FnOnce() -> Result<MerkleProof>,
+    {
+        let key = (*txid, *blockhash);
+        if let Some(proof) = self.map.lock().unwrap().get(&key) {
+            return Ok(proof.clone());
+        }
+        let proof = compute_proof_func()?;
+        let byte_size = 32 * (2 /* key */ + proof.0.len() /* branch */) + 8 /* position */;
+        self.map.lock().unwrap().put(key, proof.clone(), byte_size);
+        Ok(proof)
+    }
+}
+
+struct StatusCacheState {
+    map: SizedLruCache<ScriptHash, Arc<Status>>,
+    tip: Option<(usize, BlockHash)>, // the indexed tip, up to which the statuses are valid
+    generation: u64,                 // incremented when the index or the mempool change
+    // The dropped statuses' hashing states, for computing their next status hashes incrementally.
+    hash_states: HashMap<ScriptHash, StatusHashState>,
+}
+
+pub struct StatusCache {
+    // Shared between clients querying the same script hash.
+    state: Mutex<StatusCacheState>,
+}
+
+impl StatusCache {
+    pub fn new(bytes_capacity: usize, metrics: &Metrics) -> StatusCache {
+        StatusCache {
+            state: Mutex::new(StatusCacheState {
+                map: SizedLruCache::with_metrics(bytes_capacity, metrics, "statuses", "statuses"),
+                tip: None,
+                generation: 0,
+                hash_states: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn get_or_else<F>(
+        &self,
+        script_hash: &ScriptHash,
+        compute_status_func: F,
+    ) -> Result<Arc<Status>>
+    where
+        F: FnOnce() -> Result<Status>,
+    {
+        let generation = {
+            let mut state = self.state.lock().unwrap();
+            if let Some(status) = state.map.get(script_hash) {
+                return Ok(Arc::clone(status));
+            }
+            state.generation
+        };
+        let status = Arc::new(compute_status_func()?);
+        let mut state = self.state.lock().unwrap();
+        // Don't cache a status computed before the index or the mempool have changed.
+        if state.generation == generation {
+            let byte_size = 32 /* key */ + status.byte_size();
+            state.map.put(*script_hash, Arc::clone(&status), byte_size);
+        }
+        Ok(status)
+    }
+
+    /// Returns the hashing state of a dropped status (see `Status::statushash()`).
+    pub fn hash_state(&self, script_hash: &ScriptHash) -> Option<StatusHashState> {
+        let state = self.state.lock().unwrap();
+        state.hash_states.get(script_hash).cloned()
+    }
+
+    pub fn tip(&self) -> Option<(usize, BlockHash)> {
+        self.state.lock().unwrap().tip
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state.lock().unwrap().map.map.is_empty()
+    }
+
+    /// Drops the cached statuses affected by `changes` (or all of them, if `None`, e.g. after a
+    /// reorg), and marks the others as valid up to the new index `tip`.
+    pub fn update(&self, tip: Option<(usize, BlockHash)>, changes: Option<&StatusChanges>) {
+        let mut state = self.state.lock().unwrap();
+        if state.tip == tip && changes.map_or(false, StatusChanges::is_empty) {
+            return;
+        }
+        let dropped: Vec<ScriptHash> = state
+            .map
+            .map
+            .iter()
+            .filter(|(script_hash, (status, _))| {
+                changes.map_or(true, |changes| changes.affects(script_hash, status))
+            })
+            .map(|(script_hash, _)| *script_hash)
+            .collect();
+        let mut hash_states = HashMap::new();
+        for script_hash in dropped {
+            let status = state.map.remove(&script_hash).unwrap();
+            if let Some(hash_state) = status.hash_state() {
+                hash_states.insert(script_hash, hash_state);
+            }
+        }
+        for (_, (status, _)) in state.map.map.iter() {
+            status.advance_tip(tip);
+        }
+        state.hash_states = hash_states;
+        state.tip = tip;
+        state.generation += 1;
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -151,7 +453,9 @@ mod tests {
     fn test_sized_lru_cache_hit_and_miss() {
         let counter = CounterVec::new(prometheus::Opts::new("name", "help"), &["type"]).unwrap();
         let usage = IntGauge::new("usage", "help").unwrap();
-        let mut cache = SizedLruCache::<i8, i32>::new(100, counter.clone(), usage.clone());
+        let evictions = Counter::new("evictions", "help").unwrap();
+        let mut cache =
+            SizedLruCache::<i8, i32>::new(100, counter.clone(), usage.clone(), evictions.clone());
         assert_eq!(counter.with_label_values(&["miss"]).get(), 0);
         assert_eq!(counter.with_label_values(&["hit"]).get(), 0);
         assert_eq!(usage.get(), 0);
@@ -175,6 +479,7 @@ mod tests {
         assert_eq!(counter.with_label_values(&["miss"]).get(), 2);
         assert_eq!(counter.with_label_values(&["hit"]).get(), 3);
         assert_eq!(usage.get(), 100);
+        assert_eq!(evictions.get(), 1);
 
         cache.put(3, 33, 50); // replace existing value
         assert_eq!(cache.get(&1), None);
@@ -192,6 +497,7 @@ mod tests {
         assert_eq!(counter.with_label_values(&["miss"]).get(), 5);
         assert_eq!(counter.with_label_values(&["hit"]).get(), 7);
         assert_eq!(usage.get(), 100);
+        assert_eq!(evictions.get(), 1);
     }
 
     fn gen_hash<T: Hash>(seed: u8) -> T {
@@ -219,12 +525,12 @@ mod tests {
         // cache miss
         let result = cache.get_or_else(&block1, &miss_func).unwrap();
         assert_eq!(1, *misses.lock().unwrap());
-        assert_eq!(txids, result);
+        assert_eq!(txids, *result);
 
         // cache hit
         let result = cache.get_or_else(&block1, &miss_func).unwrap();
         assert_eq!(1, *misses.lock().unwrap());
-        assert_eq!(txids, result);
+        assert_eq!(txids, *result);
 
         // cache size is 200, test that blockhash1 falls out of cache
         cache.get_or_else(&block2, &miss_func).unwrap();
@@ -240,6 +546,70 @@ mod tests {
         assert_eq!(4, *misses.lock().unwrap());
     }
 
+    #[test]
+    fn test_merkle_proof_cache() {
+        let dummy_metrics = Metrics::new("127.0.0.1:60000".parse().unwrap());
+        // 200 bytes ~ 32 (bytes/hash) * (2 key hashes + 3 branch hashes) + 8 (position)
+        let cache = MerkleProofCache::new(200, 0, &dummy_metrics);
+        let txid: Txid = gen_hash(1);
+        let block1: BlockHash = gen_hash(2);
+        let block2: BlockHash = gen_hash(3);
+        let proof: MerkleProof = (vec![gen_hash(4), gen_hash(5), gen_hash(6)], 5);
+
+        let mut misses = 0;
+        let mut compute = || {
+            misses += 1;
+            Ok(proof.clone())
+        };
+        assert_eq!(
+            cache.get_or_else(&txid, &block1, &mut compute).unwrap(),
+            proof
+        );
+        assert_eq!(
+            cache.get_or_else(&txid, &block1, &mut compute).unwrap(),
+            proof
+        );
+        assert_eq!(
+            cache.get_or_else(&txid, &block2, &mut compute).unwrap(),
+            proof
+        ); // evicts block1
+        assert_eq!(
+            cache.get_or_else(&txid, &block1, &mut compute).unwrap(),
+            proof
+        );
+        assert_eq!(misses, 3);
+    }
+
+    #[test]
+    fn test_merkle_trees_cache() {
+        let dummy_metrics = Metrics::new("127.0.0.1:60000".parse().unwrap());
+        let cache = MerkleProofCache::new(0, 2, &dummy_metrics);
+        let tree = |seed: u8| -> BlockMerkleTree {
+            let txid: Txid = gen_hash(seed);
+            let leaf = TxMerkleNode::from_inner(txid.into_inner());
+            Arc::new((Arc::new(vec![txid]), MerkleTree::new(vec![leaf])))
+        };
+        let mut misses = 0;
+        let mut lookup = |height: usize, seed: u8| {
+            let blockhash: BlockHash = gen_hash(seed);
+            cache
+                .tree_or_else(height, &blockhash, || {
+                    misses += 1;
+                    Ok(tree(seed))
+                })
+                .unwrap()
+                .0[0]
+        };
+        assert_eq!(lookup(10, 1), gen_hash(1));
+        assert_eq!(lookup(10, 1), gen_hash(1));
+        lookup(11, 2);
+        lookup(12, 3); // evicts height 10
+        lookup(12, 4); // reorged
+        assert_eq!(lookup(11, 2), gen_hash(2));
+        assert_eq!(lookup(10, 1), gen_hash(1));
+        assert_eq!(misses, 5);
+    }
+
     #[test]
     fn test_txn_cache() {
         use hex;
@@ -4,15 +4,20 @@ use std::convert::TryInto;
 use std::ffi::{OsStr, OsString};
 use std::fmt;
 use std::fs;
-use std::net::SocketAddr;
 use std::net::ToSocketAddrs;
+use std::net::{IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
 
-use crate::daemon::CookieGetter;
+use crate::daemon::{self, CookieGetter, DaemonAddr, DaemonLimits};
+use crate::daemonize;
 use crate::errors::*;
+use crate::logger;
+use crate::mempool::MempoolFilter;
+use crate::metrics::MonitoringAccess;
+use crate::watch::WatchedScripts;
 
 const DEFAULT_SERVER_ADDRESS: [u8; 4] = [127, 0, 0, 1]; // by default, serve on IPv4 localhost
 
@@ -52,7 +57,7 @@ impl fmt::Display for AddressError {
 ///
 /// The main point of this newtype is to provide better description than what `String` type
 /// provides.
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize)]
 pub struct ResolvAddr(String);
 
 impl ::configure_me::parse_arg::ParseArg for ResolvAddr {
@@ -79,26 +84,79 @@ impl ResolvAddr {
             Err(err) => Err(AddressError::ResolvError { addr: self.0, err }),
         }
     }
+}
 
-    /// Resolves the address, but prints error and exits in case of failure.
-    fn resolve_or_exit(self) -> SocketAddr {
-        self.resolve().unwrap_or_else(|err| {
-            eprintln!("Error: {}", err);
-            std::process::exit(1)
+/// Collects the configuration problems, so all of them are reported (and can be fixed) at once.
+#[derive(Default)]
+struct Diagnostics {
+    errors: Vec<String>,
+    warnings: Vec<String>,
+}
+
+impl Diagnostics {
+    fn error(&mut self, message: String) {
+        self.errors.push(message);
+    }
+
+    fn warning(&mut self, message: String) {
+        self.warnings.push(message);
+    }
+
+    /// Reports the error (if any), returning `default` so the validation can continue.
+    fn check<T, E: fmt::Display>(&mut self, result: std::result::Result<T, E>, default: T) -> T {
+        result.unwrap_or_else(|err| {
+            self.error(err.to_string());
+            default
         })
     }
+
+    fn resolve(&mut self, addr: ResolvAddr) -> SocketAddr {
+        self.check(addr.resolve(), (DEFAULT_SERVER_ADDRESS, 0).into())
+    }
+
+    fn parse_ips(&mut self, ips: Option<String>) -> Vec<IpAddr> {
+        match ips {
+            Some(ips) => self.check(parse_ips(&ips), vec![]),
+            None => vec![],
+        }
+    }
+
+    fn check_parent_dir(&mut self, name: &str, path: &Option<PathBuf>) {
+        let dir = match path.as_ref().and_then(|path| path.parent()) {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => return,
+        };
+        if !dir.is_dir() {
+            self.error(format!(
+                "{}'s directory {:?} doesn't exist (hint: create it, or fix {})",
+                name, dir, name
+            ));
+        }
+    }
+
+    /// Prints the problems, and exits if there are any errors.
+    fn report(self) {
+        for warning in &self.warnings {
+            eprintln!("Warning: {}", warning);
+        }
+        if self.errors.is_empty() {
+            return;
+        }
+        for error in &self.errors {
+            eprintln!("Error: {}", error);
+        }
+        eprintln!(
+            "Error: found {} configuration problem(s), please fix them and restart",
+            self.errors.len()
+        );
+        std::process::exit(1)
+    }
 }
 
 /// This newtype implements `ParseArg` for `Network`.
 #[derive(Deserialize)]
 pub struct BitcoinNetwork(Network);
 
-impl Default for BitcoinNetwork {
-    fn default() -> Self {
-        BitcoinNetwork(Network::Bitcoin)
-    }
-}
-
 impl FromStr for BitcoinNetwork {
     type Err = <Network as FromStr>::Err;
 
@@ -119,26 +177,118 @@ impl From<BitcoinNetwork> for Network {
     }
 }
 
+/// The operation to perform, selected by the first positional argument.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Serve,                // index, and serve Electrum clients (default)
+    Sync,                 // keep the index and mempool in sync, without serving clients
+    Index,                // index up to bitcoind's tip, and exit
+    Compact,              // run a full DB compaction, and exit
+    Query(String),        // print an Electrum script hash's status, and exit
+    Inspect(Vec<String>), // inspect the DB offline (see `inspect::USAGE`), and exit
+}
+
+fn arg_string(arg: OsString) -> String {
+    arg.to_string_lossy().into_owned()
+}
+
+fn parse_command<I: Iterator<Item = OsString>>(
+    mut args: I,
+) -> std::result::Result<Command, String> {
+    let command = match args.next().map(arg_string).as_deref() {
+        None | Some("serve") => Command::Serve,
+        Some("sync") => Command::Sync,
+        Some("index") => Command::Index,
+        Some("compact") => Command::Compact,
+        Some("query") => match args.next() {
+            Some(script_hash) => Command::Query(arg_string(script_hash)),
+            None => return Err("query requires a script hash".to_owned()),
+        },
+        Some("inspect") => return Ok(Command::Inspect(args.map(arg_string).collect())),
+        Some(other) => {
+            return Err(format!(
+                "unknown command {:?} (expected serve, sync, index, compact, query or inspect)",
+                other
+            ))
+        }
+    };
+    match args.next().map(arg_string) {
+        Some(extra) => Err(format!("unexpected argument {:?}", extra)),
+        None => Ok(command),
+    }
+}
+
 /// Parsed and post-processed configuration
 pub struct Config {
     // See below for the documentation of each field:
+    pub command: Command,
     pub log: stderrlog::StdErrLog,
+    pub log_json: bool,
+    pub log_file: Option<PathBuf>,
+    pub daemonize: bool,
+    pub pid_file: Option<PathBuf>,
     pub network_type: Network,
     pub db_path: PathBuf,
     pub daemon_dir: PathBuf,
     pub blocks_dir: PathBuf,
-    pub daemon_rpc_addr: SocketAddr,
+    pub daemon_rpc_addr: DaemonAddr,
+    pub daemon_limits: DaemonLimits,
     pub electrum_rpc_addr: SocketAddr,
     pub monitoring_addr: SocketAddr,
+    pub monitoring_access: MonitoringAccess,
+    pub ready_max_lag: usize,
+    pub monitoring_push_addr: Option<SocketAddr>,
+    pub monitoring_push_interval: Duration,
     pub jsonrpc_import: bool,
+    pub read_only: bool,
+    pub watch: Option<WatchedScripts>,
+    pub low_memory: bool,
     pub wait_duration: Duration,
     pub index_batch_size: usize,
     pub bulk_index_threads: usize,
     pub tx_cache_size: usize,
     pub txid_limit: usize,
+    pub mempool_filter: MempoolFilter,
+    pub max_history: usize,
+    pub max_status_size: usize,
+    pub max_fee_rate: f64, // in BTC/kvB
     pub server_banner: String,
+    pub electrum_rpc_threads: usize,
+    pub electrum_rpc_queue_size: usize,
+    pub max_connections_per_ip: usize,
+    pub connection_limit_exempt_ips: Vec<IpAddr>,
+    pub electrum_request_timeout: Option<Duration>,
+    pub electrum_write_timeout: Option<Duration>,
+    pub shutdown_timeout: Duration,
+    pub http_addr: Option<SocketAddr>,
+    pub admin_socket: Option<PathBuf>,
+    pub replication_addr: Option<SocketAddr>,
+    pub replicate_from: Option<SocketAddr>,
+    pub replication_secret: Vec<u8>, // not logged
+    pub replication_timeout: Duration,
     pub blocktxids_cache_size: usize,
+    pub block_cache_size: usize,
+    pub merkle_cache_size: usize,
+    pub merkle_tree_blocks: usize,
+    pub status_cache_size: usize,
+    pub silent_payments: bool,
+    pub tweaks_cache_size: usize,
+    pub cookie_getter: Arc<dyn CookieGetter>,
+    pub extra_networks: Vec<NetworkConfig>,
+}
+
+/// The network-specific part of the configuration, for serving several networks from one process
+/// (see `Config::networks()`).
+#[derive(Clone)]
+pub struct NetworkConfig {
+    pub network_type: Network,
+    pub db_path: PathBuf,
+    pub daemon_dir: PathBuf,
+    pub blocks_dir: PathBuf,
+    pub daemon_rpc_addr: DaemonAddr,
     pub cookie_getter: Arc<dyn CookieGetter>,
+    pub electrum_rpc_addr: SocketAddr,
+    pub monitoring_addr: SocketAddr,
 }
 
 /// Returns default daemon directory
@@ -151,6 +301,87 @@ fn default_daemon_dir() -> PathBuf {
     home
 }
 
+/// Zero values disable the timeout
+fn timeout_secs(secs: u64) -> Option<Duration> {
+    if secs == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(secs))
+    }
+}
+
+/// Cache size in bytes, using smaller defaults on low memory systems
+fn cache_size(configured_mb: Option<f32>, default_mb: f32, low_memory: bool) -> usize {
+    const MB: f32 = (1 << 20) as f32;
+    let size_mb = configured_mb.unwrap_or(if low_memory {
+        default_mb / 10.0
+    } else {
+        default_mb
+    });
+    (size_mb * MB) as usize
+}
+
+/// Parses a comma-separated list of IP addresses.
+fn parse_ips(ips: &str) -> std::result::Result<Vec<IpAddr>, String> {
+    ips.split(',')
+        .map(str::trim)
+        .filter(|ip| !ip.is_empty())
+        .map(|ip| {
+            ip.parse()
+                .map_err(|err| format!("invalid IP address {:?}: {}", ip, err))
+        })
+        .collect()
+}
+
+/// Cache sizes must be non-negative numbers of megabytes
+fn check_cache_size(name: &str, configured_mb: Option<f32>, diag: &mut Diagnostics) {
+    if let Some(size_mb) = configured_mb {
+        if !(size_mb >= 0.0 && size_mb.is_finite()) {
+            diag.error(format!(
+                "invalid {}: {} (hint: use a size in MB, e.g. {} = 10 or {} = 0.5)",
+                name, size_mb, name, name
+            ));
+        }
+    }
+}
+
+/// Config files, in decreasing priority order
+fn config_files() -> Vec<PathBuf> {
+    let mut files = vec![PathBuf::from("electrs.toml")];
+    if let Some(mut dir) = home_dir() {
+        dir.extend(&[".electrs", "config.toml"]);
+        files.push(dir);
+    }
+    files.push(PathBuf::from("/etc/electrs/config.toml"));
+    files
+}
+
+/// Settings that can be changed without restarting the server (by sending `SIGHUP`).
+#[derive(Debug)]
+pub struct ReloadableConfig {
+    pub verbosity: usize,
+    pub server_banner: String,
+    pub electrum_rpc_queue_size: usize,
+    pub max_connections_per_ip: usize,
+    pub connection_limit_exempt_ips: Vec<IpAddr>,
+}
+
+/// Returns bitcoind's network-specific datadir (containing the cookie file and the blocks),
+/// also accepting it directly (e.g. `~/.bitcoin/testnet3` instead of `~/.bitcoin`).
+fn network_daemon_dir(daemon_dir: PathBuf, network: Network) -> PathBuf {
+    let subdir = match network {
+        Network::Bitcoin => return daemon_dir,
+        Network::Testnet => "testnet3",
+        Network::Regtest => "regtest",
+        Network::Signet => "signet",
+    };
+    let network_dir = daemon_dir.join(subdir);
+    if daemon_dir.ends_with(subdir) && !network_dir.exists() {
+        return daemon_dir;
+    }
+    network_dir
+}
+
 fn default_blocks_dir(daemon_dir: &Path) -> PathBuf {
     daemon_dir.join("blocks")
 }
@@ -159,20 +390,116 @@ fn create_cookie_getter(
     cookie: Option<String>,
     cookie_file: Option<PathBuf>,
     daemon_dir: &Path,
+    diag: &mut Diagnostics,
 ) -> Arc<dyn CookieGetter> {
     match (cookie, cookie_file) {
-        (None, None) => Arc::new(CookieFile::from_daemon_dir(daemon_dir)),
-        (None, Some(file)) => Arc::new(CookieFile::from_file(file)),
-        (Some(cookie), None) => Arc::new(StaticCookie::from_string(cookie)),
+        (None, None) => {
+            let cookie_file = daemon_dir.join(".cookie");
+            if !cookie_file.exists() {
+                // bitcoind may be still starting
+                diag.warning(format!(
+                    "cookie file {:?} doesn't exist yet (hint: set daemon_dir to bitcoind's datadir, or use cookie_file or auth)",
+                    cookie_file
+                ));
+            }
+            Arc::new(CookieFile::from_daemon_dir(daemon_dir))
+        }
+        (None, Some(file)) => {
+            if !file.exists() {
+                diag.warning(format!(
+                    "cookie_file {:?} doesn't exist yet (hint: it should point to bitcoind's .cookie file, or to a file containing USER:PASSWORD)",
+                    file
+                ));
+            }
+            Arc::new(CookieFile::from_file(file))
+        }
+        (Some(cookie), None) => Arc::new(StaticCookie::new(cookie, "auth".to_owned())),
+        (Some(_), Some(_)) => {
+            diag.error("ambigous configuration - cookie and cookie_file can't be specified at the same time (hint: remove one of them)".to_owned());
+            Arc::new(CookieFile::from_daemon_dir(daemon_dir))
+        }
+    }
+}
+
+/// Uses bitcoind's `rpcuser`/`rpcpassword` (or `rpcauth`) credentials, if configured
+fn create_user_cookie_getter(
+    user: Option<String>,
+    password: Option<String>,
+    password_file: Option<PathBuf>,
+    diag: &mut Diagnostics,
+) -> Option<Arc<dyn CookieGetter>> {
+    let user = match user {
+        Some(user) => user,
+        None => {
+            if password.is_some() || password_file.is_some() {
+                diag.error("daemon_rpc_password and daemon_rpc_password_file require daemon_rpc_user (hint: set it to bitcoind's rpcuser)".to_owned());
+            }
+            return None;
+        }
+    };
+    if user.contains(':') {
+        diag.error(format!("daemon_rpc_user {:?} can't contain ':'", user));
+    }
+    match (password, password_file) {
+        (Some(password), None) => {
+            let source = format!("daemon_rpc_user {:?} and daemon_rpc_password", user);
+            let cookie = format!("{}:{}", user, password);
+            Some(Arc::new(StaticCookie::new(cookie, source)))
+        }
+        (None, Some(password_file)) => {
+            if !password_file.is_file() {
+                diag.error(format!(
+                    "daemon_rpc_password_file {:?} doesn't exist (hint: it should contain bitcoind's rpcpassword)",
+                    password_file
+                ));
+            }
+            Some(Arc::new(PasswordFile {
+                user,
+                password_file,
+            }))
+        }
+        (None, None) => {
+            diag.error("daemon_rpc_user requires a password (hint: set daemon_rpc_password in a config file, or daemon_rpc_password_file)".to_owned());
+            None
+        }
         (Some(_), Some(_)) => {
-            eprintln!("Error: ambigous configuration - cookie and cookie_file can't be specified at the same time");
-            std::process::exit(1);
+            diag.error("daemon_rpc_password and daemon_rpc_password_file can't be specified at the same time (hint: remove one of them)".to_owned());
+            None
+        }
+    }
+}
+
+/// Reads the monitoring credentials from a file (e.g. a container secret), if configured
+fn monitoring_auth(
+    auth: Option<String>,
+    auth_file: Option<PathBuf>,
+    diag: &mut Diagnostics,
+) -> Option<String> {
+    match (auth, auth_file) {
+        (auth, None) => auth,
+        (None, Some(path)) => match fs::read_to_string(&path) {
+            Ok(auth) => Some(auth.trim().to_owned()),
+            Err(err) => {
+                diag.error(format!(
+                    "failed to read monitoring_auth_file {:?}: {}",
+                    path, err
+                ));
+                None
+            }
+        },
+        (Some(_), Some(_)) => {
+            diag.error("monitoring_auth and monitoring_auth_file can't be specified at the same time (hint: remove one of them)".to_owned());
+            None
         }
     }
 }
 
 /// Processes deprecation of cookie in favor of auth
-fn select_auth(auth: Option<String>, cookie: Option<String>) -> Option<String> {
+fn select_auth(
+    auth: Option<String>,
+    cookie: Option<String>,
+    diag: &mut Diagnostics,
+) -> Option<String> {
     match (cookie, auth) {
         (None, None) => None,
         (Some(value), None) => {
@@ -188,11 +515,139 @@ fn select_auth(auth: Option<String>, cookie: Option<String>) -> Option<String> {
         }
         (None, Some(value)) => Some(value),
         (Some(_), Some(_)) => {
-            eprintln!("Error: cookie and auth can't be specified at the same time");
-            eprintln!("It looks like you made a mistake during migrating cookie option, please check your config.");
-            std::process::exit(1);
+            diag.error("cookie and auth can't be specified at the same time (hint: it looks like you made a mistake during migrating cookie option, please check your config)".to_owned());
+            None
+        }
+    }
+}
+
+/// Parses a comma-separated list of networks, served in addition to the `main` one.
+fn parse_networks(networks: &str, main: Network) -> std::result::Result<Vec<Network>, String> {
+    let mut result = vec![];
+    for name in networks
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        let network = Network::from_str(name)
+            .map_err(|err| format!("invalid network {:?}: {}", name, err))?;
+        if network == main || result.contains(&network) {
+            return Err(format!("network {} is already served", network));
+        }
+        result.push(network);
+    }
+    Ok(result)
+}
+
+/// We must keep the name "mainnet" due to backwards compatibility
+fn db_subdir(network: Network) -> &'static str {
+    match network {
+        Network::Bitcoin => "mainnet",
+        Network::Testnet => "testnet",
+        Network::Regtest => "regtest",
+        Network::Signet => "signet",
+    }
+}
+
+/// Returns the default Electrum and monitoring ports
+fn default_ports(network: Network) -> (u16, u16) {
+    match network {
+        Network::Bitcoin => (50001, 4224),
+        Network::Testnet => (60001, 14224),
+        Network::Regtest => (60401, 24224),
+        Network::Signet => (60601, 34224),
+    }
+}
+
+/// Returns the daemon's JSONRPC address, its network-specific datadir and credentials
+/// (`daemon_rpc_addr` and `cookie_file` are used only for the main network, not the extra ones)
+fn daemon_rpc(
+    config: &internal::Config,
+    network: Network,
+    extra: bool,
+    diag: &mut Diagnostics,
+) -> (DaemonAddr, PathBuf, Arc<dyn CookieGetter>) {
+    let default_daemon_port = match network {
+        Network::Bitcoin => 8332,
+        Network::Testnet => 18332,
+        Network::Regtest => 18443,
+        Network::Signet => 38332,
+    };
+    let configured_addr = if extra {
+        None
+    } else {
+        config.daemon_rpc_addr.clone()
+    };
+    let daemon_rpc_addr = match config.daemon_proxy_addr.clone() {
+        // The proxy resolves the daemon address (which may be an onion service)
+        Some(proxy) => DaemonAddr::Socks5 {
+            proxy: diag.resolve(proxy),
+            target: configured_addr.map_or_else(
+                || format!("127.0.0.1:{}", default_daemon_port),
+                |addr| addr.0,
+            ),
+        },
+        None => DaemonAddr::Direct(configured_addr.map_or(
+            (DEFAULT_SERVER_ADDRESS, default_daemon_port).into(),
+            |addr| diag.resolve(addr),
+        )),
+    };
+
+    let daemon_dir = network_daemon_dir(config.daemon_dir.clone(), network);
+
+    let auth = select_auth(config.auth.clone(), config.cookie.clone(), diag);
+    let user_cookie_getter = create_user_cookie_getter(
+        config.daemon_rpc_user.clone(),
+        config.daemon_rpc_password.clone(),
+        config.daemon_rpc_password_file.clone(),
+        diag,
+    );
+    let cookie_file = if extra {
+        None
+    } else {
+        config.cookie_file.clone()
+    };
+    let cookie_getter = match user_cookie_getter {
+        Some(cookie_getter) => {
+            if auth.is_some() || cookie_file.is_some() {
+                diag.error("daemon_rpc_user can't be used together with auth, cookie or cookie_file (hint: remove them)".to_owned());
+            }
+            cookie_getter
+        }
+        None => create_cookie_getter(auth, cookie_file, &daemon_dir, diag),
+    };
+    (daemon_rpc_addr, daemon_dir, cookie_getter)
+}
+
+/// Infers the network from the chain of the first responding daemon, trying each network's
+/// default `daemon_rpc_addr` and `daemon_dir` (unless they are configured).
+fn infer_network<F>(mut query_chain: F) -> std::result::Result<Network, String>
+where
+    F: FnMut(Network) -> Result<String>,
+{
+    let networks = [
+        Network::Bitcoin,
+        Network::Testnet,
+        Network::Regtest,
+        Network::Signet,
+    ];
+    let mut errors = vec![];
+    for &network in &networks {
+        match query_chain(network) {
+            Ok(chain) => {
+                return networks
+                    .iter()
+                    .cloned()
+                    .find(|network| daemon::chain_name(*network) == chain)
+                    .ok_or_else(|| format!("bitcoind is running on unsupported '{}' chain", chain))
+            }
+            Err(err) => errors.push(format!("{}: {}", network, err)),
         }
     }
+    Err(format!(
+        "failed to infer network from bitcoind ({}) (hint: set network, or check daemon_rpc_addr and daemon_dir)",
+        errors.join(", ")
+    ))
 }
 
 impl Config {
@@ -200,124 +655,391 @@ impl Config {
     pub fn from_args() -> Config {
         use internal::ResultExt;
 
-        let system_config: &OsStr = "/etc/electrs/config.toml".as_ref();
-        let home_config = home_dir().map(|mut dir| {
-            dir.extend(&[".electrs", "config.toml"]);
-            dir
-        });
-        let cwd_config: &OsStr = "electrs.toml".as_ref();
-        let configs = std::iter::once(cwd_config)
-            .chain(home_config.as_ref().map(AsRef::as_ref))
-            .chain(std::iter::once(system_config));
-
-        let (mut config, _) =
-            internal::Config::including_optional_config_files(configs).unwrap_or_exit();
-
-        let db_subdir = match config.network {
-            // We must keep the name "mainnet" due to backwards compatibility
-            Network::Bitcoin => "mainnet",
-            Network::Testnet => "testnet",
-            Network::Regtest => "regtest",
-            Network::Signet => "signet",
-        };
+        let (mut config, args) =
+            internal::Config::including_optional_config_files(config_files()).unwrap_or_exit();
 
-        config.db_dir.push(db_subdir);
-
-        let default_daemon_port = match config.network {
-            Network::Bitcoin => 8332,
-            Network::Testnet => 18332,
-            Network::Regtest => 18443,
-            Network::Signet => 38332,
-        };
-        let default_electrum_port = match config.network {
-            Network::Bitcoin => 50001,
-            Network::Testnet => 60001,
-            Network::Regtest => 60401,
-            Network::Signet => 60601,
+        let mut diag = Diagnostics::default();
+        let daemon_limits = DaemonLimits {
+            connect_timeout: timeout_secs(config.daemon_connect_timeout_secs),
+            read_timeout: timeout_secs(config.daemon_read_timeout_secs),
+            write_timeout: timeout_secs(config.daemon_write_timeout_secs),
+            max_response_size: if config.daemon_max_response_mb == 0 {
+                None
+            } else {
+                Some(config.daemon_max_response_mb << 20)
+            },
+            startup_timeout: timeout_secs(config.daemon_startup_timeout_secs),
         };
-        let default_monitoring_port = match config.network {
-            Network::Bitcoin => 4224,
-            Network::Testnet => 14224,
-            Network::Regtest => 24224,
-            Network::Signet => 34224,
+        let network = match config.network {
+            Some(network) => network,
+            None => infer_network(|network| {
+                // the configuration errors are reported below (for the inferred network)
+                let (addr, _, cookie_getter) =
+                    daemon_rpc(&config, network, false, &mut Diagnostics::default());
+                daemon::query_chain(&addr, cookie_getter, daemon_limits)
+            })
+            .unwrap_or_else(|msg| {
+                diag.error(msg);
+                Network::Bitcoin
+            }),
         };
 
-        let daemon_rpc_addr: SocketAddr = config.daemon_rpc_addr.map_or(
-            (DEFAULT_SERVER_ADDRESS, default_daemon_port).into(),
-            ResolvAddr::resolve_or_exit,
-        );
+        let db_root = config.db_dir.clone();
+        config.db_dir.push(db_subdir(network));
+
+        let command = parse_command(args).unwrap_or_else(|msg| {
+            diag.error(msg);
+            Command::Serve
+        });
+
+        let (default_electrum_port, default_monitoring_port) = default_ports(network);
+
+        let (daemon_rpc_addr, daemon_dir, cookie_getter) =
+            daemon_rpc(&config, network, false, &mut diag);
+        let extra_networks = config.extra_networks.as_ref().map_or(vec![], |networks| {
+            diag.check(parse_networks(networks, network), vec![])
+        });
+        let extra_networks: Vec<NetworkConfig> = extra_networks
+            .into_iter()
+            .map(|network| {
+                // the credentials' errors are reported for the main network
+                let (daemon_rpc_addr, daemon_dir, cookie_getter) =
+                    daemon_rpc(&config, network, true, &mut Diagnostics::default());
+                let (electrum_port, monitoring_port) = default_ports(network);
+                NetworkConfig {
+                    network_type: network,
+                    db_path: db_root.join(db_subdir(network)),
+                    blocks_dir: default_blocks_dir(&daemon_dir),
+                    daemon_dir,
+                    daemon_rpc_addr,
+                    cookie_getter,
+                    electrum_rpc_addr: (DEFAULT_SERVER_ADDRESS, electrum_port).into(),
+                    monitoring_addr: (DEFAULT_SERVER_ADDRESS, monitoring_port).into(),
+                }
+            })
+            .collect();
+        config.daemon_dir = daemon_dir;
         let electrum_rpc_addr: SocketAddr = config.electrum_rpc_addr.map_or(
             (DEFAULT_SERVER_ADDRESS, default_electrum_port).into(),
-            ResolvAddr::resolve_or_exit,
+            |addr| diag.resolve(addr),
         );
         let monitoring_addr: SocketAddr = config.monitoring_addr.map_or(
             (DEFAULT_SERVER_ADDRESS, default_monitoring_port).into(),
-            ResolvAddr::resolve_or_exit,
+            |addr| diag.resolve(addr),
         );
+        if electrum_rpc_addr == monitoring_addr {
+            diag.error(format!(
+                "electrum_rpc_addr and monitoring_addr are both {} (hint: use different ports)",
+                electrum_rpc_addr
+            ));
+        }
+        let monitoring_push_addr = config.monitoring_push_addr.map(|addr| diag.resolve(addr));
+        let http_addr: Option<SocketAddr> = config.http_addr.map(|addr| diag.resolve(addr));
+        if let Some(http_addr) = http_addr {
+            if http_addr == electrum_rpc_addr || http_addr == monitoring_addr {
+                diag.error(format!(
+                    "http_addr {} is already used by electrum_rpc_addr or monitoring_addr",
+                    http_addr
+                ));
+            }
+        }
 
-        match config.network {
-            Network::Bitcoin => (),
-            Network::Testnet => config.daemon_dir.push("testnet3"),
-            Network::Regtest => config.daemon_dir.push("regtest"),
-            Network::Signet => config.daemon_dir.push("signet"),
+        let mut used_addrs = vec![electrum_rpc_addr, monitoring_addr];
+        used_addrs.extend(http_addr);
+        for extra in &extra_networks {
+            for addr in &[extra.electrum_rpc_addr, extra.monitoring_addr] {
+                if used_addrs.contains(addr) {
+                    diag.error(format!(
+                        "{}'s default port {} is already used (hint: change the main network's addresses)",
+                        extra.network_type, addr
+                    ));
+                }
+                used_addrs.push(*addr);
+            }
+            if config.read_only && !extra.db_path.is_dir() {
+                diag.error(format!(
+                    "read_only requires an existing index at {:?} (hint: index {} without read_only first)",
+                    extra.db_path, extra.network_type
+                ));
+            }
+        }
+
+        let replication_addr = config.replication_addr.map(|addr| diag.resolve(addr));
+        if let Some(addr) = replication_addr {
+            if used_addrs.contains(&addr) {
+                diag.error(format!("replication_addr {} is already used", addr));
+            }
         }
+        let replicate_from = config.replicate_from.map(|addr| diag.resolve(addr));
+        if replicate_from.is_some() && (config.read_only || !extra_networks.is_empty()) {
+            diag.error("replicate_from can't be used with read_only or extra_networks".to_owned());
+        }
+        let replication_secret = match config.replication_secret_file {
+            _ if replication_addr.is_none() && replicate_from.is_none() => vec![],
+            Some(ref path) => match read_secret(path) {
+                Ok(ref secret) if secret.is_empty() => {
+                    diag.error(format!("replication_secret_file {:?} is empty", path));
+                    vec![]
+                }
+                Ok(secret) => secret,
+                Err(_) => {
+                    diag.error(format!("failed to read replication_secret_file {:?}", path));
+                    vec![]
+                }
+            },
+            None => {
+                diag.error("replication_addr and replicate_from require replication_secret_file (hint: create a file with a random secret, shared by the primary and its standbys)".to_owned());
+                vec![]
+            }
+        };
+
+        let watch_gap_limit = config.watch_gap_limit;
+        let watch = match config.watch_file {
+            Some(ref path) => {
+                if !extra_networks.is_empty() {
+                    diag.error("watch_file can't be used with extra_networks".to_owned());
+                }
+                if watch_gap_limit == 0 {
+                    diag.error("watch_gap_limit must be positive".to_owned());
+                }
+                let scripts = fs::read_to_string(path)
+                    .chain_err(|| "failed to read it")
+                    .and_then(|text| WatchedScripts::parse(&text, network, watch_gap_limit))
+                    .map_err(|e| format!("invalid watch_file {:?}: {}", path, e));
+                diag.check(scripts.map(Some), None)
+            }
+            None => None,
+        };
+        let jsonrpc_import = config.jsonrpc_import || config.watch_file.is_some();
 
         let daemon_dir = &config.daemon_dir;
         let blocks_dir = config
             .blocks_dir
             .unwrap_or_else(|| default_blocks_dir(daemon_dir));
 
-        let auth = select_auth(config.auth, config.cookie);
-        let cookie_getter = create_cookie_getter(auth, config.cookie_file, daemon_dir);
+        if !jsonrpc_import && !config.read_only && !blocks_dir.is_dir() {
+            diag.warning(format!(
+                "blocks_dir {:?} doesn't exist, so blocks will be fetched via JSONRPC (hint: set daemon_dir or blocks_dir, or use jsonrpc_import)",
+                blocks_dir
+            ));
+        }
+        if config.read_only && !config.db_dir.is_dir() {
+            diag.error(format!(
+                "read_only requires an existing index at {:?} (hint: index without read_only first, or fix db_dir and network)",
+                config.db_dir
+            ));
+        }
+        let monitoring_access = MonitoringAccess {
+            auth: monitoring_auth(
+                config.monitoring_auth,
+                config.monitoring_auth_file,
+                &mut diag,
+            ),
+            allowed_ips: diag.parse_ips(config.monitoring_allowed_ips),
+        };
+        let connection_limit_exempt_ips = diag.parse_ips(config.connection_limit_exempt_ips);
+        check_cache_size("tx_cache_size_mb", config.tx_cache_size_mb, &mut diag);
+        check_cache_size(
+            "blocktxids_cache_size_mb",
+            config.blocktxids_cache_size_mb,
+            &mut diag,
+        );
+        check_cache_size("block_cache_size_mb", config.block_cache_size_mb, &mut diag);
+        check_cache_size(
+            "merkle_cache_size_mb",
+            config.merkle_cache_size_mb,
+            &mut diag,
+        );
+        check_cache_size(
+            "status_cache_size_mb",
+            config.status_cache_size_mb,
+            &mut diag,
+        );
+        check_cache_size(
+            "tweaks_cache_size_mb",
+            config.tweaks_cache_size_mb,
+            &mut diag,
+        );
+        if !(config.mempool_min_fee_rate >= 0.0 && config.mempool_min_fee_rate.is_finite()) {
+            diag.error(format!(
+                "invalid mempool_min_fee_rate: {} (hint: use a fee rate in sat/vbyte, e.g. 1.5)",
+                config.mempool_min_fee_rate
+            ));
+        }
+        if !(config.max_fee_rate >= 0.0 && config.max_fee_rate.is_finite()) {
+            diag.error(format!(
+                "invalid max_fee_rate: {} (hint: use a fee rate in BTC/kvB, e.g. 0.1)",
+                config.max_fee_rate
+            ));
+        }
+        if config.index_batch_size == 0 {
+            diag.error("index_batch_size must be positive (hint: the default is 10)".to_owned());
+        }
+        if config.log_json && config.log_syslog {
+            diag.error(
+                "log_json and log_syslog can't be used together (hint: remove one of them)"
+                    .to_owned(),
+            );
+        }
+        if config.daemonize && config.log_file.is_none() && !config.log_syslog {
+            diag.warning(
+                "logs are discarded when daemonized (hint: set log_file or log_syslog)".to_owned(),
+            );
+        }
+        diag.check_parent_dir("log_file", &config.log_file);
+        diag.check_parent_dir("pid_file", &config.pid_file);
+        diag.check_parent_dir("admin_socket", &config.admin_socket);
+        diag.report();
 
+        let verbosity: usize = config
+            .verbose
+            .try_into()
+            .expect("Overflow: Running electrs on less than 32 bit devices is unsupported");
         let mut log = stderrlog::new();
-        log.verbosity(
-            config
-                .verbose
-                .try_into()
-                .expect("Overflow: Running electrs on less than 32 bit devices is unsupported"),
-        );
+        log.verbosity(4); // filtered by `logger::set_verbosity()`, so it can be reloaded
         log.timestamp(if config.timestamp {
             stderrlog::Timestamp::Millisecond
         } else {
             stderrlog::Timestamp::Off
         });
-        log.init().unwrap_or_else(|err| {
+        if let Some(ref path) = config.log_file {
+            daemonize::redirect_stderr(path).unwrap_or_else(|err| {
+                eprintln!("Error: {}", err);
+                std::process::exit(1)
+            });
+        }
+        let log_result = if config.log_syslog {
+            logger::init_syslog(verbosity).map_err(|err| err.to_string())
+        } else if config.log_json {
+            logger::init(verbosity).map_err(|err| err.to_string())
+        } else {
+            log.init().map_err(|err| err.to_string())
+        };
+        log_result.unwrap_or_else(|err| {
             eprintln!("Error: logging initialization failed: {}", err);
             std::process::exit(1)
         });
+        logger::set_verbosity(verbosity);
         // Could have been default, but it's useful to allow the user to specify 0 when overriding
         // configs.
         if config.bulk_index_threads == 0 {
             config.bulk_index_threads = num_cpus::get();
         }
-        const MB: f32 = (1 << 20) as f32;
+        if config.electrum_rpc_threads == 0 {
+            config.electrum_rpc_threads = num_cpus::get();
+        }
         let config = Config {
+            command,
             log,
-            network_type: config.network,
+            log_json: config.log_json,
+            log_file: config.log_file,
+            daemonize: config.daemonize,
+            pid_file: config.pid_file,
+            network_type: network,
             db_path: config.db_dir,
             daemon_dir: config.daemon_dir,
             blocks_dir,
             daemon_rpc_addr,
+            daemon_limits,
             electrum_rpc_addr,
             monitoring_addr,
-            jsonrpc_import: config.jsonrpc_import,
+            monitoring_access,
+            ready_max_lag: config.ready_max_lag,
+            monitoring_push_addr,
+            monitoring_push_interval: Duration::from_secs(config.monitoring_push_interval_secs),
+            jsonrpc_import,
+            read_only: config.read_only,
+            watch,
             wait_duration: Duration::from_secs(config.wait_duration_secs),
             index_batch_size: config.index_batch_size,
             bulk_index_threads: config.bulk_index_threads,
-            tx_cache_size: (config.tx_cache_size_mb * MB) as usize,
-            blocktxids_cache_size: (config.blocktxids_cache_size_mb * MB) as usize,
+            low_memory: config.low_memory,
+            tx_cache_size: cache_size(config.tx_cache_size_mb, 10.0, config.low_memory),
+            blocktxids_cache_size: cache_size(
+                config.blocktxids_cache_size_mb,
+                10.0,
+                config.low_memory,
+            ),
+            block_cache_size: cache_size(config.block_cache_size_mb, 10.0, config.low_memory),
+            merkle_cache_size: cache_size(config.merkle_cache_size_mb, 1.0, config.low_memory),
+            merkle_tree_blocks: config.merkle_tree_blocks,
+            status_cache_size: cache_size(config.status_cache_size_mb, 10.0, config.low_memory),
+            silent_payments: config.silent_payments,
+            tweaks_cache_size: cache_size(config.tweaks_cache_size_mb, 10.0, config.low_memory),
             txid_limit: config.txid_limit,
+            mempool_filter: MempoolFilter {
+                min_fee_rate: config.mempool_min_fee_rate,
+                min_value: config.mempool_min_value,
+            },
+            max_history: config.max_history,
+            max_status_size: config.max_status_size_mb << 20,
+            max_fee_rate: config.max_fee_rate,
             server_banner: config.server_banner,
+            electrum_rpc_threads: config.electrum_rpc_threads,
+            electrum_rpc_queue_size: config.electrum_rpc_queue_size,
+            max_connections_per_ip: config.max_connections_per_ip,
+            connection_limit_exempt_ips,
+            electrum_request_timeout: timeout_secs(config.electrum_request_timeout_secs),
+            electrum_write_timeout: timeout_secs(config.electrum_write_timeout_secs),
+            shutdown_timeout: Duration::from_secs(config.shutdown_timeout_secs),
+            http_addr,
+            admin_socket: config.admin_socket,
+            replication_addr,
+            replicate_from,
+            replication_secret,
+            replication_timeout: Duration::from_secs(config.replication_timeout_secs),
             cookie_getter,
+            extra_networks,
         };
         eprintln!("{:?}", config);
         config
     }
 
+    /// Re-reads args, env vars and config files, returning the reloadable settings.
+    /// Unlike `from_args()`, invalid configuration is returned as an error.
+    pub fn reload() -> Result<ReloadableConfig> {
+        let (config, _) = match internal::Config::including_optional_config_files(config_files()) {
+            Ok(config) => config,
+            Err(err) => bail!("invalid configuration: {}", err),
+        };
+        Ok(ReloadableConfig {
+            verbosity: config
+                .verbose
+                .try_into()
+                .chain_err(|| "invalid verbosity")?,
+            server_banner: config.server_banner,
+            electrum_rpc_queue_size: config.electrum_rpc_queue_size,
+            max_connections_per_ip: config.max_connections_per_ip,
+            connection_limit_exempt_ips: match config.connection_limit_exempt_ips {
+                Some(ips) => parse_ips(&ips)?,
+                None => vec![],
+            },
+        })
+    }
+
     pub fn cookie_getter(&self) -> Arc<dyn CookieGetter> {
         Arc::clone(&self.cookie_getter)
     }
+
+    pub fn main_network(&self) -> NetworkConfig {
+        NetworkConfig {
+            network_type: self.network_type,
+            db_path: self.db_path.clone(),
+            daemon_dir: self.daemon_dir.clone(),
+            blocks_dir: self.blocks_dir.clone(),
+            daemon_rpc_addr: self.daemon_rpc_addr.clone(),
+            cookie_getter: self.cookie_getter(),
+            electrum_rpc_addr: self.electrum_rpc_addr,
+            monitoring_addr: self.monitoring_addr,
+        }
+    }
+
+    /// The main network, followed by the extra ones (served by the same process).
+    pub fn networks(&self) -> Vec<NetworkConfig> {
+        let mut networks = vec![self.main_network()];
+        networks.extend(self.extra_networks.iter().cloned());
+        networks
+    }
 }
 
 // CookieGetter + Debug isn't implemented in Rust, so we have to skip cookie_getter
@@ -336,31 +1058,79 @@ macro_rules! debug_struct {
 }
 
 debug_struct! { Config,
+    command,
     log,
+    log_json,
+    log_file,
+    daemonize,
+    pid_file,
     network_type,
     db_path,
     daemon_dir,
     blocks_dir,
     daemon_rpc_addr,
+    daemon_limits,
     electrum_rpc_addr,
     monitoring_addr,
+    monitoring_access,
+    ready_max_lag,
+    monitoring_push_addr,
+    monitoring_push_interval,
     jsonrpc_import,
+    read_only,
+    watch,
+    low_memory,
     index_batch_size,
     bulk_index_threads,
     tx_cache_size,
     txid_limit,
+    mempool_filter,
+    max_history,
+    max_status_size,
+    max_fee_rate,
     server_banner,
+    electrum_rpc_threads,
+    electrum_rpc_queue_size,
+    max_connections_per_ip,
+    connection_limit_exempt_ips,
+    electrum_request_timeout,
+    electrum_write_timeout,
+    shutdown_timeout,
+    http_addr,
+    admin_socket,
+    replication_addr,
+    replicate_from,
+    replication_timeout,
     blocktxids_cache_size,
+    block_cache_size,
+    merkle_cache_size,
+    merkle_tree_blocks,
+    status_cache_size,
+    silent_payments,
+    tweaks_cache_size,
+    extra_networks,
+}
+
+debug_struct! { NetworkConfig,
+    network_type,
+    db_path,
+    daemon_dir,
+    blocks_dir,
+    daemon_rpc_addr,
+    electrum_rpc_addr,
+    monitoring_addr,
 }
 
 struct StaticCookie {
     value: Vec<u8>,
+    source: String, // the configuration options
 }
 
 impl StaticCookie {
-    fn from_string(value: String) -> Self {
+    fn new(value: String, source: String) -> Self {
         StaticCookie {
             value: value.into(),
+            source,
         }
     }
 }
@@ -369,6 +1139,10 @@ impl CookieGetter for StaticCookie {
     fn get(&self) -> Result<Vec<u8>> {
         Ok(self.value.clone())
     }
+
+    fn source(&self) -> String {
+        self.source.clone()
+    }
 }
 
 struct CookieFile {
@@ -387,14 +1161,160 @@ impl CookieFile {
     }
 }
 
+fn read_secret(path: &Path) -> Result<Vec<u8>> {
+    let mut contents = fs::read(path).chain_err(|| {
+        ErrorKind::Connection(format!("failed to read cookie from {}", path.display()))
+    })?;
+    // hand-written files (e.g. container secrets) usually end with a newline
+    while contents.last().map_or(false, u8::is_ascii_whitespace) {
+        contents.pop();
+    }
+    Ok(contents)
+}
+
 impl CookieGetter for CookieFile {
     fn get(&self) -> Result<Vec<u8>> {
-        let contents = fs::read(&self.cookie_file).chain_err(|| {
-            ErrorKind::Connection(format!(
-                "failed to read cookie from {}",
-                self.cookie_file.display()
-            ))
-        })?;
-        Ok(contents)
+        read_secret(&self.cookie_file)
+    }
+
+    fn source(&self) -> String {
+        format!("cookie file {:?}", self.cookie_file)
+    }
+}
+
+/// Reads the password on each connection, so it can be rotated without restarting electrs.
+struct PasswordFile {
+    user: String,
+    password_file: PathBuf,
+}
+
+impl CookieGetter for PasswordFile {
+    fn get(&self) -> Result<Vec<u8>> {
+        let mut cookie = format!("{}:", self.user).into_bytes();
+        cookie.extend(read_secret(&self.password_file)?);
+        Ok(cookie)
+    }
+
+    fn source(&self) -> String {
+        format!(
+            "daemon_rpc_user {:?} and daemon_rpc_password_file {:?}",
+            self.user, self.password_file
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(args: &[&str]) -> std::result::Result<Command, String> {
+        parse_command(args.iter().map(OsString::from))
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(command(&[]), Ok(Command::Serve));
+        assert_eq!(command(&["serve"]), Ok(Command::Serve));
+        assert_eq!(command(&["sync"]), Ok(Command::Sync));
+        assert_eq!(command(&["index"]), Ok(Command::Index));
+        assert_eq!(command(&["compact"]), Ok(Command::Compact));
+        assert_eq!(
+            command(&["query", "abcd"]),
+            Ok(Command::Query("abcd".to_owned()))
+        );
+        assert_eq!(command(&["inspect"]), Ok(Command::Inspect(vec![])));
+        assert_eq!(
+            command(&["inspect", "keys", "10"]),
+            Ok(Command::Inspect(vec!["keys".to_owned(), "10".to_owned()]))
+        );
+
+        assert!(command(&["query"]).is_err());
+        assert!(command(&["query", "abcd", "efgh"]).is_err());
+        assert!(command(&["serve", "now"]).is_err());
+        assert!(command(&["index", "--verbose"]).is_err());
+        assert!(command(&["reindex"])
+            .unwrap_err()
+            .contains("unknown command \"reindex\""));
+    }
+
+    #[test]
+    fn test_parse_ips() {
+        let ips = parse_ips(" 127.0.0.1, ::1,,").unwrap();
+        let expected: Vec<IpAddr> = vec!["127.0.0.1".parse().unwrap(), "::1".parse().unwrap()];
+        assert_eq!(ips, expected);
+        assert!(parse_ips("").unwrap().is_empty());
+        assert!(parse_ips("127.0.0.1,localhost").is_err());
+        assert!(parse_ips("10.0.0.0/8").is_err());
+    }
+
+    #[test]
+    fn test_check_cache_size() {
+        let mut diag = Diagnostics::default();
+        for &size in &[None, Some(0.0), Some(0.5), Some(1024.0)] {
+            check_cache_size("db_cache_mb", size, &mut diag);
+        }
+        assert!(diag.errors.is_empty());
+        for &size in &[-1.0, std::f32::NAN, std::f32::INFINITY] {
+            check_cache_size("db_cache_mb", Some(size), &mut diag);
+        }
+        assert_eq!(diag.errors.len(), 3);
+        assert!(diag.errors[0].starts_with("invalid db_cache_mb: -1 "));
+    }
+
+    #[test]
+    fn test_infer_network() {
+        // only a regtest bitcoind is running (at the testnet's default port)
+        let mut queried = vec![];
+        let network = infer_network(|network| {
+            queried.push(network);
+            match network {
+                Network::Bitcoin => bail!("connection refused"),
+                _ => Ok("regtest".to_owned()),
+            }
+        });
+        assert_eq!(network, Ok(Network::Regtest));
+        assert_eq!(queried, vec![Network::Bitcoin, Network::Testnet]);
+
+        let err = infer_network(|_| Ok("liquidv1".to_owned())).unwrap_err();
+        assert_eq!(err, "bitcoind is running on unsupported 'liquidv1' chain");
+
+        let err = infer_network(|_| bail!("connection refused")).unwrap_err();
+        assert!(err.starts_with("failed to infer network from bitcoind (bitcoin: connection refused, testnet: connection refused, "));
+    }
+
+    #[test]
+    fn test_parse_networks() {
+        assert_eq!(parse_networks("", Network::Bitcoin), Ok(vec![]));
+        assert_eq!(
+            parse_networks("signet, testnet", Network::Bitcoin),
+            Ok(vec![Network::Signet, Network::Testnet])
+        );
+        let err = parse_networks("signet,signet", Network::Bitcoin).unwrap_err();
+        assert_eq!(err, "network signet is already served");
+        let err = parse_networks("bitcoin", Network::Bitcoin).unwrap_err();
+        assert_eq!(err, "network bitcoin is already served");
+        let err = parse_networks("liquid", Network::Bitcoin).unwrap_err();
+        assert!(err.starts_with("invalid network \"liquid\": "));
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let mut diag = Diagnostics::default();
+        assert_eq!(diag.check::<_, String>(Ok(1), 0), 1);
+        assert_eq!(diag.check(Err("bad value"), 0), 0);
+        let addr = diag.resolve(ResolvAddr("127.0.0.1:50001".to_owned()));
+        assert_eq!(addr, ([127, 0, 0, 1], 50001).into());
+        let addr = diag.resolve(ResolvAddr("127.0.0.1".to_owned())); // missing port
+        assert_eq!(addr, (DEFAULT_SERVER_ADDRESS, 0).into());
+        assert!(diag.parse_ips(None).is_empty());
+        assert!(diag.parse_ips(Some("::1,x".to_owned())).is_empty());
+        diag.check_parent_dir("log_file", &Some(PathBuf::from("electrs.log")));
+        diag.check_parent_dir("log_file", &Some(std::env::temp_dir().join("electrs.log")));
+        diag.check_parent_dir("log_file", &Some(PathBuf::from("/nonexistent/electrs.log")));
+        diag.warning("just a warning".to_owned());
+        assert_eq!(diag.warnings, vec!["just a warning"]);
+        assert_eq!(diag.errors.len(), 4);
+        assert_eq!(diag.errors[0], "bad value");
+        assert!(diag.errors[3].starts_with("log_file's directory \"/nonexistent\" doesn't exist"));
     }
 }
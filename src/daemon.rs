@@ -1,4 +1,5 @@
 use bitcoin::blockdata::block::{Block, BlockHeader};
+use bitcoin::blockdata::script::Script;
 use bitcoin::blockdata::transaction::Transaction;
 use bitcoin::consensus::encode::{deserialize, serialize};
 use bitcoin::hash_types::{BlockHash, Txid};
@@ -6,17 +7,19 @@ use bitcoin::hashes::hex::{FromHex, ToHex};
 use bitcoin::hashes::Hash;
 use bitcoin::network::constants::Network;
 use serde_json::{from_str, from_value, Map, Value};
+use std::cmp;
 use std::collections::{HashMap, HashSet};
-use std::io::{BufRead, BufReader, Lines, Write};
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
 use std::net::{SocketAddr, TcpStream};
 use std::path::PathBuf;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crate::cache::BlockTxIDsCache;
 use crate::errors::*;
-use crate::metrics::{HistogramOpts, HistogramVec, Metrics};
+use crate::metrics::{FloatGauge, HistogramOpts, HistogramVec, MetricOpts, Metrics};
 use crate::signal::Waiter;
 use crate::util::HeaderList;
 
@@ -71,7 +74,7 @@ fn check_error_code(reply_obj: &mut Map<String, Value>, method: &str) -> Result<
     Ok(())
 }
 
-fn parse_jsonrpc_reply(mut reply: Value, method: &str, expected_id: u64) -> Result<Value> {
+pub fn parse_jsonrpc_reply(mut reply: Value, method: &str, expected_id: u64) -> Result<Value> {
     if let Some(reply_obj) = reply.as_object_mut() {
         let id = reply_obj
             .get("id")
@@ -142,23 +145,222 @@ impl MempoolEntry {
 
 pub trait CookieGetter: Send + Sync {
     fn get(&self) -> Result<Vec<u8>>;
+
+    /// Describes where the credentials come from (for authentication errors).
+    fn source(&self) -> String;
+}
+
+/// Returns the chain name reported by bitcoind's `getblockchaininfo` for `network`.
+pub fn chain_name(network: Network) -> &'static str {
+    match network {
+        Network::Bitcoin => "main",
+        Network::Testnet => "test",
+        Network::Regtest => "regtest",
+        Network::Signet => "signet",
+    }
+}
+
+/// Returns the chain bitcoind is running on (e.g. for inferring the network before startup),
+/// failing if it's unavailable (without retrying).
+pub fn query_chain(
+    addr: &DaemonAddr,
+    cookie_getter: Arc<dyn CookieGetter>,
+    limits: DaemonLimits,
+) -> Result<String> {
+    let deadline = Some(Instant::now());
+    let mut conn = Connection::new(
+        addr.clone(),
+        cookie_getter,
+        limits,
+        Waiter::idle(),
+        deadline,
+    )?;
+    let request = json!({"method": "getblockchaininfo", "params": [], "id": 0});
+    let reply: Value = from_str(&conn.call(&request.to_string())?).chain_err(|| "invalid JSON")?;
+    let info: BlockchainInfo = from_value(parse_jsonrpc_reply(reply, "getblockchaininfo", 0)?)
+        .chain_err(|| "invalid blockchain info")?;
+    Ok(info.chain)
+}
+
+/// Sends JSONRPC requests to bitcoind (may be replaced by a fake, e.g. for testing).
+pub trait RpcTransport: Send {
+    /// Returns the JSONRPC response for a serialized request.
+    fn call(&mut self, request: &str) -> Result<String>;
+
+    /// Should be retried on `ErrorKind::Connection` failures (until `deadline`, if set).
+    fn reconnect(&self, deadline: Option<Instant>) -> Result<Box<dyn RpcTransport>>;
+}
+
+/// Address of the daemon JSONRPC endpoint.
+#[derive(Clone, Debug)]
+pub enum DaemonAddr {
+    Direct(SocketAddr),
+    /// Connect via a SOCKS5 proxy (e.g. Tor), which resolves the 'host:port' target.
+    Socks5 {
+        proxy: SocketAddr,
+        target: String,
+    },
+}
+
+impl fmt::Display for DaemonAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DaemonAddr::Direct(addr) => write!(f, "{}", addr),
+            DaemonAddr::Socks5 { proxy, target } => write!(f, "{} (via {})", target, proxy),
+        }
+    }
+}
+
+/// Timeouts and limits for the daemon JSONRPC connection (`None` means unlimited).
+#[derive(Clone, Copy, Debug)]
+pub struct DaemonLimits {
+    pub connect_timeout: Option<Duration>,
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    pub max_response_size: Option<usize>,
+    pub startup_timeout: Option<Duration>, // for bitcoind to become available
 }
 
 struct Connection {
     tx: TcpStream,
-    rx: Lines<BufReader<TcpStream>>,
+    rx: BufReader<TcpStream>,
     cookie_getter: Arc<dyn CookieGetter>,
-    addr: SocketAddr,
+    addr: DaemonAddr,
+    limits: DaemonLimits,
     signal: Waiter,
 }
 
-fn tcp_connect(addr: SocketAddr, signal: &Waiter) -> Result<TcpStream> {
+fn connect_with_timeout(addr: &SocketAddr, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    match timeout {
+        Some(timeout) => TcpStream::connect_timeout(addr, timeout),
+        None => TcpStream::connect(addr),
+    }
+}
+
+fn socks5_error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, msg)
+}
+
+/// Perform a SOCKS5 CONNECT handshake (RFC 1928) without authentication.
+fn socks5_connect(proxy: SocketAddr, target: &str, limits: &DaemonLimits) -> io::Result<TcpStream> {
+    let mut parts = target.rsplitn(2, ':');
+    let port: u16 = parts
+        .next()
+        .and_then(|port| port.parse().ok())
+        .ok_or_else(|| socks5_error(format!("invalid port in {:?}", target)))?;
+    let host = parts
+        .next()
+        .map(|host| host.trim_start_matches('[').trim_end_matches(']'))
+        .filter(|host| !host.is_empty() && host.len() <= 255)
+        .ok_or_else(|| socks5_error(format!("invalid host in {:?}", target)))?;
+
+    let mut conn = connect_with_timeout(&proxy, limits.connect_timeout)?;
+    conn.set_read_timeout(limits.read_timeout)?;
+    conn.set_write_timeout(limits.write_timeout)?;
+    conn.write_all(&[5, 1, 0])?; // version 5, 1 method: no authentication
+    let mut reply = [0u8; 2];
+    conn.read_exact(&mut reply)?;
+    if reply != [5, 0] {
+        return Err(socks5_error(format!(
+            "unsupported SOCKS5 method: {:?}",
+            reply
+        )));
+    }
+
+    let mut request = vec![5, 1, 0, 3, host.len() as u8]; // CONNECT to a domain name
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    conn.write_all(&request)?;
+    let mut reply = [0u8; 4];
+    conn.read_exact(&mut reply)?;
+    if reply[1] != 0 {
+        return Err(socks5_error(format!(
+            "SOCKS5 CONNECT failed: code {}",
+            reply[1]
+        )));
+    }
+    // skip the bound address and port
+    let addr_len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut len = [0u8; 1];
+            conn.read_exact(&mut len)?;
+            len[0] as usize
+        }
+        atyp => {
+            return Err(socks5_error(format!(
+                "invalid SOCKS5 address type {}",
+                atyp
+            )))
+        }
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    conn.read_exact(&mut bound)?;
+    Ok(conn)
+}
+
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(3);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Doubles the delay between consecutive retries, up to `MAX_RETRY_DELAY`.
+/// Gives up after the deadline (if set), e.g. when bitcoind doesn't start.
+struct Backoff {
+    delay: Duration,
+    started: Instant,
+    deadline: Option<Instant>,
+}
+
+impl Backoff {
+    fn new(deadline: Option<Instant>) -> Self {
+        Backoff {
+            delay: MIN_RETRY_DELAY,
+            started: Instant::now(),
+            deadline,
+        }
+    }
+
+    fn wait(&mut self, signal: &Waiter) -> Result<()> {
+        let mut delay = self.delay;
+        if let Some(deadline) = self.deadline {
+            let now = Instant::now();
+            if now >= deadline {
+                bail!(
+                    "bitcoind is unavailable after {:?} (see 'daemon_startup_timeout_secs' configuration)",
+                    self.started.elapsed()
+                );
+            }
+            delay = cmp::min(delay, deadline - now);
+        }
+        signal.wait(delay)?;
+        self.delay = cmp::min(self.delay * 2, MAX_RETRY_DELAY);
+        Ok(())
+    }
+}
+
+fn tcp_connect(
+    addr: &DaemonAddr,
+    limits: &DaemonLimits,
+    signal: &Waiter,
+    deadline: Option<Instant>,
+) -> Result<TcpStream> {
+    let mut backoff = Backoff::new(deadline);
     loop {
-        match TcpStream::connect(addr) {
+        let result = match addr {
+            DaemonAddr::Direct(addr) => connect_with_timeout(addr, limits.connect_timeout),
+            DaemonAddr::Socks5 { proxy, target } => socks5_connect(*proxy, target, limits),
+        };
+        match result {
             Ok(conn) => return Ok(conn),
             Err(err) => {
-                warn!("failed to connect daemon at {}: {}", addr, err);
-                signal.wait(Duration::from_secs(3))?;
+                warn!(
+                    "failed to connect daemon at {}: {} (retrying in {:?}, waiting for {}s)",
+                    addr,
+                    err,
+                    backoff.delay,
+                    backoff.started.elapsed().as_secs()
+                );
+                backoff.wait(signal)?;
                 continue;
             }
         }
@@ -167,26 +369,38 @@ fn tcp_connect(addr: SocketAddr, signal: &Waiter) -> Result<TcpStream> {
 
 impl Connection {
     fn new(
-        addr: SocketAddr,
+        addr: DaemonAddr,
         cookie_getter: Arc<dyn CookieGetter>,
+        limits: DaemonLimits,
         signal: Waiter,
+        deadline: Option<Instant>,
     ) -> Result<Connection> {
-        let conn = tcp_connect(addr, &signal)?;
+        let conn = tcp_connect(&addr, &limits, &signal, deadline)?;
+        conn.set_read_timeout(limits.read_timeout)
+            .and_then(|()| conn.set_write_timeout(limits.write_timeout))
+            .chain_err(|| format!("failed to set timeouts on {:?}", conn))?;
         let reader = BufReader::new(
             conn.try_clone()
                 .chain_err(|| format!("failed to clone {:?}", conn))?,
         );
         Ok(Connection {
             tx: conn,
-            rx: reader.lines(),
+            rx: reader,
             cookie_getter,
             addr,
+            limits,
             signal,
         })
     }
 
-    fn reconnect(&self) -> Result<Connection> {
-        Connection::new(self.addr, self.cookie_getter.clone(), self.signal.clone())
+    fn reconnect(&self, deadline: Option<Instant>) -> Result<Connection> {
+        Connection::new(
+            self.addr.clone(),
+            self.cookie_getter.clone(),
+            self.limits,
+            self.signal.clone(),
+            deadline,
+        )
     }
 
     fn send(&mut self, request: &str) -> Result<()> {
@@ -203,65 +417,128 @@ impl Connection {
     }
 
     fn recv(&mut self) -> Result<String> {
-        // TODO: use proper HTTP parser.
-        let mut in_header = true;
-        let mut contents: Option<String> = None;
-        let iter = self.rx.by_ref();
-        let status = iter
-            .next()
-            .chain_err(|| {
-                ErrorKind::Connection("disconnected from daemon while receiving".to_owned())
-            })?
-            .chain_err(|| "failed to read status")?;
-        let mut headers = HashMap::new();
-        for line in iter {
-            let line = line.chain_err(|| ErrorKind::Connection("failed to read".to_owned()))?;
-            if line.is_empty() {
-                in_header = false; // next line should contain the actual response.
-            } else if in_header {
-                let parts: Vec<&str> = line.splitn(2, ": ").collect();
-                if parts.len() == 2 {
-                    headers.insert(parts[0].to_owned(), parts[1].to_owned());
-                } else {
-                    warn!("invalid header: {:?}", line);
-                }
-            } else {
-                contents = Some(line);
-                break;
-            }
-        }
+        let cookie_getter = &self.cookie_getter;
+        read_response(&mut self.rx, self.limits.max_response_size, || {
+            cookie_getter.source()
+        })
+    }
+}
 
-        let contents =
-            contents.chain_err(|| ErrorKind::Connection("no reply from daemon".to_owned()))?;
-        let contents_length: &str = headers
-            .get("Content-Length")
-            .chain_err(|| format!("Content-Length is missing: {:?}", headers))?;
-        let contents_length: usize = contents_length
-            .parse()
-            .chain_err(|| format!("invalid Content-Length: {:?}", contents_length))?;
+fn read_line(rx: &mut impl BufRead) -> Result<String> {
+    let mut line = String::new();
+    let size = rx
+        .read_line(&mut line)
+        .chain_err(|| ErrorKind::Connection("failed to read".to_owned()))?;
+    if size == 0 {
+        bail!(ErrorKind::Connection(
+            "disconnected from daemon while receiving".to_owned()
+        ));
+    }
+    let len = line.trim_end_matches(&['\r', '\n'][..]).len();
+    line.truncate(len);
+    Ok(line)
+}
 
-        let expected_length = contents_length - 1; // trailing EOL is skipped
-        if expected_length != contents.len() {
-            bail!(ErrorKind::Connection(format!(
-                "expected {} bytes, got {}",
-                expected_length,
-                contents.len()
-            )));
+/// Reads bitcoind's HTTP response, returning its contents (see `fuzz/` for its fuzz target).
+/// Fails with `ErrorKind::DaemonAuth(auth_source())` if the credentials were rejected.
+pub fn read_response(
+    rx: &mut impl BufRead,
+    max_response_size: Option<usize>,
+    auth_source: impl FnOnce() -> String,
+) -> Result<String> {
+    // TODO: use proper HTTP parser.
+    let status = read_line(rx)?;
+    let mut headers = HashMap::new();
+    loop {
+        let line = read_line(rx)?;
+        if line.is_empty() {
+            break; // next bytes should contain the actual response.
         }
-
-        Ok(if status == "HTTP/1.1 200 OK" {
-            contents
-        } else if status == "HTTP/1.1 500 Internal Server Error" {
-            warn!("HTTP status: {}", status);
-            contents // the contents should have a JSONRPC error field
+        let parts: Vec<&str> = line.splitn(2, ": ").collect();
+        if parts.len() == 2 {
+            headers.insert(parts[0].to_owned(), parts[1].to_owned());
         } else {
+            warn!("invalid header: {:?}", line);
+        }
+    }
+    if status == "HTTP/1.1 401 Unauthorized" {
+        bail!(ErrorKind::DaemonAuth(auth_source()));
+    }
+
+    if status == "HTTP/1.1 503 Service Unavailable" {
+        // e.g. bitcoind is shutting down (or its RPC work queue is full), so retry later
+        bail!(ErrorKind::Connection(format!(
+            "bitcoind is unavailable: {}",
+            status
+        )));
+    }
+
+    let contents_length: &str = headers
+        .get("Content-Length")
+        .chain_err(|| format!("Content-Length is missing: {:?}", headers))?;
+    let contents_length: usize = contents_length
+        .parse()
+        .chain_err(|| format!("invalid Content-Length: {:?}", contents_length))?;
+
+    if let Some(max_size) = max_response_size {
+        if contents_length > max_size {
+            // skip the response, so that the connection can be reused
+            io::copy(
+                &mut rx.by_ref().take(contents_length as u64),
+                &mut io::sink(),
+            )
+            .chain_err(|| ErrorKind::Connection("failed to read".to_owned()))?;
             bail!(
-                "request failed {:?}: {:?} = {:?}",
-                status,
-                headers,
-                contents
+                "daemon response is too large: {} bytes (limit: {} bytes)",
+                contents_length,
+                max_size
             );
-        })
+        }
+    }
+    let mut contents = vec![0u8; contents_length];
+    rx.read_exact(&mut contents).chain_err(|| {
+        ErrorKind::Connection(format!("expected {} bytes from daemon", contents_length))
+    })?;
+    let mut contents = String::from_utf8(contents).chain_err(|| "non-UTF8 reply")?;
+    let len = contents.trim_end().len(); // trailing EOL is skipped
+    contents.truncate(len);
+
+    Ok(if status == "HTTP/1.1 200 OK" {
+        contents
+    } else if status == "HTTP/1.1 500 Internal Server Error" {
+        warn!("HTTP status: {}", status);
+        contents // the contents should have a JSONRPC error field
+    } else {
+        bail!(
+            "request failed {:?}: {:?} = {:?}",
+            status,
+            headers,
+            contents
+        );
+    })
+}
+
+impl RpcTransport for Connection {
+    fn call(&mut self, request: &str) -> Result<String> {
+        self.send(request)?;
+        match self.recv() {
+            Err(Error(ErrorKind::DaemonAuth(source), _)) => {
+                // bitcoind regenerates its cookie on restart, so retry once (on a new connection)
+                // with a re-read cookie - and fail without retrying if it's still rejected
+                warn!(
+                    "bitcoind rejected the credentials from {}, retrying",
+                    source
+                );
+                *self = Connection::reconnect(self, None)?;
+                self.send(request)?;
+                self.recv()
+            }
+            result => result,
+        }
+    }
+
+    fn reconnect(&self, deadline: Option<Instant>) -> Result<Box<dyn RpcTransport>> {
+        Ok(Box::new(Connection::reconnect(self, deadline)?))
     }
 }
 
@@ -284,39 +561,75 @@ pub struct Daemon {
     daemon_dir: PathBuf,
     blocks_dir: PathBuf,
     network: Network,
-    conn: Mutex<Connection>,
+    conn: Mutex<Box<dyn RpcTransport>>,
     message_id: Counter, // for monotonic JSONRPC 'id'
     signal: Waiter,
     blocktxids_cache: Arc<BlockTxIDsCache>,
+    startup_deadline: Option<Instant>, // cleared after startup
+    version: u64,                      // queried on (re)connection
 
     // monitoring
     latency: HistogramVec,
     size: HistogramVec,
+    sync_progress: FloatGauge,
 }
 
 impl Daemon {
     pub fn new(
         daemon_dir: &PathBuf,
         blocks_dir: &PathBuf,
-        daemon_rpc_addr: SocketAddr,
+        daemon_rpc_addr: &DaemonAddr,
         cookie_getter: Arc<dyn CookieGetter>,
+        limits: DaemonLimits,
         network: Network,
         signal: Waiter,
         blocktxids_cache: Arc<BlockTxIDsCache>,
         metrics: &Metrics,
     ) -> Result<Daemon> {
-        let daemon = Daemon {
+        let startup_deadline = limits
+            .startup_timeout
+            .map(|timeout| Instant::now() + timeout);
+        let conn = Connection::new(
+            daemon_rpc_addr.clone(),
+            cookie_getter,
+            limits,
+            signal.clone(),
+            startup_deadline,
+        )?;
+        Daemon::with_transport(
+            daemon_dir,
+            blocks_dir,
+            Box::new(conn),
+            network,
+            signal,
+            blocktxids_cache,
+            metrics,
+            startup_deadline,
+        )
+    }
+
+    /// Allows using a custom JSONRPC transport (instead of a TCP connection to bitcoind).
+    /// Fails if bitcoind isn't available (e.g. still warming up) until `startup_deadline`.
+    pub fn with_transport(
+        daemon_dir: &PathBuf,
+        blocks_dir: &PathBuf,
+        transport: Box<dyn RpcTransport>,
+        network: Network,
+        signal: Waiter,
+        blocktxids_cache: Arc<BlockTxIDsCache>,
+        metrics: &Metrics,
+        startup_deadline: Option<Instant>,
+    ) -> Result<Daemon> {
+        let mut daemon = Daemon {
             daemon_dir: daemon_dir.clone(),
             blocks_dir: blocks_dir.clone(),
             network,
-            conn: Mutex::new(Connection::new(
-                daemon_rpc_addr,
-                cookie_getter,
-                signal.clone(),
-            )?),
+            conn: Mutex::new(transport),
             message_id: Counter::new(),
             blocktxids_cache,
             signal: signal.clone(),
+            startup_deadline,
+            version: 0,
             latency: metrics.histogram_vec(
                 HistogramOpts::new("electrs_daemon_rpc", "Bitcoind RPC latency (in seconds)"),
                 &["method"],
@@ -326,6 +639,10 @@ impl Daemon {
                 HistogramOpts::new("electrs_daemon_bytes", "Bitcoind RPC size (in bytes)"),
                 &["method", "dir"],
             ),
+            sync_progress: metrics.float_gauge(MetricOpts::new(
+                "electrs_daemon_sync_progress",
+                "Bitcoind's block verification progress (1 - not in initial block download)",
+            )),
         };
         let network_info = daemon.getnetworkinfo()?;
         info!("{:?}", network_info);
@@ -335,40 +652,44 @@ impl Daemon {
                 network_info.subversion,
             )
         }
+        daemon.version = network_info.version;
         let blockchain_info = daemon.getblockchaininfo()?;
         info!("{:?}", blockchain_info);
+        if blockchain_info.chain != chain_name(network) {
+            bail!(
+                "bitcoind is running on '{}' chain, but electrs is configured for {} (check 'network' and 'daemon_rpc_addr' configuration)",
+                blockchain_info.chain,
+                network,
+            )
+        }
         if blockchain_info.pruned {
             bail!("pruned node is not supported (use '-prune=0' bitcoind flag)".to_owned())
         }
-        loop {
-            let info = daemon.getblockchaininfo()?;
-            if !info.initialblockdownload {
-                break;
-            }
-            if network == Network::Regtest && info.headers == info.blocks {
-                break;
-            }
-            warn!(
-                "wait until IBD is over: headers={} blocks={} progress={}",
-                info.headers, info.blocks, info.verificationprogress
-            );
+        while let Some(progress) = daemon.ibd_progress()? {
+            info!("waiting for bitcoind IBD ({:.2}%)", progress * 100.0);
             signal.wait(Duration::from_secs(3))?;
         }
+        daemon.startup_deadline = None; // keep retrying from now on
         Ok(daemon)
     }
 
     pub fn reconnect(&self) -> Result<Daemon> {
-        Ok(Daemon {
+        let mut daemon = Daemon {
             daemon_dir: self.daemon_dir.clone(),
             blocks_dir: self.blocks_dir.clone(),
             network: self.network,
-            conn: Mutex::new(self.conn.lock().unwrap().reconnect()?),
+            conn: Mutex::new(self.conn.lock().unwrap().reconnect(None)?), // after startup
             message_id: Counter::new(),
             signal: self.signal.clone(),
             blocktxids_cache: Arc::clone(&self.blocktxids_cache),
+            startup_deadline: None,
+            version: self.version,
             latency: self.latency.clone(),
             size: self.size.clone(),
-        })
+            sync_progress: self.sync_progress.clone(),
+        };
+        daemon.version = daemon.getnetworkinfo()?.version; // bitcoind may have been upgraded
+        Ok(daemon)
     }
 
     pub fn list_blk_files(&self) -> Result<Vec<PathBuf>> {
@@ -376,8 +697,8 @@ impl Daemon {
         info!("listing block files at {:?}", path);
         let mut paths: Vec<PathBuf> = glob::glob(path.to_str().unwrap())
             .chain_err(|| "failed to list blk*.dat files")?
-            .map(std::result::Result::unwrap)
-            .collect();
+            .collect::<std::result::Result<_, _>>()
+            .chain_err(|| "failed to list blk*.dat files")?;
         paths.sort();
         Ok(paths)
     }
@@ -390,11 +711,10 @@ impl Daemon {
         let mut conn = self.conn.lock().unwrap();
         let timer = self.latency.with_label_values(&[method]).start_timer();
         let request = request.to_string();
-        conn.send(&request)?;
+        let response = conn.call(&request)?;
         self.size
             .with_label_values(&[method, "send"])
             .observe(request.len() as f64);
-        let response = conn.recv()?;
         let result: Value = from_str(&response).chain_err(|| "invalid JSON")?;
         timer.observe_duration();
         self.size
@@ -421,13 +741,23 @@ impl Daemon {
     }
 
     fn retry_request_batch(&self, method: &str, params_list: &[Value]) -> Result<Vec<Value>> {
+        let mut backoff = Backoff::new(self.startup_deadline);
         loop {
             match self.handle_request_batch(method, params_list) {
                 Err(Error(ErrorKind::Connection(msg), _)) => {
-                    warn!("reconnecting to bitcoind: {}", msg);
-                    self.signal.wait(Duration::from_secs(3))?;
+                    if self.startup_deadline.is_some() {
+                        warn!(
+                            "waiting for bitcoind to start for {}s (retrying in {:?}): {}",
+                            backoff.started.elapsed().as_secs(),
+                            backoff.delay,
+                            msg
+                        );
+                    } else {
+                        warn!("reconnecting to bitcoind in {:?}: {}", backoff.delay, msg);
+                    }
+                    backoff.wait(&self.signal)?;
                     let mut conn = self.conn.lock().unwrap();
-                    *conn = conn.reconnect()?;
+                    *conn = conn.reconnect(self.startup_deadline)?;
                     continue;
                 }
                 result => return result,
@@ -452,6 +782,24 @@ impl Daemon {
         from_value(info).chain_err(|| "invalid blockchain info")
     }
 
+    /// Returns bitcoind's verification progress while it's in initial block download
+    /// (e.g. when started with `-reindex`), and `None` otherwise.
+    pub fn ibd_progress(&self) -> Result<Option<f64>> {
+        let info = self.getblockchaininfo()?;
+        let in_ibd = info.initialblockdownload
+            && !(self.network == Network::Regtest && info.headers == info.blocks);
+        if !in_ibd {
+            self.sync_progress.set(1.0);
+            return Ok(None);
+        }
+        debug!(
+            "bitcoind IBD: headers={} blocks={} progress={}",
+            info.headers, info.blocks, info.verificationprogress
+        );
+        self.sync_progress.set(info.verificationprogress);
+        Ok(Some(info.verificationprogress))
+    }
+
     fn getnetworkinfo(&self) -> Result<NetworkInfo> {
         let info: Value = self.request("getnetworkinfo", json!([]))?;
         from_value(info).chain_err(|| "invalid network info")
@@ -465,6 +813,10 @@ impl Daemon {
         Ok(self.getnetworkinfo()?.relayfee)
     }
 
+    pub fn get_header_height(&self) -> Result<usize> {
+        Ok(self.getblockchaininfo()?.headers as usize)
+    }
+
     pub fn getbestblockhash(&self) -> Result<BlockHash> {
         parse_hash(&self.request("getbestblockhash", json!([]))?).chain_err(|| "invalid blockhash")
     }
@@ -509,7 +861,47 @@ impl Daemon {
             .collect::<Result<Vec<Txid>>>()
     }
 
-    pub fn getblocktxids(&self, blockhash: &BlockHash) -> Result<Vec<Txid>> {
+    /// Returns the block's transactions, each one with the scripts spent by its inputs (none for
+    /// the coinbase), using `getblock` verbosity 3 (requires bitcoind 23.0+).
+    pub fn getblockprevouts(
+        &self,
+        blockhash: &BlockHash,
+    ) -> Result<Vec<(Transaction, Vec<Script>)>> {
+        let block = self.request("getblock", json!([blockhash.to_hex(), /*verbosity=*/ 3]))?;
+        let txns = block
+            .get("tx")
+            .and_then(Value::as_array)
+            .chain_err(|| "block missing txs")?;
+        txns.iter()
+            .map(|txn| {
+                let prevouts = txn
+                    .get("vin")
+                    .and_then(Value::as_array)
+                    .chain_err(|| "tx missing inputs")?
+                    .iter()
+                    .filter(|input| input.get("coinbase").is_none())
+                    .map(|input| {
+                        let script_hex = input
+                            .pointer("/prevout/scriptPubKey/hex")
+                            .and_then(Value::as_str)
+                            .chain_err(|| {
+                                format!(
+                                    "block {} is missing prevouts (bitcoind 23.0+ is required)",
+                                    blockhash
+                                )
+                            })?;
+                        let script =
+                            hex::decode(script_hex).chain_err(|| "non-hex prevout script")?;
+                        Ok(Script::from(script))
+                    })
+                    .collect::<Result<Vec<Script>>>()?;
+                let txn = tx_from_value(txn.get("hex").cloned().unwrap_or_default())?;
+                Ok((txn, prevouts))
+            })
+            .collect()
+    }
+
+    pub fn getblocktxids(&self, blockhash: &BlockHash) -> Result<Arc<Vec<Txid>>> {
         self.blocktxids_cache
             .get_or_else(blockhash, || self.load_blocktxids(blockhash))
     }
@@ -565,13 +957,30 @@ impl Daemon {
         Ok(MempoolEntry::new(fee, vsize))
     }
 
-    pub fn broadcast(&self, tx: &Transaction) -> Result<Txid> {
+    /// Broadcasts `tx`, unless its fee rate exceeds `max_fee_rate` (in BTC/kvB, bitcoind's
+    /// default if `None`, or before bitcoind 0.19).
+    pub fn broadcast(&self, tx: &Transaction, max_fee_rate: Option<f64>) -> Result<Txid> {
         let tx = hex::encode(serialize(tx));
-        let txid = self.request("sendrawtransaction", json!([tx]))?;
+        // the second argument was `allowhighfees` (a bool) before bitcoind 0.19
+        let params = match max_fee_rate {
+            Some(max_fee_rate) if self.version >= 19_00_00 => {
+                json!([tx, max_fee_rate])
+            }
+            _ => json!([tx]),
+        };
+        let txid = self.request("sendrawtransaction", params)?;
         Txid::from_hex(txid.as_str().chain_err(|| "non-string txid")?)
             .chain_err(|| "failed to parse txid")
     }
 
+    /// Submits a package of dependent transactions (parents first), so they are evaluated
+    /// together for the mempool's fee rate requirements (requires bitcoind 26.0+).
+    /// Returns bitcoind's `submitpackage` result, including per-transaction errors.
+    pub fn submit_package(&self, txns: &[Transaction]) -> Result<Value> {
+        let txns: Vec<String> = txns.iter().map(|tx| hex::encode(serialize(tx))).collect();
+        self.request("submitpackage", json!([txns]))
+    }
+
     fn get_all_headers(&self, tip: &BlockHash) -> Result<Vec<BlockHeader>> {
         let info: Value = self.request("getblockheader", json!([tip.to_hex()]))?;
         let tip_height = info
@@ -632,3 +1041,193 @@ impl Daemon {
         Ok(new_headers)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Replies to each request by its method (see `FakeState::replies`), failing the first
+    /// `FakeState::failures` calls with a connection error (e.g. bitcoind was restarted).
+    struct FakeTransport {
+        state: Arc<Mutex<FakeState>>,
+    }
+
+    struct FakeState {
+        replies: HashMap<&'static str, Value>,
+        failures: usize,
+        reconnects: Vec<Option<Instant>>, // the deadline of each reconnection
+    }
+
+    impl RpcTransport for FakeTransport {
+        fn call(&mut self, request: &str) -> Result<String> {
+            let mut state = self.state.lock().unwrap();
+            if state.failures > 0 {
+                state.failures -= 1;
+                bail!(ErrorKind::Connection("connection reset".to_owned()));
+            }
+            let requests: Value = from_str(request).unwrap();
+            let replies: Vec<Value> = requests
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|request| {
+                    let method = request["method"].as_str().unwrap();
+                    json!({"id": request["id"], "result": state.replies[method]})
+                })
+                .collect();
+            Ok(json!(replies).to_string())
+        }
+
+        fn reconnect(&self, deadline: Option<Instant>) -> Result<Box<dyn RpcTransport>> {
+            self.state.lock().unwrap().reconnects.push(deadline);
+            Ok(Box::new(FakeTransport {
+                state: Arc::clone(&self.state),
+            }))
+        }
+    }
+
+    struct TestCookie {
+        reads: AtomicU64,
+    }
+
+    impl CookieGetter for TestCookie {
+        fn get(&self) -> Result<Vec<u8>> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            Ok(b"user:password".to_vec())
+        }
+
+        fn source(&self) -> String {
+            "test cookie".to_owned()
+        }
+    }
+
+    /// Serves one HTTP response per connection (as the given status lines, with an empty body).
+    fn serve(statuses: Vec<&'static str>) -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for (stream, status) in listener.incoming().zip(statuses) {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if line.starts_with("Content-Length: ") {
+                        content_length = line["Content-Length: ".len()..].parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                let reply = if status.contains("200") { "[]" } else { "" };
+                write!(
+                    stream,
+                    "{}\r\nContent-Length: {}\r\n\r\n{}",
+                    status,
+                    reply.len(),
+                    reply
+                )
+                .unwrap();
+            }
+        });
+        addr
+    }
+
+    fn call(statuses: Vec<&'static str>, cookie: &Arc<TestCookie>) -> Result<String> {
+        let limits = DaemonLimits {
+            connect_timeout: None,
+            read_timeout: Some(Duration::from_secs(10)),
+            write_timeout: None,
+            max_response_size: None,
+            startup_timeout: None,
+        };
+        let cookie_getter = Arc::clone(cookie) as Arc<dyn CookieGetter>;
+        let addr = DaemonAddr::Direct(serve(statuses));
+        let mut conn = Connection::new(addr, cookie_getter, limits, Waiter::start(), None)?;
+        RpcTransport::call(&mut conn, "[]")
+    }
+
+    #[test]
+    fn test_unauthorized() {
+        const UNAUTHORIZED: &str = "HTTP/1.1 401 Unauthorized";
+        const OK: &str = "HTTP/1.1 200 OK";
+        let cookie = Arc::new(TestCookie { reads: 0.into() });
+
+        // e.g. bitcoind was restarted (with a new cookie), so the request is retried once
+        assert_eq!(call(vec![UNAUTHORIZED, OK], &cookie).unwrap(), "[]");
+        assert_eq!(cookie.reads.load(Ordering::Relaxed), 2);
+
+        // the credentials are wrong, so the request fails without being retried again
+        match call(vec![UNAUTHORIZED, UNAUTHORIZED, OK], &cookie) {
+            Err(Error(ErrorKind::DaemonAuth(source), _)) => assert_eq!(source, "test cookie"),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert_eq!(cookie.reads.load(Ordering::Relaxed), 4);
+    }
+
+    fn blockchain_info(ibd: bool) -> Value {
+        json!({
+            "chain": "regtest",
+            "blocks": 100,
+            "headers": 200,
+            "verificationprogress": 0.5,
+            "bestblockhash": BlockHash::default().to_hex(),
+            "pruned": false,
+            "initialblockdownload": ibd,
+        })
+    }
+
+    #[test]
+    fn test_reconnect_and_ibd() {
+        let mut replies = HashMap::new();
+        replies.insert(
+            "getnetworkinfo",
+            json!({"version": 21_00_00, "subversion": "/Satoshi:0.21.0/", "relayfee": 0.00001}),
+        );
+        replies.insert("getblockchaininfo", blockchain_info(false));
+        // bitcoind is restarted during startup
+        let state = Arc::new(Mutex::new(FakeState {
+            replies,
+            failures: 1,
+            reconnects: vec![],
+        }));
+        let startup_deadline = Instant::now() + Duration::from_secs(60);
+        let metrics = Metrics::new("127.0.0.1:60000".parse().unwrap());
+        let daemon = Daemon::with_transport(
+            &PathBuf::new(),
+            &PathBuf::new(),
+            Box::new(FakeTransport {
+                state: Arc::clone(&state),
+            }),
+            Network::Regtest,
+            Waiter::start(),
+            Arc::new(BlockTxIDsCache::new(0, &metrics)),
+            &metrics,
+            Some(startup_deadline),
+        )
+        .unwrap();
+        assert_eq!(
+            state.lock().unwrap().reconnects,
+            vec![Some(startup_deadline)]
+        );
+        assert_eq!(daemon.ibd_progress().unwrap(), None);
+        assert_eq!(daemon.version, 21_00_00);
+
+        // bitcoind re-entered IBD (e.g. restarted with `-reindex`)
+        state
+            .lock()
+            .unwrap()
+            .replies
+            .insert("getblockchaininfo", blockchain_info(true));
+        assert_eq!(daemon.ibd_progress().unwrap(), Some(0.5));
+
+        // the request is retried after reconnecting (without a deadline after startup)
+        state.lock().unwrap().failures = 1;
+        assert_eq!(daemon.getnetworkinfo().unwrap().version, 21_00_00);
+        assert_eq!(state.lock().unwrap().reconnects[1..], [None]);
+    }
+}
@@ -0,0 +1,110 @@
+//! Background mode and pidfile support, for systems without systemd.
+//!
+//! The process forks before starting any thread, and the parent waits until the child reports
+//! that it's ready (or fails), so the parent's exit code reflects the server's startup.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use crate::errors::*;
+
+/// Held by the server process, and removes the pidfile when dropped.
+pub struct Detached {
+    ready: Option<File>, // pipe to the waiting parent process
+    pid_file: Option<PathBuf>,
+}
+
+impl Detached {
+    /// Lets the parent process exit successfully (no-op if not forked).
+    pub fn ready(&mut self) {
+        if let Some(mut pipe) = self.ready.take() {
+            if let Err(e) = pipe.write_all(b"1") {
+                warn!("failed to notify parent process: {}", e);
+            }
+        }
+    }
+}
+
+impl Drop for Detached {
+    fn drop(&mut self) {
+        if let Some(ref path) = self.pid_file {
+            if let Err(e) = fs::remove_file(path) {
+                warn!("failed to remove pidfile {:?}: {}", path, e);
+            }
+        }
+    }
+}
+
+fn check(ret: libc::c_int, what: &str) -> Result<libc::c_int> {
+    if ret < 0 {
+        bail!("{} failed: {}", what, std::io::Error::last_os_error());
+    }
+    Ok(ret)
+}
+
+fn redirect(file: &File, fd: libc::c_int) -> Result<()> {
+    check(unsafe { libc::dup2(file.as_raw_fd(), fd) }, "dup2")?;
+    Ok(())
+}
+
+fn write_pid_file(path: &Path) -> Result<()> {
+    fs::write(path, format!("{}\n", process::id()))
+        .chain_err(|| format!("failed to write pidfile {:?}", path))
+}
+
+/// Forks into the background (if `background` is set) and writes the pidfile.
+/// Must be called before any thread is started, since only the calling thread survives `fork()`.
+/// Unless `keep_stderr` is set (e.g. logging to a file), the detached process discards its output.
+pub fn start(background: bool, pid_file: Option<&Path>, keep_stderr: bool) -> Result<Detached> {
+    let mut detached = Detached {
+        ready: None,
+        pid_file: None,
+    };
+    if background {
+        let mut fds = [0; 2];
+        check(unsafe { libc::pipe(fds.as_mut_ptr()) }, "pipe")?;
+        let (mut reader, writer) =
+            unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        if check(unsafe { libc::fork() }, "fork")? > 0 {
+            drop(writer);
+            let mut buf = vec![];
+            let _ = reader.read_to_end(&mut buf);
+            if buf.is_empty() {
+                eprintln!("electrs failed to start, see its logs for details");
+                process::exit(1);
+            }
+            process::exit(0);
+        }
+        drop(reader);
+        check(unsafe { libc::setsid() }, "setsid")?;
+        let null = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/null")
+            .chain_err(|| "failed to open /dev/null")?;
+        redirect(&null, libc::STDIN_FILENO)?;
+        redirect(&null, libc::STDOUT_FILENO)?;
+        if !keep_stderr {
+            redirect(&null, libc::STDERR_FILENO)?;
+        }
+        detached.ready = Some(writer);
+    }
+    if let Some(path) = pid_file {
+        write_pid_file(path)?;
+        detached.pid_file = Some(path.to_owned());
+    }
+    Ok(detached)
+}
+
+/// Appends everything written to stderr (i.e. the logs) to the given file.
+pub fn redirect_stderr(path: &Path) -> Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .chain_err(|| format!("failed to open log file {:?}", path))?;
+    redirect(&file, libc::STDERR_FILENO)
+}
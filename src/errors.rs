@@ -16,6 +16,11 @@ error_chain! {
             display("Connection error: {}", msg)
         }
 
+        DaemonAuth(source: String) {
+            description("bitcoind authentication failed")
+            display("bitcoind rejected the credentials from {} (hint: check them against bitcoind's settings)", source)
+        }
+
         Interrupt(sig: i32) {
             description("Interruption by external signal")
             display("Interrupted by signal {}", sig)
@@ -35,5 +40,30 @@ error_chain! {
             description("parse error")
             display("parse error")
         }
+
+        Timeout {
+            description("request timed out")
+            display("request timed out, please try again later")
+        }
+
+        DaemonSyncing(progress: f64) {
+            description("bitcoind is syncing")
+            display("server still syncing: waiting for bitcoind IBD ({:.2}%), please try again later", progress * 100.0)
+        }
+
+        HistoryTooLarge {
+            description("history too large")
+            display("history too large")
+        }
+
+        StatusTooLarge(limit: usize) {
+            description("status too large")
+            display("status too large (exceeds {} MB)", limit >> 20)
+        }
+
+        Overloaded {
+            description("server overloaded")
+            display("server overloaded, please try again later")
+        }
     }
 }
@@ -1,4 +1,4 @@
-use crate::store::{ReadStore, Row, WriteStore};
+use crate::store::{ReadStore, Row, RowBatch, WriteStore};
 use crate::util::Bytes;
 
 pub struct FakeStore;
@@ -14,6 +14,7 @@ impl ReadStore for FakeStore {
 
 impl WriteStore for FakeStore {
     fn write<I: IntoIterator<Item = Row>>(&self, _rows: I) {}
+    fn write_batch(&self, _rows: &RowBatch) {}
     fn flush(&self) {}
 }
 
@@ -5,19 +5,27 @@ use bitcoin::hash_types::{BlockHash, Txid};
 use sha2::{Digest, Sha256};
 use std::collections::{HashMap, HashSet};
 use std::iter::FromIterator;
-use std::sync::RwLock;
+use std::sync::{Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::daemon::Daemon;
 use crate::errors::*;
 use crate::metrics::{
-    Counter, Gauge, HistogramOpts, HistogramTimer, HistogramVec, MetricOpts, Metrics,
+    Counter, Gauge, Histogram, HistogramOpts, HistogramTimer, HistogramVec, MetricOpts, Metrics,
 };
 use crate::signal::Waiter;
-use crate::store::{ReadStore, Row, WriteStore};
+use crate::store::{ReadStore, Row, RowBatch, WriteStore};
+use crate::types::ScriptHash;
 use crate::util::{
     full_hash, hash_prefix, spawn_thread, Bytes, FullHash, HashPrefix, HeaderEntry, HeaderList,
-    HeaderMap, SyncChannel, HASH_PREFIX_LEN,
+    HeaderMap, SyncChannel, HASH_LEN, HASH_PREFIX_LEN,
 };
+use crate::watch::{self, WatchedScripts, Watchlist};
+
+// Fixed row sizes, as encoded by `bincode` (little-endian integers, arrays without lengths).
+const TXIN_ROW_LEN: usize = 1 + HASH_PREFIX_LEN + 2 + HASH_PREFIX_LEN;
+const TXOUT_ROW_LEN: usize = 1 + HASH_PREFIX_LEN + HASH_PREFIX_LEN;
+const TX_KEY_LEN: usize = 1 + HASH_LEN;
 
 #[derive(Serialize, Deserialize)]
 pub struct TxInKey {
@@ -53,9 +61,22 @@ impl TxInRow {
         .unwrap()
     }
 
+    /// Encodes the row (as its key), without allocating.
+    pub fn encode(&self) -> [u8; TXIN_ROW_LEN] {
+        let mut key = [0u8; TXIN_ROW_LEN];
+        let (code, rest) = key.split_at_mut(1);
+        let (prev_hash_prefix, rest) = rest.split_at_mut(HASH_PREFIX_LEN);
+        let (prev_index, txid_prefix) = rest.split_at_mut(2);
+        code[0] = self.key.code;
+        prev_hash_prefix.copy_from_slice(&self.key.prev_hash_prefix);
+        prev_index.copy_from_slice(&self.key.prev_index.to_le_bytes());
+        txid_prefix.copy_from_slice(&self.txid_prefix);
+        key
+    }
+
     pub fn to_row(&self) -> Row {
         Row {
-            key: bincode::serialize(&self).unwrap(),
+            key: self.encode().to_vec(),
             value: vec![],
         }
     }
@@ -96,9 +117,20 @@ impl TxOutRow {
         .unwrap()
     }
 
+    /// Encodes the row (as its key), without allocating.
+    pub fn encode(&self) -> [u8; TXOUT_ROW_LEN] {
+        let mut key = [0u8; TXOUT_ROW_LEN];
+        let (code, rest) = key.split_at_mut(1);
+        let (script_hash_prefix, txid_prefix) = rest.split_at_mut(HASH_PREFIX_LEN);
+        code[0] = self.key.code;
+        script_hash_prefix.copy_from_slice(&self.key.script_hash_prefix);
+        txid_prefix.copy_from_slice(&self.txid_prefix);
+        key
+    }
+
     pub fn to_row(&self) -> Row {
         Row {
-            key: bincode::serialize(&self).unwrap(),
+            key: self.encode().to_vec(),
             value: vec![],
         }
     }
@@ -138,10 +170,19 @@ impl TxRow {
         [b"T", &txid[..]].concat()
     }
 
+    /// Encodes the row's key and value, without allocating.
+    pub fn encode(&self) -> ([u8; TX_KEY_LEN], [u8; 4]) {
+        let mut key = [0u8; TX_KEY_LEN];
+        key[0] = self.key.code;
+        key[1..].copy_from_slice(&self.key.txid);
+        (key, self.height.to_le_bytes())
+    }
+
     pub fn to_row(&self) -> Row {
+        let (key, value) = self.encode();
         Row {
-            key: bincode::serialize(&self.key).unwrap(),
-            value: bincode::serialize(&self.height).unwrap(),
+            key: key.to_vec(),
+            value: value.to_vec(),
         }
     }
 
@@ -190,17 +231,21 @@ pub fn index_transaction<'a>(
         .chain(std::iter::once(TxRow::new(&txid, height as u32).to_row()))
 }
 
-pub fn index_block<'a>(block: &'a Block, height: usize) -> impl 'a + Iterator<Item = Row> {
+pub fn block_row(block: &Block) -> Row {
     let blockhash = block.block_hash();
     // Persist block hash and header
-    let row = Row {
+    Row {
         key: bincode::serialize(&BlockKey {
             code: b'B',
             hash: full_hash(&blockhash[..]),
         })
         .unwrap(),
         value: serialize(&block.header),
-    };
+    }
+}
+
+pub fn index_block<'a>(block: &'a Block, height: usize) -> impl 'a + Iterator<Item = Row> {
+    let row = block_row(block);
     block
         .txdata
         .iter()
@@ -208,6 +253,27 @@ pub fn index_block<'a>(block: &'a Block, height: usize) -> impl 'a + Iterator<It
         .chain(std::iter::once(row))
 }
 
+/// Appends the rows of `index_block()` to `rows`, encoding the transactions' rows without
+/// allocating them (for bulk indexing).
+pub fn index_block_into(block: &Block, height: usize, rows: &mut RowBatch) {
+    let null_hash = Txid::default();
+    for txn in &block.txdata {
+        let txid = txn.txid();
+        for input in &txn.input {
+            if input.previous_output.txid != null_hash {
+                rows.push(&TxInRow::new(&txid, input).encode(), &[]);
+            }
+        }
+        for output in &txn.output {
+            rows.push(&TxOutRow::new(&txid, output).encode(), &[]);
+        }
+        let (key, value) = TxRow::new(&txid, height as u32).encode();
+        rows.push(&key, &value);
+    }
+    let row = block_row(block);
+    rows.push(&row.key, &row.value);
+}
+
 pub fn last_indexed_block(blockhash: &BlockHash) -> Row {
     // Store last indexed block (i.e. all previous blocks were indexed)
     Row {
@@ -225,7 +291,7 @@ pub fn read_indexed_blockhashes(store: &dyn ReadStore) -> HashSet<BlockHash> {
     result
 }
 
-fn read_indexed_headers(store: &dyn ReadStore) -> HeaderList {
+pub fn read_indexed_headers(store: &dyn ReadStore) -> Result<HeaderList> {
     let latest_blockhash: BlockHash = match store.get(b"L") {
         // latest blockheader persisted in the DB.
         Some(row) => deserialize(&row).unwrap(),
@@ -265,8 +331,8 @@ fn read_indexed_headers(store: &dyn ReadStore) -> HeaderList {
     );
     let mut result = HeaderList::empty();
     let entries = result.order(headers);
-    result.apply(entries, latest_blockhash);
-    result
+    result.apply(entries, latest_blockhash)?;
+    Ok(result)
 }
 
 struct Stats {
@@ -274,7 +340,11 @@ struct Stats {
     txns: Counter,
     vsize: Counter,
     height: Gauge,
+    daemon_height: Gauge,
+    lag: Gauge,
+    tip_age: Gauge,
     duration: HistogramVec,
+    batch_rows: Histogram,
 }
 
 impl Stats {
@@ -296,10 +366,26 @@ impl Stats {
                 "electrs_index_height",
                 "Last indexed block's height",
             )),
+            daemon_height: metrics.gauge(MetricOpts::new(
+                "electrs_daemon_header_height",
+                "Bitcoind best header's height",
+            )),
+            lag: metrics.gauge(MetricOpts::new(
+                "electrs_index_lag",
+                "# of blocks behind bitcoind best header",
+            )),
+            tip_age: metrics.gauge(MetricOpts::new(
+                "electrs_index_tip_age",
+                "Time since last indexed block's timestamp (in seconds)",
+            )),
             duration: metrics.histogram_vec(
                 HistogramOpts::new("electrs_index_duration", "indexing duration (in seconds)"),
                 &["step"],
             ),
+            batch_rows: metrics.histogram(
+                HistogramOpts::new("electrs_index_batch_rows", "# of rows written per batch")
+                    .buckets(prometheus::exponential_buckets(10.0, 4.0, 10).unwrap()),
+            ),
         }
     }
 
@@ -314,6 +400,24 @@ impl Stats {
 
     fn update_height(&self, height: usize) {
         self.height.set(height as i64);
+        let daemon_height = self.daemon_height.get();
+        if daemon_height > 0 {
+            // keep the lag up-to-date during initial sync
+            self.lag.set(daemon_height - height as i64);
+        }
+    }
+
+    fn update_lag(&self, best_header: Option<&HeaderEntry>, daemon_height: usize) {
+        let indexed_height = best_header.map_or(-1, |entry| entry.height() as i64);
+        self.daemon_height.set(daemon_height as i64);
+        self.lag.set(daemon_height as i64 - indexed_height);
+        if let Some(entry) = best_header {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("invalid system time")
+                .as_secs() as i64;
+            self.tip_age.set(now - i64::from(entry.header().time));
+        }
     }
 
     fn start_timer(&self, step: &str) -> HistogramTimer {
@@ -327,6 +431,7 @@ pub struct Index {
     daemon: Daemon,
     stats: Stats,
     batch_size: usize,
+    watchlist: Option<Mutex<Watchlist>>, // in watch-only mode
 }
 
 impl Index {
@@ -335,34 +440,104 @@ impl Index {
         daemon: &Daemon,
         metrics: &Metrics,
         batch_size: usize,
+        watched: Option<&WatchedScripts>,
     ) -> Result<Index> {
         let stats = Stats::new(metrics);
-        let headers = read_indexed_headers(store);
+        let headers = read_indexed_headers(store)?;
+        watch::check_db(store, watched)?;
+        let watchlist = match watched {
+            Some(scripts) => {
+                let mut watchlist = Watchlist::new(scripts)?;
+                watchlist.load(store)?;
+                Some(Mutex::new(watchlist))
+            }
+            None => None,
+        };
+        if let Some(genesis) = headers.header_by_height(0) {
+            // don't index another chain into an existing DB
+            let expected = daemon.getblockheaders(&[0])?[0].block_hash();
+            if *genesis.hash() != expected {
+                bail!(
+                    "DB contains a chain starting at {}, but bitcoind's chain starts at {} (check 'network', 'db_dir' and 'daemon_rpc_addr' configuration)",
+                    genesis.hash(),
+                    expected
+                );
+            }
+        }
         stats.height.set((headers.len() as i64) - 1);
         Ok(Index {
             headers: RwLock::new(headers),
             daemon: daemon.reconnect()?,
             stats,
             batch_size,
+            watchlist,
         })
     }
 
-    pub fn reload(&self, store: &dyn ReadStore) {
+    pub fn reload(&self, store: &dyn ReadStore) -> Result<()> {
         let mut headers = self.headers.write().unwrap();
-        *headers = read_indexed_headers(store);
+        *headers = read_indexed_headers(store)?;
+        if let Some(ref watchlist) = self.watchlist {
+            watchlist.lock().unwrap().load(store)?;
+        }
+        Ok(())
+    }
+
+    /// Returns whether the script hash is indexed (i.e. always, unless in watch-only mode).
+    pub fn is_watched(&self, script_hash: &ScriptHash) -> bool {
+        match self.watchlist {
+            Some(ref watchlist) => watchlist.lock().unwrap().contains(script_hash),
+            None => true,
+        }
     }
 
     pub fn best_header(&self) -> Option<HeaderEntry> {
         let headers = self.headers.read().unwrap();
-        headers.header_by_blockhash(&headers.tip()).cloned()
+        headers.header_by_blockhash(&headers.tip())
+    }
+
+    /// Exports how far the index is behind the daemon's best header.
+    pub fn update_lag(&self, daemon_height: usize) {
+        self.stats
+            .update_lag(self.best_header().as_ref(), daemon_height);
     }
 
     pub fn get_header(&self, height: usize) -> Option<HeaderEntry> {
-        self.headers
-            .read()
-            .unwrap()
-            .header_by_height(height)
-            .cloned()
+        self.headers.read().unwrap().header_by_height(height)
+    }
+
+    /// Returns whether the blocks in `rows` (e.g. another server's index update) connect to the
+    /// indexed chain, so they can be written without leaving a gap.
+    pub fn connects(&self, rows: &RowBatch) -> Result<bool> {
+        let headers = self.headers.read().unwrap();
+        let null_hash = BlockHash::default();
+        let mut new_blockhashes = HashSet::new();
+        let is_known = |blockhash: &BlockHash, new_blockhashes: &HashSet<BlockHash>| {
+            *blockhash == null_hash
+                || new_blockhashes.contains(blockhash)
+                || headers.header_by_blockhash(blockhash).is_some()
+        };
+        for (key, value) in rows.iter() {
+            match key.first() {
+                Some(b'B') => {
+                    let header: BlockHeader =
+                        deserialize(value).chain_err(|| "invalid header row")?;
+                    if !is_known(&header.prev_blockhash, &new_blockhashes) {
+                        return Ok(false);
+                    }
+                    new_blockhashes.insert(header.block_hash());
+                }
+                Some(b'L') => {
+                    let blockhash: BlockHash =
+                        deserialize(value).chain_err(|| "invalid last indexed block row")?;
+                    if !is_known(&blockhash, &new_blockhashes) {
+                        return Ok(false);
+                    }
+                }
+                _ => (),
+            }
+        }
+        Ok(true)
     }
 
     pub fn update(&self, store: &impl WriteStore, waiter: &Waiter) -> Result<BlockHash> {
@@ -374,6 +549,7 @@ impl Index {
         };
         if let Some(latest_header) = new_headers.last() {
             info!("{:?} ({} left to index)", latest_header, new_headers.len());
+            self.stats.daemon_height.set(latest_header.height() as i64);
         };
         let height_map = HashMap::<BlockHash, usize>::from_iter(
             new_headers.iter().map(|h| (*h.hash(), h.height())),
@@ -397,6 +573,7 @@ impl Index {
                 .send(Ok(vec![]))
                 .expect("failed sending explicit end of stream");
         });
+        let mut rows = RowBatch::new(); // reused by all batches
         loop {
             waiter.poll()?;
             let timer = self.stats.start_timer("fetch");
@@ -409,18 +586,36 @@ impl Index {
                 break;
             }
 
-            let rows_iter = batch.iter().flat_map(|block| {
+            let timer = self.stats.start_timer("index");
+            for block in &batch {
                 let blockhash = block.block_hash();
                 let height = *height_map
                     .get(&blockhash)
                     .unwrap_or_else(|| panic!("missing header for block {}", blockhash));
 
                 self.stats.update(block, height); // TODO: update stats after the block is indexed
-                index_block(block, height).chain(std::iter::once(last_indexed_block(&blockhash)))
-            });
+                match self.watchlist {
+                    Some(ref watchlist) => {
+                        watchlist
+                            .lock()
+                            .unwrap()
+                            .index_block_into(block, height, &mut rows)?;
+                    }
+                    None => index_block_into(block, height, &mut rows),
+                }
+                let row = last_indexed_block(&blockhash);
+                rows.push(&row.key, &row.value);
+            }
+            if let Some(ref watchlist) = self.watchlist {
+                let row = watchlist.lock().unwrap().fingerprint_row();
+                rows.push(&row.key, &row.value);
+            }
+            timer.observe_duration();
 
-            let timer = self.stats.start_timer("index+write");
-            store.write(rows_iter);
+            self.stats.batch_rows.observe(rows.len() as f64);
+            let timer = self.stats.start_timer("write");
+            store.write_batch(&rows);
+            rows.clear();
             timer.observe_duration();
         }
         let timer = self.stats.start_timer("flush");
@@ -428,10 +623,47 @@ impl Index {
         timer.observe_duration();
 
         fetcher.join().expect("block fetcher failed");
-        self.headers.write().unwrap().apply(new_headers, tip);
+        self.headers.write().unwrap().apply(new_headers, tip)?;
         assert_eq!(tip, self.headers.read().unwrap().tip());
         self.stats
             .update_height(self.headers.read().unwrap().len() - 1);
         Ok(tip)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::network::constants::Network;
+
+    #[test]
+    fn test_encode_rows() {
+        let block = genesis_block(Network::Bitcoin);
+        let mut txn = block.txdata[0].clone();
+        txn.input[0].previous_output.txid = block.txdata[0].txid();
+        txn.input[0].previous_output.vout = 0x1234;
+        let txid = txn.txid();
+
+        let txin = TxInRow::new(&txid, &txn.input[0]);
+        assert_eq!(txin.encode().to_vec(), bincode::serialize(&txin).unwrap());
+        let txout = TxOutRow::new(&txid, &txn.output[0]);
+        assert_eq!(txout.encode().to_vec(), bincode::serialize(&txout).unwrap());
+        let tx = TxRow::new(&txid, 0x0102_0304);
+        let (key, value) = tx.encode();
+        assert_eq!(key.to_vec(), bincode::serialize(&tx.key).unwrap());
+        assert_eq!(value.to_vec(), bincode::serialize(&tx.height).unwrap());
+
+        let mut block = block;
+        block.txdata.push(txn);
+        let mut batch = RowBatch::new();
+        index_block_into(&block, 7, &mut batch);
+        let expected: Vec<Row> = index_block(&block, 7).collect();
+        let actual: Vec<(&[u8], &[u8])> = batch.iter().collect();
+        assert_eq!(actual.len(), expected.len());
+        for (row, (key, value)) in expected.iter().zip(actual) {
+            assert_eq!(&row.key[..], key);
+            assert_eq!(&row.value[..], value);
+        }
+    }
+}
@@ -0,0 +1,113 @@
+//! Offline index inspection (`electrs inspect ...`), for debugging index problems.
+//!
+//! Uses only the DB (opened read-only, so electrs may keep running - but rows it writes afterwards
+//! are not visible), without connecting bitcoind.
+//! Since the index stores only hash prefixes and no transactions, a script hash's history
+//! consists of its funding transactions' candidates (including prefix collisions), without
+//! the spending ones.
+
+use bitcoin::hash_types::Txid;
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::hashes::Hash;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+use crate::errors::*;
+use crate::index::{read_indexed_headers, TxOutRow, TxRow};
+use crate::store::{DBStore, ReadStore};
+use crate::types::ScriptHash;
+use crate::util::{HeaderEntry, HeaderList};
+
+pub const USAGE: &str = "tip | header HEIGHT | tx TXID | history SCRIPTHASH | stats";
+
+// key prefixes of the DB rows (see `index.rs` and `store.rs`)
+pub const ROW_TYPES: &[(&str, &[u8])] = &[
+    ("headers", b"B"),
+    ("inputs", b"I"),
+    ("outputs", b"O"),
+    ("txids", b"T"),
+    ("tip", b"L"),
+    ("compaction_marker", b"F"),
+    ("format_version", b"V"),
+    ("migration_progress", b"M"),
+    ("watched_outputs", b"P"),
+    ("watchlist", b"W"),
+];
+
+fn header_json(entry: &HeaderEntry) -> Value {
+    let header = entry.header();
+    json!({
+        "height": entry.height(),
+        "hash": entry.hash().to_hex(),
+        "version": header.version,
+        "prev_blockhash": header.prev_blockhash.to_hex(),
+        "merkle_root": header.merkle_root.to_hex(),
+        "time": header.time,
+        "bits": header.bits,
+        "nonce": header.nonce,
+    })
+}
+
+fn tx_json(headers: &HeaderList, row: &TxRow) -> Value {
+    let blockhash = headers
+        .header_by_height(row.height as usize)
+        .map(|entry| entry.hash().to_hex());
+    json!({
+        "txid": Txid::from_slice(&row.key.txid[..]).unwrap().to_hex(),
+        "height": row.height,
+        "blockhash": blockhash,
+    })
+}
+
+fn row_stats(store: &DBStore) -> Value {
+    let mut result = BTreeMap::new();
+    for (name, prefix) in ROW_TYPES {
+        let (mut rows, mut bytes) = (0u64, 0u64);
+        for row in store.iter_scan(prefix) {
+            rows += 1;
+            bytes += (row.key.len() + row.value.len()) as u64;
+        }
+        result.insert(*name, json!({"rows": rows, "bytes": bytes}));
+    }
+    json!(result)
+}
+
+/// Answers an inspection command (see `USAGE`), as JSON.
+pub fn run(store: &DBStore, args: &[String]) -> Result<Value> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let headers = read_indexed_headers(store)?;
+    Ok(match args[..] {
+        ["tip"] => match headers.header_by_height(headers.len().wrapping_sub(1)) {
+            Some(entry) => header_json(&entry),
+            None => Value::Null,
+        },
+        ["header", height] => {
+            let height: usize = height.parse().chain_err(|| "invalid height")?;
+            let entry = headers
+                .header_by_height(height)
+                .chain_err(|| format!("no header at height {}", height))?;
+            header_json(&entry)
+        }
+        ["tx", txid] => {
+            let txid = Txid::from_hex(txid).chain_err(|| "invalid txid")?;
+            let rows = store.scan(&TxRow::filter_full(&txid));
+            match rows.first() {
+                Some(row) => tx_json(&headers, &TxRow::from_row(row)),
+                None => Value::Null, // not indexed
+            }
+        }
+        ["history", script_hash] => {
+            let script_hash: ScriptHash = script_hash.parse()?;
+            let mut funding = vec![];
+            for row in store.scan(&TxOutRow::filter(script_hash.as_bytes())) {
+                let txid_prefix = TxOutRow::from_row(&row).txid_prefix;
+                for row in store.scan(&TxRow::filter_prefix(txid_prefix)) {
+                    funding.push(tx_json(&headers, &TxRow::from_row(&row)));
+                }
+            }
+            json!({ "funding": funding })
+        }
+        ["stats"] => row_stats(store),
+        _ => bail!("invalid inspect command (expected: {})", USAGE),
+    })
+}
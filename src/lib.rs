@@ -11,18 +11,34 @@ extern crate serde_json;
 // I really don't know why it fails without this line
 extern crate configure_me;
 
+pub mod admin;
 pub mod app;
 pub mod bulk;
 pub mod cache;
 pub mod config;
 pub mod daemon;
+pub mod daemonize;
 pub mod errors;
 pub mod fake;
 pub mod index;
+pub mod inspect;
+pub mod logger;
 pub mod mempool;
 pub mod metrics;
+pub mod migration;
+pub mod protocol;
 pub mod query;
+#[cfg(feature = "test-utils")]
+pub mod regtest;
+pub mod replication;
+pub mod rest;
 pub mod rpc;
 pub mod signal;
+pub mod silentpayments;
+pub mod status;
 pub mod store;
+pub mod systemd;
+pub mod types;
 pub mod util;
+pub mod wallet;
+pub mod watch;
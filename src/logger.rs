@@ -0,0 +1,140 @@
+//! JSON lines logger, so logs can be ingested (e.g. by Loki or Elasticsearch) without parsing,
+//! and syslog logger (for running in the background without systemd).
+
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::io::{self, Write};
+use std::os::raw::c_char;
+use std::thread;
+
+thread_local! {
+    static FIELDS: RefCell<Map<String, Value>> = RefCell::new(Map::new());
+}
+
+/// Adds `fields` (a JSON object) to the JSON lines logged by the current thread within `f`.
+/// Other loggers ignore them, so the log message should still be self-contained.
+pub fn with_fields<T>(fields: Value, f: impl FnOnce() -> T) -> T {
+    let fields = match fields {
+        Value::Object(fields) => fields,
+        _ => panic!("log fields must be a JSON object"),
+    };
+    let prev = FIELDS.with(|current| current.replace(fields));
+    let result = f();
+    FIELDS.with(|current| current.replace(prev));
+    result
+}
+
+struct JsonLogger;
+
+fn timestamp() -> String {
+    let now = time::now_utc();
+    format!(
+        "{}.{:03}Z",
+        now.strftime("%Y-%m-%dT%H:%M:%S").unwrap(),
+        now.tm_nsec / 1_000_000
+    )
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut line = json!({
+            "timestamp": timestamp(),
+            "level": record.level().to_string(),
+            "module": record.module_path().unwrap_or_else(|| record.target()),
+            "thread": thread::current().name(),
+            "message": record.args().to_string(),
+        });
+        FIELDS.with(|fields| {
+            let line = line.as_object_mut().unwrap();
+            for (key, value) in fields.borrow().iter() {
+                line.entry(key.as_str()).or_insert_with(|| value.clone());
+            }
+        });
+        let _ = writeln!(io::stderr().lock(), "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
+
+struct SyslogLogger;
+
+impl Log for SyslogLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let priority = match record.level() {
+            Level::Error => libc::LOG_ERR,
+            Level::Warn => libc::LOG_WARNING,
+            Level::Info => libc::LOG_INFO,
+            Level::Debug | Level::Trace => libc::LOG_DEBUG,
+        };
+        let message = format!(
+            "{}: {}",
+            record.module_path().unwrap_or_else(|| record.target()),
+            record.args()
+        );
+        let message = CString::new(message.replace('\0', "")).unwrap();
+        unsafe {
+            libc::syslog(
+                priority,
+                b"%s\0".as_ptr() as *const c_char,
+                message.as_ptr(),
+            )
+        };
+    }
+
+    fn flush(&self) {}
+}
+
+// Same verbosity levels as `stderrlog`
+fn level_filter(verbosity: usize) -> LevelFilter {
+    match verbosity {
+        0 => LevelFilter::Error,
+        1 => LevelFilter::Warn,
+        2 => LevelFilter::Info,
+        3 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// Sets the logging verbosity (also after the logger is initialized).
+pub fn set_verbosity(verbosity: usize) {
+    log::set_max_level(level_filter(verbosity));
+}
+
+/// Logs as JSON lines to stderr.
+pub fn init(verbosity: usize) -> Result<(), SetLoggerError> {
+    log::set_logger(&JsonLogger)?;
+    set_verbosity(verbosity);
+    Ok(())
+}
+
+/// Logs to syslog, using the daemon facility.
+pub fn init_syslog(verbosity: usize) -> Result<(), SetLoggerError> {
+    log::set_logger(&SyslogLogger)?;
+    set_verbosity(verbosity);
+    unsafe {
+        libc::openlog(
+            b"electrs\0".as_ptr() as *const c_char,
+            libc::LOG_PID,
+            libc::LOG_DAEMON,
+        )
+    };
+    Ok(())
+}
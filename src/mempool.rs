@@ -11,6 +11,7 @@ use crate::index::index_transaction;
 use crate::metrics::{
     Gauge, GaugeVec, HistogramOpts, HistogramTimer, HistogramVec, MetricOpts, Metrics,
 };
+use crate::status::StatusChanges;
 use crate::store::{ReadStore, Row};
 use crate::util::Bytes;
 
@@ -88,13 +89,32 @@ impl ReadStore for MempoolStore {
     }
 }
 
+/// Thresholds below which mempool transactions are not tracked for history and notifications
+/// (but are still counted by the fee histogram), e.g. during dust spam waves.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MempoolFilter {
+    pub min_fee_rate: f32, // [sat/vbyte]
+    pub min_value: u64,    // [sat], of the largest output
+}
+
+impl MempoolFilter {
+    fn skips_entry(&self, entry: &MempoolEntry) -> bool {
+        entry.fee_per_vbyte() < self.min_fee_rate
+    }
+
+    fn skips_tx(&self, tx: &Transaction) -> bool {
+        self.min_value > 0 && tx.output.iter().all(|txo| txo.value < self.min_value)
+    }
+}
+
 struct Item {
-    tx: Transaction,     // stored for faster retrieval and index removal
-    entry: MempoolEntry, // caches mempool fee rates
+    tx: Option<Transaction>, // stored for faster retrieval and index removal (unless filtered)
+    entry: MempoolEntry,     // caches mempool fee rates
 }
 
 struct Stats {
     count: Gauge,
+    filtered: Gauge,
     update: HistogramVec,
     vsize: GaugeVec,
     max_fee_rate: Mutex<f32>,
@@ -141,20 +161,26 @@ pub struct Tracker {
     items: HashMap<Txid, Item>,
     index: MempoolStore,
     histogram: Vec<(f32, u32)>,
+    filter: MempoolFilter,
     stats: Stats,
 }
 
 impl Tracker {
-    pub fn new(metrics: &Metrics) -> Tracker {
+    pub fn new(metrics: &Metrics, filter: MempoolFilter) -> Tracker {
         Tracker {
             items: HashMap::new(),
             index: MempoolStore::new(),
             histogram: vec![],
+            filter,
             stats: Stats {
                 count: metrics.gauge(MetricOpts::new(
                     "electrs_mempool_count",
                     "# of mempool transactions",
                 )),
+                filtered: metrics.gauge(MetricOpts::new(
+                    "electrs_mempool_filtered_count",
+                    "# of mempool transactions not tracked for history (see MempoolFilter)",
+                )),
                 update: metrics.histogram_vec(
                     HistogramOpts::new(
                         "electrs_mempool_update",
@@ -193,7 +219,8 @@ impl Tracker {
         &self.index
     }
 
-    pub fn update(&mut self, daemon: &Daemon) -> Result<()> {
+    /// Returns the changes of the added and removed transactions (ignoring filtered ones).
+    pub fn update(&mut self, daemon: &Daemon) -> Result<StatusChanges> {
         let timer = self.stats.start_timer("fetch");
         let new_txids = daemon
             .getmempooltxids()
@@ -201,6 +228,7 @@ impl Tracker {
         let old_txids = HashSet::from_iter(self.items.keys().cloned());
         timer.observe_duration();
 
+        let mut changes = StatusChanges::default();
         let timer = self.stats.start_timer("add");
         let txids_iter = new_txids.difference(&old_txids);
         let entries = txids_iter.filter_map(|txid| {
@@ -213,10 +241,19 @@ impl Tracker {
             }
         });
         for (txid, entry) in entries {
+            if self.filter.skips_entry(&entry) {
+                self.add(txid, None, entry); // no need to fetch it
+                continue;
+            }
             match daemon.gettransaction(txid, None) {
                 Ok(tx) => {
                     assert_eq!(tx.txid(), *txid);
-                    self.add(txid, tx, entry);
+                    if self.filter.skips_tx(&tx) {
+                        self.add(txid, None, entry);
+                    } else {
+                        changes.add_transaction(&tx);
+                        self.add(txid, Some(tx), entry);
+                    }
                 }
                 Err(err) => {
                     debug!("failed to get transaction {}: {}", txid, err); // e.g. new block or RBF
@@ -227,7 +264,9 @@ impl Tracker {
 
         let timer = self.stats.start_timer("remove");
         for txid in old_txids.difference(&new_txids) {
-            self.remove(txid);
+            if let Some(tx) = self.remove(txid) {
+                changes.add_transaction(&tx);
+            }
         }
         timer.observe_duration();
 
@@ -236,20 +275,27 @@ impl Tracker {
         timer.observe_duration();
 
         self.stats.count.set(self.items.len() as i64);
-        Ok(())
+        let filtered = self.items.values().filter(|item| item.tx.is_none()).count();
+        self.stats.filtered.set(filtered as i64);
+        Ok(changes)
     }
 
-    fn add(&mut self, txid: &Txid, tx: Transaction, entry: MempoolEntry) {
-        self.index.add(&tx);
+    fn add(&mut self, txid: &Txid, tx: Option<Transaction>, entry: MempoolEntry) {
+        if let Some(tx) = &tx {
+            self.index.add(tx);
+        }
         self.items.insert(*txid, Item { tx, entry });
     }
 
-    fn remove(&mut self, txid: &Txid) {
+    /// Returns the removed transaction, if it was tracked (i.e. not filtered).
+    fn remove(&mut self, txid: &Txid) -> Option<Transaction> {
         let stats = self
             .items
             .remove(txid)
             .unwrap_or_else(|| panic!("missing mempool tx {}", txid));
-        self.index.remove(&stats.tx);
+        let tx = stats.tx?;
+        self.index.remove(&tx);
+        Some(tx)
     }
 
     fn update_fee_histogram(&mut self) {
@@ -303,4 +349,36 @@ mod tests {
             vec![(3.0, 150_000), (1.0, 121_000)]
         );
     }
+
+    #[test]
+    fn test_mempool_filter() {
+        use crate::daemon::MempoolEntry;
+        use crate::mempool::MempoolFilter;
+        use bitcoin::blockdata::transaction::{Transaction, TxOut};
+
+        let tx = |values: &[u64]| Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: values
+                .iter()
+                .map(|value| TxOut {
+                    value: *value,
+                    script_pubkey: Default::default(),
+                })
+                .collect(),
+        };
+        let filter = MempoolFilter::default();
+        assert!(!filter.skips_entry(&MempoolEntry::new(0, 100)));
+        assert!(!filter.skips_tx(&tx(&[1])));
+
+        let filter = MempoolFilter {
+            min_fee_rate: 2.0,
+            min_value: 1000,
+        };
+        assert!(filter.skips_entry(&MempoolEntry::new(199, 100)));
+        assert!(!filter.skips_entry(&MempoolEntry::new(200, 100)));
+        assert!(filter.skips_tx(&tx(&[546, 999])));
+        assert!(!filter.skips_tx(&tx(&[546, 1000])));
+    }
 }
@@ -1,21 +1,73 @@
+use error_chain::ChainedError;
 use prometheus::{self, Encoder, IntGauge};
+use serde_json::Value;
+use std::fmt;
 use std::fs;
-use std::io;
-use std::net::SocketAddr;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 pub use prometheus::{
-    GaugeVec, Histogram, HistogramOpts, HistogramTimer, HistogramVec, IntCounter as Counter,
-    IntCounterVec as CounterVec, IntGauge as Gauge, Opts as MetricOpts,
+    Gauge as FloatGauge, GaugeVec, Histogram, HistogramOpts, HistogramTimer, HistogramVec,
+    IntCounter as Counter, IntCounterVec as CounterVec, IntGauge as Gauge, Opts as MetricOpts,
 };
 
 use crate::errors::*;
 use crate::util::spawn_thread;
 
+/// Restricts access to the monitoring HTTP endpoint.
+#[derive(Clone, Default)]
+pub struct MonitoringAccess {
+    pub auth: Option<String>,     // "USER:PASSWORD", for basic authentication
+    pub allowed_ips: Vec<IpAddr>, // empty - allow all
+}
+
+impl MonitoringAccess {
+    fn check(&self, request: &tiny_http::Request) -> std::result::Result<(), u16> {
+        let ip = request.remote_addr().ip();
+        if !self.allowed_ips.is_empty() && !self.allowed_ips.contains(&ip) {
+            return Err(403);
+        }
+        if let Some(ref auth) = self.auth {
+            let expected = format!("Basic {}", base64::encode(auth));
+            let authorized = request.headers().iter().any(|header| {
+                header.field.equiv("Authorization") && header.value.as_str() == expected
+            });
+            if !authorized {
+                return Err(401);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Don't leak the password
+impl fmt::Debug for MonitoringAccess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MonitoringAccess")
+            .field("auth", &self.auth.as_ref().map(|_| "<hidden>"))
+            .field("allowed_ips", &self.allowed_ips)
+            .finish()
+    }
+}
+
+/// Readiness is lost if the index is not synced for this long (e.g. bitcoind is unreachable).
+const SYNC_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Last sync tick, reported via the `/healthz/ready` endpoint.
+struct SyncState {
+    index_height: usize,
+    daemon_height: usize,
+    updated: Instant,
+}
+
+#[derive(Clone)]
 pub struct Metrics {
     reg: prometheus::Registry,
     addr: SocketAddr,
+    sync_state: Arc<Mutex<Option<SyncState>>>,
 }
 
 impl Metrics {
@@ -23,9 +75,19 @@ impl Metrics {
         Metrics {
             reg: prometheus::Registry::new(),
             addr,
+            sync_state: Arc::new(Mutex::new(None)),
         }
     }
 
+    /// Should be called after each successful sync tick (with the DB open and bitcoind reachable).
+    pub fn set_synced(&self, index_height: usize, daemon_height: usize) {
+        *self.sync_state.lock().unwrap() = Some(SyncState {
+            index_height,
+            daemon_height,
+            updated: Instant::now(),
+        });
+    }
+
     pub fn counter(&self, opts: prometheus::Opts) -> Counter {
         let c = Counter::with_opts(opts).unwrap();
         self.reg.register(Box::new(c.clone())).unwrap();
@@ -56,6 +118,12 @@ impl Metrics {
         g
     }
 
+    pub fn float_gauge(&self, opts: prometheus::Opts) -> FloatGauge {
+        let g = FloatGauge::with_opts(opts).unwrap();
+        self.reg.register(Box::new(g.clone())).unwrap();
+        g
+    }
+
     pub fn histogram(&self, opts: prometheus::HistogramOpts) -> Histogram {
         let h = Histogram::with_opts(opts).unwrap();
         self.reg.register(Box::new(h.clone())).unwrap();
@@ -68,7 +136,47 @@ impl Metrics {
         h
     }
 
-    pub fn start(&self) {
+    /// Returns the current values of the metrics whose name matches `filter`, as JSON.
+    pub fn dump<F>(&self, filter: F) -> Value
+    where
+        F: Fn(&str) -> bool,
+    {
+        let mut result = serde_json::Map::new();
+        for family in self.reg.gather() {
+            if !filter(family.get_name()) {
+                continue;
+            }
+            let values: Vec<Value> = family
+                .get_metric()
+                .iter()
+                .map(|m| {
+                    let labels: serde_json::Map<String, Value> = m
+                        .get_label()
+                        .iter()
+                        .map(|l| (l.get_name().to_owned(), json!(l.get_value())))
+                        .collect();
+                    let value = match family.get_field_type() {
+                        prometheus::proto::MetricType::COUNTER => {
+                            json!(m.get_counter().get_value())
+                        }
+                        prometheus::proto::MetricType::GAUGE => json!(m.get_gauge().get_value()),
+                        prometheus::proto::MetricType::HISTOGRAM => json!({
+                            "count": m.get_histogram().get_sample_count(),
+                            "sum": m.get_histogram().get_sample_sum(),
+                        }),
+                        _ => Value::Null,
+                    };
+                    json!({"labels": labels, "value": value})
+                })
+                .collect();
+            result.insert(family.get_name().to_owned(), json!(values));
+        }
+        Value::Object(result)
+    }
+
+    /// Serves the metrics, and the `/healthz/live` and `/healthz/ready` endpoints (the index is
+    /// ready if it's at most `max_lag` blocks behind bitcoind).
+    pub fn start(&self, access: MonitoringAccess, max_lag: usize) {
         let server = tiny_http::Server::http(self.addr).unwrap_or_else(|e| {
             panic!(
                 "failed to start monitoring HTTP server at {}: {}",
@@ -77,19 +185,127 @@ impl Metrics {
         });
         start_process_exporter(self);
         let reg = self.reg.clone();
+        let sync_state = Arc::clone(&self.sync_state);
         spawn_thread("metrics", move || loop {
-            if let Err(e) = handle_request(&reg, server.recv()) {
+            let result = server.recv().and_then(|request| match request.url() {
+                "/healthz/live" => respond_json(request, 200, json!({"status": "ok"})),
+                "/healthz/ready" => {
+                    let (status, body) = readiness(&sync_state.lock().unwrap(), max_lag);
+                    respond_json(request, status, body)
+                }
+                _ => handle_request(&reg, &access, request),
+            });
+            if let Err(e) = result {
                 error!("http error: {}", e);
             }
         });
     }
+
+    /// Periodically pushes the metrics to a Prometheus Pushgateway.
+    pub fn start_pusher(&self, addr: SocketAddr, interval: Duration) {
+        info!("pushing metrics to {} every {:?}", addr, interval);
+        let reg = self.reg.clone();
+        spawn_thread("pusher", move || loop {
+            if let Err(e) = push_metrics(&reg, addr) {
+                warn!("failed to push metrics: {}", e.display_chain());
+            }
+            thread::sleep(interval);
+        });
+    }
+}
+
+fn push_metrics(reg: &prometheus::Registry, addr: SocketAddr) -> Result<()> {
+    let encoder = prometheus::TextEncoder::new();
+    let mut body = vec![];
+    encoder
+        .encode(&reg.gather(), &mut body)
+        .chain_err(|| "failed to encode metrics")?;
+    let timeout = Duration::from_secs(10);
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)
+        .chain_err(|| format!("failed to connect to {}", addr))?;
+    stream
+        .set_read_timeout(Some(timeout))
+        .chain_err(|| "failed to set read timeout")?;
+    stream
+        .set_write_timeout(Some(timeout))
+        .chain_err(|| "failed to set write timeout")?;
+    // PUT replaces all the metrics previously pushed by this job
+    let request = format!(
+        "PUT /metrics/job/electrs HTTP/1.1\r\nHost: {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        addr,
+        encoder.format_type(),
+        body.len()
+    );
+    stream
+        .write_all(request.as_bytes())
+        .and_then(|()| stream.write_all(&body))
+        .chain_err(|| format!("failed to send metrics to {}", addr))?;
+    let mut status_line = String::new();
+    BufReader::new(stream)
+        .read_line(&mut status_line)
+        .chain_err(|| format!("failed to read response from {}", addr))?;
+    // e.g. "HTTP/1.1 200 OK"
+    match status_line.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => bail!("unexpected response: {:?}", status_line.trim()),
+    }
+}
+
+fn readiness(sync_state: &Option<SyncState>, max_lag: usize) -> (u16, Value) {
+    let state = match sync_state {
+        Some(state) => state,
+        None => return (503, json!({"status": "starting"})),
+    };
+    let lag = state.daemon_height.saturating_sub(state.index_height);
+    let since_sync = state.updated.elapsed();
+    let status = if since_sync > SYNC_TIMEOUT {
+        "stale"
+    } else if lag > max_lag {
+        "syncing"
+    } else {
+        "ready"
+    };
+    let body = json!({
+        "status": status,
+        "index_height": state.index_height,
+        "daemon_height": state.daemon_height,
+        "lag": lag,
+        "secs_since_sync": since_sync.as_secs(),
+    });
+    (if status == "ready" { 200 } else { 503 }, body)
+}
+
+fn respond_json(request: tiny_http::Request, status: u16, body: Value) -> io::Result<()> {
+    let header =
+        tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+    let response = tiny_http::Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(header);
+    request.respond(response)
 }
 
 fn handle_request(
     reg: &prometheus::Registry,
-    request: io::Result<tiny_http::Request>,
+    access: &MonitoringAccess,
+    request: tiny_http::Request,
 ) -> io::Result<()> {
-    let request = request?;
+    if let Err(status) = access.check(&request) {
+        warn!(
+            "denied monitoring request from {} (HTTP {})",
+            request.remote_addr(),
+            status
+        );
+        let mut response = tiny_http::Response::empty(status);
+        if status == 401 {
+            let header = tiny_http::Header::from_bytes(
+                &b"WWW-Authenticate"[..],
+                &b"Basic realm=\"electrs\""[..],
+            )
+            .unwrap();
+            response = response.with_header(header);
+        }
+        return request.respond(response);
+    }
     let mut buffer = vec![];
     prometheus::TextEncoder::new()
         .encode(&reg.gather(), &mut buffer)
@@ -100,6 +316,9 @@ fn handle_request(
 
 struct Stats {
     utime: f64,
+    stime: f64,
+    threads: u64,
+    vsize: u64,
     rss: u64,
     fds: usize,
 }
@@ -122,11 +341,21 @@ fn parse_stats() -> Result<Stats> {
 
     // For details, see '/proc/[pid]/stat' section at `man 5 proc`:
     let utime = parse_part(13, "utime")? as f64 / ticks_per_second;
+    let stime = parse_part(14, "stime")? as f64 / ticks_per_second;
+    let threads = parse_part(19, "num_threads")?;
+    let vsize = parse_part(22, "vsize")?;
     let rss = parse_part(23, "rss")? * page_size;
     let fds = fs::read_dir("/proc/self/fd")
         .chain_err(|| "failed to read /proc/self/fd directory")?
         .count();
-    Ok(Stats { utime, rss, fds })
+    Ok(Stats {
+        utime,
+        stime,
+        threads,
+        vsize,
+        rss,
+        fds,
+    })
 }
 
 fn start_process_exporter(metrics: &Metrics) {
@@ -134,6 +363,10 @@ fn start_process_exporter(metrics: &Metrics) {
         "electrs_process_memory_rss",
         "Resident memory size [bytes]",
     ));
+    let vsize = metrics.gauge(MetricOpts::new(
+        "electrs_process_memory_vsize",
+        "Virtual memory size [bytes]",
+    ));
     let cpu = metrics.gauge_vec(
         MetricOpts::new(
             "electrs_process_cpu_usage",
@@ -145,12 +378,16 @@ fn start_process_exporter(metrics: &Metrics) {
         "electrs_process_open_fds",
         "# of file descriptors",
     ));
+    let threads = metrics.gauge(MetricOpts::new("electrs_process_threads", "# of threads"));
     spawn_thread("exporter", move || loop {
         match parse_stats() {
             Ok(stats) => {
                 cpu.with_label_values(&["utime"]).set(stats.utime as f64);
+                cpu.with_label_values(&["stime"]).set(stats.stime as f64);
                 rss.set(stats.rss as i64);
+                vsize.set(stats.vsize as i64);
                 fds.set(stats.fds as i64);
+                threads.set(stats.threads as i64);
             }
             Err(e) => {
                 warn!("failed to export process stats: {}", e);
@@ -0,0 +1,230 @@
+//! Versioned DB format migrations.
+//!
+//! The DB format version is stored in the "V" row (a DB without it uses the original format,
+//! i.e. version 1). On startup, the registered migration steps are applied one version at a
+//! time, from the DB's version up to `CURRENT_FORMAT`. Each step transforms the rows matching
+//! its prefix in batches, and records its progress (the last transformed key) in the "M" row
+//! within the same atomic write - so an interrupted migration resumes where it stopped.
+
+use crate::errors::*;
+use crate::store::{DBStore, ReadStore, Row};
+use crate::util::Bytes;
+
+/// The DB format written by this version.
+pub const CURRENT_FORMAT: u32 = 1;
+
+const VERSION_KEY: &[u8] = b"V";
+const PROGRESS_KEY: &[u8] = b"M";
+const BATCH_SIZE: usize = 100_000;
+
+/// Transforms the rows of a DB format version into the next one (`from_format + 1`).
+pub struct Migration {
+    pub from_format: u32,
+    pub description: &'static str,
+    /// Key prefix of the rows to transform.
+    pub prefix: &'static [u8],
+    /// Returns the rows replacing the given one (the original row is deleted, so it should be
+    /// returned if kept). New keys should not match `prefix`, unless they are unchanged.
+    pub transform: fn(Row) -> Vec<Row>,
+}
+
+/// The registered steps, ordered by `from_format` (none yet, since the format wasn't changed).
+const MIGRATIONS: &[Migration] = &[];
+
+#[derive(Serialize, Deserialize)]
+struct Progress {
+    from_format: u32,
+    last_key: Bytes,
+}
+
+fn version_row(format: u32) -> Row {
+    Row {
+        key: VERSION_KEY.to_vec(),
+        value: bincode::serialize(&format).unwrap(),
+    }
+}
+
+fn read_format(store: &dyn ReadStore) -> Result<u32> {
+    match store.get(VERSION_KEY) {
+        Some(value) => bincode::deserialize(&value).chain_err(|| "invalid DB format version"),
+        None => Ok(1), // created before the format was versioned
+    }
+}
+
+/// Returns the steps required for migrating from `format` to `target`.
+fn pending(registry: &[Migration], format: u32, target: u32) -> Result<Vec<&Migration>> {
+    if format > target {
+        bail!(
+            "DB format {} is newer than the supported format {} (hint: upgrade electrs, or reindex)",
+            format,
+            target
+        );
+    }
+    (format..target)
+        .map(|from_format| {
+            registry
+                .iter()
+                .find(|m| m.from_format == from_format)
+                .chain_err(|| format!("no migration from DB format {}", from_format))
+        })
+        .collect()
+}
+
+fn run_step(store: &DBStore, migration: &Migration) {
+    let mut last_key = match store.get(PROGRESS_KEY) {
+        Some(value) => {
+            let progress: Progress = bincode::deserialize(&value).expect("invalid progress");
+            assert_eq!(progress.from_format, migration.from_format);
+            info!(
+                "resuming migration after {}",
+                hex::encode(&progress.last_key)
+            );
+            Some(progress.last_key)
+        }
+        None => None,
+    };
+    let mut rows_count = 0;
+    loop {
+        let start_at = last_key
+            .clone()
+            .unwrap_or_else(|| migration.prefix.to_vec());
+        let batch: Vec<Row> = store
+            .iter_scan_from(migration.prefix, &start_at)
+            .skip_while(|row| Some(&row.key) == last_key.as_ref()) // already transformed
+            .take(BATCH_SIZE)
+            .collect();
+        let batch_last_key = match batch.last() {
+            Some(row) => row.key.clone(),
+            None => break,
+        };
+        rows_count += batch.len();
+        let deleted_keys: Vec<Bytes> = batch.iter().map(|row| row.key.clone()).collect();
+        let mut rows: Vec<Row> = batch.into_iter().flat_map(migration.transform).collect();
+        rows.push(Row {
+            key: PROGRESS_KEY.to_vec(),
+            value: bincode::serialize(&Progress {
+                from_format: migration.from_format,
+                last_key: batch_last_key.clone(),
+            })
+            .unwrap(),
+        });
+        store.update(rows, deleted_keys);
+        debug!("migrated {} rows", rows_count);
+        last_key = Some(batch_last_key);
+    }
+    store.update(
+        vec![version_row(migration.from_format + 1)],
+        vec![PROGRESS_KEY.to_vec()],
+    );
+}
+
+/// Migrates the DB to the current format (a read-only DB must already use it).
+pub fn migrate(store: &DBStore, read_only: bool) -> Result<()> {
+    let format = read_format(store)?;
+    let steps = pending(MIGRATIONS, format, CURRENT_FORMAT)?;
+    if read_only {
+        if !steps.is_empty() {
+            bail!(
+                "DB format {} must be migrated to {} (hint: run without read_only first)",
+                format,
+                CURRENT_FORMAT
+            );
+        }
+        return Ok(());
+    }
+    for migration in steps {
+        info!(
+            "migrating DB format {} to {}: {}",
+            migration.from_format,
+            migration.from_format + 1,
+            migration.description
+        );
+        run_step(store, migration);
+    }
+    if store.get(VERSION_KEY).is_none() {
+        store.update(vec![version_row(CURRENT_FORMAT)], vec![]);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keep(row: Row) -> Vec<Row> {
+        vec![row]
+    }
+
+    fn mark(mut row: Row) -> Vec<Row> {
+        row.value.push(b'!');
+        vec![row]
+    }
+
+    fn step(from_format: u32) -> Migration {
+        Migration {
+            from_format,
+            description: "test",
+            prefix: b"X",
+            transform: keep,
+        }
+    }
+
+    #[test]
+    fn test_pending() {
+        let registry = [step(1), step(2), step(4)];
+        let formats = |steps: Vec<&Migration>| -> Vec<u32> {
+            steps.into_iter().map(|m| m.from_format).collect()
+        };
+        assert!(formats(pending(&registry, 3, 3).unwrap()).is_empty());
+        assert_eq!(formats(pending(&registry, 1, 3).unwrap()), vec![1, 2]);
+        assert_eq!(formats(pending(&registry, 2, 3).unwrap()), vec![2]);
+        assert!(pending(&registry, 1, 5).is_err()); // missing 3 -> 4
+        assert!(pending(&registry, 4, 3).is_err()); // newer than supported
+    }
+
+    #[test]
+    fn test_resume() {
+        let path = std::env::temp_dir().join(format!("electrs-migration-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let store = DBStore::open(&path, /*low_memory=*/ true).unwrap();
+        let row = |key: &[u8], value: &[u8]| Row {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        };
+        // interrupted after transforming the rows up to "Xc"
+        store.update(
+            vec![
+                row(b"Xa", b"1!"),
+                row(b"Xb", b"2!"),
+                row(b"Xc", b"3!"),
+                row(b"Xd", b"4"),
+                row(b"Xe", b"5"),
+                row(b"Y", b"other"),
+                Row {
+                    key: PROGRESS_KEY.to_vec(),
+                    value: bincode::serialize(&Progress {
+                        from_format: 1,
+                        last_key: b"Xc".to_vec(),
+                    })
+                    .unwrap(),
+                },
+            ],
+            vec![],
+        );
+        let migration = Migration {
+            transform: mark,
+            ..step(1)
+        };
+        run_step(&store, &migration);
+
+        let values: Vec<Bytes> = store.scan(b"X").into_iter().map(|row| row.value).collect();
+        let expected: Vec<&[u8]> = vec![b"1!", b"2!", b"3!", b"4!", b"5!"];
+        assert_eq!(values, expected);
+        assert_eq!(store.get(b"Y"), Some(b"other".to_vec()));
+        assert_eq!(store.get(PROGRESS_KEY), None);
+        assert_eq!(read_format(&store).unwrap(), 2);
+
+        drop(store);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+}
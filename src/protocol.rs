@@ -0,0 +1,175 @@
+//! Electrum protocol framing and JSONRPC request parsing.
+//!
+//! Requests are newline-delimited JSON objects, received from untrusted clients - so their
+//! sizes are limited before allocating or parsing them (see `fuzz/` for the fuzz targets).
+
+use serde_json::Value;
+use std::io::{BufRead, Read};
+
+use crate::errors::*;
+
+/// Maximum request line length (in bytes, excluding the newline) - large enough for
+/// broadcasting a package of non-standard transactions.
+pub const MAX_LINE_LEN: usize = 8 << 20;
+/// Maximum method name length (the longest supported one is 46 bytes).
+pub const MAX_METHOD_LEN: usize = 64;
+/// Maximum number of positional parameters (no method takes more than 3).
+pub const MAX_PARAMS: usize = 16;
+
+/// A parsed JSONRPC request.
+#[derive(Debug, PartialEq)]
+pub struct Request {
+    pub method: String,
+    pub params: Vec<Value>,
+}
+
+/// Reads the next request line (without its newline), returning `None` at EOF.
+/// Fails on lines longer than `MAX_LINE_LEN`, on TLS handshakes and on invalid UTF-8.
+pub fn read_line(reader: &mut impl BufRead) -> Result<Option<String>> {
+    let mut line = Vec::<u8>::new();
+    reader
+        .take(MAX_LINE_LEN as u64 + 1)
+        .read_until(b'\n', &mut line)
+        .chain_err(|| "failed to read a request")?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.starts_with(&[22, 3, 1]) {
+        // (very) naive SSL handshake detection
+        bail!(
+            "invalid request - maybe SSL-encrypted data?: {:?}",
+            &line[..3]
+        );
+    }
+    if line.last() == Some(&b'\n') {
+        line.pop();
+    } else if line.len() > MAX_LINE_LEN {
+        bail!("request is longer than {} bytes", MAX_LINE_LEN);
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .chain_err(|| "invalid UTF8")
+}
+
+fn parse_id(id: Option<Value>) -> Result<Value> {
+    match id {
+        Some(id @ Value::Null) | Some(id @ Value::Number(_)) | Some(id @ Value::String(_)) => {
+            Ok(id)
+        }
+        Some(_) => bail!(ErrorKind::InvalidRequest("id must be a string or a number")),
+        None => bail!(ErrorKind::InvalidRequest("missing id")),
+    }
+}
+
+fn parse_method(method: Option<Value>) -> Result<String> {
+    match method {
+        Some(Value::String(method)) => {
+            if method.len() > MAX_METHOD_LEN {
+                bail!(ErrorKind::InvalidRequest("method is too long"));
+            }
+            Ok(method)
+        }
+        Some(_) => bail!(ErrorKind::InvalidRequest("method must be a string")),
+        None => bail!(ErrorKind::InvalidRequest("missing method")),
+    }
+}
+
+fn parse_params(params: Option<Value>) -> Result<Vec<Value>> {
+    match params {
+        None => Ok(vec![]),
+        Some(Value::Array(params)) => {
+            if params.len() > MAX_PARAMS {
+                bail!(ErrorKind::InvalidRequest("too many params"));
+            }
+            Ok(params)
+        }
+        Some(_) => bail!(ErrorKind::InvalidRequest("params must be an array")),
+    }
+}
+
+/// Parses a request line, returning its id (`null` if it can't be parsed) and the request.
+pub fn parse_request(line: &str) -> (Value, Result<Request>) {
+    let mut cmd = match serde_json::from_str(line) {
+        Ok(Value::Object(cmd)) => cmd,
+        Ok(_) => return (Value::Null, Err(ErrorKind::ParseError.into())),
+        Err(e) => return (Value::Null, Err(e).chain_err(|| ErrorKind::ParseError)),
+    };
+    let id = match parse_id(cmd.remove("id")) {
+        Ok(id) => id,
+        Err(e) => return (Value::Null, Err(e)),
+    };
+    let request = parse_method(cmd.remove("method")).and_then(|method| {
+        let params = parse_params(cmd.remove("params"))?;
+        Ok(Request { method, params })
+    });
+    (id, request)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn lines(data: &[u8]) -> Vec<Result<Option<String>>> {
+        let mut reader = Cursor::new(data);
+        let mut result = vec![];
+        loop {
+            let line = read_line(&mut reader);
+            let done = match line {
+                Ok(None) | Err(_) => true,
+                Ok(Some(_)) => false,
+            };
+            result.push(line);
+            if done {
+                return result;
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_line() {
+        let result = lines(b"a\nb");
+        assert_eq!(result[0].as_ref().unwrap().as_deref(), Some("a"));
+        assert_eq!(result[1].as_ref().unwrap().as_deref(), Some("b"));
+        assert!(result[2].as_ref().unwrap().is_none());
+
+        assert!(lines(b"\x16\x03\x01\x02\x00")[0].is_err());
+        assert!(lines(b"\xff\n")[0].is_err());
+
+        let mut long = vec![b'x'; MAX_LINE_LEN];
+        assert_eq!(
+            lines(&long)[0].as_ref().unwrap().as_ref().unwrap().len(),
+            MAX_LINE_LEN
+        );
+        long.push(b'x');
+        assert!(lines(&long)[0].is_err());
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let (id, request) = parse_request(r#"{"id": 1, "method": "m", "params": [2]}"#);
+        assert_eq!(id, json!(1));
+        let request = request.unwrap();
+        assert_eq!(request.method, "m");
+        assert_eq!(request.params, vec![json!(2)]);
+
+        let (id, request) = parse_request(r#"{"id": "x", "method": "m"}"#);
+        assert_eq!(id, json!("x"));
+        assert!(request.unwrap().params.is_empty());
+
+        let (id, request) = parse_request(r#"{"id": 1, "method": "m", "params": {}}"#);
+        assert_eq!(id, json!(1)); // the error is still returned to the client
+        assert!(request.is_err());
+
+        let too_many = format!(r#"{{"id": 1, "method": "m", "params": {:?}}}"#, [0; 17]);
+        assert!(parse_request(&too_many).1.is_err());
+        let too_long = format!(r#"{{"id": 1, "method": "{}"}}"#, "m".repeat(65));
+        assert!(parse_request(&too_long).1.is_err());
+
+        for line in &["", "[]", "{}", r#"{"id": [], "method": "m"}"#, "{\"id\": 1"] {
+            let (id, request) = parse_request(line);
+            assert_eq!(id, Value::Null);
+            assert!(request.is_err());
+        }
+    }
+}
@@ -1,138 +1,55 @@
+use bitcoin::blockdata::block::Block;
 use bitcoin::blockdata::transaction::Transaction;
-use bitcoin::consensus::encode::deserialize;
+use bitcoin::consensus::encode::{deserialize, serialize};
 use bitcoin::hash_types::{BlockHash, TxMerkleNode, Txid};
-use bitcoin::hashes::hex::ToHex;
 use bitcoin::hashes::sha256d::Hash as Sha256dHash;
 use bitcoin::hashes::Hash;
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::util::amount::Amount;
+use error_chain::ChainedError;
 use serde_json::Value;
-use sha2::{Digest, Sha256};
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 use crate::app::App;
-use crate::cache::TransactionCache;
+use crate::cache::{
+    BlockCache, BlockMerkleTree, MerkleProof, MerkleProofCache, StatusCache, TransactionCache,
+    TweaksCache,
+};
 use crate::errors::*;
 use crate::index::{compute_script_hash, TxInRow, TxOutRow, TxRow};
-use crate::mempool::Tracker;
+use crate::mempool::{MempoolFilter, Tracker};
 use crate::metrics::{HistogramOpts, HistogramVec, Metrics};
+use crate::silentpayments;
+use crate::status::{
+    Balance, FundingOutput, HistoryEntry, HistoryPage, SpendingInput, Status, StatusChanges, Utxo,
+};
 use crate::store::{ReadStore, Row};
-use crate::util::{FullHash, HashPrefix, HeaderEntry};
-
-pub struct FundingOutput {
-    pub txn_id: Txid,
-    pub height: u32,
-    pub output_index: usize,
-    pub value: u64,
-}
-
-type OutPoint = (Txid, usize); // (txid, output_index)
-
-struct SpendingInput {
-    txn_id: Txid,
-    height: u32,
-    funding_output: OutPoint,
-    value: u64,
-}
-
-pub struct Status {
-    confirmed: (Vec<FundingOutput>, Vec<SpendingInput>),
-    mempool: (Vec<FundingOutput>, Vec<SpendingInput>),
-    txn_fees: HashMap<Txid, u64>,
+use crate::types::{HistoryToken, ScriptHash, StatusHash};
+use crate::util::{spawn_thread, HashPrefix, HeaderEntry, MerkleTree};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TxStatus {
+    Unconfirmed, // found in mempool
+    Confirmed { height: u32, blockhash: BlockHash },
+    Unknown,
 }
 
-fn calc_balance((funding, spending): &(Vec<FundingOutput>, Vec<SpendingInput>)) -> i64 {
-    let funded: u64 = funding.iter().map(|output| output.value).sum();
-    let spent: u64 = spending.iter().map(|input| input.value).sum();
-    funded as i64 - spent as i64
+/// Sent to `Query::watch()` subscribers when a script hash status changes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StatusUpdate {
+    pub script_hash: ScriptHash,
+    pub status_hash: Option<StatusHash>,
 }
 
-pub struct HistoryItem {
-    height: i32,
-    tx_hash: Txid,
-    fee: Option<u64>, // need to be set only for unconfirmed transactions (i.e. height <= 0)
-}
-
-impl HistoryItem {
-    pub fn to_json(&self) -> Value {
-        let mut result = json!({ "height": self.height, "tx_hash": self.tx_hash.to_hex()});
-        self.fee.map(|f| {
-            result
-                .as_object_mut()
-                .unwrap()
-                .insert("fee".to_string(), json!(f))
-        });
-        result
-    }
-}
-
-impl Status {
-    fn funding(&self) -> impl Iterator<Item = &FundingOutput> {
-        self.confirmed.0.iter().chain(self.mempool.0.iter())
-    }
-
-    fn spending(&self) -> impl Iterator<Item = &SpendingInput> {
-        self.confirmed.1.iter().chain(self.mempool.1.iter())
-    }
-
-    pub fn confirmed_balance(&self) -> i64 {
-        calc_balance(&self.confirmed)
-    }
-
-    pub fn mempool_balance(&self) -> i64 {
-        calc_balance(&self.mempool)
-    }
-
-    pub fn history(&self) -> Vec<HistoryItem> {
-        let mut txns_map = HashMap::<Txid, i32>::new();
-        for f in self.funding() {
-            txns_map.insert(f.txn_id, f.height as i32);
-        }
-        for s in self.spending() {
-            txns_map.insert(s.txn_id, s.height as i32);
-        }
-        let mut items: Vec<HistoryItem> = txns_map
-            .into_iter()
-            .map(|item| HistoryItem {
-                height: item.1,
-                tx_hash: item.0,
-                fee: self.txn_fees.get(&item.0).cloned(),
-            })
-            .collect();
-        items.sort_unstable_by_key(|item| item.height);
-        items
-    }
-
-    pub fn unspent(&self) -> Vec<&FundingOutput> {
-        let mut outputs_map = HashMap::<OutPoint, &FundingOutput>::new();
-        for f in self.funding() {
-            outputs_map.insert((f.txn_id, f.output_index), f);
-        }
-        for s in self.spending() {
-            if outputs_map.remove(&s.funding_output).is_none() {
-                warn!("failed to remove {:?}", s.funding_output);
-            }
-        }
-        let mut outputs = outputs_map
-            .into_iter()
-            .map(|item| item.1) // a reference to unspent output
-            .collect::<Vec<&FundingOutput>>();
-        outputs.sort_unstable_by_key(|out| out.height);
-        outputs
-    }
-
-    pub fn hash(&self) -> Option<FullHash> {
-        let txns = self.history();
-        if txns.is_empty() {
-            None
-        } else {
-            let mut sha2 = Sha256::new();
-            for item in txns {
-                let part = format!("{}:{}:", item.tx_hash.to_hex(), item.height);
-                sha2.update(part.as_bytes());
-            }
-            Some(sha2.finalize().into())
-        }
-    }
+struct Watcher {
+    script_hash: ScriptHash,
+    status_hash: Option<StatusHash>,
+    sender: Sender<StatusUpdate>,
 }
 
 struct TxnHeight {
@@ -140,27 +57,9 @@ struct TxnHeight {
     height: u32,
 }
 
-fn merklize<T: Hash>(left: T, right: T) -> T {
-    let data = [&left[..], &right[..]].concat();
-    <T as Hash>::hash(&data)
-}
-
-fn create_merkle_branch_and_root<T: Hash>(mut hashes: Vec<T>, mut index: usize) -> (Vec<T>, T) {
-    let mut merkle = vec![];
-    while hashes.len() > 1 {
-        if hashes.len() % 2 != 0 {
-            let last = *hashes.last().unwrap();
-            hashes.push(last);
-        }
-        index = if index % 2 == 0 { index + 1 } else { index - 1 };
-        merkle.push(hashes[index]);
-        index /= 2;
-        hashes = hashes
-            .chunks(2)
-            .map(|pair| merklize(pair[0], pair[1]))
-            .collect()
-    }
-    (merkle, hashes[0])
+fn create_merkle_branch_and_root<T: Hash>(hashes: Vec<T>, index: usize) -> (Vec<T>, T) {
+    let tree = MerkleTree::new(hashes);
+    (tree.branch(index), tree.root())
 }
 
 // TODO: the functions below can be part of ReadStore.
@@ -198,11 +97,88 @@ fn txids_by_funding_output(
         .collect()
 }
 
+/// Blocks to fetch for dropping only the affected cached statuses (after more, they're all
+/// dropped).
+const MAX_CHANGED_BLOCKS: usize = 10;
+
+/// Returns the maximum fee rate (in BTC/kvB) to pass to bitcoind for a client's `requested` one,
+/// which may not exceed the server's `limit` (`0` meaning no limit, as for bitcoind).
+fn limit_fee_rate(requested: Option<f64>, limit: f64) -> Result<Option<f64>> {
+    match requested {
+        Some(rate) if !(rate >= 0.0 && rate.is_finite()) => bail!("invalid maxfeerate: {}", rate),
+        Some(rate) if limit == 0.0 || (rate != 0.0 && rate <= limit) => Ok(Some(rate)),
+        _ if limit == 0.0 => Ok(None),
+        _ => Ok(Some(limit)),
+    }
+}
+
+/// Aborts long-running status computations (e.g. for very popular addresses), so they don't
+/// occupy the Electrum server's threads indefinitely.
+struct Deadline(Option<Instant>);
+
+impl Deadline {
+    fn after(timeout: Option<Duration>) -> Self {
+        Deadline(timeout.map(|timeout| Instant::now() + timeout))
+    }
+
+    fn check(&self) -> Result<()> {
+        match self.0 {
+            Some(deadline) if Instant::now() > deadline => bail!(ErrorKind::Timeout),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Counts the transactions of a status' history (funding or spending), failing once they exceed
+/// `max_history` (unless it's 0) - like ElectrumX.
+struct HistoryLimit {
+    max_history: usize,
+    txids: HashSet<Txid>,
+}
+
+impl HistoryLimit {
+    fn new(max_history: usize) -> Self {
+        HistoryLimit {
+            max_history,
+            txids: HashSet::new(),
+        }
+    }
+
+    /// Fails before scanning `funding` more transactions, if they'd exceed the limit anyway.
+    fn check_funding(&self, funding: usize) -> Result<()> {
+        self.check(self.txids.len() + funding)
+    }
+
+    fn add(&mut self, txid: Txid) -> Result<()> {
+        if self.max_history > 0 {
+            self.txids.insert(txid);
+            self.check(self.txids.len())?;
+        }
+        Ok(())
+    }
+
+    fn check(&self, count: usize) -> Result<()> {
+        if self.max_history > 0 && count > self.max_history {
+            bail!(ErrorKind::HistoryTooLarge);
+        }
+        Ok(())
+    }
+}
+
 pub struct Query {
     app: Arc<App>,
     tracker: RwLock<Tracker>,
     tx_cache: TransactionCache,
+    block_cache: BlockCache,
+    merkle_cache: MerkleProofCache,
+    status_cache: StatusCache,
+    tweaks_cache: Option<TweaksCache>, // silent payments are disabled if None
     txid_limit: usize,
+    max_history: usize,
+    max_status_size: usize,
+    max_fee_rate: f64, // in BTC/kvB
+    request_timeout: Option<Duration>,
+    watchers: Mutex<Vec<Watcher>>,
     duration: HistogramVec,
 }
 
@@ -211,13 +187,31 @@ impl Query {
         app: Arc<App>,
         metrics: &Metrics,
         tx_cache: TransactionCache,
+        block_cache: BlockCache,
+        merkle_cache: MerkleProofCache,
+        status_cache: StatusCache,
+        tweaks_cache: Option<TweaksCache>,
+        mempool_filter: MempoolFilter,
         txid_limit: usize,
+        max_history: usize,
+        max_status_size: usize,
+        max_fee_rate: f64,
+        request_timeout: Option<Duration>,
     ) -> Arc<Query> {
         Arc::new(Query {
             app,
-            tracker: RwLock::new(Tracker::new(metrics)),
+            tracker: RwLock::new(Tracker::new(metrics, mempool_filter)),
             tx_cache,
+            block_cache,
+            merkle_cache,
+            status_cache,
+            tweaks_cache,
             txid_limit,
+            max_history,
+            max_status_size,
+            max_fee_rate,
+            request_timeout,
+            watchers: Mutex::new(vec![]),
             duration: metrics.histogram_vec(
                 HistogramOpts::new("electrs_query_duration", "Request duration (in seconds)"),
                 &["type"],
@@ -225,60 +219,84 @@ impl Query {
         })
     }
 
-    fn load_txns_by_prefix(
+    /// Loads the transactions matching `prefixes` one at a time (so a gigantic history's
+    /// transactions are not kept in memory together), passing each one to `func`.
+    fn for_each_txn_by_prefix<F>(
         &self,
         store: &dyn ReadStore,
         prefixes: Vec<HashPrefix>,
-    ) -> Result<Vec<TxnHeight>> {
-        let mut txns = vec![];
+        deadline: &Deadline,
+        mut func: F,
+    ) -> Result<()>
+    where
+        F: FnMut(TxnHeight) -> Result<()>,
+    {
         for txid_prefix in prefixes {
             for tx_row in txrows_by_prefix(store, txid_prefix) {
-                let txid: Txid = deserialize(&tx_row.key.txid).unwrap();
+                deadline.check()?;
+                let txid: Txid = deserialize(&tx_row.key.txid)
+                    .chain_err(|| format!("invalid txid {}", hex::encode(tx_row.key.txid)))?;
                 let txn = self.load_txn(&txid, Some(tx_row.height))?;
-                txns.push(TxnHeight {
+                func(TxnHeight {
                     txn,
                     height: tx_row.height,
-                })
+                })?;
             }
         }
-        Ok(txns)
+        Ok(())
+    }
+
+    fn check_status_size(&self, funding: usize, spending: usize) -> Result<()> {
+        let size =
+            funding * mem::size_of::<FundingOutput>() + spending * mem::size_of::<SpendingInput>();
+        if self.max_status_size > 0 && size > self.max_status_size {
+            bail!(ErrorKind::StatusTooLarge(self.max_status_size));
+        }
+        Ok(())
     }
 
     fn find_spending_input(
         &self,
         store: &dyn ReadStore,
         funding: &FundingOutput,
+        deadline: &Deadline,
     ) -> Result<Option<SpendingInput>> {
-        let spending_txns: Vec<TxnHeight> = self.load_txns_by_prefix(
+        let mut spending_inputs = vec![];
+        self.for_each_txn_by_prefix(
             store,
             txids_by_funding_output(store, &funding.txn_id, funding.output_index),
-        )?;
-        let mut spending_inputs = vec![];
-        for t in &spending_txns {
-            for input in t.txn.input.iter() {
-                if input.previous_output.txid == funding.txn_id
-                    && input.previous_output.vout == funding.output_index as u32
-                {
-                    spending_inputs.push(SpendingInput {
-                        txn_id: t.txn.txid(),
-                        height: t.height,
-                        funding_output: (funding.txn_id, funding.output_index),
-                        value: funding.value,
-                    })
+            deadline,
+            |t| {
+                for input in t.txn.input.iter() {
+                    if input.previous_output.txid == funding.txn_id
+                        && input.previous_output.vout == funding.output_index as u32
+                    {
+                        spending_inputs.push(SpendingInput {
+                            txn_id: t.txn.txid(),
+                            height: t.height,
+                            funding_output: (funding.txn_id, funding.output_index),
+                            value: funding.value,
+                        })
+                    }
                 }
-            }
+                Ok(())
+            },
+        )?;
+        if spending_inputs.len() > 1 {
+            bail!(
+                "{}:{} is spent by {} transactions",
+                funding.txn_id,
+                funding.output_index,
+                spending_inputs.len()
+            );
         }
-        assert!(spending_inputs.len() <= 1);
-        Ok(if spending_inputs.len() == 1 {
-            Some(spending_inputs.remove(0))
-        } else {
-            None
-        })
+        Ok(spending_inputs.pop())
     }
 
     fn find_funding_outputs(&self, t: &TxnHeight, script_hash: &[u8]) -> Vec<FundingOutput> {
         let mut result = vec![];
         let txn_id = t.txn.txid();
+        let is_coinbase = t.txn.is_coin_base();
         for (index, output) in t.txn.output.iter().enumerate() {
             if compute_script_hash(&output.script_pubkey[..]) == script_hash {
                 result.push(FundingOutput {
@@ -286,6 +304,7 @@ impl Query {
                     height: t.height,
                     output_index: index,
                     value: output.value,
+                    is_coinbase,
                 })
             }
         }
@@ -295,11 +314,14 @@ impl Query {
     fn confirmed_status(
         &self,
         script_hash: &[u8],
+        history: &mut HistoryLimit,
+        deadline: &Deadline,
     ) -> Result<(Vec<FundingOutput>, Vec<SpendingInput>)> {
         let mut funding = vec![];
         let mut spending = vec![];
         let read_store = self.app.read_store();
         let txid_prefixes = txids_by_script_hash(read_store, script_hash);
+        history.check_funding(txid_prefixes.len())?;
         // if the limit is enabled
         if self.txid_limit > 0 && txid_prefixes.len() > self.txid_limit {
             bail!(
@@ -307,12 +329,19 @@ impl Query {
                 txid_prefixes.len()
             );
         }
-        for t in self.load_txns_by_prefix(read_store, txid_prefixes)? {
-            funding.extend(self.find_funding_outputs(&t, script_hash));
-        }
+        self.for_each_txn_by_prefix(read_store, txid_prefixes, deadline, |t| {
+            let outputs = self.find_funding_outputs(&t, script_hash);
+            if let Some(output) = outputs.first() {
+                history.add(output.txn_id)?;
+            }
+            funding.extend(outputs);
+            self.check_status_size(funding.len(), 0)
+        })?;
         for funding_output in &funding {
-            if let Some(spent) = self.find_spending_input(read_store, funding_output)? {
+            if let Some(spent) = self.find_spending_input(read_store, funding_output, deadline)? {
+                history.add(spent.txn_id)?;
                 spending.push(spent);
+                self.check_status_size(funding.len(), spending.len())?;
             }
         }
         Ok((funding, spending))
@@ -321,31 +350,81 @@ impl Query {
     fn mempool_status(
         &self,
         script_hash: &[u8],
-        confirmed_funding: &[FundingOutput],
+        confirmed: &(Vec<FundingOutput>, Vec<SpendingInput>),
         tracker: &Tracker,
+        history: &mut HistoryLimit,
+        deadline: &Deadline,
     ) -> Result<(Vec<FundingOutput>, Vec<SpendingInput>)> {
         let mut funding = vec![];
         let mut spending = vec![];
         let txid_prefixes = txids_by_script_hash(tracker.index(), script_hash);
-        for t in self.load_txns_by_prefix(tracker.index(), txid_prefixes)? {
-            funding.extend(self.find_funding_outputs(&t, script_hash));
-        }
+        history.check_funding(txid_prefixes.len())?;
+        self.for_each_txn_by_prefix(tracker.index(), txid_prefixes, deadline, |t| {
+            let outputs = self.find_funding_outputs(&t, script_hash);
+            if let Some(output) = outputs.first() {
+                history.add(output.txn_id)?;
+            }
+            funding.extend(outputs);
+            self.check_status_size(
+                confirmed.0.len() + funding.len(),
+                confirmed.1.len() + spending.len(),
+            )
+        })?;
         // // TODO: dedup outputs (somehow) both confirmed and in mempool (e.g. reorg?)
-        for funding_output in funding.iter().chain(confirmed_funding.iter()) {
-            if let Some(spent) = self.find_spending_input(tracker.index(), funding_output)? {
+        for funding_output in funding.iter().chain(confirmed.0.iter()) {
+            if let Some(spent) =
+                self.find_spending_input(tracker.index(), funding_output, deadline)?
+            {
+                history.add(spent.txn_id)?;
                 spending.push(spent);
+                self.check_status_size(
+                    confirmed.0.len() + funding.len(),
+                    confirmed.1.len() + spending.len(),
+                )?;
             }
         }
         Ok((funding, spending))
     }
 
-    pub fn status(&self, script_hash: &[u8]) -> Result<Status> {
+    /// Returns the status of a script hash, shared with other callers until the index or the
+    /// mempool are updated.
+    pub fn status(&self, script_hash: &ScriptHash) -> Result<Arc<Status>> {
+        self.status_until(script_hash, &Deadline(None))
+    }
+
+    /// Returns the status of a script hash for an Electrum request, failing if computing it
+    /// takes longer than the request timeout.
+    pub fn request_status(&self, script_hash: &ScriptHash) -> Result<Arc<Status>> {
+        self.status_until(script_hash, &Deadline::after(self.request_timeout))
+    }
+
+    fn status_until(&self, script_hash: &ScriptHash, deadline: &Deadline) -> Result<Arc<Status>> {
+        if !self.app.index().is_watched(script_hash) {
+            bail!("{} is not watched by this server", script_hash);
+        }
+        self.status_cache
+            .get_or_else(script_hash, || self.compute_status(script_hash, deadline))
+    }
+
+    fn compute_status(&self, script_hash: &ScriptHash, deadline: &Deadline) -> Result<Status> {
+        // the index may be updated while scanning it, so the tip is read first
+        let tip = self
+            .app
+            .index()
+            .best_header()
+            .map(|header| (header.height(), *header.hash()));
+        // continue hashing the previous status' history, unless its last block was reorged
+        let hash_state = self.status_cache.hash_state(script_hash).filter(|state| {
+            let (height, blockhash) = state.block();
+            self.app.index().get_header(height).map(|h| *h.hash()) == Some(*blockhash)
+        });
         let timer = self
             .duration
             .with_label_values(&["confirmed_status"])
             .start_timer();
+        let mut history = HistoryLimit::new(self.max_history);
         let confirmed = self
-            .confirmed_status(script_hash)
+            .confirmed_status(script_hash.as_bytes(), &mut history, deadline)
             .chain_err(|| "failed to get confirmed status")?;
         timer.observe_duration();
 
@@ -355,7 +434,13 @@ impl Query {
             .with_label_values(&["mempool_status"])
             .start_timer();
         let mempool = self
-            .mempool_status(script_hash, &confirmed.0, &tracker)
+            .mempool_status(
+                script_hash.as_bytes(),
+                &confirmed,
+                &tracker,
+                &mut history,
+                deadline,
+            )
             .chain_err(|| "failed to get mempool status")?;
         timer.observe_duration();
 
@@ -368,11 +453,118 @@ impl Query {
                 .map(|fee| txn_fees.insert(mempool_txid, fee));
         }
 
-        Ok(Status {
-            confirmed,
-            mempool,
-            txn_fees,
-        })
+        Ok(Status::new(confirmed, mempool, txn_fees, tip, hash_state))
+    }
+
+    /// Returns a page of a status' history (see `Status::history_page()`), with the current
+    /// mempool fees.
+    pub fn get_history_page(
+        &self,
+        status: &Status,
+        token: &HistoryToken,
+        limit: usize,
+    ) -> HistoryPage {
+        let tracker = self.tracker.read().unwrap();
+        status.history_page(token, limit, |txid| tracker.get_fee(txid))
+    }
+
+    /// Computes the statuses of multiple script hashes using up to `threads` threads,
+    /// returning them in the same order.
+    pub fn status_batch(
+        query: &Arc<Query>,
+        script_hashes: &[ScriptHash],
+        threads: usize,
+    ) -> Result<Vec<Arc<Status>>> {
+        let threads = cmp::max(threads, 1);
+        let chunk_size = cmp::max((script_hashes.len() + threads - 1) / threads, 1);
+        let workers: Vec<_> = script_hashes
+            .chunks(chunk_size)
+            .map(|chunk| {
+                let query = Arc::clone(query);
+                let chunk = chunk.to_vec();
+                spawn_thread("status", move || {
+                    chunk
+                        .iter()
+                        .map(|script_hash| query.status(script_hash))
+                        .collect::<Result<Vec<Arc<Status>>>>()
+                })
+            })
+            .collect();
+        let mut statuses = Vec::with_capacity(script_hashes.len());
+        for worker in workers {
+            statuses.extend(worker.join().expect("status thread panicked")?);
+        }
+        Ok(statuses)
+    }
+
+    pub fn get_balance(&self, script_hash: &ScriptHash) -> Result<Balance> {
+        let tip_height = self.get_best_header()?.height() as u32;
+        Ok(self.status(script_hash)?.balance(tip_height))
+    }
+
+    /// Returns confirmed and mempool transactions for a script hash, sorted by height.
+    pub fn get_history(&self, script_hash: &ScriptHash) -> Result<Vec<HistoryEntry>> {
+        Ok(self.status(script_hash)?.history())
+    }
+
+    /// Subscribes to status changes of a script hash: the current status is sent immediately,
+    /// and an update is sent after each `notify_watchers()` call that changes it.
+    pub fn watch(&self, script_hash: &ScriptHash) -> Result<Receiver<StatusUpdate>> {
+        let script_hash = *script_hash;
+        let status_hash = self.status(&script_hash)?.statushash();
+        let (sender, receiver) = channel();
+        sender
+            .send(StatusUpdate {
+                script_hash,
+                status_hash,
+            })
+            .unwrap(); // the receiver is alive
+        self.watchers.lock().unwrap().push(Watcher {
+            script_hash,
+            status_hash,
+            sender,
+        });
+        Ok(receiver)
+    }
+
+    /// Sends status updates to `watch()` subscribers (should be called after index and mempool
+    /// updates), and drops the subscribers whose receivers are gone. A subscriber whose status
+    /// fails to be computed (e.g. a too long history) is skipped until the next call.
+    pub fn notify_watchers(&self) {
+        let mut watchers = self.watchers.lock().unwrap();
+        let mut disconnected = vec![];
+        for (i, watcher) in watchers.iter_mut().enumerate() {
+            let status_hash = match self.status(&watcher.script_hash) {
+                Ok(status) => status.statushash(),
+                Err(e) => {
+                    warn!(
+                        "failed to notify {}: {}",
+                        watcher.script_hash,
+                        e.display_chain()
+                    );
+                    continue;
+                }
+            };
+            if status_hash == watcher.status_hash {
+                continue;
+            }
+            watcher.status_hash = status_hash;
+            let update = StatusUpdate {
+                script_hash: watcher.script_hash,
+                status_hash,
+            };
+            if watcher.sender.send(update).is_err() {
+                disconnected.push(i);
+            }
+        }
+        for i in disconnected.into_iter().rev() {
+            watchers.swap_remove(i);
+        }
+    }
+
+    /// Returns confirmed and mempool unspent outputs for a script hash, sorted by height.
+    pub fn get_utxos(&self, script_hash: &ScriptHash) -> Result<Vec<Utxo>> {
+        Ok(self.status(script_hash)?.utxos())
     }
 
     fn lookup_confirmed_blockhash(
@@ -402,6 +594,61 @@ impl Query {
         Ok(blockhash)
     }
 
+    /// Returns the confirmation state of a transaction, using the mempool and the txid index.
+    pub fn get_tx_status(&self, txid: &Txid) -> Result<TxStatus> {
+        if self.tracker.read().unwrap().has_txn(txid) {
+            return Ok(TxStatus::Unconfirmed);
+        }
+        let height = match txrow_by_txid(self.app.read_store(), txid) {
+            Some(txrow) => txrow.height,
+            None => return Ok(TxStatus::Unknown),
+        };
+        let header = self
+            .app
+            .index()
+            .get_header(height as usize)
+            .chain_err(|| format!("missing header at height {}", height))?;
+        Ok(TxStatus::Confirmed {
+            height,
+            blockhash: *header.hash(),
+        })
+    }
+
+    /// Computes the fee of a transaction (confirmed or not), by loading its inputs' previous
+    /// transactions via the transactions' cache.
+    pub fn get_fee(&self, txid: &Txid) -> Result<Amount> {
+        if let Some(fee) = self.tracker.read().unwrap().get_fee(txid) {
+            return Ok(Amount::from_sat(fee));
+        }
+        let txn = self.load_txn(txid, None)?;
+        if txn.is_coin_base() {
+            return Ok(Amount::from_sat(0));
+        }
+        let mut input_value = 0;
+        for input in &txn.input {
+            let prevout = &input.previous_output;
+            let prev_txn = self.load_txn(&prevout.txid, None)?;
+            let output = prev_txn
+                .output
+                .get(prevout.vout as usize)
+                .chain_err(|| format!("missing prevout {}", prevout))?;
+            input_value += output.value;
+        }
+        let output_value: u64 = txn.output.iter().map(|output| output.value).sum();
+        let fee = input_value
+            .checked_sub(output_value)
+            .chain_err(|| format!("{} has negative fee", txid))?;
+        Ok(Amount::from_sat(fee))
+    }
+
+    /// Sets the missing fees of history entries (i.e. for confirmed transactions).
+    pub fn fill_fees(&self, entries: &mut [HistoryEntry]) -> Result<()> {
+        for entry in entries.iter_mut().filter(|entry| entry.fee.is_none()) {
+            entry.fee = Some(self.get_fee(&entry.txid)?);
+        }
+        Ok(())
+    }
+
     // Internal API for transaction retrieval
     fn load_txn(&self, txid: &Txid, block_height: Option<u32>) -> Result<Transaction> {
         let _timer = self.duration.with_label_values(&["load_txn"]).start_timer();
@@ -416,6 +663,17 @@ impl Query {
         })
     }
 
+    /// Fetches a block from bitcoind (via the blocks' cache).
+    pub fn get_block(&self, blockhash: &BlockHash) -> Result<Block> {
+        let _timer = self
+            .duration
+            .with_label_values(&["get_block"])
+            .start_timer();
+        self.block_cache.get_or_else(blockhash, || {
+            Ok(serialize(&self.app.daemon().getblock(blockhash)?))
+        })
+    }
+
     // Public API for transaction retrieval (for Electrum RPC)
     pub fn get_transaction(&self, tx_hash: &Txid, verbose: bool) -> Result<Value> {
         let _timer = self
@@ -428,6 +686,11 @@ impl Query {
             .gettransaction_raw(tx_hash, blockhash, verbose)
     }
 
+    /// Returns a deserialized transaction (confirmed or not), via the transactions' cache.
+    pub fn get_tx(&self, txid: &Txid) -> Result<Transaction> {
+        self.load_txn(txid, None)
+    }
+
     pub fn get_confirmed_blockhash(&self, tx_hash: &Txid) -> Result<Value> {
         let blockhash = self.lookup_confirmed_blockhash(tx_hash, None)?;
         Ok(json!({ "block_hash": blockhash }))
@@ -455,22 +718,70 @@ impl Query {
         tx_hash: &Txid,
         height: usize,
     ) -> Result<(Vec<TxMerkleNode>, usize)> {
+        let mut proofs = self.get_merkle_proofs(&[*tx_hash], height)?;
+        Ok(proofs.pop().unwrap())
+    }
+
+    /// Returns the merkle proofs of transactions confirmed in the same block, fetching its txids
+    /// and building its merkle tree at most once (only if some proofs are not cached).
+    pub fn get_merkle_proofs(&self, tx_hashes: &[Txid], height: usize) -> Result<Vec<MerkleProof>> {
         let header_entry = self
             .app
             .index()
             .get_header(height)
             .chain_err(|| format!("missing block #{}", height))?;
-        let txids = self.app.daemon().getblocktxids(header_entry.hash())?;
-        let pos = txids
+        let blockhash = header_entry.hash();
+        let mut block: Option<BlockMerkleTree> = None;
+        tx_hashes
             .iter()
-            .position(|txid| txid == tx_hash)
-            .chain_err(|| format!("missing txid {}", tx_hash))?;
-        let tx_nodes: Vec<TxMerkleNode> = txids
-            .into_iter()
-            .map(|txid| TxMerkleNode::from_inner(txid.into_inner()))
-            .collect();
-        let (branch, _root) = create_merkle_branch_and_root(tx_nodes, pos);
-        Ok((branch, pos))
+            .map(|tx_hash| {
+                self.merkle_cache.get_or_else(tx_hash, blockhash, || {
+                    if block.is_none() {
+                        block = Some(self.block_merkle_tree(height, blockhash)?);
+                    }
+                    let (txids, tree) = &**block.as_ref().unwrap();
+                    let pos = txids
+                        .iter()
+                        .position(|txid| txid == tx_hash)
+                        .chain_err(|| format!("missing txid {}", tx_hash))?;
+                    Ok((tree.branch(pos), pos))
+                })
+            })
+            .collect()
+    }
+
+    fn block_merkle_tree(&self, height: usize, blockhash: &BlockHash) -> Result<BlockMerkleTree> {
+        self.merkle_cache.tree_or_else(height, blockhash, || {
+            let txids = self.app.daemon().getblocktxids(blockhash)?;
+            let tx_nodes = txids
+                .iter()
+                .map(|txid| TxMerkleNode::from_inner(txid.into_inner()))
+                .collect();
+            let tree = MerkleTree::new(tx_nodes);
+            Ok(Arc::new((txids, tree)))
+        })
+    }
+
+    /// Returns the silent payments tweaks of the block at `height` (computed on demand from the
+    /// block's prevouts, see `silentpayments.rs`).
+    pub fn get_block_tweaks(&self, height: usize) -> Result<Arc<Vec<PublicKey>>> {
+        let tweaks_cache = self
+            .tweaks_cache
+            .as_ref()
+            .chain_err(|| "silent payments are disabled (see 'silent_payments' option)")?;
+        let _timer = self
+            .duration
+            .with_label_values(&["get_block_tweaks"])
+            .start_timer();
+        let header_entry = self
+            .app
+            .index()
+            .get_header(height)
+            .chain_err(|| format!("missing block #{}", height))?;
+        let blockhash = header_entry.hash();
+        tweaks_cache.get_or_else(blockhash, || {
+            silentpayments::block_tweaks(&self.app.daemon().getblockprevouts(blockhash)?)
+        })
     }
 
     pub fn get_header_merkle_proof(
@@ -517,34 +828,87 @@ impl Query {
             .get_header(height)
             .chain_err(|| format!("missing block #{}", height))?;
 
-        let txids = self.app.daemon().getblocktxids(header_entry.hash())?;
+        if !want_merkle {
+            let txids = self.app.daemon().getblocktxids(header_entry.hash())?;
+            let txid = *txids
+                .get(tx_pos)
+                .chain_err(|| format!("No tx in position #{} in block #{}", tx_pos, height))?;
+            return Ok((txid, vec![]));
+        }
+        let block = self.block_merkle_tree(height, header_entry.hash())?;
+        let (txids, tree) = &*block;
         let txid = *txids
             .get(tx_pos)
             .chain_err(|| format!("No tx in position #{} in block #{}", tx_pos, height))?;
+        Ok((txid, tree.branch(tx_pos)))
+    }
 
-        let tx_nodes = txids
-            .into_iter()
-            .map(|txid| TxMerkleNode::from_inner(txid.into_inner()))
-            .collect();
-
-        let branch = if want_merkle {
-            create_merkle_branch_and_root(tx_nodes, tx_pos).0
-        } else {
-            vec![]
-        };
-        Ok((txid, branch))
+    /// Broadcasts `txn`, rejecting it if its fee rate exceeds the client's `max_fee_rate` (in
+    /// BTC/kvB), which may not exceed the configured one.
+    pub fn broadcast(&self, txn: &Transaction, max_fee_rate: Option<f64>) -> Result<Txid> {
+        let max_fee_rate = limit_fee_rate(max_fee_rate, self.max_fee_rate)?;
+        self.app.daemon().broadcast(txn, max_fee_rate)
     }
 
-    pub fn broadcast(&self, txn: &Transaction) -> Result<Txid> {
-        self.app.daemon().broadcast(txn)
+    pub fn submit_package(&self, txns: &[Transaction]) -> Result<Value> {
+        self.app.daemon().submit_package(txns)
     }
 
+    /// Updates the mempool, and drops the cached statuses affected by the mempool changes and
+    /// by the newly indexed blocks (should be called after index updates).
     pub fn update_mempool(&self) -> Result<()> {
         let _timer = self
             .duration
             .with_label_values(&["update_mempool"])
             .start_timer();
-        self.tracker.write().unwrap().update(self.app.daemon())
+        let mut changes = self.tracker.write().unwrap().update(self.app.daemon())?;
+        let tip = self
+            .app
+            .index()
+            .best_header()
+            .map(|header| (header.height(), *header.hash()));
+        let selective = match (self.status_cache.tip(), tip) {
+            (Some(cached), Some(tip)) if cached != tip => self
+                .add_block_changes(cached, tip, &mut changes)
+                .unwrap_or_else(|e| {
+                    warn!("dropping all cached statuses: {}", e.display_chain());
+                    false
+                }),
+            (cached, tip) => cached == tip,
+        };
+        self.status_cache
+            .update(tip, if selective { Some(&changes) } else { None });
+        Ok(())
+    }
+
+    /// Adds the changes of the blocks indexed after `cached` (up to `tip`), returning `false` if
+    /// it was reorged or if there are too many new blocks (e.g. during initial sync).
+    /// Also returns `false` if there are no cached statuses, so the blocks are not fetched.
+    fn add_block_changes(
+        &self,
+        cached: (usize, BlockHash),
+        tip: (usize, BlockHash),
+        changes: &mut StatusChanges,
+    ) -> Result<bool> {
+        if self.status_cache.is_empty() {
+            return Ok(false); // nothing to drop selectively
+        }
+        let (cached_height, cached_hash) = cached;
+        let index = self.app.index();
+        let reorged =
+            index.get_header(cached_height).map(|header| *header.hash()) != Some(cached_hash);
+        if reorged || tip.0 <= cached_height || tip.0 - cached_height > MAX_CHANGED_BLOCKS {
+            return Ok(false);
+        }
+        for height in cached_height + 1..=tip.0 {
+            let header = index
+                .get_header(height)
+                .chain_err(|| format!("missing header at {}", height))?;
+            for txn in &self.get_block(header.hash())?.txdata {
+                changes.add_transaction(txn);
+            }
+        }
+        Ok(true)
     }
 
     /// Returns [vsize, fee_rate] pairs (measured in vbytes and satoshis).
@@ -567,11 +931,60 @@ impl Query {
         (last_fee_rate as f64) * 1e-5 // [BTC/kB] = 10^5 [sat/B]
     }
 
+    pub fn check_daemon_synced(&self) -> Result<()> {
+        self.app.check_daemon_synced()
+    }
+
     pub fn get_banner(&self) -> Result<String> {
         self.app.get_banner()
     }
 
+    pub fn db_properties(&self) -> Vec<(&'static str, String)> {
+        self.app.db_properties()
+    }
+
     pub fn get_relayfee(&self) -> Result<f64> {
         self.app.daemon().get_relayfee()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limit_fee_rate() {
+        assert_eq!(limit_fee_rate(None, 0.1).unwrap(), Some(0.1));
+        assert_eq!(limit_fee_rate(Some(0.01), 0.1).unwrap(), Some(0.01));
+        assert_eq!(limit_fee_rate(Some(1.0), 0.1).unwrap(), Some(0.1));
+        assert_eq!(limit_fee_rate(Some(0.0), 0.1).unwrap(), Some(0.1));
+        assert_eq!(limit_fee_rate(None, 0.0).unwrap(), None);
+        assert_eq!(limit_fee_rate(Some(0.0), 0.0).unwrap(), Some(0.0));
+        assert_eq!(limit_fee_rate(Some(1.0), 0.0).unwrap(), Some(1.0));
+        assert!(limit_fee_rate(Some(-1.0), 0.1).is_err());
+    }
+
+    #[test]
+    fn test_history_limit() {
+        let txid = |i: u8| Txid::hash(&[i]);
+        let mut history = HistoryLimit::new(3);
+        history.check_funding(3).unwrap();
+        assert!(history.check_funding(4).is_err());
+        history.add(txid(1)).unwrap(); // funding
+        history.add(txid(1)).unwrap(); // another output of the same transaction
+        history.add(txid(2)).unwrap(); // spending
+        history.check_funding(1).unwrap();
+        assert!(history.check_funding(2).is_err());
+        history.add(txid(3)).unwrap();
+        match history.add(txid(4)) {
+            Err(Error(ErrorKind::HistoryTooLarge, _)) => (),
+            result => panic!("unexpected {:?}", result),
+        }
+
+        let mut unlimited = HistoryLimit::new(0);
+        unlimited.check_funding(1_000_000).unwrap();
+        for i in 0..10 {
+            unlimited.add(txid(i)).unwrap();
+        }
+    }
+}
@@ -0,0 +1,323 @@
+//! Regtest harness for end-to-end tests (enabled by the `test-utils` feature).
+//!
+//! Spawns `bitcoind -regtest` (with a temporary data directory) and an `electrs` process
+//! connected to it, and provides a minimal Electrum client - so reorgs, mempool churn and
+//! subscriptions can be tested deterministically. The processes are killed when dropped.
+//!
+//! ```no_run
+//! # use electrs::regtest::{Bitcoind, Electrs};
+//! # use std::path::Path;
+//! let node = Bitcoind::start(Path::new("bitcoind")).unwrap();
+//! node.mine(101).unwrap();
+//! let electrs = Electrs::start(Path::new("target/debug/electrs"), &node).unwrap();
+//! let mut client = electrs.connect().unwrap();
+//! let tip = client.call("blockchain.headers.subscribe", serde_json::json!([])).unwrap();
+//! assert_eq!(tip["height"], 101);
+//! ```
+
+use bitcoin::hash_types::{BlockHash, Txid};
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::errors::*;
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(60);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+static NEXT_DIR_ID: AtomicUsize = AtomicUsize::new(0);
+
+fn temp_dir(name: &str) -> Result<PathBuf> {
+    let id = NEXT_DIR_ID.fetch_add(1, Ordering::SeqCst);
+    let path = std::env::temp_dir().join(format!(
+        "electrs-regtest-{}-{}-{}",
+        std::process::id(),
+        name,
+        id
+    ));
+    fs::create_dir_all(&path).chain_err(|| format!("failed to create {:?}", path))?;
+    Ok(path)
+}
+
+fn free_addr() -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").chain_err(|| "failed to bind free port")?;
+    listener
+        .local_addr()
+        .chain_err(|| "failed to get local address")
+}
+
+/// Polls `f` until it returns `Some`, or fails after `timeout`.
+fn wait_for<T, F>(what: &str, timeout: Duration, mut f: F) -> Result<T>
+where
+    F: FnMut() -> Option<T>,
+{
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(value) = f() {
+            return Ok(value);
+        }
+        if Instant::now() > deadline {
+            bail!("timeout waiting for {}", what);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+struct Process {
+    child: Child,
+    dir: PathBuf,
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// A `bitcoind -regtest` instance, with a default wallet for mining and funding addresses.
+pub struct Bitcoind {
+    process: Process,
+    rpc_addr: SocketAddr,
+}
+
+impl Bitcoind {
+    /// Starts `bitcoind` (the executable at `exe`), and waits for its JSONRPC to be available.
+    pub fn start(exe: &Path) -> Result<Bitcoind> {
+        let dir = temp_dir("bitcoind")?;
+        let rpc_addr = free_addr()?;
+        let child = Command::new(exe)
+            .arg("-regtest")
+            .arg(format!("-datadir={}", dir.display()))
+            .arg(format!("-rpcport={}", rpc_addr.port()))
+            .arg("-listen=0")
+            .arg("-fallbackfee=0.0001")
+            .stdout(Stdio::null())
+            .spawn()
+            .chain_err(|| format!("failed to start {:?}", exe))?;
+        let node = Bitcoind {
+            process: Process { child, dir },
+            rpc_addr,
+        };
+        wait_for("bitcoind JSONRPC", STARTUP_TIMEOUT, || {
+            node.rpc("getblockchaininfo", json!([])).ok()
+        })?;
+        node.rpc("createwallet", json!(["default"]))?;
+        Ok(node)
+    }
+
+    /// bitcoind's data directory (i.e. electrs' `daemon_dir`).
+    pub fn datadir(&self) -> &Path {
+        &self.process.dir
+    }
+
+    pub fn rpc_addr(&self) -> SocketAddr {
+        self.rpc_addr
+    }
+
+    fn auth(&self) -> Result<String> {
+        let path = self.process.dir.join("regtest").join(".cookie");
+        let cookie = fs::read(&path).chain_err(|| format!("failed to read {:?}", path))?;
+        Ok(base64::encode(&cookie))
+    }
+
+    /// Sends a JSONRPC request (using a new connection), returning its result.
+    pub fn rpc(&self, method: &str, params: Value) -> Result<Value> {
+        let body = json!({"method": method, "params": params, "id": 0}).to_string();
+        let request = format!(
+            "POST / HTTP/1.0\r\nAuthorization: Basic {}\r\nContent-Length: {}\r\n\r\n{}",
+            self.auth()?,
+            body.len(),
+            body
+        );
+        let mut stream =
+            TcpStream::connect(self.rpc_addr).chain_err(|| "failed to connect bitcoind")?;
+        stream
+            .write_all(request.as_bytes())
+            .chain_err(|| "failed to send request")?;
+        let mut response = String::new();
+        stream
+            .read_to_string(&mut response)
+            .chain_err(|| "failed to read response")?;
+        let body = response
+            .splitn(2, "\r\n\r\n")
+            .nth(1)
+            .chain_err(|| format!("invalid response: {:?}", response))?;
+        let mut reply: Value =
+            serde_json::from_str(body).chain_err(|| format!("invalid JSON: {:?}", body))?;
+        if !reply["error"].is_null() {
+            bail!("{} failed: {}", method, reply["error"]);
+        }
+        Ok(reply["result"].take())
+    }
+
+    /// Returns a new address of the default wallet.
+    pub fn new_address(&self) -> Result<String> {
+        let address = self.rpc("getnewaddress", json!([]))?;
+        Ok(address
+            .as_str()
+            .chain_err(|| "non-string address")?
+            .to_owned())
+    }
+
+    /// Mines `count` blocks (paying to the default wallet).
+    pub fn mine(&self, count: usize) -> Result<Vec<BlockHash>> {
+        let address = self.new_address()?;
+        let hashes = self.rpc("generatetoaddress", json!([count, address]))?;
+        hashes
+            .as_array()
+            .chain_err(|| "non-array block hashes")?
+            .iter()
+            .map(|hash| {
+                let hash = hash.as_str().chain_err(|| "non-string block hash")?;
+                BlockHash::from_hex(hash).chain_err(|| "invalid block hash")
+            })
+            .collect()
+    }
+
+    /// Sends `amount` (in BTC) from the default wallet, into the mempool.
+    pub fn send(&self, address: &str, amount: f64) -> Result<Txid> {
+        let txid = self.rpc("sendtoaddress", json!([address, amount]))?;
+        Txid::from_hex(txid.as_str().chain_err(|| "non-string txid")?).chain_err(|| "invalid txid")
+    }
+
+    /// Disconnects `hash` and its descendants (e.g. for reorg tests, followed by `mine()`).
+    pub fn invalidate(&self, hash: &BlockHash) -> Result<()> {
+        self.rpc("invalidateblock", json!([hash.to_hex()]))?;
+        Ok(())
+    }
+
+    pub fn height(&self) -> Result<usize> {
+        let height = self.rpc("getblockcount", json!([]))?;
+        Ok(height.as_u64().chain_err(|| "non-integer height")? as usize)
+    }
+}
+
+/// An `electrs` process, indexing a regtest `Bitcoind`.
+pub struct Electrs {
+    _process: Process,
+    electrum_addr: SocketAddr,
+}
+
+impl Electrs {
+    /// Starts `electrs` (the executable at `exe`), and waits until its Electrum RPC is available
+    /// (i.e. after the initial indexing).
+    pub fn start(exe: &Path, node: &Bitcoind) -> Result<Electrs> {
+        let dir = temp_dir("electrs")?;
+        let electrum_addr = free_addr()?;
+        let monitoring_addr = free_addr()?;
+        let child = Command::new(exe)
+            .arg("--network=regtest")
+            .arg(format!("--db-dir={}", dir.display()))
+            .arg(format!("--daemon-dir={}", node.datadir().display()))
+            .arg(format!("--daemon-rpc-addr={}", node.rpc_addr()))
+            .arg(format!("--electrum-rpc-addr={}", electrum_addr))
+            .arg(format!("--monitoring-addr={}", monitoring_addr))
+            .arg("--jsonrpc-import")
+            .arg("--wait-duration-secs=1")
+            .arg("-vv")
+            .stdout(Stdio::null())
+            .spawn()
+            .chain_err(|| format!("failed to start {:?}", exe))?;
+        let electrs = Electrs {
+            _process: Process { child, dir },
+            electrum_addr,
+        };
+        wait_for("electrs RPC", STARTUP_TIMEOUT, || {
+            TcpStream::connect(electrum_addr).ok()
+        })?;
+        Ok(electrs)
+    }
+
+    pub fn connect(&self) -> Result<Client> {
+        Client::connect(self.electrum_addr)
+    }
+
+    /// Waits until the index reaches the given height.
+    pub fn wait_for_height(&self, height: usize) -> Result<()> {
+        let mut client = self.connect()?;
+        wait_for("index height", STARTUP_TIMEOUT, || {
+            let tip = client
+                .call("blockchain.headers.subscribe", json!([]))
+                .ok()?;
+            if tip["height"].as_u64()? as usize >= height {
+                Some(())
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// A minimal Electrum client, queueing notifications received while waiting for responses.
+pub struct Client {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    next_id: u64,
+    notifications: VecDeque<Value>,
+}
+
+impl Client {
+    pub fn connect(addr: SocketAddr) -> Result<Client> {
+        let writer = TcpStream::connect(addr).chain_err(|| "failed to connect electrs")?;
+        writer
+            .set_read_timeout(Some(STARTUP_TIMEOUT))
+            .chain_err(|| "failed to set read timeout")?;
+        let reader = BufReader::new(writer.try_clone().chain_err(|| "failed to clone")?);
+        Ok(Client {
+            reader,
+            writer,
+            next_id: 0,
+            notifications: VecDeque::new(),
+        })
+    }
+
+    fn recv(&mut self) -> Result<Value> {
+        let mut line = String::new();
+        self.reader
+            .read_line(&mut line)
+            .chain_err(|| "failed to read message")?;
+        if line.is_empty() {
+            bail!("connection closed");
+        }
+        serde_json::from_str(&line).chain_err(|| format!("invalid JSON: {:?}", line))
+    }
+
+    /// Sends a request and waits for its result.
+    pub fn call(&mut self, method: &str, params: Value) -> Result<Value> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let request = json!({"jsonrpc": "2.0", "method": method, "params": params, "id": id});
+        self.writer
+            .write_all(format!("{}\n", request).as_bytes())
+            .chain_err(|| "failed to send request")?;
+        loop {
+            let mut msg = self.recv()?;
+            if msg["id"] != id {
+                self.notifications.push_back(msg);
+                continue;
+            }
+            if !msg["error"].is_null() {
+                bail!("{} failed: {}", method, msg["error"]);
+            }
+            return Ok(msg["result"].take());
+        }
+    }
+
+    /// Returns the next notification (e.g. `blockchain.scripthash.subscribe`).
+    pub fn notification(&mut self) -> Result<Value> {
+        match self.notifications.pop_front() {
+            Some(msg) => Ok(msg),
+            None => self.recv(),
+        }
+    }
+}
@@ -0,0 +1,481 @@
+//! Streams the index updates of a primary server to hot-standby servers, which can take over
+//! serving (with an up-to-date index) as soon as the primary fails.
+//!
+//! A standby writes the primary's batches into its own DB. The index rows don't depend on the
+//! server that computed them, so they may be written again (e.g. when the standby catches up
+//! with its own bitcoind after missing some updates). Both sides prove their knowledge of a
+//! shared secret, and each frame is authenticated (but not encrypted, since the index only
+//! contains public blockchain data).
+
+use bitcoin::hashes::{hmac, sha256, Hash, HashEngine};
+use error_chain::ChainedError;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{sync_channel, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::app::App;
+use crate::errors::*;
+use crate::metrics::{Gauge, MetricOpts, Metrics};
+use crate::signal::Waiter;
+use crate::store::{Row, RowBatch, WriteStore};
+use crate::systemd;
+use crate::util::spawn_thread;
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// a slower standby is disconnected (and catches up with its bitcoind after reconnecting)
+const MAX_PENDING_BATCHES: usize = 100;
+const MAX_FRAME_SIZE: usize = 1 << 30;
+
+const HEARTBEAT: u8 = 0;
+const BATCH: u8 = 1;
+
+type Mac = hmac::Hmac<sha256::Hash>;
+
+/// Each standby's queue of encoded batches.
+type Standbys = Mutex<Vec<SyncSender<Arc<Vec<u8>>>>>;
+
+fn mac(key: &[u8], parts: &[&[u8]]) -> Mac {
+    let mut engine = hmac::HmacEngine::<sha256::Hash>::new(key);
+    for part in parts {
+        engine.input(part);
+    }
+    Mac::from_engine(engine)
+}
+
+/// Compares MACs in constant time.
+fn mac_eq(a: &[u8], b: &Mac) -> bool {
+    let b = &b[..];
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn random_nonce() -> Result<[u8; 32]> {
+    let mut nonce = [0u8; 32];
+    File::open("/dev/urandom")
+        .and_then(|mut file| file.read_exact(&mut nonce))
+        .chain_err(|| "failed to read /dev/urandom")?;
+    Ok(nonce)
+}
+
+/// Encodes the rows as (key length, key, value length, value) tuples.
+fn encode_batch(rows: &RowBatch) -> Vec<u8> {
+    let mut data = vec![];
+    for (key, value) in rows.iter() {
+        data.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        data.extend_from_slice(key);
+        data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        data.extend_from_slice(value);
+    }
+    data
+}
+
+fn decode_batch(mut data: &[u8], rows: &mut RowBatch) -> Result<()> {
+    fn take<'a>(data: &mut &'a [u8]) -> Result<&'a [u8]> {
+        if data.len() < 4 {
+            bail!("truncated replicated row");
+        }
+        let len = u32::from_le_bytes(data[..4].try_into().unwrap()) as usize;
+        if data.len() < 4 + len {
+            bail!("truncated replicated row");
+        }
+        let item = &data[4..4 + len];
+        *data = &data[4 + len..];
+        Ok(item)
+    }
+    rows.clear();
+    while !data.is_empty() {
+        let key = take(&mut data)?;
+        let value = take(&mut data)?;
+        rows.push(key, value);
+    }
+    Ok(())
+}
+
+/// An authenticated replication connection.
+struct Session {
+    key: Mac,
+    seq: u64, // of the next frame, so frames can't be replayed or reordered
+}
+
+impl Session {
+    /// Derives the session key from both sides' nonces (so frames can't be replayed into another
+    /// session), returning None if the peer doesn't know the secret.
+    fn handshake(stream: &mut TcpStream, secret: &[u8], primary: bool) -> Result<Option<Session>> {
+        let nonce = random_nonce()?;
+        stream
+            .write_all(&nonce)
+            .chain_err(|| "failed to send nonce")?;
+        let mut peer_nonce = [0u8; 32];
+        stream
+            .read_exact(&mut peer_nonce)
+            .chain_err(|| "failed to receive nonce")?;
+        let (primary_nonce, standby_nonce) = if primary {
+            (nonce, peer_nonce)
+        } else {
+            (peer_nonce, nonce)
+        };
+        let key = mac(
+            secret,
+            &[b"electrs replication", &primary_nonce, &standby_nonce],
+        );
+        let (own_role, peer_role): (&[u8], &[u8]) = if primary {
+            (b"primary", b"standby")
+        } else {
+            (b"standby", b"primary")
+        };
+        stream
+            .write_all(&mac(&key[..], &[own_role])[..])
+            .chain_err(|| "failed to send proof")?;
+        let mut proof = [0u8; 32];
+        stream
+            .read_exact(&mut proof)
+            .chain_err(|| "failed to receive proof")?;
+        if !mac_eq(&proof, &mac(&key[..], &[peer_role])) {
+            return Ok(None);
+        }
+        Ok(Some(Session { key, seq: 0 }))
+    }
+
+    fn frame_mac(&mut self, kind: u8, payload: &[u8]) -> Mac {
+        let tag = mac(&self.key[..], &[&self.seq.to_le_bytes(), &[kind], payload]);
+        self.seq += 1;
+        tag
+    }
+
+    fn write_frame(&mut self, stream: &mut impl Write, kind: u8, payload: &[u8]) -> Result<()> {
+        let tag = self.frame_mac(kind, payload);
+        let mut write = || -> io::Result<()> {
+            stream.write_all(&[kind])?;
+            stream.write_all(&(payload.len() as u32).to_le_bytes())?;
+            stream.write_all(payload)?;
+            stream.write_all(&tag[..])?;
+            stream.flush()
+        };
+        write().chain_err(|| "failed to send frame")
+    }
+
+    /// Reads the next frame's payload, returning its type.
+    fn read_frame(&mut self, stream: &mut impl Read, payload: &mut Vec<u8>) -> Result<u8> {
+        let mut header = [0u8; 5];
+        stream
+            .read_exact(&mut header)
+            .chain_err(|| "failed to receive frame")?;
+        let kind = header[0];
+        let len = u32::from_le_bytes(header[1..].try_into().unwrap()) as usize;
+        if len > MAX_FRAME_SIZE {
+            bail!("replication frame too large: {} bytes", len);
+        }
+        payload.resize(len, 0);
+        let mut tag = [0u8; 32];
+        stream
+            .read_exact(payload)
+            .and_then(|()| stream.read_exact(&mut tag))
+            .chain_err(|| "failed to receive frame")?;
+        if !mac_eq(&tag, &self.frame_mac(kind, payload)) {
+            bail!("invalid replication frame MAC");
+        }
+        Ok(kind)
+    }
+}
+
+/// Streams the index updates to the connected standbys (see `replication_addr`).
+pub struct Publisher {
+    standbys: Arc<Standbys>,
+}
+
+impl Publisher {
+    pub fn start(addr: SocketAddr, secret: Vec<u8>, metrics: &Metrics) -> Result<Publisher> {
+        let listener = TcpListener::bind(addr).chain_err(|| format!("bind({}) failed", addr))?;
+        info!("replicating index updates at {}", addr);
+        let standbys = Arc::new(Mutex::new(vec![]));
+        let connected = metrics.gauge_int(MetricOpts::new(
+            "electrs_replication_standbys",
+            "# of connected hot-standby servers",
+        ));
+        let secret = Arc::new(secret);
+        let acceptor_standbys = Arc::clone(&standbys);
+        spawn_thread("replication", move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("failed to accept standby: {}", e);
+                        continue;
+                    }
+                };
+                let (secret, standbys, connected) = (
+                    Arc::clone(&secret),
+                    Arc::clone(&acceptor_standbys),
+                    connected.clone(),
+                );
+                spawn_thread("standby", move || {
+                    if let Err(e) = serve_standby(stream, &secret, &standbys, &connected) {
+                        warn!("standby failed: {}", e.display_chain());
+                    }
+                });
+            }
+        });
+        Ok(Publisher { standbys })
+    }
+
+    /// Sends the rows to the standbys, disconnecting the ones too slow to receive them.
+    pub fn publish(&self, rows: &RowBatch) {
+        let mut standbys = self.standbys.lock().unwrap();
+        if standbys.is_empty() {
+            return;
+        }
+        let data = Arc::new(encode_batch(rows));
+        standbys.retain(|sender| match sender.try_send(Arc::clone(&data)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                warn!(
+                    "disconnecting standby, lagging by {} batches",
+                    MAX_PENDING_BATCHES
+                );
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+
+    /// Returns a store writing to `store`, which publishes the written rows.
+    pub fn store<'a, S: WriteStore>(&'a self, store: &'a S) -> PublishingStore<'a, S> {
+        PublishingStore {
+            store,
+            publisher: self,
+        }
+    }
+}
+
+fn serve_standby(
+    mut stream: TcpStream,
+    secret: &[u8],
+    standbys: &Standbys,
+    connected: &Gauge,
+) -> Result<()> {
+    let addr = stream.peer_addr().chain_err(|| "standby disconnected")?;
+    stream
+        .set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+        .and_then(|()| stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT)))
+        .chain_err(|| "failed to set timeouts")?;
+    let mut session = match Session::handshake(&mut stream, secret, /*primary=*/ true)? {
+        Some(session) => session,
+        None => bail!("standby {} doesn't know the replication secret", addr),
+    };
+    let (sender, receiver) = sync_channel(MAX_PENDING_BATCHES);
+    standbys.lock().unwrap().push(sender);
+    info!("standby {} connected", addr);
+    connected.inc();
+    let mut stream = BufWriter::new(stream);
+    let heartbeat = Arc::new(vec![]);
+    let result = loop {
+        let (kind, payload) = match receiver.recv_timeout(HEARTBEAT_INTERVAL) {
+            Ok(data) => (BATCH, data),
+            Err(RecvTimeoutError::Timeout) => (HEARTBEAT, Arc::clone(&heartbeat)),
+            Err(RecvTimeoutError::Disconnected) => break Ok(()), // disconnected by `publish()`
+        };
+        if let Err(e) = session.write_frame(&mut stream, kind, &payload) {
+            break Err(e).chain_err(|| format!("standby {} disconnected", addr));
+        }
+    };
+    connected.dec();
+    result
+}
+
+/// Writes to a store, publishing the written rows to the standbys.
+pub struct PublishingStore<'a, S> {
+    store: &'a S,
+    publisher: &'a Publisher,
+}
+
+impl<'a, S: WriteStore> WriteStore for PublishingStore<'a, S> {
+    fn write<I: IntoIterator<Item = Row>>(&self, rows: I) {
+        let mut batch = RowBatch::new();
+        batch.extend(rows);
+        self.write_batch(&batch);
+    }
+
+    fn write_batch(&self, rows: &RowBatch) {
+        self.store.write_batch(rows); // so a standby is never ahead of its primary
+        self.publisher.publish(rows);
+    }
+
+    fn flush(&self) {
+        self.store.flush();
+    }
+}
+
+/// A standby's connection to its primary.
+struct Subscriber {
+    stream: BufReader<TcpStream>,
+    session: Session,
+    payload: Vec<u8>,
+}
+
+impl Subscriber {
+    /// Connects to the primary, returning None if it doesn't know the secret.
+    fn connect(addr: SocketAddr, secret: &[u8], timeout: Duration) -> Result<Option<Subscriber>> {
+        let mut stream = TcpStream::connect_timeout(&addr, timeout)
+            .chain_err(|| format!("failed to connect to {}", addr))?;
+        // heartbeats are expected meanwhile
+        stream
+            .set_read_timeout(Some(timeout))
+            .and_then(|()| stream.set_write_timeout(Some(timeout)))
+            .chain_err(|| "failed to set timeouts")?;
+        let session = match Session::handshake(&mut stream, secret, /*primary=*/ false)? {
+            Some(session) => session,
+            None => return Ok(None),
+        };
+        Ok(Some(Subscriber {
+            stream: BufReader::new(stream),
+            session,
+            payload: vec![],
+        }))
+    }
+
+    /// Receives the next batch into `rows`, returning false for heartbeats.
+    fn recv(&mut self, rows: &mut RowBatch) -> Result<bool> {
+        match self
+            .session
+            .read_frame(&mut self.stream, &mut self.payload)?
+        {
+            HEARTBEAT => Ok(false),
+            BATCH => {
+                decode_batch(&self.payload, rows)?;
+                Ok(true)
+            }
+            kind => bail!("unknown replication frame type {}", kind),
+        }
+    }
+}
+
+/// Syncs the index with bitcoind, failing only on interrupts (e.g. if bitcoind is unavailable,
+/// the primary's updates are applied once they connect to the index).
+fn catch_up(app: &App, signal: &Waiter) -> Result<()> {
+    if let Err(e) = app.update(signal) {
+        if let ErrorKind::Interrupt(_) = e.kind() {
+            return Err(e);
+        }
+        warn!("failed to sync with bitcoind: {}", e.display_chain());
+    }
+    Ok(())
+}
+
+fn apply(app: &App, rows: &RowBatch, signal: &Waiter) -> Result<()> {
+    if app.apply_replicated(rows)? {
+        return Ok(());
+    }
+    // e.g. the primary indexed some blocks while this standby was disconnected
+    info!("catching up with bitcoind, before applying the primary's update");
+    catch_up(app, signal)?;
+    if !app.apply_replicated(rows)? {
+        warn!("skipping the primary's update, since it doesn't connect to the index");
+    }
+    Ok(())
+}
+
+/// Applies the index updates of the primary at `addr` until it fails (i.e. it can't be reached,
+/// or doesn't send anything for `timeout`), so this standby can take over serving.
+pub fn follow(
+    app: &App,
+    addr: SocketAddr,
+    secret: &[u8],
+    timeout: Duration,
+    signal: &Waiter,
+) -> Result<()> {
+    let mut rows = RowBatch::new();
+    let mut last_contact = Instant::now();
+    loop {
+        match Subscriber::connect(addr, secret, timeout) {
+            Ok(Some(mut subscriber)) => {
+                info!("following primary at {}", addr);
+                catch_up(app, signal)?; // the primary only sends its new updates
+                loop {
+                    signal.poll()?;
+                    match subscriber.recv(&mut rows) {
+                        Ok(is_batch) => {
+                            last_contact = Instant::now();
+                            systemd::notify_watchdog();
+                            if is_batch {
+                                apply(app, &rows, signal)?;
+                            }
+                        }
+                        Err(e) => {
+                            warn!("lost primary at {}: {}", addr, e.display_chain());
+                            break;
+                        }
+                    }
+                }
+            }
+            Ok(None) => bail!(
+                "primary at {} doesn't know the replication secret (hint: check replication_secret_file)",
+                addr
+            ),
+            Err(e) => warn!("primary at {} is unavailable: {}", addr, e.display_chain()),
+        }
+        if last_contact.elapsed() >= timeout {
+            warn!("primary at {} failed, taking over", addr);
+            return Ok(());
+        }
+        signal.wait(HEARTBEAT_INTERVAL)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_batch() {
+        let mut rows = RowBatch::new();
+        rows.push(b"key", b"value");
+        rows.push(b"L", b"");
+        let data = encode_batch(&rows);
+        let mut decoded = RowBatch::new();
+        decode_batch(&data, &mut decoded).unwrap();
+        assert_eq!(
+            decoded.iter().collect::<Vec<_>>(),
+            rows.iter().collect::<Vec<_>>()
+        );
+        assert!(decode_batch(&data[..data.len() - 1], &mut decoded).is_err());
+    }
+
+    fn connect(primary_secret: &'static [u8], standby_secret: &[u8]) -> Option<Subscriber> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let primary = spawn_thread("primary", move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut session = Session::handshake(&mut stream, primary_secret, true).unwrap()?;
+            session.write_frame(&mut stream, HEARTBEAT, b"").unwrap();
+            let mut rows = RowBatch::new();
+            rows.push(b"key", b"value");
+            session
+                .write_frame(&mut stream, BATCH, &encode_batch(&rows))
+                .unwrap();
+            Some(())
+        });
+        let timeout = Duration::from_secs(10);
+        let subscriber = Subscriber::connect(addr, standby_secret, timeout).unwrap();
+        assert_eq!(primary.join().unwrap().is_some(), subscriber.is_some());
+        subscriber
+    }
+
+    #[test]
+    fn test_session() {
+        assert!(connect(b"secret", b"other").is_none());
+
+        let mut subscriber = connect(b"secret", b"secret").unwrap();
+        let mut rows = RowBatch::new();
+        assert!(!subscriber.recv(&mut rows).unwrap()); // heartbeat
+        assert!(subscriber.recv(&mut rows).unwrap());
+        assert_eq!(
+            rows.iter().collect::<Vec<_>>(),
+            vec![(&b"key"[..], &b"value"[..])]
+        );
+        assert!(subscriber.recv(&mut rows).is_err()); // the primary disconnected
+    }
+}
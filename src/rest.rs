@@ -0,0 +1,544 @@
+//! Esplora-compatible HTTP REST API (a subset of it), for clients that don't speak the Electrum
+//! protocol (e.g. BDK's `esplora` blockchain backend).
+//!
+//! Supported endpoints:
+//! `GET /blocks/tip/height`, `GET /blocks/tip/hash`,
+//! `GET /tx/:txid`, `GET /tx/:txid/hex`, `GET /tx/:txid/raw`, `GET /tx/:txid/status`,
+//! `GET /address/:address/txs[/chain/:last_seen_txid]`,
+//! `GET /scripthash/:hash/txs[/chain/:last_seen_txid]`,
+//! `GET /address/:address/utxo`, `GET /scripthash/:hash/utxo`,
+//! `GET /fee-estimates` and `POST /tx`.
+
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoin::hash_types::Txid;
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::network::constants::Network;
+use bitcoin::util::address::Address;
+use error_chain::ChainedError;
+use serde_json::Value;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::errors::*;
+use crate::metrics::{Histogram, HistogramOpts, Metrics};
+use crate::query::{Query, TxStatus};
+use crate::status::HistoryEntry;
+use crate::types::ScriptHash;
+use crate::util::{spawn_thread, FullHash};
+
+const CHAIN_TXS_PER_PAGE: usize = 25;
+const MEMPOOL_TXS_LIMIT: usize = 50;
+const MAX_POST_SIZE: u64 = 8_000_000; // hex-encoded, so ~4MB transactions
+const FEE_ESTIMATE_TARGETS: &[usize] = &[1, 2, 3, 4, 5, 6, 10, 20, 25, 144, 504, 1008];
+
+enum Body {
+    Text(String),
+    Json(Value),
+    Binary(Vec<u8>),
+}
+
+#[derive(Debug)]
+struct HttpError(u16, String);
+
+impl From<Error> for HttpError {
+    fn from(e: Error) -> Self {
+        HttpError(500, e.display_chain().to_string())
+    }
+}
+
+fn bad_request(msg: &str) -> HttpError {
+    HttpError(400, msg.to_owned())
+}
+
+/// Only bitcoind's rejections (e.g. of an invalid transaction) are the client's fault.
+fn broadcast_error(error: Error) -> HttpError {
+    let mut cause: &dyn std::error::Error = &error;
+    loop {
+        if let Some(e) = cause.downcast_ref::<Error>() {
+            if let ErrorKind::Daemon(_, _) = e.kind() {
+                return bad_request(&error.display_chain().to_string());
+            }
+        }
+        cause = match cause.source() {
+            Some(cause) => cause,
+            None => return HttpError::from(error),
+        };
+    }
+}
+
+fn not_found(msg: &str) -> HttpError {
+    HttpError(404, msg.to_owned())
+}
+
+type HttpResult = std::result::Result<Body, HttpError>;
+
+fn parse_txid(txid: &str) -> std::result::Result<Txid, HttpError> {
+    Txid::from_hex(txid).map_err(|_| bad_request("Invalid hex string"))
+}
+
+/// Parses an `address` or a (hex-encoded) `scripthash` path parameter.
+fn parse_script_hash(
+    kind: &str,
+    value: &str,
+    network: Network,
+) -> std::result::Result<ScriptHash, HttpError> {
+    match kind {
+        "address" => {
+            let address =
+                Address::from_str(value).map_err(|_| bad_request("Invalid Bitcoin address"))?;
+            if !is_compatible(address.network, network) {
+                return Err(bad_request("Address on invalid network"));
+            }
+            Ok(ScriptHash::from_address(&address))
+        }
+        // unlike Electrum, Esplora script hashes are not byte-reversed
+        _ => {
+            let bytes =
+                Vec::<u8>::from_hex(value).map_err(|_| bad_request("Invalid hex string"))?;
+            if bytes.len() != 32 {
+                return Err(bad_request("Invalid script hash"));
+            }
+            let mut hash = FullHash::default();
+            hash.copy_from_slice(&bytes);
+            Ok(ScriptHash::from_inner(hash))
+        }
+    }
+}
+
+/// Returns the txids of a `history` page: mempool transactions first, followed by confirmed
+/// ones (newest first). Pages after the first continue after the `last_seen` confirmed one.
+fn history_page(
+    history: Vec<HistoryEntry>,
+    last_seen: Option<&Txid>,
+) -> std::result::Result<Vec<Txid>, HttpError> {
+    let (mempool, confirmed): (Vec<_>, Vec<_>) = history
+        .into_iter()
+        .rev()
+        .partition(|entry| !entry.height.is_confirmed());
+    let (mut txids, skip) = match last_seen {
+        None => {
+            let txids = mempool
+                .iter()
+                .take(MEMPOOL_TXS_LIMIT)
+                .map(|entry| entry.txid)
+                .collect();
+            (txids, 0)
+        }
+        Some(txid) => match confirmed.iter().position(|entry| entry.txid == *txid) {
+            Some(index) => (vec![], index + 1),
+            None => return Err(bad_request("Unknown last seen transaction")),
+        },
+    };
+    txids.extend(
+        confirmed
+            .iter()
+            .skip(skip)
+            .take(CHAIN_TXS_PER_PAGE)
+            .map(|entry| entry.txid),
+    );
+    Ok(txids)
+}
+
+struct Rest {
+    query: Arc<Query>,
+    network: Network,
+    relayfee: f64, // in BTC/kB
+}
+
+impl Rest {
+    fn status_json(&self, txid: &Txid) -> std::result::Result<Value, HttpError> {
+        Ok(match self.query.get_tx_status(txid)? {
+            TxStatus::Unconfirmed => json!({ "confirmed": false }),
+            TxStatus::Confirmed { height, blockhash } => {
+                let header = self
+                    .query
+                    .get_headers(&[height as usize])
+                    .pop()
+                    .chain_err(|| format!("missing header at height {}", height))?;
+                json!({
+                    "confirmed": true,
+                    "block_height": height,
+                    "block_hash": blockhash.to_hex(),
+                    "block_time": header.header().time,
+                })
+            }
+            TxStatus::Unknown => return Err(not_found("Transaction not found")),
+        })
+    }
+
+    fn output_json(&self, script: &Script, value: u64) -> Value {
+        let mut output = json!({
+            "scriptpubkey": script.to_hex(),
+            "value": value,
+        });
+        if let Some(address) = Address::from_script(script, self.network) {
+            output["scriptpubkey_address"] = json!(address.to_string());
+        }
+        output
+    }
+
+    fn tx_json(&self, txn: &Transaction) -> std::result::Result<Value, HttpError> {
+        let txid = txn.txid();
+        let status = self.status_json(&txid)?;
+        let is_coinbase = txn.is_coin_base();
+        let mut input_value = 0;
+        let mut vin = Vec::with_capacity(txn.input.len());
+        for input in &txn.input {
+            let prevout = if is_coinbase {
+                Value::Null
+            } else {
+                let outpoint = &input.previous_output;
+                let prev_txn = self.query.get_tx(&outpoint.txid)?;
+                let output = prev_txn
+                    .output
+                    .get(outpoint.vout as usize)
+                    .chain_err(|| format!("missing prevout {}", outpoint))?;
+                input_value += output.value;
+                self.output_json(&output.script_pubkey, output.value)
+            };
+            let witness: Vec<String> = input.witness.iter().map(|item| item.to_hex()).collect();
+            vin.push(json!({
+                "txid": input.previous_output.txid.to_hex(),
+                "vout": input.previous_output.vout,
+                "prevout": prevout,
+                "scriptsig": input.script_sig.to_hex(),
+                "witness": witness,
+                "is_coinbase": is_coinbase,
+                "sequence": input.sequence,
+            }));
+        }
+        let vout: Vec<Value> = txn
+            .output
+            .iter()
+            .map(|output| self.output_json(&output.script_pubkey, output.value))
+            .collect();
+        let output_value: u64 = txn.output.iter().map(|output| output.value).sum();
+        let fee = if is_coinbase {
+            0
+        } else {
+            input_value.saturating_sub(output_value)
+        };
+        Ok(json!({
+            "txid": txid.to_hex(),
+            "version": txn.version,
+            "locktime": txn.lock_time,
+            "size": serialize(txn).len(),
+            "weight": txn.get_weight(),
+            "fee": fee,
+            "vin": vin,
+            "vout": vout,
+            "status": status,
+        }))
+    }
+
+    fn get_tx(&self, txid: &str) -> std::result::Result<Transaction, HttpError> {
+        let txid = parse_txid(txid)?;
+        if let TxStatus::Unknown = self.query.get_tx_status(&txid)? {
+            return Err(not_found("Transaction not found"));
+        }
+        Ok(self.query.get_tx(&txid)?)
+    }
+
+    /// Returns a page of transactions (see `history_page()`), fetching only their prevouts.
+    fn history(&self, script_hash: &ScriptHash, last_seen: Option<&str>) -> HttpResult {
+        let last_seen = last_seen.map(parse_txid).transpose()?;
+        let history = self.query.get_history(script_hash)?;
+        let txids = history_page(history, last_seen.as_ref())?;
+        let mut txns = Vec::with_capacity(txids.len());
+        for txid in &txids {
+            txns.push(self.tx_json(&self.query.get_tx(txid)?)?);
+        }
+        Ok(Body::Json(json!(txns)))
+    }
+
+    fn utxos(&self, script_hash: &ScriptHash) -> HttpResult {
+        let mut utxos = vec![];
+        for utxo in self.query.get_utxos(script_hash)? {
+            let status = match utxo.height.confirmed() {
+                None => json!({ "confirmed": false }),
+                Some(height) => {
+                    let header = self
+                        .query
+                        .get_headers(&[height as usize])
+                        .pop()
+                        .chain_err(|| format!("missing header at height {}", height))?;
+                    json!({
+                        "confirmed": true,
+                        "block_height": height,
+                        "block_hash": header.hash().to_hex(),
+                        "block_time": header.header().time,
+                    })
+                }
+            };
+            utxos.push(json!({
+                "txid": utxo.outpoint.txid.to_hex(),
+                "vout": utxo.outpoint.vout,
+                "value": utxo.value.as_sat(),
+                "status": status,
+            }));
+        }
+        Ok(Body::Json(json!(utxos)))
+    }
+
+    fn fee_estimates(&self) -> Body {
+        let mut estimates = serde_json::Map::new();
+        for &blocks in FEE_ESTIMATE_TARGETS {
+            let fee_rate = self.query.estimate_fee(blocks).max(self.relayfee); // in BTC/kB
+            estimates.insert(blocks.to_string(), json!(fee_rate * 1e5)); // in sat/vB
+        }
+        Body::Json(Value::Object(estimates))
+    }
+
+    fn broadcast(&self, request: &mut tiny_http::Request) -> HttpResult {
+        let mut body = String::new();
+        request
+            .as_reader()
+            .take(MAX_POST_SIZE)
+            .read_to_string(&mut body)
+            .map_err(|_| bad_request("Invalid request body"))?;
+        let bytes =
+            Vec::<u8>::from_hex(body.trim()).map_err(|_| bad_request("Invalid hex string"))?;
+        let txn: Transaction =
+            deserialize(&bytes).map_err(|_| bad_request("Invalid transaction"))?;
+        let txid = self.query.broadcast(&txn, None).map_err(broadcast_error)?;
+        self.query.update_mempool()?;
+        Ok(Body::Text(txid.to_hex()))
+    }
+
+    fn handle(&self, request: &mut tiny_http::Request) -> HttpResult {
+        let method = request.method().clone();
+        let url = request.url().to_owned();
+        let path = url.split('?').next().unwrap_or("");
+        let parts: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match (method, &parts[..]) {
+            (tiny_http::Method::Get, ["blocks", "tip", "height"]) => {
+                let height = self.query.get_best_header()?.height();
+                Ok(Body::Text(height.to_string()))
+            }
+            (tiny_http::Method::Get, ["blocks", "tip", "hash"]) => {
+                let header = self.query.get_best_header()?;
+                Ok(Body::Text(header.hash().to_hex()))
+            }
+            (tiny_http::Method::Get, ["tx", txid]) => {
+                let txn = self.get_tx(txid)?;
+                Ok(Body::Json(self.tx_json(&txn)?))
+            }
+            (tiny_http::Method::Get, ["tx", txid, "hex"]) => {
+                let txn = self.get_tx(txid)?;
+                Ok(Body::Text(serialize(&txn).to_hex()))
+            }
+            (tiny_http::Method::Get, ["tx", txid, "raw"]) => {
+                let txn = self.get_tx(txid)?;
+                Ok(Body::Binary(serialize(&txn)))
+            }
+            (tiny_http::Method::Get, ["tx", txid, "status"]) => {
+                let txid = parse_txid(txid)?;
+                Ok(Body::Json(self.status_json(&txid)?))
+            }
+            (tiny_http::Method::Get, [kind @ "address", value, "txs"])
+            | (tiny_http::Method::Get, [kind @ "scripthash", value, "txs"]) => {
+                let script_hash = parse_script_hash(kind, value, self.network)?;
+                self.history(&script_hash, None)
+            }
+            (tiny_http::Method::Get, [kind @ "address", value, "txs", "chain", last_seen])
+            | (tiny_http::Method::Get, [kind @ "scripthash", value, "txs", "chain", last_seen]) => {
+                let script_hash = parse_script_hash(kind, value, self.network)?;
+                self.history(&script_hash, Some(last_seen))
+            }
+            (tiny_http::Method::Get, [kind @ "address", value, "utxo"])
+            | (tiny_http::Method::Get, [kind @ "scripthash", value, "utxo"]) => {
+                let script_hash = parse_script_hash(kind, value, self.network)?;
+                self.utxos(&script_hash)
+            }
+            (tiny_http::Method::Get, ["fee-estimates"]) => Ok(self.fee_estimates()),
+            (tiny_http::Method::Post, ["tx"]) => self.broadcast(request),
+            _ => Err(not_found("Not found")),
+        }
+    }
+
+    fn respond(&self, mut request: tiny_http::Request, duration: &Histogram) {
+        let timer = duration.start_timer();
+        let result = self.handle(&mut request);
+        timer.observe_duration();
+        let (status, content_type, data) = match result {
+            Ok(Body::Text(text)) => (200, "text/plain", text.into_bytes()),
+            Ok(Body::Json(value)) => (200, "application/json", value.to_string().into_bytes()),
+            Ok(Body::Binary(data)) => (200, "application/octet-stream", data),
+            Err(HttpError(status, msg)) => {
+                if status == 500 {
+                    warn!(
+                        "HTTP {} {} failed: {}",
+                        request.method(),
+                        request.url(),
+                        msg
+                    );
+                }
+                (status, "text/plain", msg.into_bytes())
+            }
+        };
+        let header =
+            tiny_http::Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes()).unwrap();
+        let response = tiny_http::Response::from_data(data)
+            .with_status_code(status)
+            .with_header(header);
+        if let Err(e) = request.respond(response) {
+            debug!("failed to send HTTP response: {}", e);
+        }
+    }
+}
+
+// testnet addresses are also used by regtest and signet
+fn is_compatible(address_network: Network, network: Network) -> bool {
+    address_network == network
+        || (address_network == Network::Testnet && network != Network::Bitcoin)
+}
+
+/// Serves the REST API at `addr`, using `threads` worker threads.
+pub fn start(
+    addr: SocketAddr,
+    threads: usize,
+    query: Arc<Query>,
+    network: Network,
+    relayfee: f64,
+    metrics: &Metrics,
+) -> Result<()> {
+    let server = tiny_http::Server::http(addr)
+        .map_err(|e| format!("failed to start REST server at {}: {}", addr, e))?;
+    info!("REST server listening on {}", addr);
+    let server = Arc::new(server);
+    let rest = Arc::new(Rest {
+        query,
+        network,
+        relayfee,
+    });
+    let duration = metrics.histogram(HistogramOpts::new(
+        "electrs_rest_duration",
+        "REST requests duration (in seconds)",
+    ));
+    for _ in 0..threads {
+        let server = Arc::clone(&server);
+        let rest = Arc::clone(&rest);
+        let duration = duration.clone();
+        spawn_thread("rest", move || loop {
+            match server.recv() {
+                Ok(request) => rest.respond(request, &duration),
+                Err(e) => warn!("REST server failed: {}", e),
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::compute_script_hash;
+    use crate::types::Height;
+    use bitcoin::hashes::Hash;
+
+    #[test]
+    fn test_is_compatible() {
+        assert!(is_compatible(Network::Bitcoin, Network::Bitcoin));
+        assert!(is_compatible(Network::Testnet, Network::Testnet));
+        assert!(is_compatible(Network::Testnet, Network::Regtest));
+        assert!(is_compatible(Network::Testnet, Network::Signet));
+        assert!(is_compatible(Network::Regtest, Network::Regtest));
+        assert!(!is_compatible(Network::Testnet, Network::Bitcoin));
+        assert!(!is_compatible(Network::Bitcoin, Network::Testnet));
+        assert!(!is_compatible(Network::Regtest, Network::Testnet));
+    }
+
+    #[test]
+    fn test_parse_script_hash() {
+        let code = |result: std::result::Result<ScriptHash, HttpError>| result.unwrap_err().0;
+
+        let address = "bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu";
+        let script = Address::from_str(address).unwrap().script_pubkey();
+        let hash = parse_script_hash("address", address, Network::Bitcoin).unwrap();
+        assert_eq!(hash.into_inner(), compute_script_hash(&script[..]));
+        assert_eq!(
+            code(parse_script_hash("address", address, Network::Testnet)),
+            400
+        );
+        assert_eq!(
+            code(parse_script_hash("address", "bc1qxyz", Network::Bitcoin)),
+            400
+        );
+
+        let bytes: Vec<u8> = (0..32).collect();
+        let hash = parse_script_hash("scripthash", &hex::encode(&bytes), Network::Bitcoin);
+        assert_eq!(hash.unwrap().as_bytes(), &bytes[..]); // not byte-reversed
+        let short = hex::encode(&bytes[1..]);
+        assert_eq!(
+            code(parse_script_hash("scripthash", &short, Network::Bitcoin)),
+            400
+        );
+        assert_eq!(
+            code(parse_script_hash("scripthash", "xyz", Network::Bitcoin)),
+            400
+        );
+    }
+
+    #[test]
+    fn test_history_page() {
+        let txid = |i: u8| Txid::from_inner([i; 32]);
+        // as returned by `Status::history()`, sorted by height: mempool (the ones spending
+        // unconfirmed inputs last), followed by confirmed
+        let mempool = |i: u8, unconfirmed_inputs: bool| HistoryEntry {
+            txid: txid(i),
+            height: Height::Unconfirmed { unconfirmed_inputs },
+            fee: None,
+        };
+        let mut history = vec![mempool(31, false), mempool(32, false), mempool(33, true)];
+        history.extend((1..=30).map(|i| HistoryEntry {
+            txid: txid(i),
+            height: Height::Confirmed(i.into()),
+            fee: None,
+        }));
+
+        // mempool (in reverse order, i.e. the children first), followed by newest confirmed
+        let first = history_page(history.clone(), None).unwrap();
+        let expected: Vec<Txid> = (6..=33).rev().map(txid).collect();
+        assert_eq!(first, expected);
+
+        let second = history_page(history.clone(), Some(&txid(6))).unwrap();
+        let expected: Vec<Txid> = (1..=5).rev().map(txid).collect();
+        assert_eq!(second, expected);
+
+        let last = history_page(history.clone(), Some(&txid(1))).unwrap();
+        assert!(last.is_empty());
+
+        // only confirmed transactions can be used for continuing
+        assert_eq!(
+            history_page(history.clone(), Some(&txid(31)))
+                .unwrap_err()
+                .0,
+            400
+        );
+        assert_eq!(history_page(history, Some(&txid(34))).unwrap_err().0, 400);
+    }
+
+    #[test]
+    fn test_broadcast_error() {
+        let rejected = Error::from(ErrorKind::Daemon(
+            "sendrawtransaction".to_owned(),
+            json!({"code": -26, "message": "min relay fee not met"}),
+        ));
+        assert_eq!(broadcast_error(rejected).0, 400);
+        let rejected: Error = Err::<(), _>(Error::from(ErrorKind::Daemon(
+            "sendrawtransaction".to_owned(),
+            json!({"code": -25, "message": "bad-txns-inputs-missingorspent"}),
+        )))
+        .chain_err(|| "failed to broadcast")
+        .unwrap_err();
+        assert_eq!(broadcast_error(rejected).0, 400);
+
+        let unavailable = Error::from(ErrorKind::Connection("connection refused".to_owned()));
+        assert_eq!(broadcast_error(unavailable).0, 500);
+        assert_eq!(broadcast_error(Error::from("invalid JSON")).0, 500);
+    }
+}
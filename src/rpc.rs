@@ -1,34 +1,50 @@
 use bitcoin::blockdata::transaction::Transaction;
 use bitcoin::consensus::encode::{deserialize, serialize};
 use bitcoin::hashes::hex::{FromHex, ToHex};
-use bitcoin::hashes::{sha256d::Hash as Sha256dHash, Hash};
+use bitcoin::hashes::Hash;
 use error_chain::ChainedError;
-use serde_json::{from_str, Value};
-use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Write};
-use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use serde_json::Value;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::io::{self, BufReader, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TrySendError};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
+use crate::config::{Config, ReloadableConfig};
 use crate::errors::*;
-use crate::metrics::{Gauge, HistogramOpts, HistogramVec, MetricOpts, Metrics};
-use crate::query::{Query, Status};
+use crate::logger;
+use crate::metrics::{
+    Counter, CounterVec, Gauge, GaugeVec, HistogramOpts, HistogramVec, MetricOpts, Metrics,
+};
+use crate::protocol;
+use crate::query::Query;
+use crate::signal::Waker;
+use crate::status::{HistoryEntry, Status};
+use crate::types::{Height, HistoryToken, ScriptHash};
 use crate::util::{spawn_thread, Channel, HeaderEntry};
 
 const ELECTRS_VERSION: &str = env!("CARGO_PKG_VERSION");
 const PROTOCOL_VERSION: &str = "1.4";
+const MAX_PACKAGE_SIZE: usize = 25; // bitcoind's package limit
+const MAX_HISTORY_PAGE: usize = 1000; // entries per `blockchain.scripthash.get_history_page`
+const MAX_SOFTWARE_LABELS: usize = 100; // distinct client software names exported as metrics
+const MAX_VERSION_LEN: usize = 64; // of the client software and protocol version strings
 
 // JSON-RPC spec errors
 const PARSE_ERROR: i16 = -32700;
 const METHOD_NOT_FOUND: i16 = -32601;
 const INVALID_REQUEST: i16 = -32600;
+const SERVER_BUSY: i16 = -32000; // reserved for implementation-defined server errors
 
 // electrum-specific errors
 const BAD_REQUEST: i16 = 1;
 const DAEMON_ERROR: i16 = 2;
 
-// TODO: Sha256dHash should be a generic hash-container (since script hash is single SHA256)
 fn hash_from_value<T: Hash>(val: Option<&Value>) -> Result<T> {
     let script_hash = val.chain_err(|| "missing hash")?;
     let script_hash = script_hash.as_str().chain_err(|| "non-string hash")?;
@@ -36,6 +52,44 @@ fn hash_from_value<T: Hash>(val: Option<&Value>) -> Result<T> {
     Ok(script_hash)
 }
 
+fn script_hash_from_value(val: Option<&Value>) -> Result<ScriptHash> {
+    let script_hash = val.chain_err(|| "missing script_hash")?;
+    let script_hash = script_hash
+        .as_str()
+        .chain_err(|| "non-string script_hash")?;
+    script_hash.parse().chain_err(|| "bad script_hash")
+}
+
+/// Maps a client's `server.version` user agent (e.g. "electrum/4.5.4") to its software's name,
+/// dropping the version and unexpected characters (to limit the metrics' label cardinality).
+fn software_label(client_id: &str) -> String {
+    let name = client_id
+        .split(|c: char| c == '/' || c.is_whitespace())
+        .next()
+        .unwrap_or("");
+    let name: String = name
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_' || *c == '.')
+        .take(32)
+        .collect();
+    if name.is_empty() {
+        "unknown".to_owned()
+    } else {
+        name.to_lowercase()
+    }
+}
+
+// protocol versions exported as metric labels (the others are counted as "other")
+const PROTOCOL_LABELS: &[&str] = &["1.0", "1.1", "1.2", "1.3", "1.4", "1.4.1", "1.4.2"];
+
+/// Returns the requested protocol version (e.g. "1.4") as a metric label.
+fn protocol_label(client_version: &str) -> &'static str {
+    PROTOCOL_LABELS
+        .iter()
+        .find(|label| **label == client_version)
+        .unwrap_or(&"other")
+}
+
 fn usize_from_value(val: Option<&Value>, name: &str) -> Result<usize> {
     let val = val.chain_err(|| format!("missing {}", name))?;
     let val = val.as_u64().chain_err(|| format!("non-integer {}", name))?;
@@ -62,6 +116,17 @@ fn bool_from_value_or(val: Option<&Value>, name: &str, default: bool) -> Result<
     bool_from_value(val, name)
 }
 
+fn history_entry_to_json(entry: &HistoryEntry) -> Value {
+    let mut result = json!({ "height": entry.height, "tx_hash": entry.txid.to_hex()});
+    if let Some(fee) = entry.fee {
+        result
+            .as_object_mut()
+            .unwrap()
+            .insert("fee".to_string(), json!(fee.as_sat()));
+    }
+    result
+}
+
 fn unspent_from_status(status: &Status) -> Value {
     json!(Value::Array(
         status
@@ -87,6 +152,13 @@ fn json_rpc_error_from_error(error: &Error) -> Value {
                     ErrorKind::InvalidRequest(_) => break INVALID_REQUEST,
                     ErrorKind::ParseError => break PARSE_ERROR,
                     ErrorKind::Daemon(_, _) => break DAEMON_ERROR,
+                    ErrorKind::Overloaded | ErrorKind::Timeout | ErrorKind::DaemonSyncing(_) => {
+                        break SERVER_BUSY
+                    }
+                    ErrorKind::HistoryTooLarge | ErrorKind::StatusTooLarge(_) => {
+                        // without the context, like ElectrumX
+                        return json!({"code": BAD_REQUEST, "message": e.to_string()});
+                    }
                     _ => (),
                 }
             }
@@ -102,17 +174,18 @@ fn json_rpc_error_from_error(error: &Error) -> Value {
     })
 }
 
-type Map = serde_json::Map<String, Value>;
-
 struct Connection {
     query: Arc<Query>,
     last_header_entry: Option<HeaderEntry>,
-    status_hashes: HashMap<Sha256dHash, Value>, // ScriptHash -> StatusHash
+    status_hashes: HashMap<ScriptHash, Value>, // ScriptHash -> StatusHash
     stream: TcpStream,
     addr: SocketAddr,
     sender: SyncSender<Message>,
     stats: Arc<Stats>,
+    limiter: Arc<RequestLimiter>,
     relayfee: f64,
+    waker: Waker,
+    labels: Option<(String, String)>, // client (software, protocol), set by `server.version`
 }
 
 impl Connection {
@@ -121,8 +194,10 @@ impl Connection {
         stream: TcpStream,
         addr: SocketAddr,
         stats: Arc<Stats>,
+        limiter: Arc<RequestLimiter>,
         relayfee: f64,
         sender: SyncSender<Message>,
+        waker: Waker,
     ) -> Connection {
         Connection {
             query,
@@ -132,7 +207,10 @@ impl Connection {
             addr,
             sender,
             stats,
+            limiter,
             relayfee,
+            waker,
+            labels: None,
         }
     }
 
@@ -140,11 +218,13 @@ impl Connection {
         let entry = self.query.get_best_header()?;
         let hex_header = hex::encode(serialize(entry.header()));
         let result = json!({"hex": hex_header, "height": entry.height()});
-        self.last_header_entry = Some(entry);
+        if self.last_header_entry.replace(entry).is_none() {
+            self.stats.header_subscriptions.inc();
+        }
         Ok(result)
     }
 
-    fn server_version(&self, params: &[Value]) -> Result<Value> {
+    fn server_version(&mut self, params: &[Value]) -> Result<Value> {
         if params.len() != 2 {
             bail!("invalid params: {:?}", params);
         }
@@ -155,6 +235,10 @@ impl Connection {
         let client_version = params[1]
             .as_str()
             .chain_err(|| format!("invalid client_version: {:?}", params[1]))?;
+        let labels = self.stats.identified(&self.addr, client_id, client_version);
+        if let Some((software, protocol)) = self.labels.replace(labels) {
+            self.stats.unidentified(&software, &protocol);
+        }
 
         if client_version != PROTOCOL_VERSION {
             bail!(
@@ -246,6 +330,16 @@ impl Connection {
         }))
     }
 
+    fn blockchain_block_tweaks(&self, params: &[Value]) -> Result<Value> {
+        let height = usize_from_value(params.get(0), "height")?;
+        let tweaks = self.query.get_block_tweaks(height)?;
+        let tweaks: Vec<String> = tweaks
+            .iter()
+            .map(|tweak| hex::encode(&tweak.serialize()[..]))
+            .collect();
+        Ok(json!(tweaks))
+    }
+
     fn blockchain_estimatefee(&self, params: &[Value]) -> Result<Value> {
         let blocks_count = usize_from_value(params.get(0), "blocks_count")?;
         let fee_rate = self.query.estimate_fee(blocks_count); // in BTC/kB
@@ -257,47 +351,74 @@ impl Connection {
     }
 
     fn blockchain_scripthash_subscribe(&mut self, params: &[Value]) -> Result<Value> {
-        let script_hash =
-            hash_from_value::<Sha256dHash>(params.get(0)).chain_err(|| "bad script_hash")?;
-        let status = self.query.status(&script_hash[..])?;
-        let result = status.hash().map_or(Value::Null, |h| json!(hex::encode(h)));
+        let script_hash = script_hash_from_value(params.get(0))?;
+        let status = self.query.request_status(&script_hash)?;
+        let result = json!(status.statushash());
         if self
             .status_hashes
             .insert(script_hash, result.clone())
             .is_none()
         {
-            self.stats.subscriptions.inc();
+            self.stats.subscribed(&self.addr);
         }
 
         Ok(result)
     }
 
+    fn blockchain_scripthash_unsubscribe(&mut self, params: &[Value]) -> Result<Value> {
+        let script_hash = script_hash_from_value(params.get(0))?;
+        let subscribed = self.status_hashes.remove(&script_hash).is_some();
+        if subscribed {
+            self.stats.unsubscribed(&self.addr, 1);
+        }
+        Ok(json!(subscribed))
+    }
+
     fn blockchain_scripthash_get_balance(&self, params: &[Value]) -> Result<Value> {
-        let script_hash =
-            hash_from_value::<Sha256dHash>(params.get(0)).chain_err(|| "bad script_hash")?;
-        let status = self.query.status(&script_hash[..])?;
+        let script_hash = script_hash_from_value(params.get(0))?;
+        let status = self.query.request_status(&script_hash)?;
         Ok(
             json!({ "confirmed": status.confirmed_balance(), "unconfirmed": status.mempool_balance() }),
         )
     }
 
     fn blockchain_scripthash_get_history(&self, params: &[Value]) -> Result<Value> {
-        let script_hash =
-            hash_from_value::<Sha256dHash>(params.get(0)).chain_err(|| "bad script_hash")?;
-        let status = self.query.status(&script_hash[..])?;
+        let script_hash = script_hash_from_value(params.get(0))?;
+        let status = self.query.request_status(&script_hash)?;
         Ok(json!(Value::Array(
             status
                 .history()
                 .into_iter()
-                .map(|item| item.to_json())
+                .map(|entry| history_entry_to_json(&entry))
                 .collect()
         )))
     }
 
+    fn blockchain_scripthash_get_history_page(&self, params: &[Value]) -> Result<Value> {
+        let script_hash = script_hash_from_value(params.get(0))?;
+        let token = match params.get(1) {
+            None | Some(Value::Null) => HistoryToken::from_height(0),
+            Some(Value::String(token)) => token.parse().chain_err(|| "bad history token")?,
+            Some(height) => HistoryToken::from_height(
+                height
+                    .as_u64()
+                    .and_then(|height| u32::try_from(height).ok())
+                    .chain_err(|| "bad from_height")?,
+            ),
+        };
+        let limit = usize_from_value_or(params.get(2), "limit", MAX_HISTORY_PAGE)?;
+        let status = self.query.request_status(&script_hash)?;
+        let page = self
+            .query
+            .get_history_page(&status, &token, cmp::min(limit, MAX_HISTORY_PAGE));
+        let history: Vec<Value> = page.entries.iter().map(history_entry_to_json).collect();
+        Ok(json!({"history": history, "next": page.next}))
+    }
+
     fn blockchain_scripthash_listunspent(&self, params: &[Value]) -> Result<Value> {
-        let script_hash =
-            hash_from_value::<Sha256dHash>(params.get(0)).chain_err(|| "bad script_hash")?;
-        Ok(unspent_from_status(&self.query.status(&script_hash[..])?))
+        let script_hash = script_hash_from_value(params.get(0))?;
+        let status = self.query.request_status(&script_hash)?;
+        Ok(unspent_from_status(&status))
     }
 
     fn blockchain_transaction_broadcast(&self, params: &[Value]) -> Result<Value> {
@@ -305,14 +426,58 @@ impl Connection {
         let tx = tx.as_str().chain_err(|| "non-string tx")?;
         let tx = hex::decode(&tx).chain_err(|| "non-hex tx")?;
         let tx: Transaction = deserialize(&tx).chain_err(|| "failed to parse tx")?;
-        let txid = self.query.broadcast(&tx)?;
+        let max_fee_rate = match params.get(1) {
+            Some(value) => Some(value.as_f64().chain_err(|| "non-numeric maxfeerate")?),
+            None => None,
+        };
+        let txid = self.query.broadcast(&tx, max_fee_rate)?;
         self.query.update_mempool()?;
         if let Err(e) = self.sender.try_send(Message::PeriodicUpdate) {
             warn!("failed to issue PeriodicUpdate after broadcast: {}", e);
         }
+        self.waker.wake(); // notify the other clients too
         Ok(json!(txid.to_hex()))
     }
 
+    fn blockchain_transaction_broadcast_package(&self, params: &[Value]) -> Result<Value> {
+        let txns = params.get(0).chain_err(|| "missing txs")?;
+        let txns = txns.as_array().chain_err(|| "non-array txs")?;
+        if txns.is_empty() || txns.len() > MAX_PACKAGE_SIZE {
+            bail!("package must contain 1-{} txs", MAX_PACKAGE_SIZE);
+        }
+        let txns = txns
+            .iter()
+            .map(|tx| {
+                let tx = tx.as_str().chain_err(|| "non-string tx")?;
+                let tx = hex::decode(&tx).chain_err(|| "non-hex tx")?;
+                deserialize(&tx).chain_err(|| "failed to parse tx")
+            })
+            .collect::<Result<Vec<Transaction>>>()?;
+        let result = self.query.submit_package(&txns)?;
+        let errors: Vec<Value> = result
+            .get("tx-results")
+            .and_then(Value::as_object)
+            .map(|results| {
+                results
+                    .values()
+                    .filter_map(|r| Some(json!({"txid": r.get("txid")?, "error": r.get("error")?})))
+                    .collect()
+            })
+            .unwrap_or_default();
+        // `package_msg` is missing before bitcoind 27.0
+        let success = match result.get("package_msg").and_then(Value::as_str) {
+            Some(msg) => msg == "success",
+            None => errors.is_empty(),
+        };
+        if success {
+            self.query.update_mempool()?;
+            if let Err(e) = self.sender.try_send(Message::PeriodicUpdate) {
+                warn!("failed to issue PeriodicUpdate after broadcast: {}", e);
+            }
+        }
+        Ok(json!({"success": success, "errors": errors}))
+    }
+
     fn blockchain_transaction_get(&self, params: &[Value]) -> Result<Value> {
         let tx_hash = hash_from_value(params.get(0)).chain_err(|| "bad tx_hash")?;
         let verbose = match params.get(1) {
@@ -366,17 +531,31 @@ impl Connection {
             .latency
             .with_label_values(&[method])
             .start_timer();
+        let limiter = Arc::clone(&self.limiter);
+        let _permit = limiter.acquire()?;
+        if method.starts_with("blockchain.") {
+            self.query.check_daemon_synced()?; // don't serve a partial index
+        }
+        let start = Instant::now();
         let result = match method {
             "blockchain.block.header" => self.blockchain_block_header(params),
             "blockchain.block.headers" => self.blockchain_block_headers(params),
+            "blockchain.block.tweaks" => self.blockchain_block_tweaks(params),
             "blockchain.estimatefee" => self.blockchain_estimatefee(params),
             "blockchain.headers.subscribe" => self.blockchain_headers_subscribe(),
             "blockchain.relayfee" => self.blockchain_relayfee(),
             "blockchain.scripthash.get_balance" => self.blockchain_scripthash_get_balance(params),
             "blockchain.scripthash.get_history" => self.blockchain_scripthash_get_history(params),
+            "blockchain.scripthash.get_history_page" => {
+                self.blockchain_scripthash_get_history_page(params)
+            }
             "blockchain.scripthash.listunspent" => self.blockchain_scripthash_listunspent(params),
             "blockchain.scripthash.subscribe" => self.blockchain_scripthash_subscribe(params),
+            "blockchain.scripthash.unsubscribe" => self.blockchain_scripthash_unsubscribe(params),
             "blockchain.transaction.broadcast" => self.blockchain_transaction_broadcast(params),
+            "blockchain.transaction.broadcast_package" => {
+                self.blockchain_transaction_broadcast_package(params)
+            }
             "blockchain.transaction.get" => self.blockchain_transaction_get(params),
             "blockchain.transaction.get_merkle" => self.blockchain_transaction_get_merkle(params),
             "blockchain.transaction.get_confirmed_blockhash" => {
@@ -392,15 +571,32 @@ impl Connection {
             &_ => Err(ErrorKind::MethodNotFound(method.to_owned()).into()),
         };
         timer.observe_duration();
-        if let Err(e) = &result {
-            warn!(
-                "rpc #{} {} {:?} failed: {}",
+        let software = self
+            .labels
+            .as_ref()
+            .map_or("unknown", |(software, _)| software);
+        self.stats
+            .client_requests
+            .with_label_values(&[software])
+            .inc();
+        let duration = start.elapsed();
+        let fields = json!({
+            "conn": self.addr.to_string(),
+            "method": method,
+            "duration_ms": duration.as_secs_f64() * 1e3,
+        });
+        logger::with_fields(fields, || match &result {
+            Ok(_) => debug!("[{}] rpc #{} {} took {:?}", self.addr, id, method, duration),
+            Err(e) => warn!(
+                "[{}] rpc #{} {} {:?} failed after {:?}: {}",
+                self.addr,
                 id,
                 method,
                 params,
+                duration,
                 e.display_chain()
-            );
-        }
+            ),
+        });
         result
     }
 
@@ -421,19 +617,45 @@ impl Connection {
                     "jsonrpc": "2.0",
                     "method": "blockchain.headers.subscribe",
                     "params": [header]}));
+                self.stats
+                    .notifications
+                    .with_label_values(&["headers"])
+                    .inc();
             }
         }
+        let tip_height = Height::Confirmed(self.query.get_best_header()?.height() as u32);
+        let mut confirmed_txids = vec![]; // in the tip block, by the changed statuses
         for (script_hash, status_hash) in self.status_hashes.iter_mut() {
-            let status = self.query.status(&script_hash[..])?;
-            let new_status_hash = status.hash().map_or(Value::Null, |h| json!(hex::encode(h)));
+            let status = self.query.status(script_hash)?;
+            let new_status_hash = json!(status.statushash());
             if new_status_hash == *status_hash {
                 continue;
             }
+            confirmed_txids.extend(
+                status
+                    .history()
+                    .into_iter()
+                    .filter(|entry| entry.height == tip_height)
+                    .map(|entry| entry.txid),
+            );
             result.push(json!({
                 "jsonrpc": "2.0",
                 "method": "blockchain.scripthash.subscribe",
-                "params": [script_hash.to_hex(), new_status_hash]}));
+                "params": [script_hash.to_string(), new_status_hash]}));
             *status_hash = new_status_hash;
+            self.stats
+                .notifications
+                .with_label_values(&["scripthash"])
+                .inc();
+        }
+        if !confirmed_txids.is_empty() {
+            // the client will probably ask for their proofs, so cache them (building the tree once)
+            confirmed_txids.sort();
+            confirmed_txids.dedup();
+            let height = tip_height.confirmed().unwrap() as usize;
+            if let Err(e) = self.query.get_merkle_proofs(&confirmed_txids, height) {
+                debug!("failed to prefetch merkle proofs: {}", e);
+            }
         }
         timer.observe_duration();
         Ok(result)
@@ -442,57 +664,30 @@ impl Connection {
     fn send_values(&mut self, values: &[Value]) -> Result<()> {
         for value in values {
             let line = value.to_string() + "\n";
-            self.stream
-                .write_all(line.as_bytes())
-                .chain_err(|| format!("failed to send {}", value))?;
+            if let Err(e) = self.stream.write_all(line.as_bytes()) {
+                if let io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut = e.kind() {
+                    // the client doesn't read its responses and notifications
+                    self.stats.slow_clients.inc();
+                    bail!("write timed out, disconnecting slow client");
+                }
+                return Err(e).chain_err(|| format!("failed to send {}", value));
+            }
+            self.stats.sent(&self.addr, line.len());
         }
         Ok(())
     }
 
     fn handle_replies(&mut self, receiver: Receiver<Message>) -> Result<()> {
-        fn parse_id_from_request(line: String) -> Result<(Value, Map)> {
-            let value: Value = from_str(&line).chain_err(|| ErrorKind::ParseError)?;
-            let mut cmd = match value {
-                Value::Object(cmd) => cmd,
-                _ => bail!(ErrorKind::ParseError),
-            };
-            let id = cmd
-                .remove("id")
-                .chain_err(|| ErrorKind::InvalidRequest("missing id"))?;
-            Ok((id, cmd))
-        }
-
-        fn parse_method_and_params_from_request(mut cmd: Map) -> Result<(String, Vec<Value>)> {
-            let method = match cmd
-                .remove("method")
-                .chain_err(|| ErrorKind::InvalidRequest("missing method"))?
-            {
-                Value::String(method) => method,
-                _ => bail!(ErrorKind::InvalidRequest("method must be a string")),
-            };
-            let params = match cmd.remove("params") {
-                None => Vec::new(),
-                Some(Value::Array(params)) => params,
-                Some(_) => bail!("params must be an array"),
-            };
-            Ok((method, params))
-        }
-
         loop {
             let msg = receiver.recv().chain_err(|| "channel closed")?;
             trace!("RPC {:?}", msg);
             match msg {
                 Message::Request(line) => {
-                    let (id, result) = match parse_id_from_request(line) {
-                        Ok((id, cmd)) => match parse_method_and_params_from_request(cmd) {
-                            Ok((method, params)) => {
-                                let result = self.handle_command(&method, &params, &id);
-                                (id, result)
-                            }
-                            Err(e) => (id, Err(e)),
-                        },
-                        Err(e) => (Value::Null, Err(e)),
-                    };
+                    self.stats.received(&self.addr, line.len() + 1); // including the newline
+                    let (id, request) = protocol::parse_request(&line);
+                    let result = request.and_then(|request| {
+                        self.handle_command(&request.method, &request.params, &id)
+                    });
                     let reply = match result {
                         Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
                         Err(e) => {
@@ -515,27 +710,17 @@ impl Connection {
 
     fn parse_requests(mut reader: BufReader<TcpStream>, tx: SyncSender<Message>) -> Result<()> {
         loop {
-            let mut line = Vec::<u8>::new();
-            reader
-                .read_until(b'\n', &mut line)
-                .chain_err(|| "failed to read a request")?;
-            if line.is_empty() {
-                tx.send(Message::Done).chain_err(|| "channel closed")?;
-                return Ok(());
-            } else {
-                if line.starts_with(&[22, 3, 1]) {
-                    // (very) naive SSL handshake detection
-                    let _ = tx.send(Message::Done);
-                    bail!("invalid request - maybe SSL-encrypted data?: {:?}", line)
+            match protocol::read_line(&mut reader) {
+                Ok(Some(req)) => tx
+                    .send(Message::Request(req))
+                    .chain_err(|| "channel closed")?,
+                Ok(None) => {
+                    tx.send(Message::Done).chain_err(|| "channel closed")?;
+                    return Ok(());
                 }
-                match String::from_utf8(line) {
-                    Ok(req) => tx
-                        .send(Message::Request(req))
-                        .chain_err(|| "channel closed")?,
-                    Err(err) => {
-                        let _ = tx.send(Message::Done);
-                        bail!("invalid UTF8: {}", err)
-                    }
+                Err(e) => {
+                    let _ = tx.send(Message::Done);
+                    return Err(e);
                 }
             }
         }
@@ -553,8 +738,17 @@ impl Connection {
             );
         }
         self.stats
-            .subscriptions
-            .sub(self.status_hashes.len() as i64);
+            .unsubscribed(&self.addr, self.status_hashes.len());
+        if self.last_header_entry.is_some() {
+            self.stats.header_subscriptions.dec();
+        }
+        self.stats.connections.dec();
+        if let Some((software, protocol)) = &self.labels {
+            self.stats.unidentified(software, protocol);
+        }
+        if let Some(client) = self.stats.clients.lock().unwrap().remove(&self.addr) {
+            debug!("[{}] connection stats: {:?}", self.addr, client.stats);
+        }
         debug!("[{}] shutting down connection", self.addr);
         let _ = self.stream.shutdown(Shutdown::Both);
         if let Err(err) = child.join().expect("receiver panicked") {
@@ -578,11 +772,270 @@ pub enum Notification {
 pub struct RPC {
     notification: Sender<Notification>,
     server: Option<thread::JoinHandle<()>>, // so we can join the server while dropping this ojbect
+    stats: Arc<Stats>,
+    limiter: Arc<RequestLimiter>,
+    shutdown_timeout: Duration,
+}
+
+/// Traffic of a single Electrum connection (requests and bytes, including the newlines), and
+/// the client software and protocol version it sent via `server.version`.
+#[derive(Clone, Debug, Default)]
+pub struct ClientStats {
+    pub requests: u64,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    pub subscriptions: usize,
+    pub software: Option<String>,
+    pub protocol: Option<String>,
+}
+
+struct Client {
+    stats: ClientStats,
+    stream: TcpStream, // for disconnecting the client
+}
+
+/// Limits the # of concurrently handled requests (across all connections), so a burst of
+/// expensive requests can't starve the rest of the server.
+/// Requests exceeding the limit wait in a bounded queue, and are rejected when it's full.
+struct RequestLimiter {
+    max_active: usize,
+    max_queued: AtomicUsize,      // can be reloaded
+    state: Mutex<(usize, usize)>, // (active, queued)
+    released: Condvar,
+    queued: Gauge,
+    rejected: Counter,
+}
+
+struct RequestPermit<'a> {
+    limiter: &'a RequestLimiter,
+}
+
+impl RequestLimiter {
+    fn new(max_active: usize, max_queued: usize, metrics: &Metrics) -> RequestLimiter {
+        RequestLimiter {
+            max_active: cmp::max(max_active, 1),
+            max_queued: AtomicUsize::new(max_queued),
+            state: Mutex::new((0, 0)),
+            released: Condvar::new(),
+            queued: metrics.gauge(MetricOpts::new(
+                "electrs_electrum_queued_requests",
+                "# of Electrum requests waiting to be handled",
+            )),
+            rejected: metrics.counter(MetricOpts::new(
+                "electrs_electrum_rejected_requests",
+                "# of Electrum requests rejected due to overload",
+            )),
+        }
+    }
+
+    fn acquire(&self) -> Result<RequestPermit> {
+        let mut state = self.state.lock().unwrap();
+        if state.0 >= self.max_active {
+            if state.1 >= self.max_queued.load(Ordering::Relaxed) {
+                self.rejected.inc();
+                bail!(ErrorKind::Overloaded);
+            }
+            state.1 += 1;
+            self.queued.set(state.1 as i64);
+            while state.0 >= self.max_active {
+                state = self.released.wait(state).unwrap();
+            }
+            state.1 -= 1;
+            self.queued.set(state.1 as i64);
+        }
+        state.0 += 1;
+        Ok(RequestPermit { limiter: self })
+    }
+}
+
+impl<'a> Drop for RequestPermit<'a> {
+    fn drop(&mut self) {
+        self.limiter.state.lock().unwrap().0 -= 1;
+        self.limiter.released.notify_one();
+    }
+}
+
+struct ConnectionLimits {
+    max_per_ip: usize, // 0 - no limit
+    exempt_ips: Vec<IpAddr>,
 }
 
 struct Stats {
     latency: HistogramVec,
+    connections: Gauge,
     subscriptions: Gauge,
+    header_subscriptions: Gauge,
+    notifications: CounterVec,
+    requests: Counter,
+    bytes_received: Counter,
+    bytes_sent: Counter,
+    rejected_connections: Counter,
+    slow_clients: Counter,
+    client_versions: GaugeVec,
+    client_requests: CounterVec,
+    software_labels: Mutex<HashSet<String>>,
+    clients: Mutex<HashMap<SocketAddr, Client>>,
+    limits: Mutex<ConnectionLimits>,
+}
+
+impl Stats {
+    fn new(metrics: &Metrics, limits: ConnectionLimits) -> Stats {
+        let stats = Stats {
+            latency: metrics.histogram_vec(
+                HistogramOpts::new("electrs_electrum_rpc", "Electrum RPC latency (seconds)"),
+                &["method"],
+            ),
+            connections: metrics.gauge(MetricOpts::new(
+                "electrs_electrum_connections",
+                "# of active Electrum connections",
+            )),
+            subscriptions: metrics.gauge(MetricOpts::new(
+                "electrs_electrum_subscriptions",
+                "# of Electrum subscriptions",
+            )),
+            header_subscriptions: metrics.gauge(MetricOpts::new(
+                "electrs_electrum_header_subscriptions",
+                "# of Electrum connections subscribed to new headers",
+            )),
+            notifications: metrics.counter_vec(
+                MetricOpts::new(
+                    "electrs_electrum_notifications",
+                    "# of Electrum notifications sent",
+                ),
+                &["type"],
+            ),
+            requests: metrics.counter(MetricOpts::new(
+                "electrs_electrum_requests",
+                "# of Electrum requests",
+            )),
+            bytes_received: metrics.counter(MetricOpts::new(
+                "electrs_electrum_received_bytes",
+                "Total size of Electrum requests (bytes)",
+            )),
+            bytes_sent: metrics.counter(MetricOpts::new(
+                "electrs_electrum_sent_bytes",
+                "Total size of Electrum responses and notifications (bytes)",
+            )),
+            rejected_connections: metrics.counter(MetricOpts::new(
+                "electrs_electrum_rejected_connections",
+                "# of Electrum connections rejected due to per-IP limit",
+            )),
+            slow_clients: metrics.counter(MetricOpts::new(
+                "electrs_electrum_slow_clients",
+                "# of Electrum connections closed due to write timeout",
+            )),
+            client_versions: metrics.gauge_vec(
+                MetricOpts::new(
+                    "electrs_electrum_client_versions",
+                    "# of Electrum connections by client software and protocol version",
+                ),
+                &["software", "protocol"],
+            ),
+            client_requests: metrics.counter_vec(
+                MetricOpts::new(
+                    "electrs_electrum_client_requests",
+                    "# of Electrum requests by client software",
+                ),
+                &["software"],
+            ),
+            software_labels: Mutex::new(HashSet::new()),
+            clients: Mutex::new(HashMap::new()),
+            limits: Mutex::new(limits),
+        };
+        stats.connections.set(0);
+        stats.subscriptions.set(0);
+        stats.header_subscriptions.set(0);
+        stats
+    }
+
+    /// Returns false if there are already `max_per_ip` connections from this client's IP,
+    /// unless it's exempt.
+    fn register_client(&self, stream: &TcpStream, addr: SocketAddr) -> Result<bool> {
+        let limits = self.limits.lock().unwrap();
+        let mut clients = self.clients.lock().unwrap();
+        let ip = addr.ip();
+        if limits.max_per_ip > 0 && !limits.exempt_ips.contains(&ip) {
+            let count = clients.keys().filter(|client| client.ip() == ip).count();
+            if count >= limits.max_per_ip {
+                self.rejected_connections.inc();
+                return Ok(false);
+            }
+        }
+        let client = Client {
+            stats: ClientStats::default(),
+            stream: stream
+                .try_clone()
+                .chain_err(|| "failed to clone TcpStream")?,
+        };
+        clients.insert(addr, client);
+        self.connections.inc();
+        Ok(true)
+    }
+
+    fn received(&self, addr: &SocketAddr, bytes: usize) {
+        self.requests.inc();
+        self.bytes_received.inc_by(bytes as i64);
+        if let Some(client) = self.clients.lock().unwrap().get_mut(addr) {
+            client.stats.requests += 1;
+            client.stats.bytes_received += bytes as u64;
+        }
+    }
+
+    fn sent(&self, addr: &SocketAddr, bytes: usize) {
+        self.bytes_sent.inc_by(bytes as i64);
+        if let Some(client) = self.clients.lock().unwrap().get_mut(addr) {
+            client.stats.bytes_sent += bytes as u64;
+        }
+    }
+
+    /// Records the client's `server.version` parameters, returning its metric labels.
+    fn identified(
+        &self,
+        addr: &SocketAddr,
+        client_id: &str,
+        client_version: &str,
+    ) -> (String, String) {
+        let mut software = software_label(client_id);
+        {
+            let mut labels = self.software_labels.lock().unwrap();
+            if !labels.contains(&software) {
+                if labels.len() < MAX_SOFTWARE_LABELS {
+                    labels.insert(software.clone());
+                } else {
+                    software = "other".to_owned();
+                }
+            }
+        }
+        let protocol = protocol_label(client_version).to_owned();
+        self.client_versions
+            .with_label_values(&[&software, &protocol])
+            .inc();
+        if let Some(client) = self.clients.lock().unwrap().get_mut(addr) {
+            client.stats.software = Some(client_id.chars().take(MAX_VERSION_LEN).collect());
+            client.stats.protocol = Some(client_version.chars().take(MAX_VERSION_LEN).collect());
+        }
+        (software, protocol)
+    }
+
+    fn unidentified(&self, software: &str, protocol: &str) {
+        self.client_versions
+            .with_label_values(&[software, protocol])
+            .dec();
+    }
+
+    fn subscribed(&self, addr: &SocketAddr) {
+        self.subscriptions.inc();
+        if let Some(client) = self.clients.lock().unwrap().get_mut(addr) {
+            client.stats.subscriptions += 1;
+        }
+    }
+
+    fn unsubscribed(&self, addr: &SocketAddr, count: usize) {
+        self.subscriptions.sub(count as i64);
+        if let Some(client) = self.clients.lock().unwrap().get_mut(addr) {
+            client.stats.subscriptions -= count;
+        }
+    }
 }
 
 impl RPC {
@@ -612,12 +1065,17 @@ impl RPC {
         });
     }
 
-    fn start_acceptor(addr: SocketAddr) -> Channel<Option<(TcpStream, SocketAddr)>> {
+    fn start_acceptor(
+        addr: SocketAddr,
+        listener: Option<TcpListener>,
+    ) -> Channel<Option<(TcpStream, SocketAddr)>> {
         let chan = Channel::unbounded();
         let acceptor = chan.sender();
         spawn_thread("acceptor", move || {
-            let listener =
-                TcpListener::bind(addr).unwrap_or_else(|e| panic!("bind({}) failed: {}", addr, e));
+            let listener = listener.unwrap_or_else(|| {
+                TcpListener::bind(addr).unwrap_or_else(|e| panic!("bind({}) failed: {}", addr, e))
+            });
+            let addr = listener.local_addr().unwrap_or(addr);
             info!(
                 "Electrum RPC server running on {} (protocol {})",
                 addr, PROTOCOL_VERSION
@@ -633,43 +1091,78 @@ impl RPC {
         chan
     }
 
-    pub fn start(addr: SocketAddr, query: Arc<Query>, metrics: &Metrics, relayfee: f64) -> RPC {
-        let stats = Arc::new(Stats {
-            latency: metrics.histogram_vec(
-                HistogramOpts::new("electrs_electrum_rpc", "Electrum RPC latency (seconds)"),
-                &["method"],
-            ),
-            subscriptions: metrics.gauge(MetricOpts::new(
-                "electrs_electrum_subscriptions",
-                "# of Electrum subscriptions",
-            )),
-        });
-        stats.subscriptions.set(0);
+    /// Listens on the configured address, unless an already bound `listener` is given (e.g. by
+    /// systemd).
+    pub fn start(
+        config: &Config,
+        listener: Option<TcpListener>,
+        query: Arc<Query>,
+        metrics: &Metrics,
+        relayfee: f64,
+        waker: Waker,
+    ) -> RPC {
+        let addr = config.electrum_rpc_addr;
+        let write_timeout = config.electrum_write_timeout;
+        let limiter = Arc::new(RequestLimiter::new(
+            config.electrum_rpc_threads,
+            config.electrum_rpc_queue_size,
+            metrics,
+        ));
+        let stats = Arc::new(Stats::new(
+            metrics,
+            ConnectionLimits {
+                max_per_ip: config.max_connections_per_ip,
+                exempt_ips: config.connection_limit_exempt_ips.clone(),
+            },
+        ));
         let notification = Channel::unbounded();
 
         RPC {
             notification: notification.sender(),
+            stats: Arc::clone(&stats),
+            limiter: Arc::clone(&limiter),
+            shutdown_timeout: config.shutdown_timeout,
             server: Some(spawn_thread("rpc", move || {
                 let senders = Arc::new(Mutex::new(Vec::<SyncSender<Message>>::new()));
 
-                let acceptor = RPC::start_acceptor(addr);
+                let acceptor = RPC::start_acceptor(addr, listener);
                 RPC::start_notifier(notification, senders.clone(), acceptor.sender());
 
                 let mut threads = HashMap::new();
                 let (garbage_sender, garbage_receiver) = crossbeam_channel::unbounded();
 
                 while let Some((stream, addr)) = acceptor.receiver().recv().unwrap() {
+                    match stats.register_client(&stream, addr) {
+                        Ok(true) => (),
+                        Ok(false) => {
+                            warn!("[{}] too many connections from {}", addr, addr.ip());
+                            let _ = stream.shutdown(Shutdown::Both);
+                            continue;
+                        }
+                        Err(e) => {
+                            error!("[{}] failed to register: {}", addr, e.display_chain());
+                            let _ = stream.shutdown(Shutdown::Both);
+                            continue;
+                        }
+                    }
+                    if let Err(e) = stream.set_write_timeout(write_timeout) {
+                        warn!("[{}] failed to set write timeout: {}", addr, e);
+                    }
                     // explicitely scope the shadowed variables for the new thread
                     let query = Arc::clone(&query);
                     let stats = Arc::clone(&stats);
+                    let limiter = Arc::clone(&limiter);
                     let garbage_sender = garbage_sender.clone();
+                    let waker = waker.clone();
                     let (sender, receiver) = mpsc::sync_channel(10);
 
                     senders.lock().unwrap().push(sender.clone());
 
                     let spawned = spawn_thread("peer", move || {
                         info!("[{}] connected peer", addr);
-                        let conn = Connection::new(query, stream, addr, stats, relayfee, sender);
+                        let conn = Connection::new(
+                            query, stream, addr, stats, limiter, relayfee, sender, waker,
+                        );
                         conn.run(receiver);
                         info!("[{}] disconnected peer", addr);
                         let _ = garbage_sender.send(std::thread::current().id());
@@ -705,6 +1198,56 @@ impl RPC {
     pub fn notify(&self) {
         self.notification.send(Notification::Periodic).unwrap();
     }
+
+    /// Applies the reloaded connection limits (affecting new connections) and request queue size.
+    pub fn reload(&self, config: &ReloadableConfig) {
+        *self.stats.limits.lock().unwrap() = ConnectionLimits {
+            max_per_ip: config.max_connections_per_ip,
+            exempt_ips: config.connection_limit_exempt_ips.clone(),
+        };
+        self.limiter
+            .max_queued
+            .store(config.electrum_rpc_queue_size, Ordering::Relaxed);
+    }
+
+    pub fn clients(&self) -> Clients {
+        Clients {
+            stats: Arc::clone(&self.stats),
+        }
+    }
+}
+
+/// Allows inspecting and disconnecting the connected clients (e.g. via the admin socket).
+#[derive(Clone)]
+pub struct Clients {
+    stats: Arc<Stats>,
+}
+
+impl Clients {
+    /// Returns the traffic of the currently connected clients, busiest first.
+    pub fn list(&self) -> Vec<(SocketAddr, ClientStats)> {
+        let clients = self.stats.clients.lock().unwrap();
+        let mut clients: Vec<(SocketAddr, ClientStats)> = clients
+            .iter()
+            .map(|(addr, client)| (*addr, client.stats.clone()))
+            .collect();
+        clients.sort_unstable_by_key(|(_, client)| {
+            cmp::Reverse(client.bytes_received + client.bytes_sent)
+        });
+        clients
+    }
+
+    /// Closes the client's connection, returning false if it's not connected.
+    pub fn disconnect(&self, addr: &SocketAddr) -> bool {
+        match self.stats.clients.lock().unwrap().get(addr) {
+            Some(client) => {
+                info!("[{}] disconnecting peer", addr);
+                let _ = client.stream.shutdown(Shutdown::Both);
+                true
+            }
+            None => false,
+        }
+    }
 }
 
 impl Drop for RPC {
@@ -712,8 +1255,131 @@ impl Drop for RPC {
         trace!("stop accepting new RPCs");
         self.notification.send(Notification::Exit).unwrap();
         if let Some(handle) = self.server.take() {
-            handle.join().unwrap();
+            // in-flight requests are handled before the connections are closed
+            let (done_sender, done_receiver) = mpsc::channel();
+            spawn_thread("rpc_join", move || {
+                handle.join().unwrap();
+                let _ = done_sender.send(());
+            });
+            if done_receiver.recv_timeout(self.shutdown_timeout).is_err() {
+                let clients = self.stats.clients.lock().unwrap();
+                warn!(
+                    "RPC server didn't stop within {:?}, closing {} connections",
+                    self.shutdown_timeout,
+                    clients.len()
+                );
+                // fail the clients' pending reads and writes, so their threads (and queries) exit
+                for client in clients.values() {
+                    let _ = client.stream.shutdown(Shutdown::Both);
+                }
+                drop(clients);
+                if done_receiver.recv_timeout(self.shutdown_timeout).is_err() {
+                    warn!(
+                        "RPC connections didn't close within {:?}",
+                        self.shutdown_timeout
+                    );
+                    return;
+                }
+            }
         }
         trace!("RPC server is stopped");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_labels() {
+        assert_eq!(software_label("electrum/4.5.4"), "electrum");
+        assert_eq!(software_label("Sparrow"), "sparrow");
+        assert_eq!(software_label("BlueWallet 6.0"), "bluewallet");
+        assert_eq!(software_label("\"}{; "), "unknown");
+        assert_eq!(software_label(&"x".repeat(100)), "x".repeat(32));
+        assert_eq!(protocol_label("1.4"), "1.4");
+        assert_eq!(protocol_label("1.4.2"), "1.4.2");
+        assert_eq!(protocol_label("1.0"), "1.0");
+        assert_eq!(protocol_label("1.5"), "other");
+        assert_eq!(protocol_label("1.4.99999"), "other");
+        assert_eq!(protocol_label(""), "other");
+        assert_eq!(protocol_label("1.4; drop"), "other");
+    }
+
+    #[test]
+    fn test_request_limiter() {
+        let dummy_metrics = Metrics::new("127.0.0.1:60000".parse().unwrap());
+        let limiter = RequestLimiter::new(2, 0, &dummy_metrics);
+        let first = limiter.acquire().unwrap();
+        let _second = limiter.acquire().unwrap();
+        match limiter.acquire() {
+            Err(Error(ErrorKind::Overloaded, _)) => (),
+            _ => panic!("expected overload"),
+        }
+        assert_eq!(limiter.rejected.get(), 1);
+
+        drop(first);
+        let _third = limiter.acquire().unwrap();
+    }
+
+    #[test]
+    fn test_connection_limits() {
+        let dummy_metrics = Metrics::new("127.0.0.1:60000".parse().unwrap());
+        let limits = ConnectionLimits {
+            max_per_ip: 2,
+            exempt_ips: vec!["10.0.0.2".parse().unwrap()],
+        };
+        let stats = Stats::new(&dummy_metrics, limits);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let register = |addr: &str| {
+            stats
+                .register_client(&stream, addr.parse().unwrap())
+                .unwrap()
+        };
+
+        assert!(register("10.0.0.1:1001"));
+        assert!(register("10.0.0.1:1002"));
+        assert!(!register("10.0.0.1:1003")); // over the limit
+        assert!(register("10.0.0.3:1001")); // the limit is per IP
+        for port in 1001..1004 {
+            assert!(register(&format!("10.0.0.2:{}", port))); // exempt
+        }
+        assert_eq!(stats.rejected_connections.get(), 1);
+        assert_eq!(stats.connections.get(), 6);
+
+        // disconnecting a client allows a new connection from its IP
+        stats
+            .clients
+            .lock()
+            .unwrap()
+            .remove(&"10.0.0.1:1001".parse().unwrap());
+        assert!(register("10.0.0.1:1003"));
+        assert!(!register("10.0.0.1:1004"));
+
+        stats.limits.lock().unwrap().max_per_ip = 0; // no limit
+        assert!(register("10.0.0.1:1004"));
+        assert_eq!(stats.rejected_connections.get(), 2);
+    }
+
+    #[test]
+    fn test_json_rpc_error() {
+        let error: Error =
+            Error::from(ErrorKind::HistoryTooLarge).chain_err(|| "failed to get status");
+        assert_eq!(
+            json_rpc_error_from_error(&error),
+            json!({"code": BAD_REQUEST, "message": "history too large"})
+        );
+        let error: Error =
+            Error::from(ErrorKind::StatusTooLarge(100 << 20)).chain_err(|| "failed to get status");
+        assert_eq!(
+            json_rpc_error_from_error(&error),
+            json!({"code": BAD_REQUEST, "message": "status too large (exceeds 100 MB)"})
+        );
+        let error: Error = Error::from(ErrorKind::Timeout).chain_err(|| "failed to get status");
+        assert_eq!(
+            json_rpc_error_from_error(&error),
+            json!({"code": SERVER_BUSY, "message": "failed to get status"})
+        );
+    }
+}
@@ -1,13 +1,59 @@
 use crossbeam_channel as channel;
-use crossbeam_channel::RecvTimeoutError;
+use crossbeam_channel::TryRecvError;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
 use crate::errors::*;
 
-#[derive(Clone)] // so multiple threads could wait on signals
 pub struct Waiter {
     receiver: channel::Receiver<i32>,
+    wakeup_sender: channel::Sender<()>,     // see `Waker`
+    wakeups: Option<channel::Receiver<()>>, // only for the original waiter (not its clones)
+    reload: Arc<AtomicBool>,                // set by SIGHUP
+    compact: Arc<AtomicBool>,               // set by SIGUSR2
+}
+
+/// So multiple threads could wait on signals. Only the original waiter (i.e. the main loop's)
+/// is woken up by a `Waker`, so the clones' waits (e.g. the daemon's reconnection backoff) are
+/// not cut short.
+impl Clone for Waiter {
+    fn clone(&self) -> Waiter {
+        Waiter {
+            receiver: self.receiver.clone(),
+            wakeup_sender: self.wakeup_sender.clone(),
+            wakeups: None,
+            reload: Arc::clone(&self.reload),
+            compact: Arc::clone(&self.compact),
+        }
+    }
+}
+
+/// Wakes up the original `Waiter::wait()` call (i.e. the main loop's, for syncing immediately
+/// after a new transaction is broadcast) - like `SIGUSR1`.
+#[derive(Clone)]
+pub struct Waker {
+    wakeups: channel::Sender<()>,
+    reload: Arc<AtomicBool>,
+    compact: Arc<AtomicBool>,
+}
+
+impl Waker {
+    pub fn wake(&self) {
+        // a pending wakeup is enough, so it's fine if the channel is full
+        let _ = self.wakeups.try_send(());
+    }
+    /// Requests a configuration reload from the main loop - like `SIGHUP`.
+    pub fn reload(&self) {
+        self.reload.store(true, Ordering::SeqCst);
+        self.wake();
+    }
+    /// Requests a DB compaction from the main loop - like `SIGUSR2`.
+    pub fn compact(&self) {
+        self.compact.store(true, Ordering::SeqCst);
+        self.wake();
+    }
 }
 
 fn notify(signals: &[i32]) -> channel::Receiver<i32> {
@@ -25,28 +71,111 @@ fn notify(signals: &[i32]) -> channel::Receiver<i32> {
 
 impl Waiter {
     pub fn start() -> Waiter {
+        let (wakeup_sender, wakeups) = channel::bounded(1);
         Waiter {
             receiver: notify(&[
                 signal_hook::SIGINT,
                 signal_hook::SIGTERM,
                 signal_hook::SIGUSR1, // allow external triggering (e.g. via bitcoind `blocknotify`)
+                signal_hook::SIGUSR2, // flush and compact the DB
+                signal_hook::SIGHUP,  // reload configuration
             ]),
+            wakeup_sender,
+            wakeups: Some(wakeups),
+            reload: Arc::new(AtomicBool::new(false)),
+            compact: Arc::new(AtomicBool::new(false)),
+        }
+    }
+    /// Isn't notified by signals (e.g. for connecting before the main loop's waiter is started).
+    pub fn idle() -> Waiter {
+        let (wakeup_sender, wakeups) = channel::bounded(1);
+        Waiter {
+            receiver: channel::never(),
+            wakeup_sender,
+            wakeups: Some(wakeups),
+            reload: Arc::new(AtomicBool::new(false)),
+            compact: Arc::new(AtomicBool::new(false)),
         }
     }
+    fn on_signal(&self, sig: i32) -> Result<()> {
+        trace!("notified via SIG{}", sig);
+        match sig {
+            signal_hook::SIGUSR1 => (),
+            signal_hook::SIGUSR2 => self.compact.store(true, Ordering::SeqCst),
+            signal_hook::SIGHUP => self.reload.store(true, Ordering::SeqCst),
+            _ => bail!(ErrorKind::Interrupt(sig)),
+        };
+        Ok(())
+    }
+    /// Waits for a signal or a wakeup (see `Waker`), up to `duration`.
     pub fn wait(&self, duration: Duration) -> Result<()> {
-        match self.receiver.recv_timeout(duration) {
-            Ok(sig) => {
-                trace!("notified via SIG{}", sig);
-                if sig != signal_hook::SIGUSR1 {
-                    bail!(ErrorKind::Interrupt(sig))
-                };
+        let never = channel::never();
+        let wakeups = self.wakeups.as_ref().unwrap_or(&never);
+        channel::select! {
+            recv(self.receiver) -> sig => match sig {
+                Ok(sig) => self.on_signal(sig),
+                Err(_) => bail!("signal hook channel disconnected"),
+            },
+            recv(wakeups) -> _ => {
+                trace!("woken up");
                 Ok(())
-            }
-            Err(RecvTimeoutError::Timeout) => Ok(()),
-            Err(RecvTimeoutError::Disconnected) => bail!("signal hook channel disconnected"),
+            },
+            default(duration) => Ok(()),
         }
     }
+    /// Checks for a pending signal (without consuming the pending wakeups).
     pub fn poll(&self) -> Result<()> {
-        self.wait(Duration::from_secs(0))
+        match self.receiver.try_recv() {
+            Ok(sig) => self.on_signal(sig),
+            Err(TryRecvError::Empty) => Ok(()),
+            Err(TryRecvError::Disconnected) => bail!("signal hook channel disconnected"),
+        }
+    }
+    pub fn waker(&self) -> Waker {
+        Waker {
+            wakeups: self.wakeup_sender.clone(),
+            reload: Arc::clone(&self.reload),
+            compact: Arc::clone(&self.compact),
+        }
+    }
+    /// Returns whether a configuration reload was requested since the last call.
+    pub fn take_reload(&self) -> bool {
+        self.reload.swap(false, Ordering::SeqCst)
+    }
+    /// Returns whether a DB compaction was requested since the last call.
+    pub fn take_compact(&self) -> bool {
+        self.compact.swap(false, Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn test_wakeups() {
+        let waiter = Waiter::start();
+        let clone = waiter.clone();
+        waiter.waker().wake();
+
+        let start = Instant::now();
+        clone.wait(Duration::from_millis(100)).unwrap(); // not woken up
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        let start = Instant::now();
+        waiter.wait(Duration::from_secs(10)).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        assert!(!waiter.take_reload());
+        waiter.waker().reload();
+        assert!(clone.take_reload());
+        assert!(!waiter.take_reload());
+        assert!(!waiter.take_compact());
+        waiter.waker().compact();
+        assert!(waiter.take_compact());
+        let start = Instant::now();
+        waiter.wait(Duration::from_secs(10)).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(10));
     }
 }
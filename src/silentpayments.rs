@@ -0,0 +1,279 @@
+//! Silent payments (BIP352) tweak data, for wallets scanning blocks client-side.
+//!
+//! An eligible transaction's tweak is `input_hash·A`, where `A` is the sum of its inputs' public
+//! keys and `input_hash` commits to `A` and the transaction's smallest outpoint. A wallet
+//! multiplies each tweak by its scan key to find its outputs, without fetching the spent
+//! transactions (which are needed for extracting the public keys).
+
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::{Transaction, TxIn};
+use bitcoin::consensus::encode::serialize;
+use bitcoin::hashes::{hash160, sha256, Hash, HashEngine};
+use bitcoin::secp256k1::{PublicKey, Secp256k1, Verification};
+
+use crate::errors::*;
+
+/// BIP341's provably unspendable internal key, used by script-path only taproot outputs.
+const NUMS_H: [u8; 32] = [
+    0x50, 0x92, 0x9b, 0x74, 0xc1, 0xa0, 0x49, 0x54, 0xb7, 0x8b, 0x4b, 0x60, 0x35, 0xe9, 0x7a, 0x5e,
+    0x07, 0x8a, 0x5a, 0x0f, 0x28, 0xec, 0x96, 0xd5, 0x47, 0xbf, 0xee, 0x9a, 0xce, 0x80, 0x3a, 0xc0,
+];
+
+const TAPROOT_ANNEX_PREFIX: u8 = 0x50;
+
+fn is_p2tr(script: &Script) -> bool {
+    let bytes = script.as_bytes();
+    bytes.len() == 34 && bytes[0] == 0x51 && bytes[1] == 0x20
+}
+
+/// Segwit versions above 1 may use other key types, so such spends make the transaction ineligible.
+fn is_future_witness_program(script: &Script) -> bool {
+    let version = script.as_bytes().first().cloned().unwrap_or(0);
+    script.is_witness_program() && version >= 0x52
+}
+
+fn tagged_hash(tag: &[u8], data: &[&[u8]]) -> sha256::Hash {
+    let tag_hash = sha256::Hash::hash(tag);
+    let mut engine = sha256::Hash::engine();
+    engine.input(&tag_hash[..]);
+    engine.input(&tag_hash[..]);
+    for item in data {
+        engine.input(item);
+    }
+    sha256::Hash::from_engine(engine)
+}
+
+/// Returns the public key of an input spending `prevout`, if it's eligible for silent payments.
+fn input_public_key(input: &TxIn, prevout: &Script) -> Option<PublicKey> {
+    let witness: Vec<&[u8]> = input.witness.iter().map(|item| &item[..]).collect();
+    let script_sig = input.script_sig.as_bytes();
+    let key: &[u8] = if prevout.is_p2pkh() {
+        // the scriptSig may be malleated, so look for the last key matching the script's hash
+        let key_hash = &prevout.as_bytes()[3..23];
+        (33..=script_sig.len())
+            .rev()
+            .map(|end| &script_sig[end - 33..end])
+            .find(|key| &hash160::Hash::hash(key)[..] == key_hash)?
+    } else if prevout.is_p2sh() {
+        // only P2SH-wrapped P2WPKH is eligible
+        if script_sig.len() != 23 || !Script::from(script_sig[1..].to_vec()).is_v0_p2wpkh() {
+            return None;
+        }
+        witness.last()?
+    } else if prevout.is_v0_p2wpkh() {
+        witness.last()?
+    } else if is_p2tr(prevout) {
+        let mut stack = &witness[..];
+        if stack.len() > 1 && stack.last()?.first() == Some(&TAPROOT_ANNEX_PREFIX) {
+            stack = &stack[..stack.len() - 1];
+        }
+        if stack.is_empty() {
+            return None;
+        }
+        if stack.len() > 1 {
+            // script path spend: the control block starts with the internal key
+            let control_block = stack.last()?;
+            if control_block.get(1..33) == Some(&NUMS_H[..]) {
+                return None;
+            }
+        }
+        let mut key = vec![0x02]; // the output key has an even Y coordinate
+        key.extend_from_slice(&prevout.as_bytes()[2..]);
+        return PublicKey::from_slice(&key).ok();
+    } else {
+        return None;
+    };
+    // uncompressed keys are not eligible
+    if key.len() == 33 {
+        PublicKey::from_slice(key).ok()
+    } else {
+        None
+    }
+}
+
+/// Returns the tweak of `txn` (spending `prevouts`), if it's eligible for silent payments.
+pub fn transaction_tweak<C: Verification>(
+    secp: &Secp256k1<C>,
+    txn: &Transaction,
+    prevouts: &[Script],
+) -> Option<PublicKey> {
+    if txn.is_coin_base()
+        || !txn
+            .output
+            .iter()
+            .any(|output| is_p2tr(&output.script_pubkey))
+        || prevouts.iter().any(is_future_witness_program)
+    {
+        return None;
+    }
+    let mut keys = txn
+        .input
+        .iter()
+        .zip(prevouts)
+        .filter_map(|(input, prevout)| input_public_key(input, prevout));
+    let first = keys.next()?;
+    let sum = keys.try_fold(first, |sum, key| sum.combine(&key)).ok()?; // may be infinity
+    let smallest_outpoint = txn
+        .input
+        .iter()
+        .map(|input| serialize(&input.previous_output))
+        .min()?;
+    let input_hash = tagged_hash(
+        b"BIP0352/Inputs",
+        &[&smallest_outpoint, &sum.serialize()[..]],
+    );
+    let mut tweak = sum;
+    tweak.mul_assign(secp, &input_hash[..]).ok()?; // may be out of range
+    Some(tweak)
+}
+
+/// Returns the tweaks of a block's eligible transactions, each one given with the scripts
+/// spent by its inputs.
+pub fn block_tweaks(txns: &[(Transaction, Vec<Script>)]) -> Result<Vec<PublicKey>> {
+    let secp = Secp256k1::verification_only();
+    let mut tweaks = vec![];
+    for (txn, prevouts) in txns {
+        if !txn.is_coin_base() && txn.input.len() != prevouts.len() {
+            bail!(
+                "tx {} has {} inputs, but {} prevouts",
+                txn.txid(),
+                txn.input.len(),
+                prevouts.len()
+            );
+        }
+        tweaks.extend(transaction_tweak(&secp, txn, prevouts));
+    }
+    Ok(tweaks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::opcodes::all::{OP_CHECKSIG, OP_DUP, OP_EQUALVERIFY, OP_HASH160};
+    use bitcoin::blockdata::script::Builder;
+    use bitcoin::blockdata::transaction::{OutPoint, TxOut};
+    use bitcoin::hash_types::Txid;
+    use bitcoin::secp256k1::{All, SecretKey};
+
+    fn p2tr(key: &PublicKey) -> Script {
+        let mut script = vec![0x51, 0x20];
+        script.extend_from_slice(&key.serialize()[1..]);
+        Script::from(script)
+    }
+
+    fn p2wpkh(key: &PublicKey) -> Script {
+        let mut script = vec![0x00, 0x14];
+        script.extend_from_slice(&hash160::Hash::hash(&key.serialize())[..]);
+        Script::from(script)
+    }
+
+    fn p2pkh(key: &PublicKey) -> Script {
+        Builder::new()
+            .push_opcode(OP_DUP)
+            .push_opcode(OP_HASH160)
+            .push_slice(&hash160::Hash::hash(&key.serialize())[..])
+            .push_opcode(OP_EQUALVERIFY)
+            .push_opcode(OP_CHECKSIG)
+            .into_script()
+    }
+
+    fn input(vout: u32, witness: &[&[u8]]) -> TxIn {
+        let mut input = TxIn {
+            previous_output: OutPoint {
+                txid: Txid::from_slice(&[vout as u8; 32]).unwrap(),
+                vout,
+            },
+            ..TxIn::default()
+        };
+        for item in witness {
+            input.witness.push(item.to_vec());
+        }
+        input
+    }
+
+    /// Computes `input_hash·(a_1 + ... + a_n)·G` using scalar arithmetic.
+    fn expected_tweak(
+        secp: &Secp256k1<All>,
+        secrets: &[SecretKey],
+        outpoint: &OutPoint,
+    ) -> PublicKey {
+        let mut sum = secrets[0];
+        for secret in &secrets[1..] {
+            sum.add_assign(&secret[..]).unwrap();
+        }
+        let sum_key = PublicKey::from_secret_key(secp, &sum);
+        let input_hash = tagged_hash(
+            b"BIP0352/Inputs",
+            &[&serialize(outpoint), &sum_key.serialize()[..]],
+        );
+        sum.mul_assign(&input_hash[..]).unwrap();
+        PublicKey::from_secret_key(secp, &sum)
+    }
+
+    #[test]
+    fn test_transaction_tweak() {
+        let secp = Secp256k1::new();
+        let secret = |byte| SecretKey::from_slice(&[byte; 32]).unwrap();
+        let (a1, a2) = (secret(1), secret(2));
+        let key1 = PublicKey::from_secret_key(&secp, &a1);
+        let key2 = PublicKey::from_secret_key(&secp, &a2);
+        let signature = [0x30; 71];
+        let mut txn = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![
+                input(7, &[&signature, &key1.serialize()]),
+                input(3, &[&signature, &key2.serialize()]),
+            ],
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: p2tr(&key1),
+            }],
+        };
+        let smallest = txn.input[1].previous_output;
+        let both = expected_tweak(&secp, &[a1, a2], &smallest);
+        let prevouts = vec![p2wpkh(&key1), p2wpkh(&key2)];
+        assert_eq!(transaction_tweak(&secp, &txn, &prevouts), Some(both));
+
+        // the key is found in a (malleated) P2PKH scriptSig
+        txn.input[1] = input(3, &[]);
+        txn.input[1].script_sig = Builder::new()
+            .push_slice(&signature)
+            .push_slice(&key2.serialize())
+            .push_int(0)
+            .into_script();
+        let prevouts = vec![p2wpkh(&key1), p2pkh(&key2)];
+        assert_eq!(transaction_tweak(&secp, &txn, &prevouts), Some(both));
+
+        // uncompressed keys are skipped, but their outpoints are still committed to
+        txn.input[1] = input(3, &[&signature, &key2.serialize_uncompressed()]);
+        let prevouts = vec![p2wpkh(&key1), p2wpkh(&key2)];
+        let first = expected_tweak(&secp, &[a1], &smallest);
+        assert_eq!(transaction_tweak(&secp, &txn, &prevouts), Some(first));
+
+        // taproot key path spends use the output key with an even Y coordinate
+        txn.input[1] = input(3, &[&[0x40; 64]]);
+        let mut a2_even = a2;
+        if key2.serialize()[0] == 0x03 {
+            a2_even.negate_assign();
+        }
+        let prevouts = vec![p2wpkh(&key1), p2tr(&key2)];
+        let both_even = expected_tweak(&secp, &[a1, a2_even], &smallest);
+        assert_eq!(transaction_tweak(&secp, &txn, &prevouts), Some(both_even));
+
+        // script path spends of NUMS_H internal keys are skipped
+        let mut control_block = vec![0xc0];
+        control_block.extend_from_slice(&NUMS_H);
+        txn.input[1] = input(3, &[&signature, &[0x51], &control_block]);
+        assert_eq!(transaction_tweak(&secp, &txn, &prevouts), Some(first));
+
+        // spending a segwit v2 output makes the transaction ineligible
+        let prevouts = vec![p2wpkh(&key1), Script::from(vec![0x52, 0x02, 0xab, 0xcd])];
+        assert_eq!(transaction_tweak(&secp, &txn, &prevouts), None);
+
+        // as does not having taproot outputs
+        let prevouts = vec![p2wpkh(&key1), p2wpkh(&key2)];
+        txn.output[0].script_pubkey = p2wpkh(&key1);
+        assert_eq!(transaction_tweak(&secp, &txn, &prevouts), None);
+    }
+}
@@ -0,0 +1,556 @@
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::hash_types::{BlockHash, Txid};
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::util::amount::{Amount, SignedAmount};
+use sha2::{Digest, Sha256};
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::sync::Mutex;
+
+use crate::errors::*;
+use crate::index::compute_script_hash;
+use crate::types::{Confirmations, Height, HistoryToken, ScriptHash, StatusHash};
+use crate::util::{hash_prefix, HashPrefix};
+
+pub struct FundingOutput {
+    pub txn_id: Txid,
+    pub height: u32,
+    pub output_index: usize,
+    pub value: u64,
+    pub is_coinbase: bool,
+}
+
+pub type OutPoint = (Txid, usize); // (txid, output_index)
+
+pub struct SpendingInput {
+    pub txn_id: Txid,
+    pub height: u32,
+    pub funding_output: OutPoint,
+    pub value: u64,
+}
+
+pub struct Status {
+    confirmed: (Vec<FundingOutput>, Vec<SpendingInput>),
+    mempool: (Vec<FundingOutput>, Vec<SpendingInput>),
+    txn_fees: HashMap<Txid, u64>,
+    tip: Mutex<Option<(usize, BlockHash)>>, // the indexed tip, up to which the status is valid
+    hash_cache: Mutex<StatusHashCache>,
+}
+
+/// The SHA256 state after hashing a confirmed history up to a block, for computing the next
+/// status hash incrementally after new blocks are indexed (if that block is not reorged).
+#[derive(Clone)]
+pub struct StatusHashState {
+    sha2: Sha256,
+    entries: usize,
+    height: usize,
+    blockhash: BlockHash,
+}
+
+impl StatusHashState {
+    fn new(height: usize, blockhash: BlockHash) -> StatusHashState {
+        StatusHashState {
+            sha2: Sha256::new(),
+            entries: 0,
+            height,
+            blockhash,
+        }
+    }
+
+    /// The height and hash of the last hashed block.
+    pub fn block(&self) -> (usize, &BlockHash) {
+        (self.height, &self.blockhash)
+    }
+}
+
+#[derive(Default)]
+struct StatusHashCache {
+    state: Option<StatusHashState>,
+    statushash: Option<Option<StatusHash>>,
+}
+
+fn hash_history_entry(sha2: &mut Sha256, entry: &HistoryEntry) {
+    let part = format!("{}:{}:", entry.txid.to_hex(), entry.height);
+    sha2.update(part.as_bytes());
+}
+
+fn to_utxo(out: &FundingOutput) -> Utxo {
+    Utxo {
+        outpoint: bitcoin::OutPoint::new(out.txn_id, out.output_index as u32),
+        value: Amount::from_sat(out.value),
+        height: Height::from_stored(out.height),
+        is_coinbase: out.is_coinbase,
+    }
+}
+
+fn calc_balance((funding, spending): &(Vec<FundingOutput>, Vec<SpendingInput>)) -> i64 {
+    let funded: u64 = funding.iter().map(|output| output.value).sum();
+    let spent: u64 = spending.iter().map(|input| input.value).sum();
+    funded as i64 - spent as i64
+}
+
+const COINBASE_MATURITY: u32 = 100;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Balance {
+    pub confirmed: Amount,         // excluding immature coinbase outputs
+    pub unconfirmed: SignedAmount, // may be negative, when spending confirmed outputs
+    pub immature: Amount,          // coinbase outputs with less than 100 confirmations
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Utxo {
+    pub outpoint: bitcoin::OutPoint,
+    pub value: Amount,
+    pub height: Height,
+    pub is_coinbase: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryEntry {
+    pub txid: Txid,
+    pub height: Height,
+    pub fee: Option<Amount>, // need to be set only for unconfirmed transactions
+}
+
+impl Status {
+    pub fn new(
+        confirmed: (Vec<FundingOutput>, Vec<SpendingInput>),
+        mempool: (Vec<FundingOutput>, Vec<SpendingInput>),
+        txn_fees: HashMap<Txid, u64>,
+        tip: Option<(usize, BlockHash)>,
+        hash_state: Option<StatusHashState>,
+    ) -> Status {
+        Status {
+            confirmed,
+            mempool,
+            txn_fees,
+            tip: Mutex::new(tip),
+            hash_cache: Mutex::new(StatusHashCache {
+                state: hash_state,
+                statushash: None,
+            }),
+        }
+    }
+
+    /// Marks the status as valid up to a new tip (if it's not affected by the new blocks).
+    pub fn advance_tip(&self, tip: Option<(usize, BlockHash)>) {
+        *self.tip.lock().unwrap() = tip;
+    }
+
+    fn funding(&self) -> impl Iterator<Item = &FundingOutput> {
+        self.confirmed.0.iter().chain(self.mempool.0.iter())
+    }
+
+    fn spending(&self) -> impl Iterator<Item = &SpendingInput> {
+        self.confirmed.1.iter().chain(self.mempool.1.iter())
+    }
+
+    pub fn confirmed_balance(&self) -> i64 {
+        calc_balance(&self.confirmed)
+    }
+
+    pub fn mempool_balance(&self) -> i64 {
+        calc_balance(&self.mempool)
+    }
+
+    /// Splits the balance, given the current tip height (for coinbase maturity).
+    pub fn balance(&self, tip_height: u32) -> Balance {
+        let immature: u64 = self
+            .confirmed
+            .0
+            .iter()
+            .filter(|output| {
+                output.is_coinbase
+                    && Height::from_stored(output.height).confirmations(tip_height)
+                        < Confirmations(COINBASE_MATURITY)
+            })
+            .map(|output| output.value)
+            .sum();
+        Balance {
+            confirmed: Amount::from_sat(self.confirmed_balance() as u64 - immature),
+            unconfirmed: SignedAmount::from_sat(self.mempool_balance()),
+            immature: Amount::from_sat(immature),
+        }
+    }
+
+    pub fn history(&self) -> Vec<HistoryEntry> {
+        let mut txns_map = HashMap::<Txid, Height>::new();
+        for f in self.funding() {
+            txns_map.insert(f.txn_id, Height::from_stored(f.height));
+        }
+        for s in self.spending() {
+            txns_map.insert(s.txn_id, Height::from_stored(s.height));
+        }
+        let mut items: Vec<HistoryEntry> = txns_map
+            .into_iter()
+            .map(|item| HistoryEntry {
+                txid: item.0,
+                height: item.1,
+                fee: self.txn_fees.get(&item.0).cloned().map(Amount::from_sat),
+            })
+            .collect();
+        // break ties by txid, for a deterministic status hash
+        items.sort_unstable_by_key(|item| (item.height, item.txid));
+        items
+    }
+
+    pub fn unspent(&self) -> Vec<&FundingOutput> {
+        let mut outputs_map = HashMap::<OutPoint, &FundingOutput>::new();
+        for f in self.funding() {
+            outputs_map.insert((f.txn_id, f.output_index), f);
+        }
+        for s in self.spending() {
+            if outputs_map.remove(&s.funding_output).is_none() {
+                warn!("failed to remove {:?}", s.funding_output);
+            }
+        }
+        let mut outputs = outputs_map
+            .into_iter()
+            .map(|item| item.1) // a reference to unspent output
+            .collect::<Vec<&FundingOutput>>();
+        outputs.sort_unstable_by_key(|out| out.height);
+        outputs
+    }
+
+    pub fn utxos(&self) -> Vec<Utxo> {
+        self.unspent().into_iter().map(to_utxo).collect()
+    }
+
+    /// Confirmed unspent outputs as of block `height` (ignoring later blocks and the mempool),
+    /// sorted by outpoint. Fails if the status was computed before that block was indexed.
+    pub fn utxos_at(&self, height: usize) -> Result<Vec<Utxo>> {
+        match *self.tip.lock().unwrap() {
+            Some((tip_height, _)) if tip_height >= height => (),
+            _ => bail!("status is older than block {}", height),
+        }
+        let height = height as u32;
+        let spent: HashSet<OutPoint> = self
+            .confirmed
+            .1
+            .iter()
+            .filter(|s| s.height <= height)
+            .map(|s| s.funding_output)
+            .collect();
+        let mut utxos: Vec<Utxo> = self
+            .confirmed
+            .0
+            .iter()
+            .filter(|f| f.height <= height && !spent.contains(&(f.txn_id, f.output_index)))
+            .map(to_utxo)
+            .collect();
+        utxos.sort_unstable_by_key(|utxo| (utxo.outpoint.txid, utxo.outpoint.vout));
+        Ok(utxos)
+    }
+
+    /// Approximate memory usage, for caching.
+    pub fn byte_size(&self) -> usize {
+        let outputs = self.confirmed.0.len() + self.mempool.0.len();
+        let inputs = self.confirmed.1.len() + self.mempool.1.len();
+        outputs * mem::size_of::<FundingOutput>()
+            + inputs * mem::size_of::<SpendingInput>()
+            + self.txn_fees.len() * mem::size_of::<(Txid, u64)>()
+            + mem::size_of::<StatusHashState>()
+    }
+
+    /// Returns the Electrum status hash (`None` for an empty history).
+    ///
+    /// The confirmed history is hashed incrementally, continuing from the state of a previous
+    /// status of the same script hash (see `StatusHashState`). Since mempool entries precede
+    /// the confirmed ones, a history with mempool entries is still hashed entirely.
+    pub fn statushash(&self) -> Option<StatusHash> {
+        let mut cache = self.hash_cache.lock().unwrap();
+        if let Some(statushash) = cache.statushash {
+            return statushash;
+        }
+        let txns = self.history();
+        let mempool_len = txns
+            .iter()
+            .take_while(|item| !item.height.is_confirmed())
+            .count();
+        let confirmed = &txns[mempool_len..];
+        // the number of confirmed entries up to (and including) `height`
+        let entries_until = |height: usize| -> usize {
+            confirmed
+                .binary_search_by(|item| {
+                    if item.height.confirmed().unwrap() as usize <= height {
+                        cmp::Ordering::Less
+                    } else {
+                        cmp::Ordering::Greater
+                    }
+                })
+                .unwrap_err()
+        };
+
+        let mut hashed = None; // the SHA256 of the whole history, if already computed
+        let tip = *self.tip.lock().unwrap();
+        if let Some((tip_height, tip_hash)) = tip {
+            let mut state = match cache.state.take() {
+                Some(state)
+                    if state.height <= tip_height
+                        && entries_until(state.height) == state.entries =>
+                {
+                    state
+                }
+                _ => StatusHashState::new(0, tip_hash),
+            };
+            let tip_entries = entries_until(tip_height);
+            for item in &confirmed[state.entries..tip_entries] {
+                hash_history_entry(&mut state.sha2, item);
+            }
+            state.entries = tip_entries;
+            state.height = tip_height;
+            state.blockhash = tip_hash;
+            if mempool_len == 0 && tip_entries == confirmed.len() {
+                hashed = Some(state.sha2.clone());
+            }
+            cache.state = Some(state);
+        }
+        let statushash = if txns.is_empty() {
+            None
+        } else {
+            let sha2 = hashed.unwrap_or_else(|| {
+                let mut sha2 = Sha256::new();
+                for item in &txns {
+                    hash_history_entry(&mut sha2, item);
+                }
+                sha2
+            });
+            Some(StatusHash::from_inner(sha2.finalize().into()))
+        };
+        cache.statushash = Some(statushash);
+        statushash
+    }
+
+    /// Returns up to `limit` history entries, starting at `token` (`HistoryToken::from_height(0)`
+    /// for starting with the mempool entries, or the previous page's `next`). Mempool entries are
+    /// refreshed using the current mempool fees (`get_fee`), since the status may be older:
+    /// evicted transactions are skipped, and fees are updated.
+    pub fn history_page<F>(&self, token: &HistoryToken, limit: usize, get_fee: F) -> HistoryPage
+    where
+        F: Fn(&Txid) -> Option<u64>,
+    {
+        let mut entries = self
+            .history()
+            .into_iter()
+            .skip_while(|entry| entry_token(entry) < *token)
+            .filter_map(|mut entry| {
+                if !entry.height.is_confirmed() {
+                    entry.fee = Some(Amount::from_sat(get_fee(&entry.txid)?));
+                }
+                Some(entry)
+            });
+        let page = entries.by_ref().take(limit).collect();
+        HistoryPage {
+            entries: page,
+            next: entries.next().map(|entry| entry_token(&entry)),
+        }
+    }
+
+    /// Returns the confirmed history's hashing state (if `statushash()` was called), for
+    /// continuing it when the next status of this script hash is computed.
+    pub fn hash_state(&self) -> Option<StatusHashState> {
+        self.hash_cache.lock().unwrap().state.clone()
+    }
+}
+
+/// A page of a history, and the position of the next one (unless it's the last page).
+#[derive(Debug)]
+pub struct HistoryPage {
+    pub entries: Vec<HistoryEntry>,
+    pub next: Option<HistoryToken>,
+}
+
+fn entry_token(entry: &HistoryEntry) -> HistoryToken {
+    HistoryToken {
+        height: entry.height,
+        txid: entry.txid,
+    }
+}
+
+/// The script hashes funded and the outputs spent by added or removed transactions (by their
+/// prefixes, as indexed), for dropping only the affected cached statuses.
+#[derive(Default)]
+pub struct StatusChanges {
+    funded: HashSet<HashPrefix>,       // script hash prefixes
+    spent: HashSet<(HashPrefix, u16)>, // funding txid prefixes and output indices
+}
+
+impl StatusChanges {
+    pub fn add_transaction(&mut self, txn: &Transaction) {
+        for input in &txn.input {
+            let prevout = &input.previous_output;
+            self.spent
+                .insert((hash_prefix(&prevout.txid[..]), prevout.vout as u16));
+        }
+        for output in &txn.output {
+            let script_hash = compute_script_hash(&output.script_pubkey[..]);
+            self.funded.insert(hash_prefix(&script_hash));
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.funded.is_empty() && self.spent.is_empty()
+    }
+
+    /// Returns whether the status of `script_hash` may have changed: if it's funded by one of
+    /// the transactions, or if one of its funding outputs is spent by them.
+    pub fn affects(&self, script_hash: &ScriptHash, status: &Status) -> bool {
+        self.funded.contains(&hash_prefix(script_hash.as_bytes()))
+            || status.funding().any(|output| {
+                let outpoint = (hash_prefix(&output.txn_id[..]), output.output_index as u16);
+                self.spent.contains(&outpoint)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::script::Script;
+    use bitcoin::blockdata::transaction::{OutPoint as TxOutPoint, TxIn, TxOut};
+    use bitcoin::hashes::Hash;
+
+    fn status(heights: &[u32], tip_height: usize, hash_state: Option<StatusHashState>) -> Status {
+        let funding = |(i, height): (usize, u32)| FundingOutput {
+            txn_id: Txid::hash(&i.to_le_bytes()),
+            height,
+            output_index: 0,
+            value: 1,
+            is_coinbase: false,
+        };
+        let (mempool, confirmed) = heights
+            .iter()
+            .cloned()
+            .enumerate()
+            .partition(|(_, height)| *height == 0);
+        let outputs = |heights: Vec<_>| heights.into_iter().map(funding).collect();
+        Status::new(
+            (outputs(confirmed), vec![]),
+            (outputs(mempool), vec![]),
+            HashMap::new(),
+            Some((tip_height, BlockHash::hash(&tip_height.to_le_bytes()))),
+            hash_state,
+        )
+    }
+
+    #[test]
+    fn test_utxos_at() {
+        let mut status = status(&[1, 2, 3, 0], 5, None);
+        let outpoint = |i: usize| (Txid::hash(&i.to_le_bytes()), 0);
+        let spend = |i: usize, height: u32| SpendingInput {
+            txn_id: Txid::hash(&[i as u8]),
+            height,
+            funding_output: outpoint(i),
+            value: 1,
+        };
+        status.confirmed.1 = vec![spend(0, 2), spend(1, 4)];
+        let utxos_at = |height| -> Vec<usize> {
+            let utxos = status.utxos_at(height).unwrap();
+            (0..4)
+                .filter(|i| {
+                    let outpoint = outpoint(*i);
+                    utxos.iter().any(|utxo| utxo.outpoint.txid == outpoint.0)
+                })
+                .collect()
+        };
+        assert_eq!(utxos_at(0), Vec::<usize>::new());
+        assert_eq!(utxos_at(1), vec![0]);
+        assert_eq!(utxos_at(2), vec![1]);
+        assert_eq!(utxos_at(3), vec![1, 2]);
+        assert_eq!(utxos_at(5), vec![2]); // the mempool output is ignored
+        assert!(status.utxos_at(6).is_err());
+    }
+
+    #[test]
+    fn test_incremental_statushash() {
+        assert_eq!(status(&[], 10, None).statushash(), None);
+
+        let first = status(&[1, 2, 2], 2, None);
+        let hash = first.statushash();
+        assert!(hash.is_some());
+        assert_eq!(first.statushash(), hash); // cached
+
+        for heights in &[vec![1, 2, 2, 5], vec![1, 2, 2, 0], vec![1, 2, 2, 3, 9]] {
+            let expected = status(heights, 5, None).statushash();
+            let next = status(heights, 5, first.hash_state());
+            assert_eq!(next.statushash(), expected);
+            let state = next.hash_state().unwrap();
+            assert_eq!(state.block().0, 5);
+            assert_eq!(
+                state.entries,
+                heights.iter().filter(|h| **h > 0 && **h <= 5).count()
+            );
+        }
+        // a state hashing other entries (e.g. reorged) is not continued
+        let other = status(&[1, 3], 3, None);
+        other.statushash();
+        assert_eq!(
+            status(&[1, 2, 2, 5], 5, other.hash_state()).statushash(),
+            status(&[1, 2, 2, 5], 5, None).statushash()
+        );
+    }
+
+    #[test]
+    fn test_history_page() {
+        let status = status(&[0, 1, 2, 2, 3], 3, None);
+        let history = status.history();
+        let confirmed = &history[1..]; // the mempool entry was evicted
+        let no_fee = |_: &Txid| None;
+
+        let first = status.history_page(&HistoryToken::from_height(0), 2, no_fee);
+        assert_eq!(first.entries, &confirmed[..2]);
+        let next = first.next.unwrap();
+        let second = status.history_page(&next, 2, no_fee);
+        assert_eq!(second.entries, &confirmed[2..]);
+        assert!(second.next.is_none());
+
+        let page = status.history_page(&HistoryToken::from_height(2), 10, no_fee);
+        assert_eq!(page.entries, &confirmed[1..]);
+        let page = status.history_page(&HistoryToken::from_height(4), 10, no_fee);
+        assert!(page.entries.is_empty());
+
+        let page = status.history_page(&HistoryToken::from_height(0), 1, |_| Some(123));
+        assert_eq!(page.entries[0].fee, Some(Amount::from_sat(123)));
+    }
+
+    #[test]
+    fn test_status_changes() {
+        let status = status(&[1, 2], 2, None);
+        let script_hash = ScriptHash::from_inner(compute_script_hash(b"script"));
+        let txn = |prevout: TxOutPoint, script: &[u8]| Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: prevout,
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: 1,
+                script_pubkey: Script::from(script.to_vec()),
+            }],
+        };
+        let unrelated = TxOutPoint::new(Txid::hash(b"unrelated"), 0);
+
+        let mut changes = StatusChanges::default();
+        assert!(changes.is_empty());
+        changes.add_transaction(&txn(unrelated, b"other"));
+        assert!(!changes.is_empty());
+        assert!(!changes.affects(&script_hash, &status));
+
+        // funding the script hash
+        changes.add_transaction(&txn(unrelated, b"script"));
+        assert!(changes.affects(&script_hash, &status));
+
+        // spending one of its outputs
+        let mut changes = StatusChanges::default();
+        let funding = TxOutPoint::new(Txid::hash(&1usize.to_le_bytes()), 0);
+        changes.add_transaction(&txn(funding, b"other"));
+        assert!(changes.affects(&script_hash, &status));
+        let other_index = TxOutPoint::new(funding.txid, 1);
+        let mut changes = StatusChanges::default();
+        changes.add_transaction(&txn(other_index, b"other"));
+        assert!(!changes.affects(&script_hash, &status));
+    }
+}
@@ -1,5 +1,6 @@
 use std::path::{Path, PathBuf};
 
+use crate::errors::*;
 use crate::util::Bytes;
 
 #[derive(Clone)]
@@ -14,6 +15,63 @@ impl Row {
     }
 }
 
+/// Rows stored contiguously in a single buffer, which is kept when the batch is cleared - so
+/// bulk indexing can reuse its batches, instead of allocating each row's key and value.
+#[derive(Default)]
+pub struct RowBatch {
+    data: Vec<u8>,
+    rows: Vec<(usize, usize, usize)>, // (key offset, key length, value length)
+}
+
+impl RowBatch {
+    pub fn new() -> RowBatch {
+        RowBatch::default()
+    }
+
+    pub fn push(&mut self, key: &[u8], value: &[u8]) {
+        self.rows.push((self.data.len(), key.len(), value.len()));
+        self.data.extend_from_slice(key);
+        self.data.extend_from_slice(value);
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// Removes the rows, keeping the allocated memory.
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.rows.clear();
+    }
+
+    /// Sorts the rows by key (only their offsets are moved).
+    pub fn sort(&mut self) {
+        let data = &self.data;
+        self.rows
+            .sort_unstable_by(|a, b| data[a.0..a.0 + a.1].cmp(&data[b.0..b.0 + b.1]));
+    }
+
+    /// Returns the (key, value) pairs.
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &[u8])> {
+        self.rows.iter().map(move |&(offset, key_len, value_len)| {
+            let (key, value) = self.data[offset..offset + key_len + value_len].split_at(key_len);
+            (key, value)
+        })
+    }
+}
+
+impl Extend<Row> for RowBatch {
+    fn extend<I: IntoIterator<Item = Row>>(&mut self, rows: I) {
+        for row in rows {
+            self.push(&row.key, &row.value);
+        }
+    }
+}
+
 pub trait ReadStore: Sync {
     fn get(&self, key: &[u8]) -> Option<Bytes>;
     fn scan(&self, prefix: &[u8]) -> Vec<Row>;
@@ -21,14 +79,29 @@ pub trait ReadStore: Sync {
 
 pub trait WriteStore: Sync {
     fn write<I: IntoIterator<Item = Row>>(&self, rows: I);
+    /// Writes a batch of rows (like `write()`), which can be reused afterwards.
+    fn write_batch(&self, rows: &RowBatch);
     fn flush(&self);
 }
 
+// see https://github.com/facebook/rocksdb/blob/master/include/rocksdb/db.h
+const DB_PROPERTIES: &[&str] = &[
+    "rocksdb.estimate-num-keys",
+    "rocksdb.estimate-live-data-size",
+    "rocksdb.total-sst-files-size",
+    "rocksdb.cur-size-all-mem-tables",
+    "rocksdb.estimate-table-readers-mem",
+    "rocksdb.compaction-pending",
+    "rocksdb.num-running-compactions",
+    "rocksdb.background-errors",
+];
+
 #[derive(Clone)]
 struct Options {
     path: PathBuf,
     bulk_import: bool,
     low_memory: bool,
+    read_only: bool,
 }
 
 pub struct DBStore {
@@ -37,17 +110,18 @@ pub struct DBStore {
 }
 
 impl DBStore {
-    fn open_opts(opts: Options) -> Self {
+    fn open_opts(opts: Options) -> Result<Self> {
         debug!("opening DB at {:?}", opts.path);
         let mut db_opts = rocksdb::Options::default();
-        db_opts.create_if_missing(true);
+        db_opts.create_if_missing(!opts.read_only);
         // db_opts.set_keep_log_file_num(10);
         db_opts.set_max_open_files(if opts.bulk_import { 16 } else { 256 });
         db_opts.set_compaction_style(rocksdb::DBCompactionStyle::Level);
         db_opts.set_compression_type(rocksdb::DBCompressionType::Snappy);
         db_opts.set_target_file_size_base(256 << 20);
         db_opts.set_write_buffer_size(256 << 20);
-        db_opts.set_disable_auto_compactions(opts.bulk_import); // for initial bulk load
+        // for initial bulk load (and in order not to modify read-only DB)
+        db_opts.set_disable_auto_compactions(opts.bulk_import || opts.read_only);
         db_opts.set_advise_random_on_open(!opts.bulk_import); // bulk load uses sequential I/O
         if !opts.low_memory {
             db_opts.set_compaction_readahead_size(1 << 20);
@@ -55,18 +129,34 @@ impl DBStore {
 
         let mut block_opts = rocksdb::BlockBasedOptions::default();
         block_opts.set_block_size(if opts.low_memory { 256 << 10 } else { 1 << 20 });
-        DBStore {
-            db: rocksdb::DB::open(&db_opts, &opts.path).unwrap(),
-            opts,
-        }
+        let db = if opts.read_only {
+            // doesn't take the DB lock, so another process may keep writing to it
+            rocksdb::DB::open_for_read_only(&db_opts, &opts.path, false)
+        } else {
+            rocksdb::DB::open(&db_opts, &opts.path)
+        };
+        let db = db.chain_err(|| format!("failed to open DB at {:?}", opts.path))?;
+        Ok(DBStore { db, opts })
     }
 
     /// Opens a new RocksDB at the specified location.
-    pub fn open(path: &Path, low_memory: bool) -> Self {
+    pub fn open(path: &Path, low_memory: bool) -> Result<Self> {
         DBStore::open_opts(Options {
             path: path.to_path_buf(),
             bulk_import: true,
             low_memory,
+            read_only: false,
+        })
+    }
+
+    /// Opens an existing RocksDB, which is not modified (so it may be used by another process).
+    /// Rows written by the other process after opening are not visible.
+    pub fn open_read_only(path: &Path) -> Result<Self> {
+        DBStore::open_opts(Options {
+            path: path.to_path_buf(),
+            bulk_import: false,
+            low_memory: false,
+            read_only: true,
         })
     }
 
@@ -88,6 +178,41 @@ impl DBStore {
         self
     }
 
+    /// Flushes the memtables to disk and compacts the whole DB, while it's still in use
+    /// (e.g. before taking a filesystem snapshot, or for reclaiming disk space).
+    /// Persists the memtables (unless the DB is read-only), to avoid a long WAL recovery on next
+    /// start.
+    pub fn flush_memtables(&self) {
+        if self.opts.read_only {
+            return;
+        }
+        trace!("flushing DB at {:?}", self.opts.path);
+        if let Err(e) = self.db.flush() {
+            warn!("failed to flush DB at {:?}: {}", self.opts.path, e);
+        }
+    }
+
+    pub fn flush_and_compact(&self) {
+        assert!(!self.opts.read_only, "compacting read-only DB");
+        if let Err(e) = self.db.flush() {
+            warn!("failed to flush DB at {:?}: {}", self.opts.path, e);
+        }
+        info!("starting manual compaction");
+        self.db.compact_range(None::<&[u8]>, None::<&[u8]>); // would take a while
+        info!("finished manual compaction");
+    }
+
+    /// Returns RocksDB's statistics (skipping unavailable ones), e.g. for logging.
+    pub fn properties(&self) -> Vec<(&'static str, String)> {
+        DB_PROPERTIES
+            .iter()
+            .filter_map(|name| match self.db.property_value(name) {
+                Ok(Some(value)) => Some((*name, value)),
+                _ => None,
+            })
+            .collect()
+    }
+
     pub fn iter_scan(&self, prefix: &[u8]) -> ScanIterator {
         ScanIterator {
             prefix: prefix.to_vec(),
@@ -95,6 +220,33 @@ impl DBStore {
             done: false,
         }
     }
+
+    /// Scans the rows matching `prefix`, starting from `start_at` (inclusive).
+    pub fn iter_scan_from(&self, prefix: &[u8], start_at: &[u8]) -> ScanIterator {
+        ScanIterator {
+            prefix: prefix.to_vec(),
+            iter: self.db.iterator(rocksdb::IteratorMode::From(
+                start_at,
+                rocksdb::Direction::Forward,
+            )),
+            done: false,
+        }
+    }
+
+    /// Atomically writes and deletes rows (e.g. for DB format migrations).
+    pub fn update(&self, rows: Vec<Row>, deleted_keys: Vec<Bytes>) {
+        assert!(!self.opts.read_only, "updating read-only DB");
+        let mut batch = rocksdb::WriteBatch::default();
+        for key in deleted_keys {
+            batch.delete(key.as_slice());
+        }
+        for row in rows {
+            batch.put(row.key.as_slice(), row.value.as_slice());
+        }
+        let mut opts = rocksdb::WriteOptions::new();
+        opts.set_sync(true);
+        self.db.write_opt(batch, &opts).unwrap();
+    }
 }
 
 pub struct ScanIterator<'a> {
@@ -146,19 +298,35 @@ impl ReadStore for DBStore {
     }
 }
 
+impl DBStore {
+    fn write_opt(&self, batch: rocksdb::WriteBatch) {
+        assert!(!self.opts.read_only, "writing to read-only DB");
+        let mut opts = rocksdb::WriteOptions::new();
+        opts.set_sync(!self.opts.bulk_import);
+        opts.disable_wal(self.opts.bulk_import);
+        self.db.write_opt(batch, &opts).unwrap();
+    }
+}
+
 impl WriteStore for DBStore {
     fn write<I: IntoIterator<Item = Row>>(&self, rows: I) {
         let mut batch = rocksdb::WriteBatch::default();
         for row in rows {
-            batch.put(row.key.as_slice(), row.value.as_slice()).unwrap();
+            batch.put(row.key.as_slice(), row.value.as_slice());
         }
-        let mut opts = rocksdb::WriteOptions::new();
-        opts.set_sync(!self.opts.bulk_import);
-        opts.disable_wal(self.opts.bulk_import);
-        self.db.write_opt(batch, &opts).unwrap();
+        self.write_opt(batch);
+    }
+
+    fn write_batch(&self, rows: &RowBatch) {
+        let mut batch = rocksdb::WriteBatch::default();
+        for (key, value) in rows.iter() {
+            batch.put(key, value);
+        }
+        self.write_opt(batch);
     }
 
     fn flush(&self) {
+        assert!(!self.opts.read_only, "flushing read-only DB");
         let mut opts = rocksdb::WriteOptions::new();
         opts.set_sync(true);
         opts.disable_wal(false);
@@ -169,6 +337,7 @@ impl WriteStore for DBStore {
 
 impl Drop for DBStore {
     fn drop(&mut self) {
+        self.flush_memtables();
         trace!("closing DB at {:?}", self.opts.path);
     }
 }
@@ -191,3 +360,58 @@ pub fn is_fully_compacted(store: &dyn ReadStore) -> bool {
     let marker = store.get(&full_compaction_marker().key);
     marker.is_some()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_read_only() {
+        let path = std::env::temp_dir().join(format!("electrs-store-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        assert!(DBStore::open_read_only(&path).is_err()); // no DB yet
+
+        let store = DBStore::open(&path, /*low_memory=*/ true).unwrap();
+        store.write(vec![Row {
+            key: b"key".to_vec(),
+            value: b"value".to_vec(),
+        }]);
+        store.flush_and_compact(); // the rows are written without WAL during bulk import
+        assert!(DBStore::open(&path, /*low_memory=*/ true).is_err()); // locked by `store`
+
+        // e.g. `electrs inspect`, while electrs is running
+        let first = DBStore::open_read_only(&path).unwrap();
+        let second = DBStore::open_read_only(&path).unwrap();
+        assert_eq!(first.get(b"key"), Some(b"value".to_vec()));
+        assert_eq!(second.scan(b"k").len(), 1);
+
+        drop((first, second, store));
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn test_row_batch() {
+        let mut batch = RowBatch::new();
+        batch.push(b"b", b"2");
+        batch.push(b"ab", b"");
+        batch.extend(vec![Row {
+            key: b"a".to_vec(),
+            value: b"11".to_vec(),
+        }]);
+        batch.sort();
+        let rows: Vec<(&[u8], &[u8])> = batch.iter().collect();
+        assert_eq!(
+            rows,
+            vec![
+                (&b"a"[..], &b"11"[..]),
+                (&b"ab"[..], &b""[..]),
+                (&b"b"[..], &b"2"[..])
+            ]
+        );
+
+        let capacity = batch.data.capacity();
+        batch.clear();
+        assert!(batch.is_empty());
+        assert_eq!(batch.data.capacity(), capacity);
+    }
+}
@@ -0,0 +1,78 @@
+//! systemd integration (see `man sd_notify` and `man sd_listen_fds`).
+//! Both are no-ops when electrs is not started by systemd.
+
+use std::env;
+use std::net::TcpListener;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::process;
+use std::time::Duration;
+
+use crate::errors::*;
+
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// Sends a state update (e.g. "READY=1") to the service manager.
+pub fn notify(state: &str) {
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+    if path.starts_with('@') {
+        warn!("abstract NOTIFY_SOCKET is not supported: {}", path);
+        return;
+    }
+    let result = UnixDatagram::unbound().and_then(|socket| socket.send_to(state.as_bytes(), &path));
+    if let Err(e) = result {
+        warn!("failed to notify {:?} to {}: {}", state, path, e);
+    }
+}
+
+fn is_current_pid(var: &str) -> bool {
+    env::var(var)
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .map_or(false, |pid| pid == process::id())
+}
+
+/// Returns the service manager's watchdog timeout (`WatchdogSec=`), if it's enabled for this
+/// process - which should then call `notify_watchdog()` more frequently.
+pub fn watchdog_timeout() -> Option<Duration> {
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    if env::var("WATCHDOG_PID").is_ok() && !is_current_pid("WATCHDOG_PID") {
+        return None; // the watchdog is meant for another process
+    }
+    Some(Duration::from_micros(usec))
+}
+
+/// Tells the service manager's watchdog that the main loop is making progress (so it restarts
+/// the process if the loop hangs).
+pub fn notify_watchdog() {
+    notify("WATCHDOG=1");
+}
+
+/// Returns the Electrum listener passed via socket activation, if any.
+pub fn take_listener() -> Result<Option<TcpListener>> {
+    if !is_current_pid("LISTEN_PID") {
+        return Ok(None);
+    }
+    let fds = env::var("LISTEN_FDS")
+        .chain_err(|| "missing LISTEN_FDS")?
+        .parse::<i32>()
+        .chain_err(|| "invalid LISTEN_FDS")?;
+    // don't pass the sockets to child processes
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    match fds {
+        0 => Ok(None),
+        1 => {
+            let listener = unsafe { TcpListener::from_raw_fd(SD_LISTEN_FDS_START) };
+            let addr = listener
+                .local_addr()
+                .chain_err(|| "socket activation requires a TCP listener")?;
+            info!("using socket activation listener at {}", addr);
+            Ok(Some(listener))
+        }
+        _ => bail!("expected a single socket activation listener, got {}", fds),
+    }
+}
@@ -0,0 +1,346 @@
+//! Public types of the Electrum protocol, for library users and tools.
+
+use bitcoin::blockdata::script::Script;
+use bitcoin::hash_types::Txid;
+use bitcoin::hashes::hex::FromHex;
+use bitcoin::util::address::Address;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::errors::*;
+use crate::index::compute_script_hash;
+use crate::util::{FullHash, HASH_LEN};
+
+// Implements hex parsing, formatting and serde for a 32-byte hash newtype, whose hex is
+// byte-reversed if `$reversed` is set.
+macro_rules! hash_type {
+    ($name:ident, $what:expr, $reversed:expr) => {
+        impl $name {
+            /// Wraps a SHA256 hash (in SHA256's byte order, i.e. not reversed).
+            pub fn from_inner(hash: FullHash) -> $name {
+                $name(hash)
+            }
+
+            pub fn into_inner(self) -> FullHash {
+                self.0
+            }
+
+            pub fn as_bytes(&self) -> &[u8] {
+                &self.0[..]
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = Error;
+
+            fn from_str(s: &str) -> Result<$name> {
+                let mut bytes = hex::decode(s).chain_err(|| format!("non-hex {}", $what))?;
+                if bytes.len() != HASH_LEN {
+                    bail!("{} must be {} bytes long", $what, HASH_LEN);
+                }
+                if $reversed {
+                    bytes.reverse();
+                }
+                let mut hash = FullHash::default();
+                hash.copy_from_slice(&bytes);
+                Ok($name(hash))
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                let mut bytes = self.0;
+                if $reversed {
+                    bytes.reverse();
+                }
+                for byte in bytes.iter() {
+                    write!(f, "{:02x}", byte)?;
+                }
+                Ok(())
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{}({})", stringify!($name), self)
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(
+                &self,
+                serializer: S,
+            ) -> std::result::Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(
+                deserializer: D,
+            ) -> std::result::Result<Self, D::Error> {
+                let s = String::deserialize(deserializer)?;
+                s.parse()
+                    .map_err(|e: Error| de::Error::custom(e.to_string()))
+            }
+        }
+    };
+}
+
+/// An [Electrum script hash](https://electrumx-spesmilo.readthedocs.io/en/latest/protocol-basics.html#script-hashes):
+/// the SHA256 of an output script.
+///
+/// It is stored (and indexed, see `as_bytes()`) in SHA256's byte order, but parsed, displayed
+/// and serialized as reversed hex - like the Electrum protocol does.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ScriptHash(FullHash);
+
+hash_type!(ScriptHash, "script hash", true);
+
+impl ScriptHash {
+    pub fn from_script(script: &Script) -> ScriptHash {
+        ScriptHash(compute_script_hash(&script[..]))
+    }
+
+    pub fn from_address(address: &Address) -> ScriptHash {
+        ScriptHash::from_script(&address.script_pubkey())
+    }
+}
+
+/// An [Electrum status hash](https://electrumx-spesmilo.readthedocs.io/en/latest/protocol-basics.html#status):
+/// the SHA256 of a script hash's history (as `txid:height:` entries), as non-reversed hex.
+///
+/// An empty history has no status hash (i.e. `null` in the protocol), so it's returned as
+/// `Option<StatusHash>` (see `Status::statushash()`) - which can be persisted and compared across
+/// restarts, for detecting history changes.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct StatusHash(FullHash);
+
+hash_type!(StatusHash, "status hash", false);
+
+/// A transaction's (or an output's) position in the chain.
+///
+/// Mempool entries sort before confirmed ones (as in `Status::history()`), and are encoded by
+/// the Electrum protocol as `-1` if they spend other mempool transactions' outputs, or `0`
+/// otherwise (see `electrum()`).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Height {
+    Unconfirmed { unconfirmed_inputs: bool },
+    Confirmed(u32),
+}
+
+impl Height {
+    /// Converts a height stored by the index and the mempool tracker (using 0 for mempool
+    /// transactions, whose inputs are not tracked yet).
+    pub fn from_stored(height: u32) -> Height {
+        match height {
+            0 => Height::Unconfirmed {
+                unconfirmed_inputs: false,
+            },
+            height => Height::Confirmed(height),
+        }
+    }
+
+    /// The block height, unless in the mempool.
+    pub fn confirmed(self) -> Option<u32> {
+        match self {
+            Height::Confirmed(height) => Some(height),
+            Height::Unconfirmed { .. } => None,
+        }
+    }
+
+    pub fn is_confirmed(self) -> bool {
+        self.confirmed().is_some()
+    }
+
+    /// Electrum's encoding: the block height, `0` for mempool transactions, or `-1` for
+    /// mempool transactions with unconfirmed inputs.
+    pub fn electrum(self) -> i32 {
+        match self {
+            Height::Confirmed(height) => height as i32,
+            Height::Unconfirmed {
+                unconfirmed_inputs: false,
+            } => 0,
+            Height::Unconfirmed {
+                unconfirmed_inputs: true,
+            } => -1,
+        }
+    }
+
+    /// The number of confirmations, given the current tip height.
+    pub fn confirmations(self, tip_height: u32) -> Confirmations {
+        match self {
+            Height::Confirmed(height) => Confirmations(tip_height.saturating_sub(height) + 1),
+            Height::Unconfirmed { .. } => Confirmations(0),
+        }
+    }
+}
+
+impl fmt::Display for Height {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.electrum())
+    }
+}
+
+/// Serialized using Electrum's encoding (see `Height::electrum()`).
+impl Serialize for Height {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_i32(self.electrum())
+    }
+}
+
+/// The number of confirmations of a transaction (0 while it's in the mempool).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub struct Confirmations(pub u32);
+
+/// A position in a history (as ordered by `Status::history()`), for continuing to page it
+/// (see `Status::history_page()`).
+///
+/// It's formatted as `<height>:<txid>` (using Electrum's height encoding), so it can be passed
+/// to clients and parsed back.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct HistoryToken {
+    pub height: Height,
+    pub txid: Txid,
+}
+
+impl HistoryToken {
+    /// The position of the first entry at `height` (or the first mempool entry, for 0).
+    pub fn from_height(height: u32) -> HistoryToken {
+        let height = match height {
+            0 => Height::Unconfirmed {
+                unconfirmed_inputs: false,
+            },
+            height => Height::Confirmed(height),
+        };
+        HistoryToken {
+            height,
+            txid: Txid::default(),
+        }
+    }
+}
+
+impl FromStr for HistoryToken {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<HistoryToken> {
+        let mut parts = s.splitn(2, ':');
+        let height: i32 = parts
+            .next()
+            .unwrap()
+            .parse()
+            .chain_err(|| "invalid history token height")?;
+        let height = match height {
+            -1 => Height::Unconfirmed {
+                unconfirmed_inputs: true,
+            },
+            0 => Height::Unconfirmed {
+                unconfirmed_inputs: false,
+            },
+            height if height > 0 => Height::Confirmed(height as u32),
+            _ => bail!("invalid history token height {}", height),
+        };
+        let txid = parts.next().chain_err(|| "missing history token txid")?;
+        let txid = Txid::from_hex(txid).chain_err(|| "invalid history token txid")?;
+        Ok(HistoryToken { height, txid })
+    }
+}
+
+impl fmt::Display for HistoryToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.height, self.txid)
+    }
+}
+
+impl Serialize for HistoryToken {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script_hash() {
+        // https://electrumx-spesmilo.readthedocs.io/en/latest/protocol-basics.html#script-hashes
+        let address = Address::from_str("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa").unwrap();
+        let expected = "8b01df4e368ea28f8dc0423bcf7a4923e3a12d307c875e47a0cfbf90b5c39161";
+        let script_hash = ScriptHash::from_address(&address);
+        assert_eq!(script_hash.to_string(), expected);
+        assert_eq!(ScriptHash::from_str(expected).unwrap(), script_hash);
+        assert_eq!(
+            script_hash.as_bytes(),
+            &compute_script_hash(&address.script_pubkey()[..])[..]
+        );
+
+        let json = serde_json::to_value(&script_hash).unwrap();
+        assert_eq!(json, json!(expected));
+        assert_eq!(
+            serde_json::from_value::<ScriptHash>(json).unwrap(),
+            script_hash
+        );
+
+        assert!(ScriptHash::from_str("8b01").is_err());
+        assert!(ScriptHash::from_str(&"x".repeat(64)).is_err());
+    }
+
+    #[test]
+    fn test_status_hash() {
+        let mut hash = FullHash::default();
+        hash[0] = 0xab;
+        let status_hash = StatusHash::from_inner(hash);
+        let hex = format!("ab{}", "00".repeat(31));
+        assert_eq!(status_hash.to_string(), hex); // not reversed
+        assert_eq!(StatusHash::from_str(&hex).unwrap(), status_hash);
+
+        let statuses = vec![Some(status_hash), None];
+        let json = serde_json::to_value(&statuses).unwrap();
+        assert_eq!(json, json!([hex, null]));
+        let parsed: Vec<Option<StatusHash>> = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed, statuses);
+    }
+
+    #[test]
+    fn test_height() {
+        let mempool = Height::from_stored(0);
+        let unconfirmed_inputs = Height::Unconfirmed {
+            unconfirmed_inputs: true,
+        };
+        let confirmed = Height::from_stored(100);
+        assert_eq!(confirmed, Height::Confirmed(100));
+        assert_eq!(
+            json!([unconfirmed_inputs, mempool, confirmed]),
+            json!([-1, 0, 100])
+        );
+        assert!(mempool < confirmed);
+
+        assert_eq!(mempool.confirmations(100), Confirmations(0));
+        assert_eq!(confirmed.confirmations(100), Confirmations(1));
+        assert_eq!(confirmed.confirmations(199), Confirmations(100));
+        assert_eq!(confirmed.confirmations(99), Confirmations(1)); // racing with a reorg
+    }
+
+    #[test]
+    fn test_history_token() {
+        let txid = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+        for height in &["-1", "0", "100"] {
+            let s = format!("{}:{}", height, txid);
+            let token = HistoryToken::from_str(&s).unwrap();
+            assert_eq!(token.to_string(), s);
+            assert_eq!(serde_json::to_value(&token).unwrap(), json!(s));
+        }
+        assert!(
+            HistoryToken::from_height(0) < HistoryToken::from_str(&format!("0:{}", txid)).unwrap()
+        );
+        assert!(
+            HistoryToken::from_height(100)
+                > HistoryToken::from_str(&format!("0:{}", txid)).unwrap()
+        );
+        for s in &["", "100", "-2:00", &format!("x:{}", txid), "1:xyz"] {
+            assert!(HistoryToken::from_str(s).is_err());
+        }
+    }
+}
@@ -1,18 +1,20 @@
 use bitcoin::blockdata::block::BlockHeader;
 use bitcoin::hash_types::BlockHash;
+use bitcoin::hashes::Hash;
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fmt;
 use std::iter::FromIterator;
-use std::slice;
 use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
 use std::thread;
 
+use crate::errors::*;
+
 pub type Bytes = Vec<u8>;
 pub type HeaderMap = HashMap<BlockHash, BlockHeader>;
 
 // TODO: consolidate serialization/deserialize code for bincode/bitcoin.
-const HASH_LEN: usize = 32;
+pub const HASH_LEN: usize = 32;
 pub const HASH_PREFIX_LEN: usize = 8;
 
 pub type FullHash = [u8; HASH_LEN];
@@ -28,6 +30,54 @@ pub fn full_hash(hash: &[u8]) -> FullHash {
     hash.try_into().expect("failed to convert into FullHash")
 }
 
+fn merklize<T: Hash>(left: T, right: T) -> T {
+    let data = [&left[..], &right[..]].concat();
+    <T as Hash>::hash(&data)
+}
+
+/// All the levels of a merkle tree, for extracting the branches of many leaves (e.g. of the
+/// transactions confirmed in the same block) while hashing the tree only once.
+pub struct MerkleTree<T> {
+    levels: Vec<Vec<T>>, // from the leaves to the root (odd levels are padded by their last hash)
+}
+
+impl<T: Hash> MerkleTree<T> {
+    pub fn new(mut hashes: Vec<T>) -> MerkleTree<T> {
+        assert!(!hashes.is_empty(), "empty merkle tree");
+        let mut levels = vec![];
+        while hashes.len() > 1 {
+            if hashes.len() % 2 != 0 {
+                let last = *hashes.last().unwrap();
+                hashes.push(last);
+            }
+            let next = hashes
+                .chunks(2)
+                .map(|pair| merklize(pair[0], pair[1]))
+                .collect();
+            levels.push(hashes);
+            hashes = next;
+        }
+        levels.push(hashes);
+        MerkleTree { levels }
+    }
+
+    pub fn root(&self) -> T {
+        self.levels.last().unwrap()[0]
+    }
+
+    /// Returns the branch of the leaf at `index` (from the leaf's sibling to the root's child).
+    pub fn branch(&self, mut index: usize) -> Vec<T> {
+        let leaves = &self.levels[0];
+        assert!(index < leaves.len(), "leaf #{} out of range", index);
+        let mut merkle = vec![];
+        for level in &self.levels[..self.levels.len() - 1] {
+            merkle.push(level[index ^ 1]);
+            index /= 2;
+        }
+        merkle
+    }
+}
+
 #[derive(Eq, PartialEq, Clone)]
 pub struct HeaderEntry {
     height: usize,
@@ -84,16 +134,25 @@ fn hash_headers(headers: Vec<BlockHeader>) -> Vec<HashedHeader> {
     hashed_headers
 }
 
+/// Block headers of the best chain, indexed by height and by blockhash.
+///
+/// The headers and their hashes are kept in two contiguous arrays (so the height is implied by
+/// the position), and the blockhash lookup table maps only a hash prefix into a 32-bit height,
+/// which keeps the per-header memory overhead low on constrained devices.
 pub struct HeaderList {
-    headers: Vec<HeaderEntry>,
-    heights: HashMap<BlockHash, usize>,
+    headers: Vec<BlockHeader>,
+    hashes: Vec<BlockHash>,
+    heights: HashMap<HashPrefix, u32>,
+    collisions: HashMap<BlockHash, u32>, // blockhashes whose prefix is already in `heights`
 }
 
 impl HeaderList {
     pub fn empty() -> HeaderList {
         HeaderList {
             headers: vec![],
+            hashes: vec![],
             heights: HashMap::new(),
+            collisions: HashMap::new(),
         }
     }
 
@@ -123,86 +182,145 @@ impl HeaderList {
             .collect()
     }
 
-    pub fn apply(&mut self, new_headers: Vec<HeaderEntry>, tip: BlockHash) {
+    /// Fails (without modifying the list) if `new_headers` don't connect to the existing chain,
+    /// so that `headers` and `hashes` stay aligned by height.
+    pub fn apply(&mut self, new_headers: Vec<HeaderEntry>, tip: BlockHash) -> Result<()> {
         if tip == BlockHash::default() {
-            assert!(new_headers.is_empty());
-            self.heights.clear();
-            self.headers.clear();
-            return;
-        }
-        // new_headers[i] -> new_headers[i - 1] (i.e. new_headers.last() is the tip)
-        for i in 1..new_headers.len() {
-            assert_eq!(new_headers[i - 1].height() + 1, new_headers[i].height());
-            assert_eq!(
-                *new_headers[i - 1].hash(),
-                new_headers[i].header().prev_blockhash
-            );
+            if !new_headers.is_empty() {
+                bail!("{} new headers without a tip", new_headers.len());
+            }
+            self.truncate(0);
+            return Ok(());
         }
         let new_height = match new_headers.first() {
             Some(entry) => {
                 // Make sure tip is consistent (if there are new headers)
                 let expected_tip = new_headers.last().unwrap().hash();
-                assert_eq!(tip, *expected_tip);
-                // Make sure first header connects correctly to existing chain
-                let height = entry.height();
-                let expected_prev_blockhash = if height > 0 {
-                    *self.headers[height - 1].hash()
-                } else {
-                    BlockHash::default()
-                };
-                assert_eq!(entry.header().prev_blockhash, expected_prev_blockhash);
+                if tip != *expected_tip {
+                    bail!(
+                        "new headers end at {}, instead of tip {}",
+                        expected_tip,
+                        tip
+                    );
+                }
                 // First new header's height (may override existing headers)
-                height
+                entry.height()
             }
             // No new headers - chain's "tail" may be removed
             None => {
-                let tip_height = *self
-                    .heights
-                    .get(&tip)
-                    .unwrap_or_else(|| panic!("missing tip: {}", tip));
+                let tip_height = self
+                    .height_by_blockhash(&tip)
+                    .chain_err(|| format!("missing tip: {}", tip))?;
                 tip_height + 1 // keep the tip, drop the rest
             }
         };
+        if new_height > self.len() {
+            bail!(
+                "new headers start at height {}, after the existing {} headers",
+                new_height,
+                self.len()
+            );
+        }
+        // Make sure new headers connect correctly to the kept part of the existing chain
+        // (new_headers[i] -> new_headers[i - 1], i.e. new_headers.last() is the tip)
+        let mut prev_blockhash = match new_height {
+            0 => BlockHash::default(),
+            height => self.hashes[height - 1],
+        };
+        for (height, entry) in (new_height..).zip(&new_headers) {
+            if entry.height() != height || entry.header().prev_blockhash != prev_blockhash {
+                bail!(
+                    "header {} at height {} doesn't connect to {}",
+                    entry.hash(),
+                    entry.height(),
+                    prev_blockhash
+                );
+            }
+            prev_blockhash = *entry.hash();
+        }
         debug!(
             "applying {} new headers from height {}",
             new_headers.len(),
             new_height
         );
-        self.headers.truncate(new_height); // keep [0..new_height) entries
-        assert_eq!(new_height, self.headers.len());
+        // keep [0..new_height) entries
+        self.truncate(new_height);
+        // avoid doubling the capacity of the (large) arrays on every new block
+        self.headers.reserve_exact(new_headers.len());
+        self.hashes.reserve_exact(new_headers.len());
         for new_header in new_headers {
-            assert_eq!(new_header.height(), self.headers.len());
-            assert_eq!(new_header.header().prev_blockhash, self.tip());
-            self.heights.insert(*new_header.hash(), new_header.height());
-            self.headers.push(new_header);
+            self.insert_height(new_header.hash, new_header.height);
+            self.headers.push(new_header.header);
+            self.hashes.push(new_header.hash);
         }
-        assert_eq!(tip, self.tip());
-        assert!(self.heights.contains_key(&tip));
+        Ok(())
     }
 
-    pub fn header_by_blockhash(&self, blockhash: &BlockHash) -> Option<&HeaderEntry> {
-        let height = self.heights.get(blockhash)?;
-        let header = self.headers.get(*height)?;
-        if *blockhash == *header.hash() {
-            Some(header)
+    /// Removes the headers from `height` onwards, with their `heights` and `collisions` entries.
+    fn truncate(&mut self, height: usize) {
+        for (removed_height, blockhash) in (height..).zip(&self.hashes[height..]) {
+            let prefix = hash_prefix(&blockhash[..]);
+            if self.heights.get(&prefix) == Some(&(removed_height as u32)) {
+                self.heights.remove(&prefix);
+            }
+            self.collisions.remove(blockhash);
+        }
+        self.headers.truncate(height);
+        self.hashes.truncate(height);
+    }
+
+    fn insert_height(&mut self, blockhash: BlockHash, height: usize) {
+        let height = height as u32;
+        let prefix = hash_prefix(&blockhash[..]);
+        // A stale entry (e.g. pointing to a reorged or trimmed block) can be safely replaced.
+        let collision = match self.heights.get(&prefix) {
+            Some(existing) => self
+                .hashes
+                .get(*existing as usize)
+                .map_or(false, |h| hash_prefix(&h[..]) == prefix && *h != blockhash),
+            None => false,
+        };
+        if collision {
+            self.collisions.insert(blockhash, height);
+        } else {
+            self.heights.insert(prefix, height);
+        }
+    }
+
+    fn height_by_blockhash(&self, blockhash: &BlockHash) -> Option<usize> {
+        let prefix = hash_prefix(&blockhash[..]);
+        if let Some(&height) = self.heights.get(&prefix) {
+            if self.hashes.get(height as usize) == Some(blockhash) {
+                return Some(height as usize);
+            }
+        }
+        let height = *self.collisions.get(blockhash)? as usize;
+        if self.hashes.get(height) == Some(blockhash) {
+            Some(height)
         } else {
             None
         }
     }
 
-    pub fn header_by_height(&self, height: usize) -> Option<&HeaderEntry> {
-        self.headers.get(height).map(|entry| {
-            assert_eq!(entry.height(), height);
-            entry
+    pub fn header_by_blockhash(&self, blockhash: &BlockHash) -> Option<HeaderEntry> {
+        let height = self.height_by_blockhash(blockhash)?;
+        self.header_by_height(height)
+    }
+
+    pub fn header_by_height(&self, height: usize) -> Option<HeaderEntry> {
+        Some(HeaderEntry {
+            height,
+            hash: *self.hashes.get(height)?,
+            header: *self.headers.get(height)?,
         })
     }
 
     pub fn equals(&self, other: &HeaderList) -> bool {
-        self.headers.last() == other.headers.last()
+        self.hashes.last() == other.hashes.last()
     }
 
     pub fn tip(&self) -> BlockHash {
-        self.headers.last().map(|h| *h.hash()).unwrap_or_default()
+        self.hashes.last().cloned().unwrap_or_default()
     }
 
     pub fn len(&self) -> usize {
@@ -213,8 +331,12 @@ impl HeaderList {
         self.headers.is_empty()
     }
 
-    pub fn iter(&self) -> slice::Iter<HeaderEntry> {
-        self.headers.iter()
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = HeaderEntry> + 'a {
+        (0..self.len()).map(move |height| HeaderEntry {
+            height,
+            hash: self.hashes[height],
+            header: self.headers[height],
+        })
     }
 }
 
@@ -294,7 +416,7 @@ mod tests {
         assert_eq!(header_list.tip(), null_hash);
         let ordered = header_list.order(vec![]);
         assert_eq!(ordered.len(), 0);
-        header_list.apply(vec![], null_hash);
+        header_list.apply(vec![], null_hash).unwrap();
 
         let merkle_root = TxMerkleNode::hash(&[255]);
         let mut headers = vec![BlockHeader {
@@ -321,7 +443,7 @@ mod tests {
         // Test adding some new headers
         let ordered = header_list.order(headers[..3].to_vec());
         assert_eq!(ordered.len(), 3);
-        header_list.apply(ordered.clone(), ordered[2].hash);
+        header_list.apply(ordered.clone(), ordered[2].hash).unwrap();
         assert_eq!(header_list.len(), 3);
         assert_eq!(header_list.tip(), ordered[2].hash);
         for h in 0..3 {
@@ -335,7 +457,7 @@ mod tests {
         // Test adding some more headers
         let ordered = header_list.order(headers[3..6].to_vec());
         assert_eq!(ordered.len(), 3);
-        header_list.apply(ordered.clone(), ordered[2].hash);
+        header_list.apply(ordered.clone(), ordered[2].hash).unwrap();
         assert_eq!(header_list.len(), 6);
         assert_eq!(header_list.tip(), ordered[2].hash);
         for h in 0..6 {
@@ -349,7 +471,7 @@ mod tests {
         // Test adding some more headers (with an overlap)
         let ordered = header_list.order(headers[5..].to_vec());
         assert_eq!(ordered.len(), 5);
-        header_list.apply(ordered.clone(), ordered[4].hash);
+        header_list.apply(ordered.clone(), ordered[4].hash).unwrap();
         assert_eq!(header_list.len(), 10);
         assert_eq!(header_list.tip(), ordered[4].hash);
         for h in 0..10 {
@@ -368,7 +490,7 @@ mod tests {
         // Test reorging the chain
         let ordered = header_list.order(headers[8..10].to_vec());
         assert_eq!(ordered.len(), 2);
-        header_list.apply(ordered.clone(), ordered[1].hash);
+        header_list.apply(ordered.clone(), ordered[1].hash).unwrap();
         assert_eq!(header_list.len(), 10);
         assert_eq!(header_list.tip(), ordered[1].hash);
         for h in 0..10 {
@@ -380,7 +502,7 @@ mod tests {
         }
 
         // Test "trimming" the chain
-        header_list.apply(vec![], headers[7].block_hash());
+        header_list.apply(vec![], headers[7].block_hash()).unwrap();
         assert_eq!(header_list.len(), 8);
         assert_eq!(header_list.tip(), headers[7].block_hash());
         for h in 0..8 {
@@ -394,7 +516,7 @@ mod tests {
         // Test "un-trimming" the chain
         let ordered = header_list.order(headers[8..].to_vec());
         assert_eq!(ordered.len(), 2);
-        header_list.apply(ordered.clone(), ordered[1].hash);
+        header_list.apply(ordered.clone(), ordered[1].hash).unwrap();
         assert_eq!(header_list.len(), 10);
         assert_eq!(header_list.tip(), ordered[1].hash);
         for h in 0..10 {
@@ -405,4 +527,144 @@ mod tests {
             assert_eq!(header_list.header_by_blockhash(&entry.hash), Some(entry));
         }
     }
+
+    #[test]
+    fn test_reorg() {
+        use bitcoin::blockdata::block::BlockHeader;
+        use bitcoin::hash_types::BlockHash;
+
+        use super::{HeaderEntry, HeaderList};
+
+        // a chain of `len` headers on top of `headers[..height]`, tagged by `nonce`
+        fn extend(
+            headers: &[BlockHeader],
+            height: usize,
+            len: usize,
+            nonce: u32,
+        ) -> Vec<BlockHeader> {
+            let mut result = headers[..height].to_vec();
+            for _ in 0..len {
+                let prev_blockhash = result
+                    .last()
+                    .map_or(BlockHash::default(), |h| h.block_hash());
+                result.push(BlockHeader {
+                    version: 1,
+                    prev_blockhash,
+                    merkle_root: Default::default(),
+                    time: 0,
+                    bits: 0,
+                    nonce,
+                });
+            }
+            result
+        }
+
+        fn check(header_list: &HeaderList, headers: &[BlockHeader], stale: &[BlockHeader]) {
+            assert_eq!(header_list.len(), headers.len());
+            assert_eq!(header_list.tip(), headers.last().unwrap().block_hash());
+            for (h, header) in headers.iter().enumerate() {
+                let entry = header_list.header_by_height(h).unwrap();
+                assert_eq!(entry.header, *header);
+                assert_eq!(header_list.header_by_blockhash(&entry.hash), Some(entry));
+            }
+            assert!(header_list.header_by_height(headers.len()).is_none());
+            // stale entries are pruned
+            assert_eq!(
+                header_list.heights.len() + header_list.collisions.len(),
+                headers.len()
+            );
+            for header in stale {
+                assert!(header_list
+                    .header_by_blockhash(&header.block_hash())
+                    .is_none());
+            }
+        }
+
+        let mut header_list = HeaderList::empty();
+        let headers = extend(&[], 0, 10, 0);
+        let ordered = header_list.order(headers.clone());
+        header_list.apply(ordered, headers[9].block_hash()).unwrap();
+        check(&header_list, &headers, &[]);
+
+        // a reorg that shortens the chain (10 -> 8 headers)
+        let shorter = extend(&headers, 6, 2, 1);
+        let ordered = header_list.order(shorter[6..].to_vec());
+        header_list.apply(ordered, shorter[7].block_hash()).unwrap();
+        check(&header_list, &shorter, &headers[6..]);
+
+        // a reorg that lengthens the chain (8 -> 12 headers)
+        let longer = extend(&shorter, 4, 8, 2);
+        let ordered = header_list.order(longer[4..].to_vec());
+        header_list.apply(ordered, longer[11].block_hash()).unwrap();
+        check(&header_list, &longer, &shorter[4..]);
+
+        // reorg back to the original chain (after it has grown)
+        let original = extend(&headers, 10, 3, 0);
+        let ordered = header_list.order(original[4..].to_vec());
+        header_list
+            .apply(ordered, original[12].block_hash())
+            .unwrap();
+        check(&header_list, &original, &longer[4..]);
+
+        // headers that don't connect to the chain are rejected (keeping the list unchanged)
+        let entries = |headers: &[BlockHeader], heights: std::ops::Range<usize>| {
+            heights
+                .zip(headers)
+                .map(|(height, header)| HeaderEntry {
+                    height,
+                    hash: header.block_hash(),
+                    header: *header,
+                })
+                .collect::<Vec<_>>()
+        };
+        let other = extend(&[], 0, 13, 3);
+        let err = header_list
+            .apply(entries(&other[5..7], 5..7), other[6].block_hash())
+            .unwrap_err();
+        assert!(err.to_string().contains("doesn't connect to"));
+        let misaligned = entries(&original[5..7], 6..8); // off by one
+        assert!(header_list
+            .apply(misaligned, original[6].block_hash())
+            .is_err());
+        let detached = entries(&original[5..7], 20..22);
+        assert!(header_list
+            .apply(detached, original[6].block_hash())
+            .is_err());
+        assert!(header_list.apply(vec![], other[12].block_hash()).is_err()); // missing tip
+        check(&header_list, &original, &[]);
+    }
+
+    #[test]
+    fn test_merkle_tree() {
+        use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+        use bitcoin::hashes::Hash;
+
+        use super::{merklize, MerkleTree};
+
+        for len in 1..10u8 {
+            let leaves: Vec<Sha256dHash> = (0..len).map(|i| Sha256dHash::hash(&[i])).collect();
+            let tree = MerkleTree::new(leaves.clone());
+            for (index, leaf) in leaves.iter().enumerate() {
+                let branch = tree.branch(index);
+                // recompute the root from the leaf and its branch
+                let mut pos = index;
+                let mut hash = *leaf;
+                for sibling in &branch {
+                    hash = if pos % 2 == 0 {
+                        merklize(hash, *sibling)
+                    } else {
+                        merklize(*sibling, hash)
+                    };
+                    pos /= 2;
+                }
+                assert_eq!(hash, tree.root());
+            }
+        }
+        let leaves: Vec<Sha256dHash> = (0..3u8).map(|i| Sha256dHash::hash(&[i])).collect();
+        let root = merklize(
+            merklize(leaves[0], leaves[1]),
+            merklize(leaves[2], leaves[2]),
+        );
+        assert_eq!(MerkleTree::new(leaves).root(), root);
+    }
 }
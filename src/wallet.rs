@@ -0,0 +1,740 @@
+use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::script::{Builder, Script};
+use bitcoin::consensus::encode::{serialize, VarInt};
+use bitcoin::hash_types::{BlockHash, Txid};
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::secp256k1::{Secp256k1, VerifyOnly};
+use bitcoin::util::address::Address;
+use bitcoin::util::bip32::{ChildNumber, ExtendedPubKey};
+use bitcoin::util::key::PublicKey;
+use bitcoin::OutPoint;
+use std::collections::{hash_map, BTreeSet, HashMap};
+use std::ops::Range;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::errors::*;
+use crate::query::Query;
+use crate::status::{Balance, HistoryEntry, Status, Utxo};
+use crate::types::{ScriptHash, StatusHash};
+
+const INPUT_CHARSET: &str =
+    "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+const CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+fn polymod(c: u64, val: u64) -> u64 {
+    let c0 = c >> 35;
+    let mut c = ((c & 0x7_ffff_ffff) << 5) ^ val;
+    for (bit, generator) in [
+        0xf5_dee5_1989,
+        0xa9_fdca_3312,
+        0x1b_ab10_e32d,
+        0x37_06b1_677a,
+        0x64_4d62_6ffd,
+    ]
+    .iter()
+    .enumerate()
+    {
+        if c0 & (1 << bit) != 0 {
+            c ^= generator;
+        }
+    }
+    c
+}
+
+/// Descriptor checksum, as defined in BIP-380.
+fn descriptor_checksum(desc: &str) -> Result<String> {
+    let mut c = 1;
+    let mut cls = 0;
+    let mut cls_count = 0;
+    for ch in desc.chars() {
+        let pos = INPUT_CHARSET
+            .find(ch)
+            .chain_err(|| format!("invalid character {:?} in descriptor", ch))?
+            as u64;
+        c = polymod(c, pos & 31);
+        cls = cls * 3 + (pos >> 5);
+        cls_count += 1;
+        if cls_count == 3 {
+            c = polymod(c, cls);
+            cls = 0;
+            cls_count = 0;
+        }
+    }
+    if cls_count > 0 {
+        c = polymod(c, cls);
+    }
+    for _ in 0..8 {
+        c = polymod(c, 0);
+    }
+    c ^= 1;
+    Ok((0..8)
+        .map(|j| CHECKSUM_CHARSET[((c >> (5 * (7 - j))) & 31) as usize] as char)
+        .collect())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScriptType {
+    Pkh,
+    Wpkh,
+    ShWpkh,
+    Multi,
+    ShMulti,
+    WshMulti,
+}
+
+impl ScriptType {
+    /// The largest number of keys allowed in a multisig script of this type
+    /// (following Bitcoin Core's standardness and script size limits).
+    fn max_keys(self) -> usize {
+        match self {
+            ScriptType::Pkh | ScriptType::Wpkh | ScriptType::ShWpkh => 1,
+            ScriptType::Multi => 3,
+            ScriptType::ShMulti => 15,
+            ScriptType::WshMulti => 20,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct DescriptorKey {
+    xpub: ExtendedPubKey,
+    path: Vec<ChildNumber>, // non-hardened steps before the final wildcard
+}
+
+/// A ranged output descriptor, e.g. `wpkh([d34db33f/84'/0'/0']xpub.../0/*)`.
+///
+/// Supports `pkh()`, `wpkh()` and `sh(wpkh())` of an extended public key, and `multi()` or
+/// `sortedmulti()` of extended public keys, either bare or wrapped in `sh()` or `wsh()`.
+/// Other descriptors (e.g. `sh(wsh(multi()))`, `tr()`, `combo()`) are rejected.
+#[derive(Clone, Debug)]
+pub struct Descriptor {
+    script_type: ScriptType,
+    threshold: usize,
+    sorted: bool,
+    keys: Vec<DescriptorKey>,
+    secp: Secp256k1<VerifyOnly>,
+}
+
+fn strip_function<'a>(desc: &'a str, name: &str) -> Option<&'a str> {
+    if desc.starts_with(name) && desc[name.len()..].starts_with('(') && desc.ends_with(')') {
+        Some(&desc[name.len() + 1..desc.len() - 1])
+    } else {
+        None
+    }
+}
+
+fn parse_key(key: &str) -> Result<DescriptorKey> {
+    // skip key origin information (e.g. "[d34db33f/84'/0'/0']")
+    let key = if key.starts_with('[') {
+        let end = key.find(']').chain_err(|| "unterminated key origin")?;
+        &key[end + 1..]
+    } else {
+        key
+    };
+    let mut parts = key.split('/');
+    let xpub = parts.next().unwrap(); // split() returns at least one item
+    let xpub = ExtendedPubKey::from_str(xpub).chain_err(|| format!("invalid xpub {}", xpub))?;
+    let mut steps: Vec<&str> = parts.collect();
+    if steps.pop() != Some("*") {
+        bail!("descriptor key must end with '/*': {}", key);
+    }
+    let path = steps
+        .into_iter()
+        .map(|step| {
+            let index = u32::from_str(step)
+                .chain_err(|| format!("invalid (or hardened) derivation step {:?}", step))?;
+            ChildNumber::from_normal_idx(index)
+                .chain_err(|| format!("invalid derivation step {:?}", step))
+        })
+        .collect::<Result<_>>()?;
+    Ok(DescriptorKey { xpub, path })
+}
+
+/// Parses the arguments of `multi()` or `sortedmulti()`, returning the threshold and the keys.
+fn parse_multi(args: &str) -> Result<(usize, Vec<DescriptorKey>)> {
+    let mut args = args.split(',');
+    let threshold = args.next().unwrap(); // split() returns at least one item
+    let threshold =
+        usize::from_str(threshold).chain_err(|| format!("invalid threshold {:?}", threshold))?;
+    let keys = args.map(parse_key).collect::<Result<Vec<_>>>()?;
+    if threshold == 0 || threshold > keys.len() {
+        bail!("invalid threshold {} for {} keys", threshold, keys.len());
+    }
+    Ok((threshold, keys))
+}
+
+impl FromStr for Descriptor {
+    type Err = Error;
+
+    fn from_str(desc: &str) -> Result<Self> {
+        let desc = match desc.find('#') {
+            Some(pos) => {
+                let (desc, checksum) = (&desc[..pos], &desc[pos + 1..]);
+                if descriptor_checksum(desc)? != checksum {
+                    bail!("invalid descriptor checksum: {}", checksum);
+                }
+                desc
+            }
+            None => desc,
+        };
+        let (script_type, inner) = if let Some(inner) = strip_function(desc, "sh") {
+            if let Some(key) = strip_function(inner, "wpkh") {
+                (ScriptType::ShWpkh, key)
+            } else {
+                (ScriptType::ShMulti, inner)
+            }
+        } else if let Some(inner) = strip_function(desc, "wsh") {
+            (ScriptType::WshMulti, inner)
+        } else if let Some(key) = strip_function(desc, "pkh") {
+            (ScriptType::Pkh, key)
+        } else if let Some(key) = strip_function(desc, "wpkh") {
+            (ScriptType::Wpkh, key)
+        } else {
+            (ScriptType::Multi, desc)
+        };
+        let (threshold, sorted, keys) = match script_type {
+            ScriptType::Pkh | ScriptType::Wpkh | ScriptType::ShWpkh => {
+                (1, false, vec![parse_key(inner)?])
+            }
+            ScriptType::Multi | ScriptType::ShMulti | ScriptType::WshMulti => {
+                let (sorted, args) = if let Some(args) = strip_function(inner, "multi") {
+                    (false, args)
+                } else if let Some(args) = strip_function(inner, "sortedmulti") {
+                    (true, args)
+                } else {
+                    bail!("unsupported descriptor: {}", desc);
+                };
+                let (threshold, keys) = parse_multi(args)?;
+                (threshold, sorted, keys)
+            }
+        };
+        if keys.len() > script_type.max_keys() {
+            bail!("too many keys ({}) in descriptor: {}", keys.len(), desc);
+        }
+        Ok(Descriptor {
+            script_type,
+            threshold,
+            sorted,
+            keys,
+            secp: Secp256k1::verification_only(),
+        })
+    }
+}
+
+impl Descriptor {
+    pub fn script_type(&self) -> ScriptType {
+        self.script_type
+    }
+
+    fn derive_key(&self, key: &DescriptorKey, index: u32) -> Result<PublicKey> {
+        let mut path = key.path.clone();
+        path.push(ChildNumber::from_normal_idx(index).chain_err(|| "invalid index")?);
+        let xpub = key
+            .xpub
+            .derive_pub(&self.secp, &path)
+            .chain_err(|| format!("failed to derive {:?}", path))?;
+        let public_key = xpub.public_key;
+        Ok(public_key)
+    }
+
+    /// Derives the output script at the specified wildcard index.
+    pub fn script_pubkey(&self, index: u32) -> Result<Script> {
+        let mut keys = self
+            .keys
+            .iter()
+            .map(|key| self.derive_key(key, index))
+            .collect::<Result<Vec<PublicKey>>>()?;
+        let network = self.keys[0].xpub.network;
+        let script_pubkey = match self.script_type {
+            ScriptType::Pkh => Address::p2pkh(&keys[0], network).script_pubkey(),
+            ScriptType::Wpkh => Address::p2wpkh(&keys[0], network)
+                .chain_err(|| "uncompressed key in wpkh()")?
+                .script_pubkey(),
+            ScriptType::ShWpkh => Address::p2shwpkh(&keys[0], network)
+                .chain_err(|| "uncompressed key in sh(wpkh())")?
+                .script_pubkey(),
+            ScriptType::Multi | ScriptType::ShMulti | ScriptType::WshMulti => {
+                if self.sorted {
+                    keys.sort_by_key(|key| key.to_bytes()); // BIP-67
+                }
+                let mut builder = Builder::new().push_int(self.threshold as i64);
+                for key in &keys {
+                    builder = builder.push_key(key);
+                }
+                let script = builder
+                    .push_int(keys.len() as i64)
+                    .push_opcode(opcodes::all::OP_CHECKMULTISIG)
+                    .into_script();
+                match self.script_type {
+                    ScriptType::ShMulti => script.to_p2sh(),
+                    ScriptType::WshMulti => script.to_v0_p2wsh(),
+                    _ => script,
+                }
+            }
+        };
+        Ok(script_pubkey)
+    }
+
+    pub fn script_hash(&self, index: u32) -> Result<ScriptHash> {
+        Ok(ScriptHash::from_script(&self.script_pubkey(index)?))
+    }
+}
+
+struct Entry {
+    script_hash: ScriptHash,
+    status_hash: Option<StatusHash>,
+}
+
+/// Watches a group of script hashes derived from a descriptor, extending the derivation
+/// range so that at least `gap_limit` unused addresses follow the last used one.
+pub struct DescriptorSubscription {
+    descriptor: Descriptor,
+    start: u32,
+    min_end: u32,
+    gap_limit: u32,
+    entries: Vec<Entry>, // by derivation index (starting from `start`)
+}
+
+impl DescriptorSubscription {
+    pub fn new(descriptor: Descriptor, range: Range<u32>, gap_limit: u32) -> Self {
+        DescriptorSubscription {
+            descriptor,
+            start: range.start,
+            min_end: range.end,
+            gap_limit,
+            entries: vec![],
+        }
+    }
+
+    pub fn descriptor(&self) -> &Descriptor {
+        &self.descriptor
+    }
+
+    /// Currently derived indices (updated by `update()`).
+    pub fn range(&self) -> Range<u32> {
+        self.start..self.start + self.entries.len() as u32
+    }
+
+    pub fn script_hashes(&self) -> impl Iterator<Item = &ScriptHash> {
+        self.entries.iter().map(|entry| &entry.script_hash)
+    }
+
+    /// Recomputes the statuses of all derived script hashes, deriving more of them if needed.
+    /// Returns the derivation indices whose status has changed.
+    pub fn update(&mut self, query: &Query) -> Result<Vec<u32>> {
+        let mut changed = vec![];
+        for (i, entry) in self.entries.iter_mut().enumerate() {
+            let status_hash = query.status(&entry.script_hash)?.statushash();
+            if status_hash != entry.status_hash {
+                entry.status_hash = status_hash;
+                changed.push(self.start + i as u32);
+            }
+        }
+        loop {
+            let used = self
+                .entries
+                .iter()
+                .rposition(|entry| entry.status_hash.is_some())
+                .map_or(0, |i| i + 1);
+            let end = self.start + self.entries.len() as u32;
+            if end >= self.min_end && self.entries.len() >= used + self.gap_limit as usize {
+                break;
+            }
+            let script_hash = self.descriptor.script_hash(end)?;
+            let status_hash = query.status(&script_hash)?.statushash();
+            if status_hash.is_some() {
+                changed.push(end);
+            }
+            self.entries.push(Entry {
+                script_hash,
+                status_hash,
+            });
+        }
+        Ok(changed)
+    }
+}
+
+/// A derived output script with transaction history, found by `scan()`.
+pub struct UsedScript {
+    pub index: u32,
+    pub script_pubkey: Script,
+    pub confirmed_balance: i64,
+    pub mempool_balance: i64,
+}
+
+pub struct ScanResult {
+    pub used: Vec<UsedScript>,
+    pub confirmed_balance: i64,
+    pub mempool_balance: i64,
+}
+
+/// Derives output scripts until `gap_limit` consecutive unused ones are found, returning the
+/// used ones and their total balance (e.g. for restoring a wallet from an xpub).
+pub fn scan(query: &Query, descriptor: &Descriptor, gap_limit: u32) -> Result<ScanResult> {
+    let mut result = ScanResult {
+        used: vec![],
+        confirmed_balance: 0,
+        mempool_balance: 0,
+    };
+    let mut index = 0;
+    let mut unused = 0;
+    while unused < gap_limit {
+        let script_pubkey = descriptor.script_pubkey(index)?;
+        let status = query.status(&ScriptHash::from_script(&script_pubkey))?;
+        if status.statushash().is_none() {
+            unused += 1;
+        } else {
+            unused = 0;
+            let used = UsedScript {
+                index,
+                script_pubkey,
+                confirmed_balance: status.confirmed_balance(),
+                mempool_balance: status.mempool_balance(),
+            };
+            result.confirmed_balance += used.confirmed_balance;
+            result.mempool_balance += used.mempool_balance;
+            result.used.push(used);
+        }
+        index += 1;
+    }
+    Ok(result)
+}
+
+/// Groups the statuses of many script hashes (e.g. all addresses of a customer),
+/// providing aggregate balance and history.
+#[derive(Default)]
+pub struct Wallet {
+    script_hashes: Vec<ScriptHash>,
+    statuses: Vec<Arc<Status>>, // updated by `update()`
+    status_hashes: Vec<Option<StatusHash>>,
+}
+
+impl Wallet {
+    pub fn new(script_hashes: Vec<ScriptHash>) -> Self {
+        Wallet {
+            script_hashes,
+            ..Default::default()
+        }
+    }
+
+    /// The new script hash status is computed on the next `update()`.
+    pub fn add(&mut self, script_hash: ScriptHash) {
+        if !self.script_hashes.contains(&script_hash) {
+            self.script_hashes.push(script_hash);
+        }
+    }
+
+    pub fn script_hashes(&self) -> &[ScriptHash] {
+        &self.script_hashes
+    }
+
+    /// Recomputes the statuses of all script hashes, returning whether any of them has changed.
+    pub fn update(&mut self, query: &Query) -> Result<bool> {
+        let statuses = self
+            .script_hashes
+            .iter()
+            .map(|script_hash| query.status(script_hash))
+            .collect::<Result<Vec<Arc<Status>>>>()?;
+        let status_hashes: Vec<Option<StatusHash>> =
+            statuses.iter().map(|status| status.statushash()).collect();
+        let changed = status_hashes != self.status_hashes;
+        self.statuses = statuses;
+        self.status_hashes = status_hashes;
+        Ok(changed)
+    }
+
+    pub fn confirmed_balance(&self) -> i64 {
+        self.statuses
+            .iter()
+            .map(|status| status.confirmed_balance())
+            .sum()
+    }
+
+    pub fn mempool_balance(&self) -> i64 {
+        self.statuses
+            .iter()
+            .map(|status| status.mempool_balance())
+            .sum()
+    }
+
+    /// Total balance, given the current tip height (for coinbase maturity).
+    pub fn balance(&self, tip_height: u32) -> Balance {
+        let mut total = Balance::default();
+        for balance in self
+            .statuses
+            .iter()
+            .map(|status| status.balance(tip_height))
+        {
+            total.confirmed += balance.confirmed;
+            total.unconfirmed += balance.unconfirmed;
+            total.immature += balance.immature;
+        }
+        total
+    }
+
+    /// Transactions touching any of the script hashes (each one is returned once).
+    pub fn history(&self) -> Vec<HistoryEntry> {
+        let mut entries = HashMap::<Txid, HistoryEntry>::new();
+        for status in &self.statuses {
+            for entry in status.history() {
+                entries.insert(entry.txid, entry);
+            }
+        }
+        let mut entries: Vec<HistoryEntry> = entries.into_iter().map(|item| item.1).collect();
+        entries.sort_unstable_by_key(|entry| entry.height);
+        entries
+    }
+
+    pub fn utxos(&self) -> Vec<Utxo> {
+        let mut utxos: Vec<Utxo> = self
+            .statuses
+            .iter()
+            .flat_map(|status| status.utxos())
+            .collect();
+        utxos.sort_unstable_by_key(|utxo| utxo.height);
+        utxos
+    }
+}
+
+/// A confirmed unspent output in a `ReservesSnapshot`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SnapshotUtxo {
+    pub script_hash: ScriptHash,
+    pub outpoint: OutPoint,
+    pub value: u64, // in satoshis
+    pub height: usize,
+    pub blockhash: BlockHash,
+}
+
+/// The confirmed UTXOs of a set of script hashes at a specific block, e.g. for proof-of-reserves
+/// tooling (which can sign `digest()` or verify each UTXO against its block).
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ReservesSnapshot {
+    pub height: usize,
+    pub blockhash: BlockHash,
+    pub total: u64,               // in satoshis
+    pub utxos: Vec<SnapshotUtxo>, // sorted by outpoint
+}
+
+impl ReservesSnapshot {
+    /// A canonical binary encoding: the block hash and height, followed by the number of UTXOs
+    /// and their script hash, outpoint, value, height and block hash (Bitcoin consensus
+    /// encoding, with little-endian integers).
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = serialize(&self.blockhash);
+        bytes.extend(serialize(&(self.height as u32)));
+        bytes.extend(serialize(&VarInt(self.utxos.len() as u64)));
+        for utxo in &self.utxos {
+            bytes.extend_from_slice(utxo.script_hash.as_bytes());
+            bytes.extend(serialize(&utxo.outpoint));
+            bytes.extend(serialize(&utxo.value));
+            bytes.extend(serialize(&(utxo.height as u32)));
+            bytes.extend(serialize(&utxo.blockhash));
+        }
+        bytes
+    }
+
+    /// The SHA256 of `encode()`, for signing the snapshot.
+    pub fn digest(&self) -> sha256::Hash {
+        sha256::Hash::hash(&self.encode())
+    }
+}
+
+/// Exports the confirmed UTXOs of `script_hashes` at block `height` (which may be below the
+/// current tip), failing if that block is not indexed or is reorged meanwhile.
+pub fn reserves_snapshot(
+    query: &Query,
+    script_hashes: &[ScriptHash],
+    height: usize,
+) -> Result<ReservesSnapshot> {
+    let block_hash = |height: usize| {
+        query
+            .get_headers(&[height])
+            .first()
+            .map(|header| *header.hash())
+    };
+    let blockhash = block_hash(height).chain_err(|| format!("block {} is not indexed", height))?;
+    let mut blockhashes = HashMap::new();
+    let mut utxos = vec![];
+    let script_hashes: BTreeSet<&ScriptHash> = script_hashes.iter().collect();
+    for script_hash in script_hashes {
+        for utxo in query.status(script_hash)?.utxos_at(height)? {
+            let height = utxo.height.confirmed().chain_err(|| "unconfirmed UTXO")? as usize;
+            let utxo_blockhash = match blockhashes.entry(height) {
+                hash_map::Entry::Occupied(entry) => *entry.get(),
+                hash_map::Entry::Vacant(entry) => *entry
+                    .insert(block_hash(height).chain_err(|| format!("missing block {}", height))?),
+            };
+            utxos.push(SnapshotUtxo {
+                script_hash: *script_hash,
+                outpoint: utxo.outpoint,
+                value: utxo.value.as_sat(),
+                height,
+                blockhash: utxo_blockhash,
+            });
+        }
+    }
+    // the statuses may be computed after a reorg, making them inconsistent with `blockhash`
+    if block_hash(height) != Some(blockhash) {
+        bail!("block {} was reorged during snapshot", height);
+    }
+    utxos.sort_unstable_by_key(|utxo| (utxo.outpoint.txid, utxo.outpoint.vout));
+    Ok(ReservesSnapshot {
+        height,
+        blockhash,
+        total: utxos.iter().map(|utxo| utxo.value).sum(),
+        utxos,
+    })
+}
+
+/// Exports the confirmed UTXOs of the descriptors' used scripts (see `scan()`) at block
+/// `height`.
+pub fn descriptors_snapshot(
+    query: &Query,
+    descriptors: &[Descriptor],
+    gap_limit: u32,
+    height: usize,
+) -> Result<ReservesSnapshot> {
+    let mut script_hashes = vec![];
+    for descriptor in descriptors {
+        let result = scan(query, descriptor, gap_limit)?;
+        script_hashes.extend(
+            result
+                .used
+                .iter()
+                .map(|used| ScriptHash::from_script(&used.script_pubkey)),
+        );
+    }
+    reserves_snapshot(query, &script_hashes, height)
+}
+
+/// Manages multiple wallets by their IDs (e.g. one per customer).
+#[derive(Default)]
+pub struct Wallets {
+    wallets: HashMap<String, Wallet>,
+}
+
+impl Wallets {
+    pub fn insert(&mut self, id: String, wallet: Wallet) -> Option<Wallet> {
+        self.wallets.insert(id, wallet)
+    }
+
+    pub fn remove(&mut self, id: &str) -> Option<Wallet> {
+        self.wallets.remove(id)
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Wallet> {
+        self.wallets.get(id)
+    }
+
+    pub fn get_mut(&mut self, id: &str) -> Option<&mut Wallet> {
+        self.wallets.get_mut(id)
+    }
+
+    /// Updates all wallets, returning the IDs of the changed ones.
+    pub fn update(&mut self, query: &Query) -> Result<Vec<String>> {
+        let mut changed = vec![];
+        for (id, wallet) in self.wallets.iter_mut() {
+            if wallet.update(query)? {
+                changed.push(id.clone());
+            }
+        }
+        Ok(changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // BIP-84 test vector (account 0 of "abandon abandon ... about")
+    const XPUB: &str = "xpub6CatWdiZiodmUeTDp8LT5or8nmbKNcuyvz7WyksVFkKB4RHwCD3XyuvPEbvqAQY3rAPshWcMLoP2fMFMKHPJ4ZeZXYVUhLv1VMrjPC7PW6V";
+
+    #[test]
+    fn test_checksum() {
+        // BIP-380 test vector
+        assert_eq!(descriptor_checksum("raw(deadbeef)").unwrap(), "89f8spxm");
+        assert!(Descriptor::from_str(&format!("wpkh({}/0/*)#89f8spxm", XPUB)).is_err());
+    }
+
+    #[test]
+    fn test_derive() {
+        let desc = format!("wpkh([73c5da0a/84'/0'/0']{}/0/*)", XPUB);
+        let checksum = descriptor_checksum(&desc).unwrap();
+        let desc = Descriptor::from_str(&format!("{}#{}", desc, checksum)).unwrap();
+        assert_eq!(desc.script_type(), ScriptType::Wpkh);
+        let expected = Address::from_str("bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu").unwrap();
+        assert_eq!(desc.script_pubkey(0).unwrap(), expected.script_pubkey());
+
+        assert!(Descriptor::from_str(&format!("wpkh({}/0)", XPUB)).is_err());
+        assert!(Descriptor::from_str(&format!("wpkh({}/0'/*)", XPUB)).is_err());
+        assert!(Descriptor::from_str(&format!("tr({}/0/*)", XPUB)).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_encoding() {
+        let snapshot = ReservesSnapshot {
+            height: 100,
+            blockhash: BlockHash::from_inner([1; 32]),
+            total: 50_000,
+            utxos: vec![SnapshotUtxo {
+                script_hash: ScriptHash::from_inner([2; 32]),
+                outpoint: OutPoint::new(Txid::from_inner([3; 32]), 1),
+                value: 50_000,
+                height: 90,
+                blockhash: BlockHash::from_inner([4; 32]),
+            }],
+        };
+        let expected = [
+            "01".repeat(32),               // block hash
+            "64000000".to_owned(),         // height
+            "01".to_owned(),               // number of UTXOs
+            "02".repeat(32),               // script hash
+            "03".repeat(32),               // txid
+            "01000000".to_owned(),         // vout
+            "50c3000000000000".to_owned(), // value
+            "5a000000".to_owned(),         // confirmation height
+            "04".repeat(32),               // confirmation block hash
+        ]
+        .concat();
+        assert_eq!(hex::encode(snapshot.encode()), expected);
+        assert_eq!(
+            hex::encode(snapshot.digest().into_inner()),
+            "b5afdbc1c6b89e7ea3907a16f61445330b69cb742e5022f19abb1d9db3a7e516"
+        );
+    }
+
+    #[test]
+    fn test_multi() {
+        // BIP-84 test vector keys m/84'/0'/0'/0/0 and m/84'/0'/0'/1/0
+        let receive = "0330d54fd0dd420a6e5f8d3624f5f3482cae350f79d5f0753bf5beef9c2d91af3c";
+        let change = "03025324888e429ab8e3dbaf1f7802648b9cd01e9b418485c5fa4c1b9b5700e1a6";
+        let multi = |desc: &str| {
+            let desc = desc.replace("K", XPUB);
+            Descriptor::from_str(&desc)
+                .unwrap()
+                .script_pubkey(0)
+                .unwrap()
+        };
+        let script = |hex: String| Script::from(hex::decode(hex).unwrap());
+
+        let unsorted = script(format!("5121{}21{}52ae", receive, change));
+        let sorted = script(format!("5121{}21{}52ae", change, receive));
+        assert_eq!(multi("multi(1,K/0/*,K/1/*)"), unsorted);
+        assert_eq!(multi("sortedmulti(1,K/0/*,K/1/*)"), sorted);
+        assert_eq!(multi("sortedmulti(1,K/1/*,K/0/*)"), sorted);
+        assert_eq!(multi("sh(multi(1,K/0/*,K/1/*))"), unsorted.to_p2sh());
+        assert_eq!(
+            multi("wsh(sortedmulti(1,K/0/*,K/1/*))"),
+            sorted.to_v0_p2wsh()
+        );
+
+        let invalid = |desc: &str| Descriptor::from_str(&desc.replace("K", XPUB)).is_err();
+        assert!(invalid("multi(0,K/0/*,K/1/*)"));
+        assert!(invalid("multi(3,K/0/*,K/1/*)"));
+        assert!(invalid("multi(1,K/0/*,K/1/*,K/2/*,K/3/*)")); // bare multisig allows 3 keys
+        assert!(invalid("sh(wsh(multi(1,K/0/*,K/1/*)))"));
+        assert!(invalid("wsh(wpkh(K/0/*))"));
+    }
+}
@@ -0,0 +1,299 @@
+//! Watch-only index mode (see `watch_file`): only the outputs paying to the watched scripts, the
+//! inputs spending them and their transactions are indexed - instead of the whole chain.
+//!
+//! The watched outputs are persisted as "P" rows (so their spending inputs are recognized after
+//! a restart), and the watched scripts' fingerprint as the "W" row. Since the other scripts'
+//! rows are never written, a DB can't be reused after changing the watched scripts.
+
+use bitcoin::blockdata::block::Block;
+use bitcoin::blockdata::transaction::OutPoint;
+use bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::network::constants::Network;
+use bitcoin::util::address::Address;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+use crate::errors::*;
+use crate::index::{block_row, compute_script_hash, TxInRow, TxOutRow, TxRow};
+use crate::store::{ReadStore, Row, RowBatch};
+use crate::types::ScriptHash;
+use crate::util::FullHash;
+use crate::wallet::Descriptor;
+
+const FINGERPRINT_KEY: &[u8] = b"W";
+const OUTPOINT_PREFIX: &[u8] = b"P";
+
+/// The scripts to index in watch-only mode.
+#[derive(Clone, Debug)]
+pub struct WatchedScripts {
+    descriptors: Vec<Descriptor>,
+    script_hashes: Vec<FullHash>, // of the addresses
+    gap_limit: u32,
+    fingerprint: sha256::Hash,
+}
+
+impl WatchedScripts {
+    /// Parses an output descriptor (see `wallet::Descriptor`) or an address per line, skipping
+    /// empty lines and comments (starting with '#').
+    pub fn parse(text: &str, network: Network, gap_limit: u32) -> Result<WatchedScripts> {
+        let mut lines: Vec<&str> = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect();
+        if lines.is_empty() {
+            bail!("no scripts to watch");
+        }
+        lines.sort();
+        lines.dedup();
+        let mut descriptors = vec![];
+        let mut script_hashes = vec![];
+        let mut engine = sha256::Hash::engine();
+        for line in &lines {
+            if line.contains('(') {
+                descriptors.push(Descriptor::from_str(line)?);
+            } else {
+                let address = Address::from_str(line)
+                    .chain_err(|| format!("invalid descriptor or address {:?}", line))?;
+                // legacy addresses don't distinguish testnet, signet and regtest
+                if (address.network == Network::Bitcoin) != (network == Network::Bitcoin) {
+                    bail!("address {} is not valid for {}", line, network);
+                }
+                script_hashes.push(compute_script_hash(&address.script_pubkey()[..]));
+            }
+            engine.input(line.as_bytes());
+            engine.input(b"\n");
+        }
+        engine.input(&gap_limit.to_le_bytes());
+        Ok(WatchedScripts {
+            descriptors,
+            script_hashes,
+            gap_limit,
+            fingerprint: sha256::Hash::from_engine(engine),
+        })
+    }
+}
+
+/// Fails if the DB was indexed with other watched scripts (or with none, i.e. the whole chain).
+pub fn check_db(store: &dyn ReadStore, scripts: Option<&WatchedScripts>) -> Result<()> {
+    let fingerprint = store.get(FINGERPRINT_KEY);
+    let is_empty = store.get(b"L").is_none();
+    match (scripts, fingerprint) {
+        (None, None) => Ok(()),
+        (None, Some(_)) => bail!(
+            "DB indexes only the watched scripts (hint: set the same watch_file, or use another db_dir)"
+        ),
+        (Some(scripts), Some(ref fingerprint)) if fingerprint[..] == scripts.fingerprint[..] => {
+            Ok(())
+        }
+        (Some(_), None) if is_empty => Ok(()),
+        (Some(_), _) => bail!(
+            "DB was indexed for other scripts than the watched ones (hint: changing watch_file requires reindexing into another db_dir)"
+        ),
+    }
+}
+
+fn outpoint_key(outpoint: &OutPoint) -> Vec<u8> {
+    [OUTPOINT_PREFIX, &serialize(outpoint)[..]].concat()
+}
+
+/// The watched script hashes (extending the descriptors' derivation ranges as their addresses
+/// are used) and outputs, for indexing new blocks.
+pub struct Watchlist {
+    scripts: WatchedScripts,
+    derived: Vec<Vec<FullHash>>, // by descriptor and derivation index
+    script_hashes: HashMap<FullHash, Option<(usize, u32)>>, // to their descriptor and index
+    outpoints: HashSet<OutPoint>,
+}
+
+impl Watchlist {
+    pub fn new(scripts: &WatchedScripts) -> Result<Watchlist> {
+        let mut watchlist = Watchlist {
+            scripts: scripts.clone(),
+            derived: vec![vec![]; scripts.descriptors.len()],
+            script_hashes: scripts.script_hashes.iter().map(|h| (*h, None)).collect(),
+            outpoints: HashSet::new(),
+        };
+        for descriptor in 0..scripts.descriptors.len() {
+            watchlist.derive(descriptor, scripts.gap_limit)?;
+        }
+        Ok(watchlist)
+    }
+
+    /// Derives the descriptor's script hashes up to (excluding) the `end` index.
+    fn derive(&mut self, descriptor: usize, end: u32) -> Result<()> {
+        let mut index = self.derived[descriptor].len() as u32;
+        while index < end {
+            let script = self.scripts.descriptors[descriptor].script_pubkey(index)?;
+            let script_hash = compute_script_hash(&script[..]);
+            self.derived[descriptor].push(script_hash);
+            self.script_hashes
+                .insert(script_hash, Some((descriptor, index)));
+            index += 1;
+        }
+        Ok(())
+    }
+
+    /// Returns whether an output script hash is watched, deriving the following addresses if
+    /// it's a descriptor's.
+    fn is_watched_output(&mut self, script_hash: &FullHash) -> Result<bool> {
+        match self.script_hashes.get(script_hash) {
+            Some(&Some((descriptor, index))) => {
+                self.derive(descriptor, index + 1 + self.scripts.gap_limit)?;
+                Ok(true)
+            }
+            Some(None) => Ok(true),
+            None => Ok(false),
+        }
+    }
+
+    pub fn contains(&self, script_hash: &ScriptHash) -> bool {
+        self.script_hashes.contains_key(&script_hash.into_inner())
+    }
+
+    /// Loads the indexed watched outputs, and extends the derivation ranges past the indexed
+    /// addresses (e.g. after a restart).
+    pub fn load(&mut self, store: &dyn ReadStore) -> Result<()> {
+        for row in store.scan(OUTPOINT_PREFIX) {
+            let outpoint: OutPoint =
+                deserialize(&row.key[OUTPOINT_PREFIX.len()..]).chain_err(|| "invalid P row")?;
+            self.outpoints.insert(outpoint);
+        }
+        for descriptor in 0..self.derived.len() {
+            let mut index = 0;
+            while index < self.derived[descriptor].len() {
+                let script_hash = self.derived[descriptor][index];
+                if !store.scan(&TxOutRow::filter(&script_hash)).is_empty() {
+                    self.is_watched_output(&script_hash)?;
+                }
+                index += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends the rows of the block's watched outputs and the inputs spending them (and their
+    /// transactions' rows), and the block's header row.
+    pub fn index_block_into(
+        &mut self,
+        block: &Block,
+        height: usize,
+        rows: &mut RowBatch,
+    ) -> Result<()> {
+        for txn in &block.txdata {
+            let txid = txn.txid();
+            let mut is_watched = false;
+            for input in &txn.input {
+                if self.outpoints.contains(&input.previous_output) {
+                    rows.push(&TxInRow::new(&txid, input).encode(), &[]);
+                    is_watched = true;
+                }
+            }
+            for (vout, output) in txn.output.iter().enumerate() {
+                let script_hash = compute_script_hash(&output.script_pubkey[..]);
+                if self.is_watched_output(&script_hash)? {
+                    let outpoint = OutPoint::new(txid, vout as u32);
+                    rows.push(&TxOutRow::new(&txid, output).encode(), &[]);
+                    rows.push(&outpoint_key(&outpoint), &[]);
+                    self.outpoints.insert(outpoint);
+                    is_watched = true;
+                }
+            }
+            if is_watched {
+                let (key, value) = TxRow::new(&txid, height as u32).encode();
+                rows.push(&key, &value);
+            }
+        }
+        let row = block_row(block);
+        rows.push(&row.key, &row.value);
+        Ok(())
+    }
+
+    /// Marks the DB as indexed for the watched scripts (see `check_db()`).
+    pub fn fingerprint_row(&self) -> Row {
+        Row {
+            key: FINGERPRINT_KEY.to_vec(),
+            value: self.scripts.fingerprint[..].to_vec(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut};
+
+    // BIP-84 test vector (account 0 of "abandon abandon ... about")
+    const DESCRIPTOR: &str = "wpkh([73c5da0a/84'/0'/0']xpub6CatWdiZiodmUeTDp8LT5or8nmbKNcuyvz7WyksVFkKB4RHwCD3XyuvPEbvqAQY3rAPshWcMLoP2fMFMKHPJ4ZeZXYVUhLv1VMrjPC7PW6V/0/*)";
+
+    fn txn(input: OutPoint, scripts: &[&bitcoin::Script]) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: input,
+                ..TxIn::default()
+            }],
+            output: scripts
+                .iter()
+                .map(|script| TxOut {
+                    value: 1000,
+                    script_pubkey: (*script).clone(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_index_watched() {
+        let address = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+        let text = format!("# comment\n{}\n\n{}\n", DESCRIPTOR, address);
+        let scripts = WatchedScripts::parse(&text, Network::Bitcoin, 2).unwrap();
+        assert!(WatchedScripts::parse(&text, Network::Testnet, 2).is_err());
+        assert!(WatchedScripts::parse("# nothing", Network::Bitcoin, 2).is_err());
+        let reordered = format!("{}\n{}", address, DESCRIPTOR);
+        let same = WatchedScripts::parse(&reordered, Network::Bitcoin, 2).unwrap();
+        assert_eq!(same.fingerprint, scripts.fingerprint);
+
+        let mut watchlist = Watchlist::new(&scripts).unwrap();
+        let descriptor = Descriptor::from_str(DESCRIPTOR).unwrap();
+        let script = |index| descriptor.script_pubkey(index).unwrap();
+        let watched = |index| ScriptHash::from_script(&script(index));
+        assert!(watchlist.contains(&ScriptHash::from_address(
+            &Address::from_str(address).unwrap()
+        )));
+        assert!(watchlist.contains(&watched(1)));
+        assert!(!watchlist.contains(&watched(2)));
+
+        // pays to the 2nd address and another one, and then spends it
+        let other = bitcoin::Script::from(vec![0x6a]); // OP_RETURN
+        let mut block = genesis_block(Network::Bitcoin);
+        let funding = txn(OutPoint::default(), &[&other, &script(1)]);
+        let spending = txn(OutPoint::new(funding.txid(), 1), &[&other]);
+        let unrelated = txn(OutPoint::new(funding.txid(), 0), &[&other]);
+        block.txdata = vec![funding.clone(), spending.clone(), unrelated];
+        let mut rows = RowBatch::new();
+        watchlist.index_block_into(&block, 1, &mut rows).unwrap();
+
+        // the gap limit follows the used address
+        assert!(watchlist.contains(&watched(3)));
+        assert!(!watchlist.contains(&watched(4)));
+
+        let keys: Vec<&[u8]> = rows.iter().map(|(key, _)| key).collect();
+        let expected = vec![
+            TxOutRow::new(&funding.txid(), &funding.output[1])
+                .to_row()
+                .key,
+            outpoint_key(&OutPoint::new(funding.txid(), 1)),
+            TxRow::new(&funding.txid(), 1).to_row().key,
+            TxInRow::new(&spending.txid(), &spending.input[0])
+                .to_row()
+                .key,
+            TxRow::new(&spending.txid(), 1).to_row().key,
+            block_row(&block).key,
+        ];
+        assert_eq!(keys, expected);
+    }
+}
@@ -0,0 +1,86 @@
+//! End-to-end tests, using the regtest harness (`cargo test --features test-utils`).
+//! Skipped unless `BITCOIND_EXE` is set, and `ELECTRS_EXE` defaults to `target/debug/electrs`.
+#![cfg(feature = "test-utils")]
+
+#[macro_use]
+extern crate serde_json;
+
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::util::address::Address;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use electrs::regtest::{Bitcoind, Electrs};
+use electrs::types::ScriptHash;
+
+fn executables() -> Option<(PathBuf, PathBuf)> {
+    let bitcoind = PathBuf::from(env::var_os("BITCOIND_EXE")?);
+    let electrs = env::var_os("ELECTRS_EXE").map_or_else(
+        || Path::new(env!("CARGO_MANIFEST_DIR")).join("target/debug/electrs"),
+        PathBuf::from,
+    );
+    Some((bitcoind, electrs))
+}
+
+fn electrum_script_hash(address: &str) -> String {
+    let address = Address::from_str(address).unwrap();
+    ScriptHash::from_address(&address).to_string()
+}
+
+#[test]
+fn test_mempool_confirmation_and_reorg() {
+    let (bitcoind, electrs) = match executables() {
+        Some(paths) => paths,
+        None => {
+            eprintln!("skipped: BITCOIND_EXE is not set");
+            return;
+        }
+    };
+    let node = Bitcoind::start(&bitcoind).unwrap();
+    node.mine(101).unwrap(); // mature the first coinbase
+    let server = Electrs::start(&electrs, &node).unwrap();
+    server.wait_for_height(101).unwrap();
+
+    let mut client = server.connect().unwrap();
+    let address = node.new_address().unwrap();
+    let script_hash = electrum_script_hash(&address);
+    let status = client
+        .call("blockchain.scripthash.subscribe", json!([script_hash]))
+        .unwrap();
+    assert!(status.is_null());
+
+    let txid = node.send(&address, 1.0).unwrap();
+    let notification = client.notification().unwrap();
+    assert_eq!(notification["method"], "blockchain.scripthash.subscribe");
+    let history = client
+        .call("blockchain.scripthash.get_history", json!([script_hash]))
+        .unwrap();
+    assert_eq!(history[0]["tx_hash"], txid.to_hex());
+    assert_eq!(history[0]["height"], 0); // in mempool
+    assert!(history[0]["fee"].as_u64().unwrap() > 0);
+
+    let blocks = node.mine(1).unwrap();
+    server.wait_for_height(102).unwrap();
+    client.notification().unwrap();
+    let history = client
+        .call("blockchain.scripthash.get_history", json!([script_hash]))
+        .unwrap();
+    assert_eq!(history, json!([{"tx_hash": txid.to_hex(), "height": 102}]));
+
+    // replace the confirming block by a longer chain, which confirms the transaction again
+    node.invalidate(&blocks[0]).unwrap();
+    let blocks = node.mine(2).unwrap();
+    server.wait_for_height(103).unwrap();
+    let header = client
+        .call("blockchain.block.header", json!([102]))
+        .unwrap();
+    let expected = node
+        .rpc("getblockheader", json!([blocks[0].to_hex(), false]))
+        .unwrap();
+    assert_eq!(header, expected);
+    let history = client
+        .call("blockchain.scripthash.get_history", json!([script_hash]))
+        .unwrap();
+    assert_eq!(history, json!([{"tx_hash": txid.to_hex(), "height": 102}]));
+}